@@ -0,0 +1,16 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // the C program of the ffi tests calls the emulator through its header, like a host does.
+    // It only defines `mips_ffi_test`, which the tests call.
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=include/mips_emulator.h");
+        println!("cargo:rerun-if-changed=tests/ffi/ffi_test.c");
+        cc::Build::new()
+            .file("tests/ffi/ffi_test.c")
+            .include("include")
+            .warnings_into_errors(true)
+            .compile("mips_ffi_test");
+    }
+}
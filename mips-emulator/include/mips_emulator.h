@@ -0,0 +1,162 @@
+#ifndef MIPS_EMULATOR_H
+#define MIPS_EMULATOR_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * the call succeeded.
+ */
+#define MIPS_VM_OK 0
+
+/**
+ * `mips_vm_step` stopped as the guest exited, now or before the call.
+ */
+#define MIPS_VM_EXITED 1
+
+/**
+ * a pointer argument was null.
+ */
+#define MIPS_VM_ERR_NULL -1
+
+/**
+ * the ELF, the config or the memory range is invalid.
+ */
+#define MIPS_VM_ERR_INVALID_ARG -2
+
+/**
+ * a step failed, the error of the emulator is the message.
+ */
+#define MIPS_VM_ERR_EMULATOR -3
+
+/**
+ * a callback of the oracle returned a non-zero code.
+ */
+#define MIPS_VM_ERR_ORACLE -4
+
+/**
+ * the emulator panicked, the `MipsVm` should be freed.
+ */
+#define MIPS_VM_ERR_PANIC -5
+
+/**
+ * the output callback returned a non-zero code.
+ */
+#define MIPS_VM_ERR_OUTPUT -6
+
+/**
+ * MipsVm is an emulator loaded from an ELF, the opaque handle of the C ABI.
+ */
+typedef struct MipsVm MipsVm;
+
+/**
+ * Serves the preimage of the 32 bytes `key`: writes up to `cap` bytes of it to `buf` and its
+ * length to `len`. A preimage longer than `cap` is asked again with a buffer of its length.
+ * Returns 0, or a non-zero code failing the step.
+ */
+typedef int (*MipsGetPreimageFn)(void *ctx,
+                                 const uint8_t *key,
+                                 uint8_t *buf,
+                                 size_t cap,
+                                 size_t *len);
+
+/**
+ * Receives a complete hint of `len` bytes. Returns 0, or a non-zero code failing the step that
+ * sent the hint, which is executed nonetheless.
+ */
+typedef int (*MipsHintFn)(void *ctx, const uint8_t *hint, size_t len);
+
+/**
+ * Receives `len` bytes the guest wrote to `fd`, 1 for stdout and 2 for stderr. Returns 0, or a
+ * non-zero code failing the step that wrote them, which is executed nonetheless.
+ */
+typedef int (*MipsWriteFn)(void *ctx, int fd, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Loads the ELF of `elf_len` bytes at `elf` like `State::load_elf`, with the config JSON
+ * `config_json`, null or empty for the default config. The guest has no preimages until
+ * `mips_vm_register_oracle`, and its output is discarded until `mips_vm_set_output`. Returns
+ * null on failure.
+ *
+ * # Safety
+ * `elf` points to `elf_len` readable bytes, `config_json` is null or a NUL terminated string.
+ */
+MipsVm *mips_vm_new(const uint8_t *elf, size_t elf_len, const char *config_json);
+
+/**
+ * Executes `n` steps, fewer if the guest exits. Returns `MIPS_VM_EXITED` once the guest exited,
+ * `MIPS_VM_OK` if it is still running. A failing step leaves the state like a failing
+ * `InstrumentedState::step` does, a failing hint or output callback fails the step after it
+ * executed.
+ *
+ * # Safety
+ * `vm` is null or was returned by `mips_vm_new` and not freed.
+ */
+int mips_vm_step(MipsVm *vm, uint64_t n);
+
+/**
+ * Writes the 32 bytes hash of the state to `out`, see `InstrumentedState::state_hash`.
+ *
+ * # Safety
+ * `vm` is null or a live `MipsVm`, `out` is null or points to 32 writable bytes.
+ */
+int mips_vm_state_hash(MipsVm *vm, uint8_t *out);
+
+/**
+ * Serves the preimages from `get` and sends the hints to `hint`, which may be null, both called
+ * with `ctx`. Replaces the oracle registered before.
+ *
+ * # Safety
+ * `vm` is null or a live `MipsVm`. The callbacks may be called with `ctx` by every step until
+ * the oracle is replaced or `vm` is freed.
+ */
+int mips_vm_register_oracle(MipsVm *vm, MipsGetPreimageFn get, MipsHintFn hint, void *ctx);
+
+/**
+ * Sends the output of the guest to stdout and stderr to `write`, called with `ctx`, or
+ * discards it if `write` is null. Replaces the callback set before.
+ *
+ * # Safety
+ * `vm` is null or a live `MipsVm`. The callback may be called with `ctx` by every step until
+ * it is replaced or `vm` is freed.
+ */
+int mips_vm_set_output(MipsVm *vm, MipsWriteFn write, void *ctx);
+
+/**
+ * Copies the `len` bytes of the guest memory from `addr` to `out`, the unmapped ones are zero.
+ * Fails if the range goes past the end of the address space.
+ *
+ * # Safety
+ * `vm` is null or a live `MipsVm`, `out` is null or points to `len` writable bytes.
+ */
+int mips_vm_read_mem(MipsVm *vm, uint32_t addr, uint8_t *out, size_t len);
+
+/**
+ * Frees `vm`, null is ignored.
+ *
+ * # Safety
+ * `vm` is null or a live `MipsVm`, it is not used after the call.
+ */
+void mips_vm_free(MipsVm *vm);
+
+/**
+ * Returns the message of the last failing call of the calling thread, null if none failed.
+ * The string is valid until the next failing call of the thread. A Go host locks the goroutine
+ * to its thread around the call and this one.
+ */
+const char *mips_vm_last_error_msg(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* MIPS_EMULATOR_H */
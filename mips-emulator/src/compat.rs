@@ -0,0 +1,3 @@
+//! interoperability with the formats of other MIPS fault proof VMs.
+
+pub mod cannon;
@@ -0,0 +1,277 @@
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Deserializer};
+use crate::error::EmulatorError;
+use crate::hash::{HashFunction, Hasher32, Keccak256Hasher};
+use crate::memory::Memory;
+use crate::pre_image::{EmptyPreimageOracle, PreimageOracle};
+use crate::state::{
+    InstrumentedState, State, STATE_WITNESS_SIZE, WITNESS_EXITED, WITNESS_EXIT_CODE, WITNESS_PC,
+    WITNESS_NEXT_PC,
+};
+use crate::witness::{MemoryAccess, MemoryOperation, StepKind};
+
+/// the size of a memory proof: the 32 bytes leaf holding the word, then the 27 siblings from the
+/// leaf up to the root.
+pub const PROOF_SIZE: usize = 28 * 32;
+
+/// OneStepInput is the proof of a step produced by Cannon, `cannon run --proof-at`: the
+/// pre-state witness, the proofs of the instruction and of the memory word the step accesses,
+/// and the preimage the step reads, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OneStepInput {
+    pub step: u64,
+    /// the hash of `state_data`, checked if present.
+    #[serde(default, deserialize_with = "deserialize_hash")]
+    pub pre: Option<[u8; 32]>,
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub state_data: Vec<u8>,
+    /// the instruction proof, followed by the memory proof if the step accesses memory.
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub proof_data: Vec<u8>,
+    #[serde(default, deserialize_with = "deserialize_hash")]
+    pub oracle_key: Option<[u8; 32]>,
+    /// the preimage, including its 8 bytes length prefix.
+    #[serde(default, deserialize_with = "deserialize_hex")]
+    pub oracle_value: Vec<u8>,
+    #[serde(default)]
+    pub oracle_offset: Option<u32>,
+}
+
+/// OneStepOutput is the post-state of the step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneStepOutput {
+    /// the witness encoding of the post-state, its memory root computed from the proofs.
+    pub post_state: Vec<u8>,
+    pub post_hash: [u8; 32],
+}
+
+#[derive(Debug)]
+pub enum OneStepError {
+    Json(serde_json::Error),
+    /// the input is malformed, or the state data doesn't hash to `pre`.
+    InvalidInput(String),
+    /// the proof of the word at `addr` doesn't lead to the memory root of the pre-state.
+    InvalidProof { addr: u32 },
+    /// the step accessed the word at `addr`, which none of the proofs covers.
+    UncoveredMemory { addr: u32 },
+    Emulator(EmulatorError),
+}
+
+impl Display for OneStepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OneStepError::Json(err) => write!(f, "invalid proof json: {}", err),
+            OneStepError::InvalidInput(msg) => write!(f, "invalid one step input: {}", msg),
+            OneStepError::InvalidProof { addr } => {
+                write!(f, "invalid memory proof for 0x{:x}", addr)
+            }
+            OneStepError::UncoveredMemory { addr } => {
+                write!(f, "memory at 0x{:x} is not covered by the proofs", addr)
+            }
+            OneStepError::Emulator(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for OneStepError {}
+
+impl From<EmulatorError> for OneStepError {
+    fn from(err: EmulatorError) -> Self {
+        OneStepError::Emulator(err)
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(s.strip_prefix("0x").unwrap_or(s))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let s = <&str>::deserialize(d)?;
+    decode_hex(s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_hash<'de, D: Deserializer<'de>>(d: D) -> Result<Option<[u8; 32]>, D::Error> {
+    let s = <&str>::deserialize(d)?;
+    let bytes = decode_hex(s).map_err(serde::de::Error::custom)?;
+    let hash = bytes.try_into()
+        .map_err(|_| serde::de::Error::custom("expected 32 bytes"))?;
+    Ok(Some(hash))
+}
+
+impl OneStepInput {
+    pub fn from_json(json: &str) -> Result<Self, OneStepError> {
+        serde_json::from_str(json).map_err(OneStepError::Json)
+    }
+}
+
+/// Serves the one preimage of the input, without its length prefix.
+struct OneStepOracle {
+    key: [u8; 32],
+    data: Vec<u8>,
+}
+
+impl PreimageOracle for OneStepOracle {
+    fn hint(&mut self, _v: &[u8]) {}
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        if k != self.key {
+            return Err(EmulatorError::PreimageNotFound { key: k });
+        }
+        Ok(self.data.clone())
+    }
+}
+
+/// Returns Cannon's VM status of the witness encoding `state_data`: 0 after an exit with code 0,
+/// 1 with code 1, 2 with any other code, which is a panic, and 3 while the VM runs.
+fn vm_status(state_data: &[u8]) -> u8 {
+    match (state_data[WITNESS_EXITED] & 1 != 0, state_data[WITNESS_EXIT_CODE]) {
+        (false, _) => 3,
+        (true, 0) => 0,
+        (true, 1) => 1,
+        (true, _) => 2,
+    }
+}
+
+/// Returns Cannon's hash of the witness encoding `state_data`: its keccak256 with the first
+/// byte replaced by the VM status.
+pub fn state_hash(state_data: &[u8]) -> [u8; 32] {
+    let mut hash = Keccak256Hasher.hash(state_data);
+    hash[0] = vm_status(state_data);
+    hash
+}
+
+/// Returns the memory root `proof` leads to from its leaf holding `addr`.
+fn proof_root(hasher: &dyn Hasher32, addr: u32, leaf: &[u8; 32], proof: &[u8]) -> [u8; 32] {
+    let mut node = *leaf;
+    for i in 1..28 {
+        let sibling: [u8; 32] = proof[32 * i..32 * (i + 1)].try_into().unwrap();
+        node = if (addr >> (4 + i)) & 1 != 0 {
+            hasher.hash_pair(&sibling, &node)
+        } else {
+            hasher.hash_pair(&node, &sibling)
+        };
+    }
+    node
+}
+
+/// Checks `proof` of the word at `addr` against `root`, and writes its leaf into `memory`.
+fn load_proof(
+    memory: &mut Memory,
+    hasher: &dyn Hasher32,
+    root: &[u8; 32],
+    addr: u32,
+    proof: &[u8],
+) -> Result<(), OneStepError> {
+    let leaf: [u8; 32] = proof[..32].try_into().unwrap();
+    if proof_root(hasher, addr, &leaf, proof) != *root {
+        return Err(OneStepError::InvalidProof { addr });
+    }
+    for (i, word) in leaf.chunks(4).enumerate() {
+        let word = u32::from_be_bytes(word.try_into().unwrap());
+        memory.set_memory((addr & !31) + 4 * i as u32, word)?;
+    }
+    Ok(())
+}
+
+/// Executes the step on `state`, returns the memory it read and wrote.
+fn step_accesses(
+    state: Box<State>,
+    oracle: Box<dyn PreimageOracle>,
+) -> Result<(Box<InstrumentedState>, Vec<MemoryAccess>), OneStepError> {
+    let mut is = InstrumentedState::new(state, oracle);
+    is.discard_output();
+    let (wit, _, mut accesses) = is.step(true)?;
+    if let StepKind::Syscall(syscall) = &wit.kind {
+        accesses.extend(&syscall.mem_ops);
+    }
+    Ok((is, accesses))
+}
+
+/// Executes the step of `input` on a state holding only the memory covered by its proofs, like
+/// the on-chain verifier does. The memory root of the post-state is computed from the memory
+/// proof and the word the step wrote. The memory tree and the state hash are Cannon's: keccak256,
+/// with the VM status in the first byte of the state hash, see `state_hash`.
+pub fn execute_one_step(input: &OneStepInput) -> Result<OneStepOutput, OneStepError> {
+    execute_step(input, HashFunction::Keccak256, state_hash)
+}
+
+/// Executes the step of `input` like `execute_one_step`, with the memory tree hashed by
+/// `memory_hash` and the state witness hashed by `hash`, for the proofs of the emulator.
+pub(crate) fn execute_step(
+    input: &OneStepInput,
+    memory_hash: HashFunction,
+    hash: fn(&[u8]) -> [u8; 32],
+) -> Result<OneStepOutput, OneStepError> {
+    let hasher = memory_hash.hasher();
+    let state_data = &input.state_data;
+    if state_data.len() != STATE_WITNESS_SIZE {
+        return Err(OneStepError::InvalidInput(format!(
+            "state data is {} bytes, expected {}", state_data.len(), STATE_WITNESS_SIZE)));
+    }
+    if input.pre.is_some_and(|pre| pre != hash(state_data)) {
+        return Err(OneStepError::InvalidInput("state data does not hash to pre".to_string()));
+    }
+    let proofs = &input.proof_data;
+    if proofs.is_empty() || proofs.len() % PROOF_SIZE != 0 || proofs.len() > 2 * PROOF_SIZE {
+        return Err(OneStepError::InvalidInput(format!(
+            "proof data is {} bytes, expected one or two proofs", proofs.len())));
+    }
+    let (insn_proof, mem_proof) = proofs.split_at(PROOF_SIZE);
+    let root: [u8; 32] = state_data[..32].try_into().unwrap();
+    let pc = u32::from_be_bytes(state_data[WITNESS_PC..WITNESS_NEXT_PC].try_into().unwrap());
+
+    let oracle = || -> Result<Box<dyn PreimageOracle>, OneStepError> {
+        match input.oracle_key {
+            Some(key) if input.oracle_value.len() >= 8 => Ok(Box::new(OneStepOracle {
+                key,
+                data: input.oracle_value[8..].to_vec(),
+            })),
+            Some(_) => Err(OneStepError::InvalidInput("oracle value without length prefix".to_string())),
+            None => Ok(Box::new(EmptyPreimageOracle)),
+        }
+    };
+
+    let mut memory = Memory::new();
+    load_proof(&mut memory, hasher, &root, pc, insn_proof)?;
+    let mut covered = vec![pc & !31];
+
+    // the accessed address only depends on the registers and the instruction, a first run on
+    // the instruction alone finds the word the memory proof is for
+    let state = State::decode_witness(state_data, memory.clone()).unwrap();
+    let (_, accesses) = step_accesses(state, oracle()?)?;
+    let mem_addr = accesses.iter()
+        .find(|access| access.op != MemoryOperation::Fetch)
+        .map(|access| access.addr);
+    if let Some(addr) = mem_addr.filter(|_| !mem_proof.is_empty()) {
+        load_proof(&mut memory, hasher, &root, addr, mem_proof)?;
+        covered.push(addr & !31);
+    }
+
+    let state = State::decode_witness(state_data, memory).unwrap();
+    let (mut is, accesses) = step_accesses(state, oracle()?)?;
+    let mut post_root = root;
+    for MemoryAccess { addr, op, .. } in accesses {
+        if !covered.contains(&(addr & !31)) {
+            return Err(OneStepError::UncoveredMemory { addr });
+        }
+        if op == MemoryOperation::Write {
+            // only the word of the memory proof may be written, its siblings are unchanged
+            if mem_proof.is_empty() || Some(addr & !31) != mem_addr.map(|addr| addr & !31) {
+                return Err(OneStepError::UncoveredMemory { addr });
+            }
+            let mut leaf = [0u8; 32];
+            let endianness = is.state.memory.endianness();
+            for i in 0..8 {
+                let word = is.state.memory.get_memory((addr & !31) + 4 * i as u32);
+                leaf[4 * i..4 * i + 4].copy_from_slice(&endianness.word_to_bytes(word));
+            }
+            post_root = proof_root(hasher, addr, &leaf, mem_proof);
+        }
+    }
+
+    let mut post_state = is.state.encode_witness();
+    post_state[..32].copy_from_slice(&post_root);
+    let post_hash = hash(&post_state);
+    Ok(OneStepOutput { post_state, post_hash })
+}
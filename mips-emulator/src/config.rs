@@ -0,0 +1,131 @@
+use crate::hash::HashFunction;
+use crate::hint::DEFAULT_MAX_HINT_SIZE;
+use crate::memory::{Endianness, DEFAULT_MAX_READ_RANGE, DEFAULT_MEMORY_HASH};
+use crate::journal::JournalConfig;
+use crate::layout::NULL_GUARD_END;
+
+/// ExecutionMode decides what the emulator does on anomalies of the guest: divisions by zero and
+/// misaligned accesses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// fails the step with an error.
+    Strict,
+    /// continues with whatever result the emulator computes, like the reference traces do.
+    #[default]
+    Lenient,
+}
+
+/// UnknownSyscallPolicy decides what a syscall the emulator doesn't implement returns. The
+/// syscalls the guest runtimes make for nothing, like close or rt_sigaction, succeed regardless.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownSyscallPolicy {
+    /// succeeds with v0 = 0 without doing anything, like Cannon does.
+    SilentZero,
+    /// fails with v0 = -1 and ENOSYS in v1, like Linux does.
+    #[default]
+    Enosys,
+    /// fails the step with `UnknownSyscall`.
+    Fault,
+}
+
+/// VmConfig holds the options of the emulator that are not part of the VM state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmConfig {
+    /// seed of the deterministic random stream served by the getrandom syscall.
+    pub random_seed: [u8; 32],
+    /// the memory pages the host allocates at most for the guest, unlimited if none. An mmap
+    /// larger than the pages left fails with ENOMEM, touching a page past the limit ends the
+    /// run with `VmStatus::HostOom`.
+    pub max_host_pages: Option<usize>,
+    /// preimage reads copy up to the requested count per syscall rather than at most the 4 bytes
+    /// of a word. Off by default, as Cannon only supports word sized reads.
+    pub wide_preimage_io: bool,
+    pub mode: ExecutionMode,
+    /// what an unknown syscall returns. The journal records every unknown syscall, whatever the
+    /// policy.
+    pub unknown_syscall: UnknownSyscallPolicy,
+    /// what an instruction the emulator doesn't implement does: `Strict` fails the step with
+    /// `InvalidOpcode`, or `UnsupportedCoprocessor` for the COP1 and COP2 ones, `Lenient` skips
+    /// it with a warning, see `InstrumentedState::skipped_instructions`. Strict by default, for
+    /// bring-up only.
+    pub invalid_opcodes: ExecutionMode,
+    /// fails the step with `UnpredictableDelaySlot` on a branch or jump in the delay slot of a
+    /// branch not taken too, rather than executing it. The delay slots of the taken branches
+    /// and jumps are always checked.
+    pub strict_delay_slots: bool,
+    /// fails the step of a branch or jump with `BadPc` when its target can't be fetched, rather
+    /// than the step at the target.
+    pub eager_pc_check: bool,
+    /// fails the step with `StackOverflow` on a load or store in the guard page below the stack
+    /// limit of the `MemoryLayout`.
+    pub stack_guard: bool,
+    /// fails the step with `NullAccess` on a load or store below `null_guard_end`, so a null
+    /// pointer dereference doesn't read zeros. Instruction fetches are not checked.
+    pub null_guard: bool,
+    /// the end of the addresses guarded by `null_guard`. A program linked below it lowers the
+    /// guard to its first segment.
+    pub null_guard_end: u32,
+    /// fails the step with `WriteToReadOnly` on a store into the text of the program, the
+    /// executable segments of the ELF. Off for guests modifying their own code.
+    pub protect_text: bool,
+    /// fails the step with `HiLoHazard` on a mfhi/mflo within two instructions of a mult/div,
+    /// which real MIPS CPUs leave unpredictable.
+    pub hilo_hazards: bool,
+    /// fails the step with `OversizedHint` on a hint length prefix over it, so a guest can't
+    /// make the host buffer a huge or malformed hint.
+    pub max_hint_size: usize,
+    /// the write syscalls write at most it of a buffer, so a guest can't make the host copy a
+    /// huge buffer at once. Buffers wrapping the address space fail with EFAULT.
+    pub max_read_range: u32,
+    /// the hash of the state witness, `InstrumentedState::state_hash`.
+    pub state_hash: HashFunction,
+    /// the hash of the memory merkle tree, its root is in the state witness.
+    pub memory_hash: HashFunction,
+    /// the byte order of the guest, big endian like Cannon unless the program is a mipsel one.
+    /// The loader only accepts ELFs of this byte order. `InstrumentedState` runs the state in
+    /// its own byte order and sets it here.
+    pub endianness: Endianness,
+    /// enables the event journal.
+    pub journal: Option<JournalConfig>,
+    /// `InstrumentedState::run` samples the state every this many steps, and ends with
+    /// `VmStatus::LivelockSuspected` once a sample repeats with no memory write in between, see
+    /// `DEFAULT_LIVELOCK_INTERVAL`. Disabled if none, the detector never changes the state.
+    pub livelock_interval: Option<u64>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            random_seed: [0; 32],
+            max_host_pages: None,
+            wide_preimage_io: false,
+            mode: ExecutionMode::default(),
+            unknown_syscall: UnknownSyscallPolicy::default(),
+            invalid_opcodes: ExecutionMode::Strict,
+            strict_delay_slots: false,
+            eager_pc_check: false,
+            stack_guard: false,
+            null_guard: false,
+            null_guard_end: NULL_GUARD_END,
+            protect_text: false,
+            hilo_hazards: false,
+            max_hint_size: DEFAULT_MAX_HINT_SIZE,
+            max_read_range: DEFAULT_MAX_READ_RANGE,
+            state_hash: HashFunction::Keccak256,
+            memory_hash: DEFAULT_MEMORY_HASH,
+            endianness: Endianness::Big,
+            journal: None,
+            livelock_interval: None,
+        }
+    }
+}
+
+impl VmConfig {
+    /// The config matching the behavior of Cannon, for guests built against it.
+    pub fn cannon() -> Self {
+        Self {
+            unknown_syscall: UnknownSyscallPolicy::SilentZero,
+            ..Default::default()
+        }
+    }
+}
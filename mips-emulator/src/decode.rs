@@ -0,0 +1,325 @@
+//! Decoding of the instruction fields: the I-type immediates shared by the operand fetch of
+//! `mips_step` and the ALU, the control transfers, the alignment of loads and stores, and the
+//! `OpcodeId` of an instruction along with the coverage of the executed ones.
+
+use crate::opcode_id::OpcodeId;
+
+/// Returns whether the immediate of `opcode` is zero extended, only andi, ori and xori are.
+/// Every other immediate is sign extended, sltiu included, which then compares unsigned.
+pub fn zero_extends_imm(opcode: u32) -> bool {
+    matches!(opcode, 0xc | 0xd | 0xe)
+}
+
+/// Returns the 16 bits immediate of `insn` extended to 32 bits as its opcode requires.
+pub fn imm(insn: u32) -> u32 {
+    if zero_extends_imm(insn >> 26) {
+        insn & 0xffff
+    } else {
+        insn as u16 as i16 as i32 as u32
+    }
+}
+
+/// Returns the SPECIAL function the arithmetic/logic immediate `opcode` executes as, on the
+/// extended immediate in place of rt.
+pub fn arith_imm_fun(opcode: u32) -> Option<u32> {
+    match opcode {
+        0x8 => Some(0x20), // addi -> add
+        0x9 => Some(0x21), // addiu -> addu
+        0xa => Some(0x2a), // slti -> slt
+        0xb => Some(0x2b), // sltiu -> sltu
+        0xc => Some(0x24), // andi -> and
+        0xd => Some(0x25), // ori -> or
+        0xe => Some(0x26), // xori -> xor
+        _ => None,
+    }
+}
+
+/// Returns whether `insn` transfers control: the branches, j/jal and jr/jalr.
+pub fn is_control_transfer(insn: u32) -> bool {
+    match insn >> 26 {
+        0 => matches!(insn & 0x3f, 0x8 | 0x9),
+        1 | 2 | 3 | 4 | 5 | 6 | 7 => true,
+        _ => false,
+    }
+}
+
+/// Returns the target of the j/jal `insn` with its delay slot at `next_pc`: like MIPS32 CPUs,
+/// the 26 bits index shifted by 2 replaces the low 28 bits of the delay slot address, so jumps
+/// stay in the 256 MiB region of their delay slot. The index is not sign extended.
+pub fn jump_target(next_pc: u32, insn: u32) -> u32 {
+    (next_pc & 0xF0000000) | ((insn & 0x03ffFFff) << 2)
+}
+
+/// Returns the result of the ext/ins `insn` on the values of its rs and rt registers, none if
+/// the bitfield is UNPREDICTABLE: an ext field crossing bit 31, lsb + size > 32, or an ins field
+/// whose msb is below its lsb. The lsb of the field is in the shamt bits, the rd bits hold
+/// size - 1 for ext and the msb of the field for ins.
+pub fn bitfield(insn: u32, rs: u32, rt: u32) -> Option<u32> {
+    let (lsb, msb) = ((insn >> 6) & 0x1f, (insn >> 11) & 0x1f);
+    let mask = |size: u32| ((1u64 << size) - 1) as u32;
+    match insn & 0x3f {
+        // ext: rt = rs[lsb + size - 1 : lsb]
+        0x00 => {
+            let size = msb + 1;
+            (lsb + size <= 32).then(|| (rs >> lsb) & mask(size))
+        }
+        // ins: rt[msb : lsb] = rs[msb - lsb : 0]
+        0x04 => {
+            let field = mask(msb.checked_sub(lsb)? + 1) << lsb;
+            Some((rt & !field) | ((rs << lsb) & field))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the low address bits the load/store `opcode` requires to be zero: the half word
+/// and word accesses are aligned, lwl/lwr/swl/swr and the byte accesses are not.
+pub fn alignment_mask(opcode: u32) -> u32 {
+    match opcode {
+        0x21 | 0x25 | 0x29 => 1, // lh, lhu, sh
+        0x23 | 0x2b | 0x30 | 0x38 => 3, // lw, sw, ll, sc
+        _ => 0,
+    }
+}
+
+/// Returns the coprocessor of `insn`, none for the instructions of the integer unit: 1 for the
+/// floating point instructions, the COP1 and COP1X opcodes, the FP loads and stores and
+/// movf/movt, which test the FP condition codes; 2 for the COP2 opcode and its loads and stores.
+pub fn coprocessor(insn: u32) -> Option<u32> {
+    match insn >> 26 {
+        0x11 | 0x13 => Some(1), // cop1, cop1x
+        0x31 | 0x35 | 0x39 | 0x3d => Some(1), // lwc1, ldc1, swc1, sdc1
+        0x00 if insn & 0x3f == 0x01 => Some(1), // movf, movt
+        0x12 => Some(2), // cop2
+        0x32 | 0x36 | 0x3a | 0x3e => Some(2), // lwc2, ldc2, swc2, sdc2
+        _ => None,
+    }
+}
+
+/// The instructions the emulator executes. bltzal and bgezal are decoded, but not implemented.
+pub const IMPLEMENTED: [OpcodeId; 73] = {
+    use OpcodeId::*;
+    [
+        ADD, ADDU, SUB, SUBU, ADDI, ADDIU, AND, ANDI, XOR, XORI, OR, ORI, NOR, LUI, SLT, SLTI,
+        SLTIU, SLTU, MOVZ, MOVN, CLZ, CLO, SLL, SLLV, SRA, SRAV, SRL, SRLV, ROTR, ROTRV, MULT, MULTU, MUL, DIV,
+        DIVU, MFHI, MFLO, MTHI, MTLO, BEQ, BGEZ, BGTZ, BLEZ, BLTZ, BNE, J, JAL, JALR, JR, SYSCALL,
+        LB, LBU, LH, LHU, LW, LWL, LWR, LL, SB, SH, SW, SWL, SWR, SC, RDHWR, EXT, INS, SEB, SEH,
+        WSBH, CACHE, BREAK, SYNC,
+    ]
+};
+
+/// Returns the instruction `insn` encodes, none if the emulator doesn't execute it.
+pub fn opcode_id(insn: u32) -> Option<OpcodeId> {
+    use OpcodeId::*;
+    let id = match (insn >> 26, insn & 0x3f) {
+        (0, fun) => match fun {
+            0x00 => SLL,
+            // the R bit of rotr/rotrv, in the rs and shamt bits of srl/srlv
+            0x02 if insn & (1 << 21) != 0 => ROTR,
+            0x02 => SRL,
+            0x03 => SRA,
+            0x04 => SLLV,
+            0x06 if insn & (1 << 6) != 0 => ROTRV,
+            0x06 => SRLV,
+            0x07 => SRAV,
+            0x08 => JR,
+            0x09 => JALR,
+            0x0a => MOVZ,
+            0x0b => MOVN,
+            0x0c => SYSCALL,
+            0x0d => BREAK,
+            0x0f => SYNC,
+            0x10 => MFHI,
+            0x11 => MTHI,
+            0x12 => MFLO,
+            0x13 => MTLO,
+            0x18 => MULT,
+            0x19 => MULTU,
+            0x1a => DIV,
+            0x1b => DIVU,
+            0x20 => ADD,
+            0x21 => ADDU,
+            0x22 => SUB,
+            0x23 => SUBU,
+            0x24 => AND,
+            0x25 => OR,
+            0x26 => XOR,
+            0x27 => NOR,
+            0x2a => SLT,
+            0x2b => SLTU,
+            _ => return None,
+        },
+        // REGIMM selects the instruction with the rt bits, the linking bltzal and bgezal are
+        // not implemented
+        (1, _) => match (insn >> 16) & 0x1f {
+            0x00 => BLTZ,
+            0x01 => BGEZ,
+            _ => return None,
+        },
+        (0x02, _) => J,
+        (0x03, _) => JAL,
+        (0x04, _) => BEQ,
+        (0x05, _) => BNE,
+        (0x06, _) => BLEZ,
+        (0x07, _) => BGTZ,
+        (0x08, _) => ADDI,
+        (0x09, _) => ADDIU,
+        (0x0a, _) => SLTI,
+        (0x0b, _) => SLTIU,
+        (0x0c, _) => ANDI,
+        (0x0d, _) => ORI,
+        (0x0e, _) => XORI,
+        (0x0f, _) => LUI,
+        (0x1c, 0x02) => MUL,
+        (0x1c, 0x20) => CLZ,
+        (0x1c, 0x21) => CLO,
+        (0x1f, 0x00) => EXT,
+        (0x1f, 0x04) => INS,
+        (0x1f, 0x20) => match (insn >> 6) & 0x1f {
+            0x02 => WSBH,
+            0x10 => SEB,
+            0x18 => SEH,
+            _ => return None,
+        },
+        // rdhwr reads the hardware registers 0 (CPU number), 3 (cycle counter) and 29 (TLS
+        // base), the others raise Reserved Instruction
+        (0x1f, 0x3b) if matches!((insn >> 11) & 0x1f, 0 | 3 | 29) => RDHWR,
+        (0x20, _) => LB,
+        (0x21, _) => LH,
+        (0x22, _) => LWL,
+        (0x23, _) => LW,
+        (0x24, _) => LBU,
+        (0x25, _) => LHU,
+        (0x26, _) => LWR,
+        (0x28, _) => SB,
+        (0x29, _) => SH,
+        (0x2a, _) => SWL,
+        (0x2b, _) => SW,
+        (0x2e, _) => SWR,
+        (0x2f, _) => CACHE,
+        (0x30, _) => LL,
+        (0x38, _) => SC,
+        _ => return None,
+    };
+    Some(id)
+}
+
+/// Collects the instructions executed by the tests on the current thread, to find the
+/// implemented ones no test executes.
+#[cfg(test)]
+pub mod coverage {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use crate::opcode_id::OpcodeId;
+    use super::{IMPLEMENTED, opcode_id};
+
+    thread_local! {
+        static COVERED: RefCell<HashSet<OpcodeId>> = RefCell::new(HashSet::new());
+    }
+
+    /// Records the execution of `insn`, called by `mips_step` for every instruction.
+    pub fn record(insn: u32) {
+        if let Some(id) = opcode_id(insn) {
+            COVERED.with(|covered| covered.borrow_mut().insert(id));
+        }
+    }
+
+    pub fn reset() {
+        COVERED.with(|covered| covered.borrow_mut().clear());
+    }
+
+    /// Returns the implemented instructions not executed since the last reset.
+    pub fn report() -> Vec<OpcodeId> {
+        COVERED.with(|covered| {
+            let covered = covered.borrow();
+            IMPLEMENTED.iter().filter(|id| !covered.contains(id)).copied().collect()
+        })
+    }
+
+    /// Returns the first encoding of each implemented instruction, searching the opcode and
+    /// function bits with the bits the decoder also looks at: the R bits of rotr and rotrv,
+    /// the rt bits of REGIMM, the rd bits of rdhwr and the shamt bits of bshfl.
+    pub fn encodings() -> Vec<(OpcodeId, u32)> {
+        let mut found: Vec<(OpcodeId, u32)> = Vec::new();
+        for opcode in 0..0x40 {
+            for fun in 0..0x40 {
+                for bits in 0..0x40 {
+                    let (r, field) = (bits >> 5, bits & 0x1f);
+                    let insn = (opcode << 26) | (r << 21) | (field << 16) | (field << 11)
+                        | (field << 6) | (r << 6) | fun;
+                    match opcode_id(insn) {
+                        Some(id) if found.iter().all(|(other, _)| *other != id) => {
+                            found.push((id, insn))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Asserts every implemented instruction was executed since the last reset.
+    macro_rules! assert_full_coverage {
+        () => {
+            let missing = $crate::decode::coverage::report();
+            assert!(missing.is_empty(), "instructions not executed: {:?}", missing);
+        };
+    }
+    pub(crate) use assert_full_coverage;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opcode_id::{is_supported, OpcodeId};
+    use super::{arith_imm_fun, imm, IMPLEMENTED, opcode_id};
+    use super::coverage::encodings;
+
+    #[test]
+    fn test_imm_extension() {
+        for opcode in [0x8, 0x9, 0xa, 0xb] {
+            assert_eq!(imm((opcode << 26) | 0x8000), 0xffff8000);
+            assert_eq!(imm((opcode << 26) | 0x7fff), 0x7fff);
+        }
+        for opcode in [0xc, 0xd, 0xe] {
+            assert_eq!(imm((opcode << 26) | 0x8000), 0x8000);
+            assert_eq!(imm((opcode << 26) | 0x7fff), 0x7fff);
+        }
+        assert_eq!(arith_imm_fun(0xf), None);
+    }
+
+    #[test]
+    fn test_opcode_id() {
+        for id in IMPLEMENTED {
+            assert_eq!(IMPLEMENTED.iter().filter(|other| **other == id).count(), 1, "{:?}", id);
+        }
+        assert_eq!(opcode_id(0x0000000c), Some(OpcodeId::SYSCALL));
+        assert_eq!(opcode_id(0x7c03e83b), Some(OpcodeId::RDHWR));
+        assert_eq!(opcode_id(0x0000003f), None);
+    }
+
+    #[test]
+    fn test_supported_instructions() {
+        // the decoder tells apart exactly the implemented instructions
+        let mut decoded: Vec<OpcodeId> = encodings().iter().map(|(id, _)| *id).collect();
+        let mut implemented = IMPLEMENTED.to_vec();
+        decoded.sort_by_key(|id| format!("{:?}", id));
+        implemented.sort_by_key(|id| format!("{:?}", id));
+        assert_eq!(decoded, implemented);
+
+        // REGIMM has bltz and bgez only, rdhwr reads three hardware registers
+        for rt in 0..0x20 {
+            let insn = (1 << 26) | (rt << 16);
+            assert_eq!(is_supported(insn), rt < 2, "0x{:08x}", insn);
+        }
+        for hwr in 0..0x20 {
+            let insn = (0x1f << 26) | (hwr << 11) | 0x3b;
+            assert_eq!(is_supported(insn), matches!(hwr, 0 | 3 | 29), "0x{:08x}", insn);
+        }
+        // bshfl is told apart by the shamt bits
+        for shamt in 0..0x20 {
+            let insn = (0x1f << 26) | (shamt << 6) | 0x20;
+            assert_eq!(is_supported(insn), matches!(shamt, 0x02 | 0x10 | 0x18), "0x{:08x}", insn);
+        }
+    }
+}
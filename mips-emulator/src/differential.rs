@@ -0,0 +1,128 @@
+use std::fmt::{Display, Formatter};
+use crate::state::InstrumentedState;
+
+/// StateDiff is how two states differ, see `State::diff`. Each entry holds the value of the
+/// first state, then the one of the second.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    /// the differing fields of the states other than the registers and memory, by name.
+    pub fields: Vec<(&'static str, u64, u64)>,
+    pub preimage_key: Option<([u8; 32], [u8; 32])>,
+    /// the differing registers, by index.
+    pub registers: Vec<(usize, u32, u32)>,
+    /// the differing memory words, by address.
+    pub memory: Vec<(u32, u32, u32)>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+            && self.preimage_key.is_none()
+            && self.registers.is_empty()
+            && self.memory.is_empty()
+    }
+}
+
+impl Display for StateDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, a, b) in &self.fields {
+            writeln!(f, "{}: 0x{:x} != 0x{:x}", name, a, b)?;
+        }
+        if let Some((a, b)) = &self.preimage_key {
+            writeln!(f, "preimage_key: 0x{} != 0x{}", hex::encode(a), hex::encode(b))?;
+        }
+        for (i, a, b) in &self.registers {
+            writeln!(f, "${}: 0x{:08x} != 0x{:08x}", i, a, b)?;
+        }
+        for (addr, a, b) in &self.memory {
+            writeln!(f, "[0x{:08x}]: 0x{:08x} != 0x{:08x}", addr, a, b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Divergence is the first difference `run_lockstep` found between the two runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// the steps both runs executed when they were found to differ.
+    pub step: u64,
+    pub diff: StateDiff,
+    /// the errors of the last step of each run, they differ if the diff is empty.
+    pub errors: [Option<String>; 2],
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "diverged at step {}", self.step)?;
+        for (name, error) in ["a", "b"].iter().zip(&self.errors) {
+            if let Some(error) = error {
+                writeln!(f, "{} failed: {}", name, error)?;
+            }
+        }
+        write!(f, "{}", self.diff)
+    }
+}
+
+/// LockstepResult is the outcome of `run_lockstep`, with both runs in the state they stopped at.
+pub struct LockstepResult {
+    pub a: Box<InstrumentedState>,
+    pub b: Box<InstrumentedState>,
+    /// the steps both runs executed.
+    pub steps: u64,
+    /// the first difference found, none if the runs agreed until they stopped.
+    pub divergence: Option<Divergence>,
+    /// the error both runs failed the last step with, if they agree on it.
+    pub error: Option<String>,
+}
+
+/// Runs `a` and `b` a step at a time and compares their states after each step, until both
+/// exited, `max_steps` steps were executed or they diverge. The two runs keep their own config
+/// and oracle, to check that the options meant to be transparent don't change the execution.
+/// The hash functions of the configs must be the same, the hashes are compared.
+pub fn run_lockstep(
+    a: Box<InstrumentedState>,
+    b: Box<InstrumentedState>,
+    max_steps: u64,
+) -> LockstepResult {
+    run_lockstep_every(a, b, max_steps, 1)
+}
+
+/// Like `run_lockstep`, comparing the states every `interval` steps only, when a run exits or
+/// fails and after the last step. A divergence is then found up to `interval - 1` steps after
+/// the step it happened at, in exchange for hashing the memory less often.
+pub fn run_lockstep_every(
+    mut a: Box<InstrumentedState>,
+    mut b: Box<InstrumentedState>,
+    max_steps: u64,
+    interval: u64,
+) -> LockstepResult {
+    assert!(interval > 0, "the compare interval must be positive");
+    let hashes = |is: &InstrumentedState| (is.config().state_hash, is.config().memory_hash);
+    assert!(hashes(&a) == hashes(&b), "the runs must hash their states with the same functions");
+    let mut steps = 0;
+    let mut error = None;
+    let mut divergence = None;
+    while steps < max_steps && !(a.state.exited && b.state.exited) {
+        let errors = [
+            a.step(false).err().map(|e| e.to_string()),
+            b.step(false).err().map(|e| e.to_string()),
+        ];
+        steps += 1;
+
+        let failed = errors.iter().any(Option::is_some);
+        let exited = a.state.exited || b.state.exited;
+        if !(failed || exited || steps % interval == 0 || steps == max_steps) {
+            continue;
+        }
+        if errors[0] != errors[1] || a.state_hash() != b.state_hash() {
+            let diff = a.state.diff(&b.state);
+            divergence = Some(Divergence { step: steps, diff, errors });
+            break;
+        }
+        if failed {
+            error = errors[0].clone();
+            break;
+        }
+    }
+    LockstepResult { a, b, steps, divergence, error }
+}
@@ -0,0 +1,20 @@
+//! The MIPS Linux error numbers the syscalls return. A failed syscall returns -1 in v0 and the
+//! error number in v1 (a3 on linux), see `fail`.
+
+pub const EBADF: u32 = 9;
+pub const ECHILD: u32 = 10;
+pub const EAGAIN: u32 = 11;
+pub const ENOMEM: u32 = 12;
+pub const EFAULT: u32 = 14;
+pub const EINVAL: u32 = 22;
+pub const ESPIPE: u32 = 29;
+/// the number differs from the 38 of most other architectures.
+pub const ENOSYS: u32 = 89;
+
+/// the v0 of a failed syscall, -1.
+pub const SYSCALL_ERROR: u32 = 0xFFffFFff;
+
+/// Returns the (v0, v1) of a syscall failing with `errno`.
+pub fn fail(errno: u32) -> (u32, u32) {
+    (SYSCALL_ERROR, errno)
+}
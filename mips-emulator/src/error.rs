@@ -0,0 +1,247 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use crate::layout::LayoutError;
+use crate::opcode_id::OpcodeId;
+
+/// FaultContext locates the load or store that raised a memory error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultContext {
+    pub pc: u32,
+    /// the step of the faulting instruction, counted like `State::step`.
+    pub step: u64,
+    pub insn: u32,
+    pub opcode: Option<OpcodeId>,
+    /// the effective address, the base register plus the offset.
+    pub addr: u32,
+    pub base_reg: u32,
+    pub offset: i16,
+    /// the 32 bytes aligned block of memory holding `addr`, starting at `dump_addr`.
+    pub dump_addr: u32,
+    pub dump: [u8; 32],
+}
+
+impl FaultContext {
+    /// Returns the dump of the memory around the address, a line of 16 bytes per row.
+    pub fn hexdump(&self) -> String {
+        self.dump.chunks(16).enumerate().map(|(i, row)| {
+            let bytes: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:08x}: {}\n", self.dump_addr.wrapping_add(16 * i as u32), bytes.join(" "))
+        }).collect()
+    }
+}
+
+/// The alternate form `{:#}` appends the hexdump of the memory.
+impl Display for FaultContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mnemonic = match self.opcode {
+            Some(opcode) => format!("{:?}", opcode).to_lowercase(),
+            None => format!("0x{:08x}", self.insn),
+        };
+        write!(f, "{} ${}, {}(${}) at 0x{:x}, step {}, address 0x{:x}",
+               mnemonic, (self.insn >> 16) & 0x1f, self.offset, self.base_reg, self.pc, self.step,
+               self.addr)?;
+        if f.alternate() {
+            write!(f, "\n{}", self.hexdump())?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the context of a memory error after its message, if it has one.
+fn write_context(f: &mut Formatter<'_>, ctx: &Option<Box<FaultContext>>) -> std::fmt::Result {
+    match ctx {
+        Some(ctx) if f.alternate() => write!(f, " by {:#}", ctx),
+        Some(ctx) => write!(f, " by {}", ctx),
+        None => Ok(()),
+    }
+}
+
+/// BadPcReason tells why the emulator refused to fetch from a pc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadPcReason {
+    /// the pc is not aligned to 4 bytes.
+    Misaligned,
+    /// the pc is outside the text of the program, the executable segments of the ELF.
+    NotExecutable,
+    /// the pc is in a page never written, checked in strict mode for programs without a text.
+    Unmapped,
+}
+
+impl Display for BadPcReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BadPcReason::Misaligned => write!(f, "misaligned"),
+            BadPcReason::NotExecutable => write!(f, "outside the text of the program"),
+            BadPcReason::Unmapped => write!(f, "in an unmapped page"),
+        }
+    }
+}
+
+/// EmulatorError is returned when the emulator can not continue executing the guest.
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// the oracle does not know the preimage of the key.
+    PreimageNotFound { key: [u8; 32] },
+    /// the oracle failed to read the preimage of the key from `path`.
+    PreimageRead { key: [u8; 32], path: PathBuf, err: io::Error },
+    /// the oracle does not serve keys of this type, the first byte of the key.
+    UnsupportedKeyType { key: [u8; 32] },
+    /// the preimage does not hash to the key.
+    PreimageHashMismatch { key: [u8; 32] },
+    /// the oracle returned a preimage of the key different from the one it returned before.
+    OracleInconsistent { key: [u8; 32] },
+    Io(io::Error),
+    /// the instruction at `pc` is not supported, see `opcode_id::is_supported`.
+    InvalidOpcode { pc: u32, insn: u32 },
+    /// the instruction at `pc` is an instruction of coprocessor `coprocessor`, like the floating
+    /// point instructions of COP1. The emulator only executes integer instructions. `fmt` and
+    /// `funct` are bits 21-25 and 0-5 of the instruction, which tell the FP operation and its
+    /// format apart, add.s from add.d.
+    UnsupportedCoprocessor { pc: u32, insn: u32, coprocessor: u32, fmt: u32, funct: u32 },
+    /// allocating the page of `addr` would exceed the host page limit, `pages` are allocated.
+    /// The context is set when a store allocated the page.
+    HostOom { addr: u32, pages: usize, ctx: Option<Box<FaultContext>> },
+    /// the instruction at `pc`, in the delay slot of a branch or jump, is itself a control
+    /// transfer. Raised in the delay slot of a taken branch or jump like Cannon, and in every
+    /// delay slot in the strict mode of `VmConfig::strict_delay_slots`.
+    UnpredictableDelaySlot { pc: u32, insn: u32 },
+    /// the ext `insn` at `pc` has a bitfield crossing bit 31, or the ins one a msb below its lsb.
+    UnpredictableBitfield { pc: u32, insn: u32 },
+    /// the load or store at `pc` touched the stack guard page at `addr`. Only raised if
+    /// `VmConfig::stack_guard` is enabled.
+    StackOverflow { addr: u32, pc: u32, ctx: Option<Box<FaultContext>> },
+    /// the load or store at `pc` touched `addr` below `VmConfig::null_guard_end`, through a null
+    /// pointer. Only raised if `VmConfig::null_guard` is enabled.
+    NullAccess { addr: u32, pc: u32, ctx: Option<Box<FaultContext>> },
+    /// the guest called the syscall `num` the emulator doesn't know, only raised by
+    /// `UnknownSyscallPolicy::Fault`.
+    UnknownSyscall { num: u32, pc: u32 },
+    /// div or divu by zero at `pc`, only raised in strict mode. In lenient mode hi and lo are
+    /// left unchanged, like Cannon.
+    DivideByZero { pc: u32 },
+    /// the load or store at `pc` accessed `addr` not aligned to its size, only raised in
+    /// strict mode.
+    MisalignedAccess { addr: u32, pc: u32, ctx: Option<Box<FaultContext>> },
+    /// the mfhi/mflo at `pc` reads hi/lo within two instructions of a mult/div. Only raised if
+    /// `VmConfig::hilo_hazards` is enabled.
+    HiLoHazard { pc: u32 },
+    /// the store at `pc` wrote `addr` in the text of the program. Only raised if
+    /// `VmConfig::protect_text` is enabled. The context is set when a store, not a syscall,
+    /// wrote it.
+    WriteToReadOnly { addr: u32, pc: u32, ctx: Option<Box<FaultContext>> },
+    /// the guest wrote a hint length prefix over `VmConfig::max_hint_size`.
+    OversizedHint { declared: u32 },
+    /// the instruction at `pc` can't be fetched. `jump` is the pc of the branch or jump to it,
+    /// set when `VmConfig::eager_pc_check` caught it before the delay slot.
+    BadPc { pc: u32, reason: BadPcReason, jump: Option<u32> },
+    /// a syscall would place memory across the regions of the `MemoryLayout`.
+    Layout(LayoutError),
+    /// the instruction at `pc` writes the register `reg`, there are only 32 general purpose
+    /// registers. Only a malformed decode writes one.
+    InvalidRegister { reg: u32, pc: u32 },
+}
+
+impl Display for EmulatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmulatorError::PreimageNotFound { key } => {
+                write!(f, "missing preimage for key 0x{}", hex::encode(key))
+            }
+            EmulatorError::PreimageRead { key, path, err } => {
+                write!(f, "failed to read preimage for key 0x{} from {}: {}",
+                       hex::encode(key), path.display(), err)
+            }
+            EmulatorError::UnsupportedKeyType { key } => {
+                write!(f, "unsupported type {} of preimage key 0x{}", key[0], hex::encode(key))
+            }
+            EmulatorError::PreimageHashMismatch { key } => {
+                write!(f, "preimage does not hash to key 0x{}", hex::encode(key))
+            }
+            EmulatorError::OracleInconsistent { key } => {
+                write!(f, "oracle returned different preimages for key 0x{}", hex::encode(key))
+            }
+            EmulatorError::Io(err) => write!(f, "io error: {}", err),
+            EmulatorError::InvalidOpcode { pc, insn } => {
+                write!(f, "invalid instruction 0x{:08x} at 0x{:x}", insn, pc)
+            }
+            EmulatorError::UnsupportedCoprocessor { pc, insn, coprocessor, fmt, funct } => write!(
+                f,
+                "instruction 0x{:08x} at 0x{:x} uses the unsupported coprocessor COP{} \
+                 (fmt 0x{:02x}, funct 0x{:02x})",
+                insn, pc, coprocessor, fmt, funct,
+            ),
+            EmulatorError::HostOom { addr, pages, ctx } => {
+                write!(f, "out of host memory at 0x{:x}, {} pages allocated", addr, pages)?;
+                write_context(f, ctx)
+            }
+            EmulatorError::UnpredictableDelaySlot { pc, insn } => {
+                write!(f, "control transfer 0x{:08x} in the delay slot at 0x{:x}", insn, pc)
+            }
+            EmulatorError::UnpredictableBitfield { pc, insn } => {
+                write!(f, "bitfield of 0x{:08x} at 0x{:x} is out of range: ext needs \
+                    lsb + size <= 32, ins needs msb >= lsb", insn, pc)
+            }
+            EmulatorError::StackOverflow { addr, pc, ctx } => {
+                write!(f, "stack overflow of 0x{:x} at 0x{:x}", addr, pc)?;
+                write_context(f, ctx)
+            }
+            EmulatorError::NullAccess { addr, pc, ctx } => {
+                write!(f, "null pointer access of 0x{:x} at 0x{:x}", addr, pc)?;
+                write_context(f, ctx)
+            }
+            EmulatorError::UnknownSyscall { num, pc } => {
+                write!(f, "unknown syscall {} at 0x{:x}", num, pc)
+            }
+            EmulatorError::DivideByZero { pc } => write!(f, "division by zero at 0x{:x}", pc),
+            EmulatorError::MisalignedAccess { addr, pc, ctx } => {
+                write!(f, "misaligned access of 0x{:x} at 0x{:x}", addr, pc)?;
+                write_context(f, ctx)
+            }
+            EmulatorError::HiLoHazard { pc } => {
+                write!(f, "hi/lo read too soon after a mult/div at 0x{:x}", pc)
+            }
+            EmulatorError::WriteToReadOnly { addr, pc, ctx } => {
+                write!(f, "write to read only 0x{:x} at 0x{:x}", addr, pc)?;
+                write_context(f, ctx)
+            }
+            EmulatorError::OversizedHint { declared } => {
+                write!(f, "hint of {} bytes exceeds the max hint size", declared)
+            }
+            EmulatorError::BadPc { pc, reason, jump } => {
+                write!(f, "bad pc 0x{:x}, {}", pc, reason)?;
+                match jump {
+                    Some(jump) => write!(f, ", jumped to at 0x{:x}", jump),
+                    None => Ok(()),
+                }
+            }
+            EmulatorError::Layout(err) => write!(f, "memory layout violation: {}", err),
+            EmulatorError::InvalidRegister { reg, pc } => {
+                write!(f, "write to invalid register {} at 0x{:x}", reg, pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::PreimageRead { err, .. } => Some(err),
+            EmulatorError::Io(err) => Some(err),
+            EmulatorError::Layout(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(err: io::Error) -> Self {
+        EmulatorError::Io(err)
+    }
+}
+
+impl From<LayoutError> for EmulatorError {
+    fn from(err: LayoutError) -> Self {
+        EmulatorError::Layout(err)
+    }
+}
@@ -0,0 +1,504 @@
+//! The C ABI of the emulator, for hosts embedding it in their process, declared by
+//! `include/mips_emulator.h`. No panic unwinds across the boundary: every entry point catches
+//! them and returns `MIPS_VM_ERR_PANIC`. A failing call returns a negative code, its message is
+//! kept for `mips_vm_last_error_msg`.
+//!
+//! A `MipsVm` is not thread safe, the host calls it from one thread at a time. The library a
+//! host links is built with
+//! `cargo rustc -p mips_emulator --release --features ffi --crate-type staticlib`, or `cdylib`.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::config::{ExecutionMode, UnknownSyscallPolicy, VmConfig};
+use crate::error::EmulatorError;
+use crate::memory::{copy_from_word, Endianness};
+use crate::pre_image::PreimageOracle;
+use crate::replay::ReplayImage;
+use crate::state::{InstrumentedState, InstrumentedStateBuilder};
+
+/// the call succeeded.
+pub const MIPS_VM_OK: c_int = 0;
+/// `mips_vm_step` stopped as the guest exited, now or before the call.
+pub const MIPS_VM_EXITED: c_int = 1;
+/// a pointer argument was null.
+pub const MIPS_VM_ERR_NULL: c_int = -1;
+/// the ELF, the config or the memory range is invalid.
+pub const MIPS_VM_ERR_INVALID_ARG: c_int = -2;
+/// a step failed, the error of the emulator is the message.
+pub const MIPS_VM_ERR_EMULATOR: c_int = -3;
+/// a callback of the oracle returned a non-zero code.
+pub const MIPS_VM_ERR_ORACLE: c_int = -4;
+/// the emulator panicked, the `MipsVm` should be freed.
+pub const MIPS_VM_ERR_PANIC: c_int = -5;
+/// the output callback returned a non-zero code.
+pub const MIPS_VM_ERR_OUTPUT: c_int = -6;
+
+/// the buffer passed to the first call of the preimage callback, a larger preimage is fetched by
+/// a second call.
+const PREIMAGE_BUFFER_SIZE: usize = 4096;
+
+/// Serves the preimage of the 32 bytes `key`: writes up to `cap` bytes of it to `buf` and its
+/// length to `len`. A preimage longer than `cap` is asked again with a buffer of its length.
+/// Returns 0, or a non-zero code failing the step.
+pub type MipsGetPreimageFn = Option<
+    unsafe extern "C" fn(
+        ctx: *mut c_void,
+        key: *const u8,
+        buf: *mut u8,
+        cap: usize,
+        len: *mut usize,
+    ) -> c_int,
+>;
+
+/// Receives a complete hint of `len` bytes. Returns 0, or a non-zero code failing the step that
+/// sent the hint, which is executed nonetheless.
+pub type MipsHintFn =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, hint: *const u8, len: usize) -> c_int>;
+
+/// Receives `len` bytes the guest wrote to `fd`, 1 for stdout and 2 for stderr. Returns 0, or a
+/// non-zero code failing the step that wrote them, which is executed nonetheless.
+pub type MipsWriteFn = Option<
+    unsafe extern "C" fn(ctx: *mut c_void, fd: c_int, data: *const u8, len: usize) -> c_int,
+>;
+
+/// MipsVm is an emulator loaded from an ELF, the opaque handle of the C ABI.
+pub struct MipsVm {
+    is: Box<InstrumentedState>,
+    /// the failure of a callback during the current step, it takes over the error of the step.
+    callback_failure: Arc<Mutex<Option<FfiError>>>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// FfiError is the code and the message of a failing call.
+struct FfiError {
+    code: c_int,
+    message: String,
+}
+
+impl FfiError {
+    fn new(code: c_int, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    fn null(arg: &str) -> Self {
+        Self::new(MIPS_VM_ERR_NULL, format!("{} is null", arg))
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::new(MIPS_VM_ERR_INVALID_ARG, message)
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    };
+    format!("the emulator panicked: {}", message)
+}
+
+/// Runs the body of an entry point, an error or a panic is kept as the last error and turned
+/// into the return value by `on_error`, with its code.
+fn guard<T>(on_error: impl FnOnce(c_int) -> T, f: impl FnOnce() -> Result<T, FfiError>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_last_error(err.message);
+            on_error(err.code)
+        }
+        Err(payload) => {
+            set_last_error(panic_message(payload));
+            on_error(MIPS_VM_ERR_PANIC)
+        }
+    }
+}
+
+unsafe fn vm_mut<'a>(vm: *mut MipsVm) -> Result<&'a mut MipsVm, FfiError> {
+    vm.as_mut().ok_or_else(|| FfiError::null("vm"))
+}
+
+/// CallbackOracle serves the preimages and receives the hints through the callbacks of the
+/// host. A failing callback is recorded in `failure`, the step sees a missing preimage.
+struct CallbackOracle {
+    get: unsafe extern "C" fn(*mut c_void, *const u8, *mut u8, usize, *mut usize) -> c_int,
+    hint: MipsHintFn,
+    ctx: *mut c_void,
+    failure: Arc<Mutex<Option<FfiError>>>,
+}
+
+impl CallbackOracle {
+    fn fail(&self, message: String) {
+        let error = FfiError::new(MIPS_VM_ERR_ORACLE, message);
+        self.failure.lock().unwrap().get_or_insert(error);
+    }
+}
+
+impl PreimageOracle for CallbackOracle {
+    fn hint(&mut self, v: &[u8]) {
+        if let Some(hint) = self.hint {
+            let code = unsafe { hint(self.ctx, v.as_ptr(), v.len()) };
+            if code != 0 {
+                self.fail(format!("the hint callback failed with code {}", code));
+            }
+        }
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        let mut data = vec![0; PREIMAGE_BUFFER_SIZE];
+        for _ in 0..2 {
+            let mut len = 0;
+            let (buf, cap) = (data.as_mut_ptr(), data.len());
+            let code = unsafe { (self.get)(self.ctx, k.as_ptr(), buf, cap, &mut len) };
+            if code != 0 {
+                self.fail(format!(
+                    "the preimage callback failed with code {} for key 0x{}",
+                    code,
+                    hex::encode(k)
+                ));
+                return Err(EmulatorError::PreimageNotFound { key: k });
+            }
+            if len <= data.len() {
+                data.truncate(len);
+                return Ok(data);
+            }
+            data.resize(len, 0);
+        }
+        self.fail(format!("the preimage callback grew the preimage of 0x{}", hex::encode(k)));
+        Err(EmulatorError::PreimageNotFound { key: k })
+    }
+}
+
+/// CallbackWriter passes the guest output to `fd` to the callback of the host. A failing
+/// callback is recorded in `failure`, the guest sees the bytes written.
+struct CallbackWriter {
+    write: unsafe extern "C" fn(*mut c_void, c_int, *const u8, usize) -> c_int,
+    fd: c_int,
+    ctx: *mut c_void,
+    failure: Arc<Mutex<Option<FfiError>>>,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let code = unsafe { (self.write)(self.ctx, self.fd, buf.as_ptr(), buf.len()) };
+        if code != 0 {
+            let fd = self.fd;
+            let message = format!("the output callback failed with code {} for fd {}", code, fd);
+            let error = FfiError::new(MIPS_VM_ERR_OUTPUT, message);
+            self.failure.lock().unwrap().get_or_insert(error);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfigMode {
+    Strict,
+    Lenient,
+}
+
+impl From<ConfigMode> for ExecutionMode {
+    fn from(mode: ConfigMode) -> Self {
+        match mode {
+            ConfigMode::Strict => ExecutionMode::Strict,
+            ConfigMode::Lenient => ExecutionMode::Lenient,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfigUnknownSyscall {
+    SilentZero,
+    Enosys,
+    Fault,
+}
+
+impl From<ConfigUnknownSyscall> for UnknownSyscallPolicy {
+    fn from(policy: ConfigUnknownSyscall) -> Self {
+        match policy {
+            ConfigUnknownSyscall::SilentZero => UnknownSyscallPolicy::SilentZero,
+            ConfigUnknownSyscall::Enosys => UnknownSyscallPolicy::Enosys,
+            ConfigUnknownSyscall::Fault => UnknownSyscallPolicy::Fault,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfigEndianness {
+    Big,
+    Little,
+}
+
+/// FfiConfig is the config JSON of `mips_vm_new`, an object with the options of `VmConfig`
+/// changing the execution, by their name. The missing options keep their default, the enums
+/// are snake case strings and the random seed is hex.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FfiConfig {
+    random_seed: Option<String>,
+    max_host_pages: Option<usize>,
+    wide_preimage_io: Option<bool>,
+    mode: Option<ConfigMode>,
+    unknown_syscall: Option<ConfigUnknownSyscall>,
+    invalid_opcodes: Option<ConfigMode>,
+    strict_delay_slots: Option<bool>,
+    eager_pc_check: Option<bool>,
+    stack_guard: Option<bool>,
+    null_guard: Option<bool>,
+    protect_text: Option<bool>,
+    hilo_hazards: Option<bool>,
+    max_hint_size: Option<usize>,
+    max_read_range: Option<u32>,
+    endianness: Option<ConfigEndianness>,
+}
+
+impl FfiConfig {
+    fn vm_config(self) -> Result<VmConfig, FfiError> {
+        let mut config = VmConfig::default();
+        if let Some(seed) = self.random_seed {
+            let seed = seed.strip_prefix("0x").unwrap_or(&seed);
+            hex::decode_to_slice(seed, &mut config.random_seed)
+                .map_err(|e| FfiError::invalid(format!("invalid random seed: {}", e)))?;
+        }
+        config.max_host_pages = self.max_host_pages.or(config.max_host_pages);
+        config.wide_preimage_io = self.wide_preimage_io.unwrap_or(config.wide_preimage_io);
+        config.mode = self.mode.map_or(config.mode, ExecutionMode::from);
+        config.unknown_syscall = self.unknown_syscall.map_or(config.unknown_syscall, Into::into);
+        config.invalid_opcodes = self.invalid_opcodes.map_or(config.invalid_opcodes, Into::into);
+        config.strict_delay_slots = self.strict_delay_slots.unwrap_or(config.strict_delay_slots);
+        config.eager_pc_check = self.eager_pc_check.unwrap_or(config.eager_pc_check);
+        config.stack_guard = self.stack_guard.unwrap_or(config.stack_guard);
+        config.null_guard = self.null_guard.unwrap_or(config.null_guard);
+        config.protect_text = self.protect_text.unwrap_or(config.protect_text);
+        config.hilo_hazards = self.hilo_hazards.unwrap_or(config.hilo_hazards);
+        config.max_hint_size = self.max_hint_size.unwrap_or(config.max_hint_size);
+        config.max_read_range = self.max_read_range.unwrap_or(config.max_read_range);
+        config.endianness = match self.endianness {
+            Some(ConfigEndianness::Big) => Endianness::Big,
+            Some(ConfigEndianness::Little) => Endianness::Little,
+            None => config.endianness,
+        };
+        Ok(config)
+    }
+}
+
+unsafe fn parse_config(config_json: *const c_char) -> Result<VmConfig, FfiError> {
+    if config_json.is_null() {
+        return Ok(VmConfig::default());
+    }
+    let json = CStr::from_ptr(config_json)
+        .to_str()
+        .map_err(|e| FfiError::invalid(format!("invalid config: {}", e)))?;
+    if json.trim().is_empty() {
+        return Ok(VmConfig::default());
+    }
+    let config: FfiConfig = serde_json::from_str(json)
+        .map_err(|e| FfiError::invalid(format!("invalid config: {}", e)))?;
+    config.vm_config()
+}
+
+/// Loads the ELF of `elf_len` bytes at `elf` like `State::load_elf`, with the config JSON
+/// `config_json`, null or empty for the default config. The guest has no preimages until
+/// `mips_vm_register_oracle`, and its output is discarded until `mips_vm_set_output`. Returns
+/// null on failure.
+///
+/// # Safety
+/// `elf` points to `elf_len` readable bytes, `config_json` is null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_new(
+    elf: *const u8,
+    elf_len: usize,
+    config_json: *const c_char,
+) -> *mut MipsVm {
+    guard(|_| ptr::null_mut(), || {
+        if elf.is_null() {
+            return Err(FfiError::null("elf"));
+        }
+        let config = parse_config(config_json)?;
+        let image = ReplayImage::Elf(slice::from_raw_parts(elf, elf_len).to_vec());
+        let state = image
+            .state_for(&config)
+            .map_err(|e| FfiError::invalid(e.to_string()))?;
+        let is = InstrumentedStateBuilder::new(state)
+            .with_config(config)
+            .with_output_discarded()
+            .build()
+            .map_err(|e| FfiError::invalid(e.to_string()))?;
+        let vm = MipsVm { is, callback_failure: Default::default() };
+        Ok(Box::into_raw(Box::new(vm)))
+    })
+}
+
+/// Executes `n` steps, fewer if the guest exits. Returns `MIPS_VM_EXITED` once the guest exited,
+/// `MIPS_VM_OK` if it is still running. A failing step leaves the state like a failing
+/// `InstrumentedState::step` does, a failing hint or output callback fails the step after it
+/// executed.
+///
+/// # Safety
+/// `vm` is null or was returned by `mips_vm_new` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_step(vm: *mut MipsVm, n: u64) -> c_int {
+    guard(|code| code, || {
+        let vm = vm_mut(vm)?;
+        for _ in 0..n {
+            if vm.is.state.exited {
+                break;
+            }
+            let result = vm.is.step(false);
+            if let Some(error) = vm.callback_failure.lock().unwrap().take() {
+                return Err(error);
+            }
+            result.map_err(|e| FfiError::new(MIPS_VM_ERR_EMULATOR, e.to_string()))?;
+        }
+        Ok(if vm.is.state.exited { MIPS_VM_EXITED } else { MIPS_VM_OK })
+    })
+}
+
+/// Writes the 32 bytes hash of the state to `out`, see `InstrumentedState::state_hash`.
+///
+/// # Safety
+/// `vm` is null or a live `MipsVm`, `out` is null or points to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_state_hash(vm: *mut MipsVm, out: *mut u8) -> c_int {
+    guard(|code| code, || {
+        let vm = vm_mut(vm)?;
+        if out.is_null() {
+            return Err(FfiError::null("out"));
+        }
+        let hash = vm.is.state_hash();
+        ptr::copy_nonoverlapping(hash.as_ptr(), out, hash.len());
+        Ok(MIPS_VM_OK)
+    })
+}
+
+/// Serves the preimages from `get` and sends the hints to `hint`, which may be null, both called
+/// with `ctx`. Replaces the oracle registered before.
+///
+/// # Safety
+/// `vm` is null or a live `MipsVm`. The callbacks may be called with `ctx` by every step until
+/// the oracle is replaced or `vm` is freed.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_register_oracle(
+    vm: *mut MipsVm,
+    get: MipsGetPreimageFn,
+    hint: MipsHintFn,
+    ctx: *mut c_void,
+) -> c_int {
+    guard(|code| code, || {
+        let vm = vm_mut(vm)?;
+        let get = get.ok_or_else(|| FfiError::null("get"))?;
+        let failure = vm.callback_failure.clone();
+        vm.is.replace_oracle(Box::new(CallbackOracle { get, hint, ctx, failure }));
+        Ok(MIPS_VM_OK)
+    })
+}
+
+/// Sends the output of the guest to stdout and stderr to `write`, called with `ctx`, or
+/// discards it if `write` is null. Replaces the callback set before.
+///
+/// # Safety
+/// `vm` is null or a live `MipsVm`. The callback may be called with `ctx` by every step until
+/// it is replaced or `vm` is freed.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_set_output(
+    vm: *mut MipsVm,
+    write: MipsWriteFn,
+    ctx: *mut c_void,
+) -> c_int {
+    guard(|code| code, || {
+        let vm = vm_mut(vm)?;
+        let Some(write) = write else {
+            vm.is.discard_output();
+            return Ok(MIPS_VM_OK);
+        };
+        let writer = |fd| {
+            let failure = vm.callback_failure.clone();
+            Box::new(CallbackWriter { write, fd, ctx, failure })
+        };
+        let (stdout, stderr) = (writer(1), writer(2));
+        vm.is.set_stdout_writer(stdout);
+        vm.is.set_stderr_writer(stderr);
+        Ok(MIPS_VM_OK)
+    })
+}
+
+/// Copies the `len` bytes of the guest memory from `addr` to `out`, the unmapped ones are zero.
+/// Fails if the range goes past the end of the address space.
+///
+/// # Safety
+/// `vm` is null or a live `MipsVm`, `out` is null or points to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_read_mem(
+    vm: *mut MipsVm,
+    addr: u32,
+    out: *mut u8,
+    len: usize,
+) -> c_int {
+    guard(|code| code, || {
+        let vm = vm_mut(vm)?;
+        if out.is_null() {
+            return Err(FfiError::null("out"));
+        }
+        if addr as u64 + len as u64 > 1 << 32 {
+            return Err(FfiError::invalid(format!("{} bytes from 0x{:x} overflow", len, addr)));
+        }
+        let out = slice::from_raw_parts_mut(out, len);
+        let memory = &vm.is.state.memory;
+        let mut copied = 0;
+        while copied < len {
+            let addr = addr + copied as u32;
+            let word = memory.peek_memory(addr & !3);
+            copied += copy_from_word(word, addr & 3, &mut out[copied..], memory.endianness());
+        }
+        Ok(MIPS_VM_OK)
+    })
+}
+
+/// Frees `vm`, null is ignored.
+///
+/// # Safety
+/// `vm` is null or a live `MipsVm`, it is not used after the call.
+#[no_mangle]
+pub unsafe extern "C" fn mips_vm_free(vm: *mut MipsVm) {
+    if !vm.is_null() {
+        guard(|_| (), || {
+            drop(Box::from_raw(vm));
+            Ok(())
+        })
+    }
+}
+
+/// Returns the message of the last failing call of the calling thread, null if none failed.
+/// The string is valid until the next failing call of the thread. A Go host locks the goroutine
+/// to its thread around the call and this one.
+#[no_mangle]
+pub extern "C" fn mips_vm_last_error_msg() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+    })
+    .unwrap_or(ptr::null())
+}
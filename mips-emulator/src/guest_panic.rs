@@ -0,0 +1,142 @@
+//! Detects the panic message a Rust guest prints on stderr right before it exits.
+//! Both the `panicked at 'msg', file:line:col` format of older toolchains and the
+//! `panicked at file:line:col:\nmsg` format of newer toolchains are recognized, the message
+//! may be split across several write syscalls and span several lines.
+
+const MARKER: &[u8] = b"panicked at ";
+/// the captured stderr output after the marker is bounded by this many bytes.
+const MAX_CAPTURE: usize = 64 * 1024;
+
+/// GuestPanic is the panic message and location reported by the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestPanic {
+    pub message: String,
+    /// the `file:line:col` location, if it could be parsed.
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PanicDetector {
+    /// stderr output starting at the last marker, or the tail that may hold a split marker.
+    buffer: Vec<u8>,
+    /// whether `buffer` starts with the marker.
+    seen: bool,
+}
+
+impl PanicDetector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Feeds the bytes the guest wrote to stderr.
+    pub fn feed(&mut self, data: &[u8]) {
+        // only search the new bytes, plus the tail a split marker may start in
+        let search_from = self.buffer.len().saturating_sub(MARKER.len() - 1);
+        self.buffer.extend_from_slice(data);
+
+        if let Some(pos) = rfind(&self.buffer[search_from..], MARKER) {
+            self.buffer.drain(..search_from + pos);
+            self.seen = true;
+        } else if !self.seen {
+            let keep = MARKER.len() - 1;
+            if self.buffer.len() > keep {
+                self.buffer.drain(..self.buffer.len() - keep);
+            }
+        }
+
+        self.buffer.truncate(MAX_CAPTURE);
+    }
+
+    /// Returns the last panic reported on stderr, if any.
+    pub fn panic(&self) -> Option<GuestPanic> {
+        if !self.seen {
+            return None;
+        }
+
+        let text = String::from_utf8_lossy(&self.buffer[MARKER.len()..]);
+        if let Some(quoted) = text.strip_prefix('\'') {
+            // panicked at 'msg', src/main.rs:2:5
+            let mut search = 0;
+            while let Some(pos) = quoted[search..].find("', ") {
+                let end = search + pos;
+                let rest = &quoted[end + 3..];
+                let location = rest.split('\n').next().unwrap();
+                if is_location(location) {
+                    return Some(GuestPanic {
+                        message: quoted[..end].to_string(),
+                        location: Some(location.to_string()),
+                    });
+                }
+                search = end + 3;
+            }
+            // the location was not written (yet), report what we have
+            return Some(GuestPanic {
+                message: quoted.trim_end().to_string(),
+                location: None,
+            });
+        }
+
+        // panicked at src/main.rs:2:5:\nmsg\nnote: run with `RUST_BACKTRACE=1` ...
+        let (first_line, message) = text.split_once('\n').unwrap_or((&text, ""));
+        let location = first_line.strip_suffix(':').filter(|l| is_location(l));
+        let message = message.split("\nnote: ").next().unwrap();
+        Some(GuestPanic {
+            message: message.trim_end_matches('\n').to_string(),
+            location: location.map(|l| l.to_string()),
+        })
+    }
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+/// checks if `s` looks like `file:line:col`.
+fn is_location(s: &str) -> bool {
+    let mut parts = s.rsplitn(3, ':');
+    let col = parts.next().unwrap_or("");
+    let line = parts.next().unwrap_or("");
+    let file = parts.next().unwrap_or("");
+    !file.is_empty()
+        && !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit())
+        && !col.is_empty() && col.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GuestPanic, PanicDetector};
+
+    fn detect(chunks: &[&str]) -> Option<GuestPanic> {
+        let mut detector = PanicDetector::new();
+        for chunk in chunks {
+            detector.feed(chunk.as_bytes());
+        }
+        detector.panic()
+    }
+
+    #[test]
+    fn test_quoted_format() {
+        let panic = detect(&[
+            "some output\nthread 'main' pani",
+            "cked at 'first line\nsecond line', src/main.rs:4:37\n",
+        ]).unwrap();
+        assert_eq!(panic.message, "first line\nsecond line");
+        assert_eq!(panic.location.as_deref(), Some("src/main.rs:4:37"));
+    }
+
+    #[test]
+    fn test_location_first_format() {
+        let panic = detect(&[
+            "thread 'main' panicked at src/main.rs:2:5:\n",
+            "boom\n",
+            "note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n",
+        ]).unwrap();
+        assert_eq!(panic.message, "boom");
+        assert_eq!(panic.location.as_deref(), Some("src/main.rs:2:5"));
+    }
+
+    #[test]
+    fn test_no_panic() {
+        assert_eq!(detect(&["hello\n", "world\n"]), None);
+    }
+}
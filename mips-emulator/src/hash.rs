@@ -0,0 +1,207 @@
+use std::fmt::Debug;
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256, Sha3_256};
+
+/// Hasher32 is a hash with 32 bytes digests. The emulator hashes the state, the memory tree and
+/// the preimages through it, and the circuits use the same implementations for their lookups.
+pub trait Hasher32: Debug + Send + Sync {
+    fn hash(&self, data: &[u8]) -> [u8; 32];
+
+    /// Hashes the concatenation of two nodes of a merkle tree.
+    fn hash_pair(&self, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut data = [0; 64];
+        data[..32].copy_from_slice(left);
+        data[32..].copy_from_slice(right);
+        self.hash(&data)
+    }
+}
+
+/// The hash of the state witness and of the keccak256 preimage keys.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Keccak256Hasher;
+
+impl Hasher32 for Keccak256Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        Keccak256::digest(data).into()
+    }
+}
+
+/// The hash of the memory tree.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sha3Hasher;
+
+impl Hasher32 for Sha3Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        Sha3_256::digest(data).into()
+    }
+}
+
+/// The hash of the sha256 preimage keys.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sha256Hasher;
+
+impl Hasher32 for Sha256Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+}
+
+/// Poseidon over the pallas base field, cheaper to prove than the bit oriented hashes. The data
+/// is split into 31 bytes little-endian field elements, absorbed two to one after its length,
+/// the digest is the little-endian encoding of the last element.
+#[cfg(feature = "poseidon")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PoseidonHasher;
+
+#[cfg(feature = "poseidon")]
+impl Hasher32 for PoseidonHasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        use ff::PrimeField;
+        use halo2_gadgets::poseidon::primitives::{ConstantLength, Hash, P128Pow5T3};
+        use pasta_curves::pallas;
+
+        let mut acc = pallas::Base::from(data.len() as u64);
+        for chunk in data.chunks(31) {
+            let mut repr = [0; 32];
+            repr[..chunk.len()].copy_from_slice(chunk);
+            let element = pallas::Base::from_repr(repr).unwrap();
+            acc = Hash::<_, P128Pow5T3, ConstantLength<2>, 3, 2>::init().hash([acc, element]);
+        }
+        acc.to_repr()
+    }
+}
+
+/// HashFunction selects a `Hasher32` in the `VmConfig`, see `VmConfig::state_hash` and
+/// `VmConfig::memory_hash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashFunction {
+    Keccak256,
+    Sha3_256,
+    #[cfg(feature = "poseidon")]
+    Poseidon,
+}
+
+/// the levels of the memory tree below the root, its leaves are 32 bytes.
+const TREE_DEPTH: usize = 28;
+
+lazy_static! {
+    static ref KECCAK256_ZERO_HASHES: [[u8; 32]; TREE_DEPTH + 1] = zero_hashes(&Keccak256Hasher);
+    static ref SHA3_256_ZERO_HASHES: [[u8; 32]; TREE_DEPTH + 1] = zero_hashes(&Sha3Hasher);
+}
+
+#[cfg(feature = "poseidon")]
+lazy_static! {
+    static ref POSEIDON_ZERO_HASHES: [[u8; 32]; TREE_DEPTH + 1] = zero_hashes(&PoseidonHasher);
+}
+
+fn zero_hashes(hasher: &dyn Hasher32) -> [[u8; 32]; TREE_DEPTH + 1] {
+    let mut out = [[0; 32]; TREE_DEPTH + 1];
+    for i in 1..out.len() {
+        out[i] = hasher.hash_pair(&out[i - 1], &out[i - 1]);
+    }
+    out
+}
+
+impl HashFunction {
+    pub fn hasher(self) -> &'static dyn Hasher32 {
+        match self {
+            HashFunction::Keccak256 => &Keccak256Hasher,
+            HashFunction::Sha3_256 => &Sha3Hasher,
+            #[cfg(feature = "poseidon")]
+            HashFunction::Poseidon => &PoseidonHasher,
+        }
+    }
+
+    /// The id of the hash function in the serialized replays and states.
+    pub fn id(self) -> u8 {
+        match self {
+            HashFunction::Keccak256 => 0,
+            HashFunction::Sha3_256 => 1,
+            #[cfg(feature = "poseidon")]
+            HashFunction::Poseidon => 2,
+        }
+    }
+
+    /// Returns the hash function of `id`, see `HashFunction::id`.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(HashFunction::Keccak256),
+            1 => Some(HashFunction::Sha3_256),
+            #[cfg(feature = "poseidon")]
+            2 => Some(HashFunction::Poseidon),
+            _ => None,
+        }
+    }
+
+    /// The roots of the memory subtrees of zero words by height, a leaf at 0.
+    pub fn zero_hashes(self) -> &'static [[u8; 32]; TREE_DEPTH + 1] {
+        match self {
+            HashFunction::Keccak256 => &KECCAK256_ZERO_HASHES,
+            HashFunction::Sha3_256 => &SHA3_256_ZERO_HASHES,
+            #[cfg(feature = "poseidon")]
+            HashFunction::Poseidon => &POSEIDON_ZERO_HASHES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HashFunction, Hasher32, Keccak256Hasher, Sha256Hasher, Sha3Hasher};
+
+    fn hex_hash(hasher: &dyn Hasher32, data: &[u8]) -> String {
+        hex::encode(hasher.hash(data))
+    }
+
+    #[test]
+    fn test_known_answers() {
+        assert_eq!(
+            hex_hash(&Keccak256Hasher, b""),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            hex_hash(&Keccak256Hasher, b"abc"),
+            "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45"
+        );
+        assert_eq!(
+            hex_hash(&Sha3Hasher, b""),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        assert_eq!(
+            hex_hash(&Sha3Hasher, b"abc"),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+        assert_eq!(
+            hex_hash(&Sha256Hasher, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_zero_hashes() {
+        for function in [HashFunction::Keccak256, HashFunction::Sha3_256] {
+            let (hasher, zero) = (function.hasher(), function.zero_hashes());
+            assert_eq!(zero[0], [0; 32]);
+            // a pair of zero leaves is 64 zero bytes, like the bottom layer of a page
+            assert_eq!(zero[1], hasher.hash(&[0; 64]));
+            assert_eq!(zero[28], hasher.hash_pair(&zero[27], &zero[27]));
+        }
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon() {
+        use ff::PrimeField;
+        use halo2_gadgets::poseidon::primitives::{ConstantLength, Hash, P128Pow5T3};
+        use pasta_curves::pallas;
+        use super::PoseidonHasher;
+
+        // a single chunk is the hash of the length and the chunk
+        let expected = Hash::<_, P128Pow5T3, ConstantLength<2>, 3, 2>::init()
+            .hash([pallas::Base::from(3), pallas::Base::from(0x636261)]);
+        assert_eq!(PoseidonHasher.hash(b"abc"), expected.to_repr());
+        // the length tells apart the data padded with zeros
+        assert_ne!(PoseidonHasher.hash(b"abc"), PoseidonHasher.hash(b"abc\0"));
+        assert_ne!(PoseidonHasher.hash(&[1; 31]), PoseidonHasher.hash(&[1; 32]));
+    }
+}
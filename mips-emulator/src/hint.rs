@@ -0,0 +1,228 @@
+use crate::error::EmulatorError;
+
+/// the default of `VmConfig::max_hint_size`.
+pub const DEFAULT_MAX_HINT_SIZE: usize = 4 << 20;
+
+/// HintBuffer reassembles the hints the guest writes to the hint fd, each one prefixed by its
+/// big-endian u32 length, from writes of any size. A length prefix over the max hint size is
+/// rejected as soon as it is buffered, so at most one incomplete hint of at most the max size
+/// stays buffered between writes. The complete hints are kept in order, so a VM resumed from
+/// the state can repeat them to the oracle.
+#[derive(Debug, Clone)]
+pub struct HintBuffer {
+    buf: Vec<u8>,
+    max_hint_size: usize,
+    hints: Vec<Vec<u8>>,
+}
+
+impl Default for HintBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_HINT_SIZE)
+    }
+}
+
+impl HintBuffer {
+    pub fn new(max_hint_size: usize) -> Self {
+        Self { buf: Vec::new(), max_hint_size, hints: Vec::new() }
+    }
+
+    pub fn set_max_hint_size(&mut self, max_hint_size: usize) {
+        self.max_hint_size = max_hint_size;
+    }
+
+    /// The bytes buffered of the incomplete hint, its length prefix included.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The buffered bytes of the incomplete hint, its length prefix included.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// The hints completed, in order, without their length prefix.
+    pub fn hints(&self) -> &[Vec<u8>] {
+        &self.hints
+    }
+
+    /// The last hint completed, without its length prefix.
+    pub fn last(&self) -> Option<&[u8]> {
+        self.hints.last().map(Vec::as_slice)
+    }
+
+    /// Restores the buffer of a serialized state, the hint sizes are not checked until the next
+    /// write.
+    pub fn restore(&mut self, pending: Vec<u8>, hints: Vec<Vec<u8>>) {
+        self.buf = pending;
+        self.hints = hints;
+    }
+
+    /// Encodes the hints a host saves with the witness of the state, which doesn't commit to
+    /// them: the big-endian u32 length of the incomplete hint and its buffered bytes, then the
+    /// u32 count of the complete hints, each one with its length and bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = (self.buf.len() as u32).to_be_bytes().to_vec();
+        out.extend(&self.buf);
+        out.extend((self.hints.len() as u32).to_be_bytes());
+        for hint in &self.hints {
+            out.extend((hint.len() as u32).to_be_bytes());
+            out.extend(hint);
+        }
+        out
+    }
+
+    /// Decodes the hints encoded by `encode`, with the default max hint size. Returns none if
+    /// `data` is truncated or has trailing bytes.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if data.len() < len {
+                return None;
+            }
+            let (head, tail) = data.split_at(len);
+            *data = tail;
+            Some(head)
+        }
+        fn take_bytes(data: &mut &[u8]) -> Option<Vec<u8>> {
+            let len = u32::from_be_bytes(take(data, 4)?.try_into().unwrap());
+            take(data, len as usize).map(|bytes| bytes.to_vec())
+        }
+
+        let mut data = data;
+        let pending = take_bytes(&mut data)?;
+        let count = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+        // each hint takes at least its 4 bytes length, don't trust the count for the capacity
+        let mut hints = Vec::with_capacity((count as usize).min(data.len() / 4));
+        for _ in 0..count {
+            hints.push(take_bytes(&mut data)?);
+        }
+        if !data.is_empty() {
+            return None;
+        }
+        let mut buf = Self::default();
+        buf.restore(pending, hints);
+        Some(buf)
+    }
+
+    /// Buffers `data` and calls `on_hint` with every hint it completes, in order. Fails with
+    /// `OversizedHint` on a length prefix over the max hint size, the hints before it are
+    /// dispatched and the buffer is left at the oversized prefix.
+    pub fn feed(&mut self, data: &[u8], mut on_hint: impl FnMut(&[u8])) -> Result<(), EmulatorError> {
+        self.buf.extend_from_slice(data);
+        // consumed hints are skipped with a cursor and dropped from the buffer once at the end,
+        // so many small hints don't re-copy the buffer for each hint.
+        let mut cursor = 0;
+        let mut result = Ok(());
+        while self.buf.len() - cursor >= 4 {
+            let declared = u32::from_be_bytes(self.buf[cursor..cursor + 4].try_into().unwrap());
+            if declared as usize > self.max_hint_size {
+                result = Err(EmulatorError::OversizedHint { declared });
+                break;
+            }
+            let end = cursor + 4 + declared as usize;
+            if end > self.buf.len() {
+                // the rest of the hint comes with the next writes
+                break;
+            }
+            on_hint(&self.buf[cursor + 4..end]);
+            self.hints.push(self.buf[cursor + 4..end].to_vec());
+            cursor = end;
+        }
+        self.buf.drain(..cursor);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EmulatorError;
+    use super::HintBuffer;
+
+    fn hint(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend(data);
+        out
+    }
+
+    fn feed(buf: &mut HintBuffer, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut hints = vec![];
+        buf.feed(data, |h| hints.push(h.to_vec())).unwrap();
+        hints
+    }
+
+    #[test]
+    fn test_partial_prefix() {
+        let mut buf = HintBuffer::default();
+        let data = hint(b"hello");
+        assert!(feed(&mut buf, &data[..3]).is_empty());
+        assert_eq!(buf.len(), 3);
+        assert_eq!(feed(&mut buf, &data[3..]), [b"hello".to_vec()]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_split_across_writes() {
+        let mut buf = HintBuffer::default();
+        let mut data = hint(b"first");
+        data.extend(hint(b""));
+        data.extend(hint(b"second"));
+        let mut hints = vec![];
+        for chunk in data.chunks(3) {
+            hints.extend(feed(&mut buf, chunk));
+        }
+        assert_eq!(hints, [b"first".to_vec(), vec![], b"second".to_vec()]);
+        assert!(buf.is_empty());
+        assert_eq!(buf.hints(), [b"first".to_vec(), vec![], b"second".to_vec()]);
+        assert_eq!(buf.last(), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn test_encode_partial_hint() {
+        let mut buf = HintBuffer::default();
+        let mut data = hint(b"done");
+        data.extend(&hint(b"pending")[..6]);
+        assert_eq!(feed(&mut buf, &data), [b"done".to_vec()]);
+
+        let encoded = buf.encode();
+        let mut decoded = HintBuffer::decode(&encoded).unwrap();
+        assert_eq!(decoded.pending(), buf.pending());
+        assert_eq!(decoded.last(), Some(&b"done"[..]));
+        assert_eq!(feed(&mut decoded, &hint(b"pending")[6..]), [b"pending".to_vec()]);
+
+        let empty = HintBuffer::default().encode();
+        assert_eq!(empty, [0; 8]);
+        assert!(HintBuffer::decode(&empty).unwrap().last().is_none());
+        assert!(HintBuffer::decode(&encoded[..encoded.len() - 1]).is_none());
+        assert!(HintBuffer::decode(&[encoded.as_slice(), &[0]].concat()).is_none());
+    }
+
+    #[test]
+    fn test_oversized_hint() {
+        let mut buf = HintBuffer::new(8);
+        let mut data = hint(b"12345678");
+        data.extend(0xFFffFFffu32.to_be_bytes());
+        let mut hints = vec![];
+        let result = buf.feed(&data, |h| hints.push(h.to_vec()));
+        assert!(matches!(result, Err(EmulatorError::OversizedHint { declared: 0xFFffFFff })));
+        // the hint before the oversized one went through, only the prefix is kept
+        assert_eq!(hints, [b"12345678".to_vec()]);
+        assert_eq!(buf.len(), 4);
+
+        // rejected before the data arrives
+        let mut buf = HintBuffer::new(8);
+        assert!(matches!(buf.feed(&9u32.to_be_bytes(), |_| {}), Err(EmulatorError::OversizedHint { declared: 9 })));
+    }
+
+    #[test]
+    fn test_many_small_hints() {
+        let mut buf = HintBuffer::new(16);
+        let data: Vec<u8> = (0..10_000u32).flat_map(|i| hint(&i.to_be_bytes())).collect();
+        let hints = feed(&mut buf, &data);
+        assert_eq!(hints.len(), 10_000);
+        assert!(hints.iter().enumerate().all(|(i, h)| h == &(i as u32).to_be_bytes()));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// JournalConfig enables the event journal of `InstrumentedState`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalConfig {
+    /// the latest events kept in memory.
+    pub capacity: usize,
+    /// records the pc every `pc_interval` steps, 0 disables it.
+    pub pc_interval: u64,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            pc_interval: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Syscall,
+    UnknownSyscall,
+    PreimageKey,
+    Exit,
+    Error,
+    Pc,
+}
+
+/// Event is an interesting event of the execution, recorded with the step it happened at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// a syscall with its arguments (a0-a2) and results (v0, v1).
+    Syscall { step: u64, pc: u32, num: u32, args: [u32; 3], v0: u32, v1: u32 },
+    /// a syscall the emulator doesn't implement, recorded before `VmConfig::unknown_syscall`
+    /// applies, so also when it fails the step.
+    UnknownSyscall { step: u64, pc: u32, num: u32 },
+    /// the preimage key the guest reads from changed.
+    PreimageKey {
+        step: u64,
+        #[serde(serialize_with = "serialize_key", deserialize_with = "deserialize_key")]
+        key: [u8; 32],
+    },
+    Exit { step: u64, code: u8 },
+    /// the step failed with the error.
+    Error { step: u64, pc: u32, message: String },
+    /// the pc sampled every `JournalConfig::pc_interval` steps.
+    Pc { step: u64, pc: u32 },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Syscall { .. } => EventKind::Syscall,
+            Event::UnknownSyscall { .. } => EventKind::UnknownSyscall,
+            Event::PreimageKey { .. } => EventKind::PreimageKey,
+            Event::Exit { .. } => EventKind::Exit,
+            Event::Error { .. } => EventKind::Error,
+            Event::Pc { .. } => EventKind::Pc,
+        }
+    }
+}
+
+fn serialize_key<S: Serializer>(key: &[u8; 32], s: S) -> Result<S::Ok, S::Error> {
+    let mut out = [0u8; 64];
+    hex::encode_to_slice(key, &mut out).unwrap();
+    s.serialize_str(std::str::from_utf8(&out).unwrap())
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 32], D::Error> {
+    let s = <&str>::deserialize(d)?;
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(s, &mut key).map_err(serde::de::Error::custom)?;
+    Ok(key)
+}
+
+/// JournalSink receives the events of the journal as they are recorded.
+pub trait JournalSink {
+    fn record(&mut self, event: &Event) -> io::Result<()>;
+}
+
+/// JsonlSink appends the events to a writer, one JSON object per line.
+pub struct JsonlSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> JournalSink for JsonlSink<W> {
+    fn record(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// Journal keeps the latest events in a bounded ring, and forwards every event to the sink if
+/// one is attached.
+pub struct Journal {
+    events: VecDeque<Event>,
+    capacity: usize,
+    pc_interval: u64,
+    sink: Option<Box<dyn JournalSink>>,
+}
+
+impl Journal {
+    pub fn new(config: &JournalConfig) -> Self {
+        Self {
+            events: VecDeque::with_capacity(config.capacity),
+            capacity: config.capacity,
+            pc_interval: config.pc_interval,
+            sink: None,
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn JournalSink>) {
+        self.sink = Some(sink);
+    }
+
+    pub fn pc_interval(&self) -> u64 {
+        self.pc_interval
+    }
+
+    pub fn record(&mut self, event: Event) -> io::Result<()> {
+        if let Some(sink) = &mut self.sink {
+            sink.record(&event)?;
+        }
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    /// Drops the kept events, the sink is kept.
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Returns the kept events, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    /// Returns the kept events of `kind`, oldest first.
+    pub fn replay_filter(&self, kind: EventKind) -> impl Iterator<Item = &Event> {
+        self.events.iter().filter(move |e| e.kind() == kind)
+    }
+
+    /// Writes the kept events as JSON lines.
+    pub fn to_jsonl(&self, writer: impl Write) -> io::Result<()> {
+        let mut sink = JsonlSink::new(writer);
+        for event in &self.events {
+            sink.record(event)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the events written by `JsonlSink` or `Journal::to_jsonl`.
+pub fn read_jsonl(reader: impl BufRead) -> io::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line)?);
+    }
+    Ok(events)
+}
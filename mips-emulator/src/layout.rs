@@ -0,0 +1,164 @@
+use std::fmt::{Display, Formatter};
+use crate::page::PAGE_SIZE;
+
+/// the heap base of programs loaded from an ELF, mmap hands out memory upwards from it.
+pub const HEAP_START: u32 = 0x20000000;
+/// the program break returned by brk, which never moves.
+pub const BRK_START: u32 = 0x40000000;
+/// the initial stack pointer set by `State::patch_stack`.
+pub const STACK_POINTER: u32 = 0x7fFFd000;
+/// the address space reserved for the stack to grow down, 8 MiB like the linux default.
+pub const STACK_SIZE: u32 = 8 << 20;
+/// the default end of the addresses reached through a null pointer, the first page.
+pub const NULL_GUARD_END: u32 = PAGE_SIZE as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// the text and data segments of the program.
+    Program,
+    Heap,
+    Stack,
+    /// the memory a mmap syscall asks for.
+    Mmap,
+    /// the page at the program break returned by brk.
+    Brk,
+}
+
+/// Region is the address range [start, end) of a part of the guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Region {
+    pub fn new(kind: RegionKind, start: u32, end: u32) -> Self {
+        Self { kind, start, end }
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn contains(&self, other: &Region) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            RegionKind::Program => "program",
+            RegionKind::Heap => "heap",
+            RegionKind::Stack => "stack",
+            RegionKind::Mmap => "mmap",
+            RegionKind::Brk => "brk",
+        };
+        write!(f, "{} [0x{:x}, 0x{:x})", kind, self.start, self.end)
+    }
+}
+
+/// LayoutError names the two regions of the guest memory in conflict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    Overlap { first: Region, second: Region },
+    /// `inner` does not fit in `outer`.
+    OutOfRegion { inner: Region, outer: Region },
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutError::Overlap { first, second } => write!(f, "{} overlaps {}", first, second),
+            LayoutError::OutOfRegion { inner, outer } => {
+                write!(f, "{} does not fit in {}", inner, outer)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// MemoryLayout describes where the program, the heap and the stack of the guest live.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// the extent of the loaded segments, none if nothing is loaded.
+    pub program: Option<(u32, u32)>,
+    /// the extent of the executable segments.
+    pub text: Option<(u32, u32)>,
+    pub heap_base: u32,
+    /// mmap fails to grow the heap past it.
+    pub heap_limit: u32,
+    /// the top of the stack, exclusive.
+    pub stack_base: u32,
+    /// the lowest address the stack grows down to.
+    pub stack_limit: u32,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        let stack_base = STACK_POINTER + PAGE_SIZE as u32;
+        let stack_limit = stack_base - STACK_SIZE;
+        Self {
+            program: None,
+            text: None,
+            heap_base: HEAP_START,
+            heap_limit: stack_limit,
+            stack_base,
+            stack_limit,
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn program(&self) -> Option<Region> {
+        self.program.map(|(start, end)| Region::new(RegionKind::Program, start, end))
+    }
+
+    pub fn heap(&self) -> Region {
+        Region::new(RegionKind::Heap, self.heap_base, self.heap_limit)
+    }
+
+    pub fn stack(&self) -> Region {
+        Region::new(RegionKind::Stack, self.stack_limit, self.stack_base)
+    }
+
+    /// Returns the guard page right below the stack limit.
+    pub fn stack_guard(&self) -> Region {
+        Region::new(RegionKind::Stack, self.stack_limit - PAGE_SIZE as u32, self.stack_limit)
+    }
+
+    /// Checks that the program, the heap and the stack are pairwise disjoint.
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        let mut regions = vec![];
+        regions.extend(self.program());
+        regions.push(self.heap());
+        regions.push(self.stack());
+        for (i, first) in regions.iter().enumerate() {
+            for second in &regions[i + 1..] {
+                if first.overlaps(second) {
+                    return Err(LayoutError::Overlap { first: *first, second: *second });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `region`, about to be used at runtime, fits in `outer` and stays clear of the
+    /// program and the stack.
+    pub fn check_alloc(&self, region: Region, outer: Option<Region>) -> Result<(), LayoutError> {
+        let others = self.program().into_iter().chain([self.stack()]);
+        for other in others.filter(|other| other.kind != region.kind) {
+            if region.overlaps(&other) {
+                return Err(LayoutError::Overlap { first: region, second: other });
+            }
+        }
+        match outer {
+            Some(outer) if !outer.contains(&region) => {
+                Err(LayoutError::OutOfRegion { inner: region, outer })
+            }
+            _ => Ok(()),
+        }
+    }
+}
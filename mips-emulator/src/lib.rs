@@ -2,9 +2,38 @@
 
 pub mod state;
 pub mod witness;
+pub mod witness_stream;
 pub mod opcode_id;
+pub mod memory;
+pub mod memory_backend;
+pub mod guest_panic;
+pub mod hint;
+pub mod error;
+pub mod errno;
+pub mod hash;
+pub mod config;
+pub mod symbols;
+pub mod profile;
+pub mod trace_export;
+pub mod syscall_override;
+pub mod region_log;
+pub mod reg;
+pub mod journal;
+pub mod livelock;
+pub mod metrics;
+pub mod layout;
+pub mod reloc;
+pub mod compat;
+pub mod replay;
+pub mod summary;
+pub mod differential;
+pub mod verifier;
+pub mod time_travel;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod decode;
 mod page;
-mod memory;
-mod pre_image;
+pub mod pre_image;
+mod random;
 mod sinsemilla;
 mod tests;
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+/// a sampling interval of `VmConfig::livelock_interval`, a loop of a few instructions is caught
+/// within a few hundred steps.
+pub const DEFAULT_LIVELOCK_INTERVAL: u64 = 64;
+
+/// the samples kept at most, the older ones are dropped past it. A loop whose period spans
+/// more samples is not caught.
+const MAX_SAMPLES: usize = 1024;
+
+/// LivelockSample is the state of the guest but its memory and its step count: with the memory
+/// unchanged, the guest executing from two equal samples does the same thing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct LivelockSample {
+    pub pc: u32,
+    pub next_pc: u32,
+    pub registers: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub heap: u32,
+    pub preimage_key: [u8; 32],
+    pub preimage_offset: u32,
+    pub random_position: u64,
+    pub thread_pointer: u32,
+    pub in_delay_slot: bool,
+    /// the steps since the last mult/div, capped past the hazard window.
+    pub hilo_age: Option<u64>,
+    pub stdin_offset: usize,
+    /// the hints sent and the bytes of the incomplete one, the guest sending hints progresses.
+    pub hints_posted: u64,
+    pub pending_hint: usize,
+}
+
+/// LivelockDetector keeps the samples taken since the last write to the memory, by the step
+/// they were taken at. A sample equal to a kept one means the guest went back to the same state:
+/// it loops forever.
+#[derive(Debug, Default)]
+pub(crate) struct LivelockDetector {
+    generation: u64,
+    samples: HashMap<LivelockSample, u64>,
+}
+
+impl LivelockDetector {
+    /// Records `sample` of `step`, with the generation of the memory. Returns the steps since the
+    /// equal sample, a multiple of the period of the loop, if one was kept.
+    pub fn observe(&mut self, sample: LivelockSample, step: u64, generation: u64) -> Option<u64> {
+        if generation != self.generation || self.samples.len() >= MAX_SAMPLES {
+            self.generation = generation;
+            self.samples.clear();
+        }
+        match self.samples.insert(sample, step) {
+            Some(seen) if seen < step => Some(step - seen),
+            _ => None,
+        }
+    }
+}
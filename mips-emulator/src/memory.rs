@@ -1,50 +1,324 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::Read;
-use std::rc::Rc;
-use crate::page::{CachedPage, hash_pair, PAGE_ADDR_MASK, PAGE_ADDR_SIZE, PAGE_KEY_SIZE, PAGE_SIZE, ZERO_HASHS};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use sha3::{Digest, Keccak256};
+use crate::error::EmulatorError;
+use crate::memory_backend::{InMemoryBackend, MemoryBackend};
+use crate::hash::HashFunction;
+use crate::page::{CachedPage, PAGE_ADDR_MASK, PAGE_ADDR_SIZE, PAGE_KEY_MASK, PAGE_KEY_SIZE, PAGE_SIZE};
+
+/// the hash of the memory tree, unless `VmConfig::memory_hash` selects another.
+pub const DEFAULT_MEMORY_HASH: HashFunction = HashFunction::Sha3_256;
+
+/// the default of `VmConfig::max_read_range`.
+pub const DEFAULT_MAX_READ_RANGE: u32 = 64 << 20;
+
+/// Endianness is the byte order of the words of the guest memory. The pages hold the bytes of
+/// the guest, the endianness decides the words they are read as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// MIPS big endian, the byte order of Cannon.
+    #[default]
+    Big,
+    /// MIPS little endian, the mipsel guests.
+    Little,
+}
+
+impl Endianness {
+    pub fn word_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn word_to_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            Endianness::Big => word.to_be_bytes(),
+            Endianness::Little => word.to_le_bytes(),
+        }
+    }
+
+    /// Returns the address whose offset in its word is the byte lane of `addr` counted from the
+    /// most significant byte, the lane the big endian formulas of the loads and stores expect.
+    pub fn lane_addr(self, addr: u32) -> u32 {
+        match self {
+            Endianness::Big => addr,
+            Endianness::Little => addr ^ 3,
+        }
+    }
+}
 
-#[derive(Debug)]
+impl Display for Endianness {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endianness::Big => write!(f, "big endian"),
+            Endianness::Little => write!(f, "little endian"),
+        }
+    }
+}
+
+/// Memory is cheap to clone: pages are shared between clones behind an `Arc` and copied on the
+/// first write (copy-on-write), so a clone costs O(pages-in-table) rather than copying page data.
+/// Each clone keeps its own merkle node cache, so clones can be hashed in parallel from
+/// different threads. The pages are held by a `MemoryBackend`, in RAM unless the memory is
+/// created `with_backend`.
+#[derive(Debug, Clone)]
 pub struct Memory {
     /// generalized index -> merkle node or none if invalidate
     nodes: HashMap<u32, Option<Box<[u8; 32]>>>,
 
     /// page index -> cached page
-    pages: HashMap<u32, Rc<RefCell<CachedPage>>>,
+    pages: Box<dyn MemoryBackend>,
 
     // two caches: we often read instructions from one page, and do memory things with another page.
     // this prevents map lookups each instruction
     last_page_keys: [Option<u32>; 2],
-    last_page: [Option<Rc<RefCell<CachedPage>>>; 2],
+    last_page: [Option<Arc<CachedPage>>; 2],
 
     // for implement std::io::Read trait
     addr: u32,
     count: u32,
+
+    /// pages allocated, including the copies made when writing a shared page.
+    page_allocations: u64,
+    /// shared pages copied on write.
+    page_copies: u64,
+    /// the pages the page table may hold at most, further allocations fail with `HostOom`.
+    max_pages: Option<usize>,
+    /// the addresses `store` refuses to write with `WriteToReadOnly`.
+    read_only: Option<Range<u32>>,
+    /// the hash of the merkle tree.
+    hash: HashFunction,
+    /// the byte order of the words.
+    endianness: Endianness,
+    /// bumped by every write and by `clear` and `restore`, see `generation`.
+    generation: u64,
+}
+
+/// Allocation statistics of a `Memory`, the counters are inherited by clones.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// pages currently in the page table.
+    pub pages: usize,
+    /// pages allocated, including the copies made when writing a shared page.
+    pub page_allocations: u64,
+    /// shared pages copied on write.
+    pub page_copies: u64,
+}
+
+fn is_zero_page(page: &CachedPage) -> bool {
+    page.data[0..].iter().all(|b| *b == 0)
+}
+
+/// Memories are equal when they hold the same words, a page of zeros equals an absent page. The
+/// merkle caches, the statistics, the limits and the hash function are not compared.
+impl PartialEq for Memory {
+    fn eq(&self, other: &Self) -> bool {
+        self.page_union(other).into_iter().all(|page_index| {
+            match (self.pages.get(page_index), other.pages.get(page_index)) {
+                (Some(a), Some(b)) => Arc::ptr_eq(&a, &b) || a.data[0..] == b.data[0..],
+                (Some(page), None) | (None, Some(page)) => is_zero_page(&page),
+                (None, None) => true,
+            }
+        })
+    }
+}
+
+impl Eq for Memory {}
+
+/// Writes `bytes` into the byte lanes of `word`, in the memory order of `endianness`, from byte
+/// `offset` on, as many as fit before the end of the word. Returns the new word and the bytes
+/// copied.
+pub fn copy_into_word(
+    word: u32,
+    offset: u32,
+    bytes: &[u8],
+    endianness: Endianness,
+) -> (u32, usize) {
+    let mut lanes = endianness.word_to_bytes(word);
+    let start = offset as usize;
+    let n = bytes.len().min(4 - start);
+    lanes[start..start + n].copy_from_slice(&bytes[..n]);
+    (endianness.word_from_bytes(lanes), n)
+}
+
+/// Reads the byte lanes of `word`, in the memory order of `endianness`, from byte `offset` on
+/// into `out`, as many as fit before the end of the word. Returns the bytes copied.
+pub fn copy_from_word(word: u32, offset: u32, out: &mut [u8], endianness: Endianness) -> usize {
+    let lanes = endianness.word_to_bytes(word);
+    let start = offset as usize;
+    let n = out.len().min(4 - start);
+    out[..n].copy_from_slice(&lanes[start..start + n]);
+    n
 }
 
 impl Memory {
     pub fn new() -> Self {
-        Self {
+        Self::with_backend(Box::<InMemoryBackend>::default())
+    }
+
+    /// Creates a memory holding its pages in `backend`. The pages already in the backend are
+    /// part of the memory, like after `FileBackend::open`.
+    pub fn with_backend(backend: Box<dyn MemoryBackend>) -> Self {
+        let mut memory = Self {
             nodes: HashMap::new(),
-            pages: HashMap::new(),
+            pages: backend,
 
             last_page_keys: Default::default(), // default to invalid keys, to not match any pages
             last_page: Default::default(),
 
             addr: 0,
             count: 0,
+
+            page_allocations: 0,
+            page_copies: 0,
+            max_pages: None,
+            read_only: None,
+            hash: DEFAULT_MEMORY_HASH,
+            endianness: Endianness::Big,
+            generation: 0,
+        };
+        for page_index in memory.pages.page_indices() {
+            memory.invalidate_page_nodes(page_index);
+        }
+        memory
+    }
+
+    /// The pages held in RAM, less than `page_count` if the backend stores pages elsewhere.
+    pub fn resident_pages(&self) -> usize {
+        self.pages.resident_pages()
+    }
+
+    /// Writes the pages changed since the last flush to the storage of the backend.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pages.flush()
+    }
+
+    /// Returns the first error of the backend since the last call, see
+    /// `MemoryBackend::take_error`.
+    pub fn take_backend_error(&mut self) -> Option<io::Error> {
+        self.pages.take_error()
+    }
+
+    /// The file of the backend holding the pages, if any.
+    pub fn page_file(&self) -> Option<&Path> {
+        self.pages.page_file()
+    }
+
+    /// Drops every page and merkle node, keeping the allocations for the pages written next. The
+    /// limits, the read-only range, the hash function, the endianness and the statistics are
+    /// kept.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.nodes.clear();
+        self.last_page_keys = Default::default();
+        self.last_page = Default::default();
+        self.addr = 0;
+        self.count = 0;
+        self.generation += 1;
+    }
+
+    /// Makes the memory a clone of `other`, its pages shared copy-on-write, reusing the
+    /// allocations of the page table and of the merkle nodes. The merkle nodes above the pages
+    /// are hashed again on demand, the pages keep theirs.
+    pub fn restore(&mut self, other: &Memory) {
+        self.clear();
+        for page_index in other.pages.page_indices() {
+            if let Some(page) = other.pages.get(page_index) {
+                self.pages.insert(page_index, page);
+                // the nodes up to the root, the ancestors of a node already invalid are too
+                let mut generalized_index = (1 << PAGE_KEY_SIZE) | page_index;
+                while generalized_index > 0
+                    && self.nodes.insert(generalized_index, None).is_none()
+                {
+                    generalized_index >>= 1;
+                }
+            }
         }
+        self.page_allocations = other.page_allocations;
+        self.page_copies = other.page_copies;
+        self.max_pages = other.max_pages;
+        self.read_only.clone_from(&other.read_only);
+        self.hash = other.hash;
+        self.endianness = other.endianness;
+    }
+
+    /// A counter changing whenever the content of the memory may have changed: the memory holds
+    /// the same words as long as it doesn't change. Writing a word with its value changes it too.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether the page of `addr` was ever written.
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.pages.contains(addr >> PAGE_ADDR_SIZE)
     }
 
     pub fn page_count(&self) -> usize {
         self.pages.len()
     }
 
-    pub fn for_each_page<T: Fn(u32, &Rc<RefCell<CachedPage>>) -> Result<(), String>>
+    /// Limits the pages the page table may hold, to protect the host from guests touching many
+    /// pages. Pages already allocated are kept.
+    pub fn set_max_pages(&mut self, max_pages: Option<usize>) {
+        self.max_pages = max_pages;
+    }
+
+    /// The pages the page table may still allocate under the limit, none if unlimited.
+    pub fn available_pages(&self) -> Option<usize> {
+        self.max_pages.map(|max_pages| max_pages.saturating_sub(self.pages.len()))
+    }
+
+    /// Makes `store` fail on the words of `read_only`, the text of the program.
+    pub fn set_read_only(&mut self, read_only: Option<Range<u32>>) {
+        self.read_only = read_only;
+    }
+
+    pub fn hash_function(&self) -> HashFunction {
+        self.hash
+    }
+
+    /// Hashes the merkle tree with `hash`. The nodes above the pages are invalidated, the pages
+    /// hash their nodes again when they are hashed next, so they are neither copied nor read
+    /// from the backend here.
+    pub fn set_hash_function(&mut self, hash: HashFunction) {
+        if hash == self.hash {
+            return;
+        }
+        self.hash = hash;
+        for node in self.nodes.values_mut() {
+            *node = None;
+        }
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// Reads the words in the byte order `endianness`. The bytes of the pages are kept, the
+    /// words read from them change.
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            pages: self.pages.len(),
+            page_allocations: self.page_allocations,
+            page_copies: self.page_copies,
+        }
+    }
+
+    pub fn for_each_page<T: Fn(u32, &Arc<CachedPage>) -> Result<(), String>>
     (&mut self, handler: T) -> Result<(), String>{
 
-        for (page_index, cached_page) in self.pages.iter() {
-            let r = handler(*page_index, cached_page);
+        for page_index in self.pages.page_indices() {
+            let cached_page = self.pages.get(page_index).unwrap();
+            let r = handler(page_index, &cached_page);
             if let Err(e) = r {
                 return Err(e)
             }
@@ -53,7 +327,7 @@ impl Memory {
         Ok(())
     }
 
-    fn page_lookup(&mut self, page_index: u32) -> Option<Rc<RefCell<CachedPage>>> {
+    fn page_lookup(&mut self, page_index: u32) -> Option<Arc<CachedPage>> {
         // find cache first
         if Some(page_index) == self.last_page_keys[0] {
             return self.last_page[0].clone();
@@ -62,33 +336,60 @@ impl Memory {
             return self.last_page[1].clone();
         }
 
-        match self.pages.get(&page_index) {
+        match self.pages.get(page_index) {
             None => {None}
             Some(cached_page) => {
                 self.last_page_keys[1] = self.last_page_keys[0];
                 self.last_page[1] = self.last_page[0].clone();
 
                 self.last_page_keys[0] = Some(page_index);
-                self.last_page[0] = Some(cached_page.clone());
+                self.last_page[0] = Some(cached_page);
 
                 return self.last_page[0].clone();
             }
         }
     }
 
+    /// Returns the page for modification, the page is copied first if it is still shared with
+    /// a clone of this memory.
+    fn page_mut(&mut self, page_index: u32) -> Option<&mut CachedPage> {
+        // drop the reference held by the lookup cache, otherwise the page always looks shared.
+        for i in 0..2 {
+            if self.last_page_keys[i] == Some(page_index) {
+                self.last_page_keys[i] = None;
+                self.last_page[i] = None;
+            }
+        }
+
+        let page = self.pages.get_mut(page_index)?;
+        if Arc::strong_count(page) > 1 {
+            self.page_allocations += 1;
+            self.page_copies += 1;
+        }
+        Some(Arc::make_mut(page))
+    }
+
+    /// invalidate the merkle nodes from the page to the root.
+    fn invalidate_page_nodes(&mut self, page_index: u32) {
+        let mut generalized_index = (1 << PAGE_KEY_SIZE) | page_index;
+        while generalized_index > 0 {
+            self.nodes.insert(generalized_index, None);
+            generalized_index >>= 1;
+        }
+    }
+
     pub fn invalidate(&mut self, addr: u32) {
         if addr & 0x3 != 0 {
             panic!("unaligned memory access: {:x?}", addr)
         }
 
-        let should_ret = match self.page_lookup(addr >> PAGE_ADDR_SIZE) {
+        let should_ret = match self.page_mut(addr >> PAGE_ADDR_SIZE) {
             None => {
                 // no page, nothing to invalidate
                 true
             }
             Some(cached_page) => {
-                let mut cached_page = cached_page.borrow_mut();
-                let pre_valid = cached_page.ok[1].clone();
+                let pre_valid = cached_page.ok[1];
                 cached_page.invalidate(addr & (PAGE_ADDR_MASK as u32));
                 !pre_valid
             }
@@ -98,48 +399,52 @@ impl Memory {
             return;
         }
 
-        // find the generalized index of the first page covering the address
-        let mut generalized_index = (((1<<32 as u64) | (addr as u64)) >> PAGE_ADDR_SIZE) as u32;
-
-        while generalized_index > 0 {
-            self.nodes.insert(generalized_index, None);
-            generalized_index >>= 1;
-        }
+        self.invalidate_page_nodes(addr >> PAGE_ADDR_SIZE);
     }
 
     pub fn merklelize_subtree(&mut self, generalized_index: usize) -> [u8; 32] {
-        let l = generalized_index.ilog2() as usize;
+        // bit length of the index as in cannon, the root is at 1 and the 32 bytes leaves at 28,
+        // so a proof holds the leaf and its 27 siblings.
+        let l = generalized_index.ilog2() as usize + 1;
         if l > 28 {
             panic!("generalized index is too deep");
         }
 
-        let (hash, ok) = match self.nodes.get(&(generalized_index as u32)) {
-            None => {
-                // the generalized index node is not exist, then zero hash
-                (Box::new(ZERO_HASHS[28-l].clone()), true)
-            }
-            Some(node) => {
-                match node {
-                    None => {
-                        // the generalized index node was invalidated
-                        (Box::new([0; 32]), false)
-                    }
-                    Some(hash) => {
-                        // got the generalized index node
-                        (hash.clone(), true)
-                    }
-                }
-            }
-        };
+        if l > PAGE_KEY_SIZE {
+            // the node is inside a page, the page caches its own subtree
+            let depth_into_page = l - 1 - PAGE_KEY_SIZE;
+            let page_index = ((generalized_index >> depth_into_page) & PAGE_KEY_MASK) as u32;
+            let page_generalized_index = (1 << depth_into_page) |
+                (generalized_index & ((1 << depth_into_page) - 1));
+            let cached_page = match self.pages.get(page_index) {
+                None => return self.hash.zero_hashes()[28-l],
+                Some(cached_page) => cached_page,
+            };
+            // only re-hash through a mutable page when its caches are stale, so hashing does not
+            // copy pages shared with a clone.
+            return if cached_page.is_hashed(self.hash) {
+                cached_page.subtree_node(page_generalized_index)
+            } else {
+                drop(cached_page);
+                let hash = self.hash;
+                self.page_mut(page_index).unwrap().merklelize_subtree(page_generalized_index, hash)
+            };
+        }
 
-        if ok {
-            return *hash;
+        // copy the cached node out, so hashing an unchanged memory does not allocate
+        match self.nodes.get(&(generalized_index as u32)) {
+            // the generalized index node is not exist, then zero hash
+            None => return self.hash.zero_hashes()[28-l],
+            // got the generalized index node
+            Some(Some(hash)) => return **hash,
+            // the generalized index node was invalidated
+            Some(None) => {}
         }
 
         // the generalized index node was invalidated, then re compute
         let left = self.merklelize_subtree(generalized_index<<1);
         let right = self.merklelize_subtree(generalized_index<<1 | 1);
-        let hash = hash_pair(&left, &right);
+        let hash = self.hash.hasher().hash_pair(&left, &right);
         self.nodes.insert(generalized_index as u32, Some(Box::new(hash)));
         return hash;
     }
@@ -186,31 +491,116 @@ impl Memory {
         match self.page_lookup(addr >> PAGE_ADDR_SIZE) {
             None => {0u32}
             Some(cached_page) => {
-                let cached_page = cached_page.borrow();
                 // lookup in page
                 let page_addr = (addr as usize) & PAGE_ADDR_MASK;
-                u32::from_be_bytes((&cached_page.data[page_addr..page_addr+4]).try_into().unwrap())
+                let bytes = (&cached_page.data[page_addr..page_addr+4]).try_into().unwrap();
+                self.endianness.word_from_bytes(bytes)
+            }
+        }
+    }
+
+    /// Reads the word at `addr` like `get_memory`, without going through the page cache.
+    pub fn peek_memory(&self, addr: u32) -> u32 {
+        if addr & 0x3 != 0 {
+            panic!("unaligned memory access: {:x?}", addr);
+        }
+
+        match self.pages.get(addr >> PAGE_ADDR_SIZE) {
+            None => 0,
+            Some(page) => {
+                let page_addr = (addr as usize) & PAGE_ADDR_MASK;
+                let bytes = page.data[page_addr..page_addr+4].try_into().unwrap();
+                self.endianness.word_from_bytes(bytes)
             }
         }
     }
 
-    fn alloc_page(&mut self, page_index: u32) -> Rc<RefCell<CachedPage>> {
-        let cached_page = Rc::new(
-            RefCell::new(
-                CachedPage::new()
-            )
-        );
-        self.pages.insert(page_index, cached_page.clone());
+    /// Returns the bytes of the word at `word_addr` and of the next one, in memory order.
+    fn word_pair(&mut self, word_addr: u32) -> [u8; 8] {
+        let mut pair = [0; 8];
+        let first = self.get_memory(word_addr);
+        let second = self.get_memory(word_addr.wrapping_add(4));
+        pair[..4].copy_from_slice(&self.endianness.word_to_bytes(first));
+        pair[4..].copy_from_slice(&self.endianness.word_to_bytes(second));
+        pair
+    }
+
+    /// Reads the u32 at `addr` in the byte order of the memory, `addr` may be unaligned: the
+    /// value then spans the word at `addr` and the next one, the address wraps at 2^32.
+    pub fn read_u32(&mut self, addr: u32) -> u32 {
+        let (word_addr, offset) = (addr & !3, (addr & 3) as usize);
+        if offset == 0 {
+            return self.get_memory(word_addr);
+        }
+        let pair = self.word_pair(word_addr);
+        self.endianness.word_from_bytes(pair[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Writes `v` as a u32 at `addr` in the byte order of the memory, `addr` may be unaligned,
+    /// the counterpart of `read_u32`. Fails like `set_memory` if one of the two words can't be
+    /// written, the other one may be written then.
+    pub fn write_u32(&mut self, addr: u32, v: u32) -> Result<(), EmulatorError> {
+        let (word_addr, offset) = (addr & !3, (addr & 3) as usize);
+        if offset == 0 {
+            return self.set_memory(word_addr, v);
+        }
+        let mut pair = self.word_pair(word_addr);
+        pair[offset..offset + 4].copy_from_slice(&self.endianness.word_to_bytes(v));
+        let first = self.endianness.word_from_bytes(pair[..4].try_into().unwrap());
+        let second = self.endianness.word_from_bytes(pair[4..].try_into().unwrap());
+        self.set_memory(word_addr, first)?;
+        self.set_memory(word_addr.wrapping_add(4), second)
+    }
+
+    /// Returns the (address, word) pairs of the aligned words covering `len` bytes from `addr`,
+    /// in address order. The words of unmapped pages read as zero, no page is allocated.
+    pub fn words_in_range(&self, addr: u32, len: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let start = (addr & !3) as u64;
+        let end = if len == 0 { start } else { (addr as u64 + len as u64 + 3) & !3 };
+        let page_size = PAGE_SIZE as u64;
+        (start / page_size..(end + page_size - 1) / page_size).flat_map(move |page_index| {
+            let page = self.pages.get(page_index as u32);
+            let from = start.max(page_index * page_size);
+            let to = end.min((page_index + 1) * page_size);
+            (from..to).step_by(4).map(move |a| {
+                let word = page.as_ref().map_or(0, |page| {
+                    let page_addr = (a as usize) & PAGE_ADDR_MASK;
+                    let bytes = page.data[page_addr..page_addr+4].try_into().unwrap();
+                    self.endianness.word_from_bytes(bytes)
+                });
+                (a as u32, word)
+            })
+        })
+    }
+
+    /// allocates the page of `addr`.
+    fn alloc_page(&mut self, addr: u32) -> Result<(), EmulatorError> {
+        if let Some(max_pages) = self.max_pages {
+            if self.pages.len() >= max_pages {
+                return Err(EmulatorError::HostOom { addr, pages: self.pages.len(), ctx: None });
+            }
+        }
+        let page_index = addr >> PAGE_ADDR_SIZE;
+        self.pages.insert(page_index, Arc::new(CachedPage::new()));
+        self.page_allocations += 1;
         // make nodes to root
-        let mut k = (1 << PAGE_KEY_SIZE) | (page_index as u64);
-        while k > 0 {
-            self.nodes.insert(k as u32, None);
-            k >>= 1;
+        self.invalidate_page_nodes(page_index);
+        Ok(())
+    }
+
+    /// Writes the word at `addr` for the guest instruction at `pc`, failing with
+    /// `WriteToReadOnly` on the words made read only by `set_read_only`.
+    pub fn store(&mut self, addr: u32, v: u32, pc: u32) -> Result<(), EmulatorError> {
+        if let Some(read_only) = &self.read_only {
+            if read_only.contains(&addr) {
+                return Err(EmulatorError::WriteToReadOnly { addr, pc, ctx: None });
+            }
         }
-        cached_page
+        self.set_memory(addr, v)
     }
 
-    pub fn set_memory(&mut self, addr: u32, v: u32) {
+    /// Writes the word at `addr` even if it is read only, for the loader and the runtime patches.
+    pub fn set_memory(&mut self, addr: u32, v: u32) -> Result<(), EmulatorError> {
         // addr must be aligned to 4 bytes
         if addr & 0x3 != 0 {
             panic!("unaligned memory access: {:x?}", addr);
@@ -218,19 +608,66 @@ impl Memory {
 
         let page_index = addr >> PAGE_ADDR_SIZE;
         let page_addr = (addr as usize) & PAGE_ADDR_MASK;
-        let cached_page = match self.page_lookup(page_index) {
-            None => {
-                // allocate the page if we have not already
-                // Golang may mmap relatively large ranges, but we only allocate just in time.
-                self.alloc_page(page_index)
+        if self.pages.contains(page_index) {
+            self.invalidate(addr);
+        } else {
+            // allocate the page if we have not already
+            // Golang may mmap relatively large ranges, but we only allocate just in time.
+            self.alloc_page(addr)?;
+        }
+        let bytes = self.endianness.word_to_bytes(v);
+        let cached_page = self.page_mut(page_index).unwrap();
+        cached_page.data[page_addr..page_addr+4].copy_from_slice(&bytes);
+        self.pages.mark_dirty(page_index);
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Returns the words differing from `other` in address order, as (address, word of self,
+    /// word of other). The pages shared with `other` are skipped.
+    pub fn diff(&self, other: &Memory) -> Vec<(u32, u32, u32)> {
+        let mut out = vec![];
+        for page_index in self.page_union(other) {
+            if let (Some(a), Some(b)) = (self.pages.get(page_index), other.pages.get(page_index)) {
+                if Arc::ptr_eq(&a, &b) {
+                    continue;
+                }
             }
-            Some(cached_page) => {
-                self.invalidate(addr);
-                cached_page
+            for offset in (0..PAGE_SIZE as u32).step_by(4) {
+                let addr = (page_index << PAGE_ADDR_SIZE) | offset;
+                let (a, b) = (self.peek_memory(addr), other.peek_memory(addr));
+                if a != b {
+                    out.push((addr, a, b));
+                }
             }
-        };
-        let mut cached_page = cached_page.borrow_mut();
-        cached_page.data[page_addr..page_addr+4].copy_from_slice(&v.to_be_bytes());
+        }
+        out
+    }
+
+    /// Returns the keccak256 hash of the words of the memory: the index and the data of every
+    /// page holding a non-zero word, in index order. Unlike the merkle root it needs no caches,
+    /// and equal memories have the same hash whatever their hash function.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut hasher = Keccak256::new();
+        let mut page_indices = self.pages.page_indices();
+        page_indices.sort_unstable();
+        for page_index in page_indices {
+            let page = self.pages.get(page_index).unwrap();
+            if !is_zero_page(&page) {
+                hasher.update(page_index.to_be_bytes());
+                hasher.update(&page.data[0..]);
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Returns the indices of the pages of `self` or `other` in order.
+    fn page_union(&self, other: &Memory) -> Vec<u32> {
+        let mut pages = self.pages.page_indices();
+        pages.extend(other.pages.page_indices());
+        pages.sort_unstable();
+        pages.dedup();
+        pages
     }
 
     pub fn usage(&self) -> String {
@@ -252,28 +689,28 @@ impl Memory {
         return format!("{}, {}iB", total/div, exp_table[exp] as char);
     }
 
+    /// Sets the `count` bytes at `addr` the `Read` impl reads, cut at the end of the address
+    /// space: the range never wraps to address 0.
     pub fn read_memory_range(&mut self, addr: u32, count: u32) {
-        self.addr =  addr;
-        self.count = count;
+        self.addr = addr;
+        self.count = (count as u64).min((1u64 << 32) - addr as u64) as u32;
     }
 
-    pub fn set_memory_range<'a>(&mut self, mut addr: u32, mut r: Box<dyn Read+'a>) -> Result<(), std::io::ErrorKind> {
+    pub fn set_memory_range<'a>(&mut self, mut addr: u32, mut r: Box<dyn Read+'a>) -> Result<(), EmulatorError> {
         loop {
             let page_index = addr >> PAGE_ADDR_SIZE;
             let page_addr = addr & (PAGE_ADDR_MASK as u32);
-            let cached_page = self.page_lookup(page_index);
-            let page = match cached_page {
-                None => {
-                    self.alloc_page(page_index)
-                }
-                Some(page) => {
-                    page
-                }
-            };
+            if self.pages.contains(page_index) {
+                self.invalidate_page_nodes(page_index);
+            } else {
+                self.alloc_page(addr)?;
+            }
 
-            let mut page = page.borrow_mut();
+            let page = self.page_mut(page_index).unwrap();
             page.invalidate_full();
-            let n = r.read(&mut page.data[(page_addr as usize)..]).unwrap();
+            let n = r.read(&mut page.data[(page_addr as usize)..])?;
+            self.pages.mark_dirty(page_index);
+            self.generation += 1;
             if n == 0 {
                 return Ok(());
             }
@@ -288,14 +725,15 @@ impl Read for Memory {
             return Ok(0usize);
         }
 
-        let end_addr = self.addr + self.count;
+        // the range ends at 1 << 32 at most
+        let end_addr = self.addr as u64 + self.count as u64;
 
         let page_index = self.addr >> PAGE_ADDR_SIZE;
         // todo: fix bug, read too much
         let (start, mut end) = (self.addr & (PAGE_ADDR_MASK as u32), PAGE_SIZE as u32);
 
-        if page_index == (end_addr >> PAGE_ADDR_SIZE) {
-            end = end_addr & (PAGE_ADDR_MASK as u32);
+        if page_index as u64 == (end_addr >> PAGE_ADDR_SIZE) {
+            end = end_addr as u32 & (PAGE_ADDR_MASK as u32);
         }
 
         let cached_page = self.page_lookup(page_index);
@@ -307,8 +745,7 @@ impl Read for Memory {
                 }
                 size
             }
-            Some(cached_page) => {
-                let page = cached_page.borrow_mut();
+            Some(page) => {
                 let size = buf.len().min( (end - start) as usize );
                 for i in 0..size {
                     buf[i] = page.data[(start as usize)+i];
@@ -316,7 +753,7 @@ impl Read for Memory {
                 size
             }
         };
-        self.addr += n as u32;
+        self.addr = self.addr.wrapping_add(n as u32);
         self.count -= n as u32;
 
         Ok(n)
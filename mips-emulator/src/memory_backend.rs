@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::page::{CachedPage, PAGE_SIZE};
+
+/// MemoryBackend holds the pages of a `Memory` by page index. The pages are handed out behind
+/// an `Arc`, so `Memory` can share them with its clones and copy them on write.
+pub trait MemoryBackend: Debug + Send + Sync {
+    fn get(&self, page_index: u32) -> Option<Arc<CachedPage>>;
+
+    /// Returns the page to update its merkle caches, `mark_dirty` must follow a change of its
+    /// data.
+    fn get_mut(&mut self, page_index: u32) -> Option<&mut Arc<CachedPage>>;
+
+    /// Records that the data of the page changed since it was read from the backend.
+    fn mark_dirty(&mut self, _page_index: u32) {}
+
+    fn insert(&mut self, page_index: u32, page: Arc<CachedPage>);
+
+    fn contains(&self, page_index: u32) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the indices of the pages, in no particular order.
+    fn page_indices(&self) -> Vec<u32>;
+
+    /// Drops every page, keeping the allocations of the backend for the pages inserted next.
+    fn clear(&mut self);
+
+    /// The pages held in RAM.
+    fn resident_pages(&self) -> usize {
+        self.len()
+    }
+
+    /// Writes the pages changed since the last flush to the backing storage, if any.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the first error of the backing storage since the last call, if any. The page
+    /// access that failed found no page, `InstrumentedState::step` fails with the error.
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+
+    /// The file holding the pages, a saved state refers to it, see `State::save`.
+    fn page_file(&self) -> Option<&Path> {
+        None
+    }
+
+    fn box_clone(&self) -> Box<dyn MemoryBackend>;
+}
+
+impl Clone for Box<dyn MemoryBackend> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// InMemoryBackend keeps every page in RAM, the default backend of `Memory`.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryBackend {
+    pages: HashMap<u32, Arc<CachedPage>>,
+}
+
+impl MemoryBackend for InMemoryBackend {
+    fn get(&self, page_index: u32) -> Option<Arc<CachedPage>> {
+        self.pages.get(&page_index).cloned()
+    }
+
+    fn get_mut(&mut self, page_index: u32) -> Option<&mut Arc<CachedPage>> {
+        self.pages.get_mut(&page_index)
+    }
+
+    fn insert(&mut self, page_index: u32, page: Arc<CachedPage>) {
+        self.pages.insert(page_index, page);
+    }
+
+    fn contains(&self, page_index: u32) -> bool {
+        self.pages.contains_key(&page_index)
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page_indices(&self) -> Vec<u32> {
+        self.pages.keys().copied().collect()
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    fn box_clone(&self) -> Box<dyn MemoryBackend> {
+        Box::new(self.clone())
+    }
+}
+
+/// the page file shared by a `FileBackend` and its clones.
+struct PageFile {
+    file: File,
+    /// the end of the file, where new slots are appended.
+    len: u64,
+    /// the slots no backend refers to anymore.
+    free: Vec<u64>,
+}
+
+/// Slot is where a page is stored in the page file. Clones of a backend share the slots of
+/// their pages, a shared slot is never overwritten and returns to the free list once dropped.
+struct Slot {
+    offset: u64,
+    file: Arc<Mutex<PageFile>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Ok(mut file) = self.file.lock() {
+            file.free.push(self.offset);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HotPage {
+    page: Arc<CachedPage>,
+    /// the data changed since the page was read from its slot.
+    dirty: bool,
+    tick: u64,
+}
+
+#[derive(Clone)]
+struct Pages {
+    /// every page, with its slot if it was written to the file.
+    index: HashMap<u32, Option<Arc<Slot>>>,
+    hot: HashMap<u32, HotPage>,
+    /// the hot pages by their last use, the least recently used first.
+    lru: BTreeMap<u64, u32>,
+    tick: u64,
+    capacity: usize,
+}
+
+/// FileBackend stores the pages in a file and keeps the least recently used ones in RAM only,
+/// up to the capacity, for guests with more memory than the host. The merkle caches of a page
+/// are not stored, they are computed again when the page is hashed after being read back.
+///
+/// A page that can't be read from the page file is not found, a page that can't be written to
+/// it stays in RAM. The error is kept for `take_error`.
+pub struct FileBackend {
+    path: PathBuf,
+    file: Arc<Mutex<PageFile>>,
+    /// behind a mutex, as reading a page through `get` moves it into RAM.
+    pages: Mutex<Pages>,
+    /// the first IO error since the last `take_error`.
+    error: Mutex<Option<io::Error>>,
+}
+
+impl Debug for FileBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let pages = self.pages.lock().unwrap();
+        f.debug_struct("FileBackend")
+            .field("path", &self.path)
+            .field("pages", &pages.index.len())
+            .field("resident_pages", &pages.hot.len())
+            .field("capacity", &pages.capacity)
+            .finish()
+    }
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".index");
+    PathBuf::from(name)
+}
+
+impl FileBackend {
+    /// Creates an empty page file at `path`, keeping at most `capacity` pages in RAM.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(Self::new(path, PageFile { file, len: 0, free: Vec::new() }, capacity))
+    }
+
+    /// Opens the page file at `path` with the pages of its last `flush`.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut entries = vec![];
+        File::open(index_path(&path))?.read_to_end(&mut entries)?;
+        if entries.len() % 12 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated page index"));
+        }
+
+        let used: HashSet<u64> = entries.chunks(12)
+            .map(|entry| u64::from_le_bytes(entry[4..].try_into().unwrap()))
+            .collect();
+        let free = (0..len / PAGE_SIZE as u64)
+            .map(|slot| slot * PAGE_SIZE as u64)
+            .filter(|offset| !used.contains(offset))
+            .collect();
+        let backend = Self::new(path, PageFile { file, len, free }, capacity);
+        {
+            let mut pages = backend.pages.lock().unwrap();
+            for entry in entries.chunks(12) {
+                let page_index = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                let offset = u64::from_le_bytes(entry[4..].try_into().unwrap());
+                let slot = Slot { offset, file: backend.file.clone() };
+                pages.index.insert(page_index, Some(Arc::new(slot)));
+            }
+        }
+        Ok(backend)
+    }
+
+    fn new(path: PathBuf, file: PageFile, capacity: usize) -> Self {
+        Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+            pages: Mutex::new(Pages {
+                index: HashMap::new(),
+                hot: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                capacity: capacity.max(1),
+            }),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Keeps the first error for `take_error`.
+    fn keep_error(error: &Mutex<Option<io::Error>>, err: io::Error) {
+        error.lock().unwrap().get_or_insert(err);
+    }
+}
+
+impl Pages {
+    fn touch(&mut self, page_index: u32) {
+        self.tick += 1;
+        let hot = self.hot.get_mut(&page_index).unwrap();
+        self.lru.remove(&hot.tick);
+        hot.tick = self.tick;
+        self.lru.insert(self.tick, page_index);
+    }
+
+    /// Moves the page into RAM, returns false if there is no such page.
+    fn load(&mut self, file: &Arc<Mutex<PageFile>>, page_index: u32) -> io::Result<bool> {
+        if self.hot.contains_key(&page_index) {
+            self.touch(page_index);
+            return Ok(true);
+        }
+        let offset = match self.index.get(&page_index) {
+            Some(Some(slot)) => slot.offset,
+            // a page without slot is always hot, it is written to its slot when evicted
+            _ => return Ok(false),
+        };
+
+        let mut page = CachedPage::new();
+        {
+            let mut file = file.lock().unwrap();
+            file.file.seek(SeekFrom::Start(offset))?;
+            file.file.read_exact(&mut page.data[0..PAGE_SIZE])?;
+        }
+        self.insert(file, page_index, Arc::new(page), false)?;
+        Ok(true)
+    }
+
+    /// Inserts the page in RAM, evicting the least recently used pages over the capacity. A page
+    /// that can't be written back stays in RAM.
+    fn insert(
+        &mut self,
+        file: &Arc<Mutex<PageFile>>,
+        page_index: u32,
+        page: Arc<CachedPage>,
+        dirty: bool,
+    ) -> io::Result<()> {
+        self.index.entry(page_index).or_insert(None);
+        self.tick += 1;
+        if let Some(old) = self.hot.insert(page_index, HotPage { page, dirty, tick: self.tick }) {
+            self.lru.remove(&old.tick);
+        }
+        self.lru.insert(self.tick, page_index);
+
+        // the page just inserted is the most recently used, it is never evicted here
+        while self.hot.len() > self.capacity {
+            let (tick, evicted) = self.lru.pop_first().unwrap();
+            let hot = self.hot.remove(&evicted).unwrap();
+            if hot.dirty {
+                if let Err(err) = self.write_back(file, evicted, &hot.page) {
+                    self.hot.insert(evicted, hot);
+                    self.lru.insert(tick, evicted);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the data of the page to its slot, or to a new one if the slot is shared with a
+    /// clone of the backend.
+    fn write_back(
+        &mut self,
+        file: &Arc<Mutex<PageFile>>,
+        page_index: u32,
+        page: &CachedPage,
+    ) -> io::Result<()> {
+        let owned = match self.index.get(&page_index) {
+            Some(Some(slot)) if Arc::strong_count(slot) == 1 => Some(slot.offset),
+            _ => None,
+        };
+        let mut page_file = file.lock().unwrap();
+        let offset = owned.unwrap_or_else(|| {
+            page_file.free.pop().unwrap_or_else(|| {
+                page_file.len += PAGE_SIZE as u64;
+                page_file.len - PAGE_SIZE as u64
+            })
+        });
+        let written = page_file.file.seek(SeekFrom::Start(offset))
+            .and_then(|_| page_file.file.write_all(&page.data[0..PAGE_SIZE]));
+        if let Err(err) = written {
+            if owned.is_none() {
+                page_file.free.push(offset);
+            }
+            return Err(err);
+        }
+        drop(page_file);
+
+        if owned.is_none() {
+            // the shared slot is dropped once the file is unlocked
+            let slot = Slot { offset, file: file.clone() };
+            self.index.insert(page_index, Some(Arc::new(slot)));
+        }
+        Ok(())
+    }
+}
+
+impl MemoryBackend for FileBackend {
+    fn get(&self, page_index: u32) -> Option<Arc<CachedPage>> {
+        let mut pages = self.pages.lock().unwrap();
+        match pages.load(&self.file, page_index) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => {
+                Self::keep_error(&self.error, err);
+                // the page read is in RAM even if an eviction failed
+                pages.hot.get(&page_index)?;
+            }
+        }
+        Some(pages.hot[&page_index].page.clone())
+    }
+
+    fn get_mut(&mut self, page_index: u32) -> Option<&mut Arc<CachedPage>> {
+        let pages = self.pages.get_mut().unwrap();
+        if let Err(err) = pages.load(&self.file, page_index) {
+            Self::keep_error(&self.error, err);
+        }
+        pages.hot.get_mut(&page_index).map(|hot| &mut hot.page)
+    }
+
+    fn mark_dirty(&mut self, page_index: u32) {
+        let pages = self.pages.get_mut().unwrap();
+        if let Some(hot) = pages.hot.get_mut(&page_index) {
+            hot.dirty = true;
+        }
+    }
+
+    fn insert(&mut self, page_index: u32, page: Arc<CachedPage>) {
+        if let Err(err) = self.pages.get_mut().unwrap().insert(&self.file, page_index, page, true) {
+            Self::keep_error(&self.error, err);
+        }
+    }
+
+    fn contains(&self, page_index: u32) -> bool {
+        self.pages.lock().unwrap().index.contains_key(&page_index)
+    }
+
+    fn len(&self) -> usize {
+        self.pages.lock().unwrap().index.len()
+    }
+
+    fn page_indices(&self) -> Vec<u32> {
+        self.pages.lock().unwrap().index.keys().copied().collect()
+    }
+
+    /// The slots of the pages go back to the free list of the file, unless a clone still uses
+    /// them.
+    fn clear(&mut self) {
+        let pages = self.pages.get_mut().unwrap();
+        pages.index.clear();
+        pages.hot.clear();
+        pages.lru.clear();
+    }
+
+    fn resident_pages(&self) -> usize {
+        self.pages.lock().unwrap().hot.len()
+    }
+
+    /// Writes the dirty pages and the index of the pages next to the page file, as
+    /// `<path>.index`, for `FileBackend::open`.
+    fn flush(&mut self) -> io::Result<()> {
+        let pages = self.pages.get_mut().unwrap();
+        let dirty: Vec<u32> = pages.hot.iter()
+            .filter(|(_, hot)| hot.dirty)
+            .map(|(page_index, _)| *page_index)
+            .collect();
+        for page_index in dirty {
+            let page = pages.hot[&page_index].page.clone();
+            pages.write_back(&self.file, page_index, &page)?;
+            pages.hot.get_mut(&page_index).unwrap().dirty = false;
+        }
+
+        let mut entries = Vec::with_capacity(pages.index.len() * 12);
+        for (page_index, slot) in &pages.index {
+            let slot = slot.as_ref().expect("every page has a slot after the write back");
+            entries.extend(page_index.to_le_bytes());
+            entries.extend(slot.offset.to_le_bytes());
+        }
+        self.file.lock().unwrap().file.flush()?;
+        std::fs::write(index_path(&self.path), entries)
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.get_mut().unwrap().take()
+    }
+
+    fn page_file(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn box_clone(&self) -> Box<dyn MemoryBackend> {
+        let pages = self.pages.lock().unwrap().clone();
+        Box::new(FileBackend {
+            path: self.path.clone(),
+            file: self.file.clone(),
+            pages: Mutex::new(pages),
+            error: Mutex::new(None),
+        })
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// the steps executed.
+pub const STEPS: &str = "steps";
+/// the steps executed per second of wall time, observed for each batch of steps.
+pub const STEPS_PER_SECOND: &str = "steps_per_second";
+/// the memory pages allocated, see `MemoryStats::page_allocations`.
+pub const PAGES_ALLOCATED: &str = "pages_allocated";
+/// the preimages read from the oracle.
+pub const PREIMAGES_FETCHED: &str = "preimages_fetched";
+/// the complete hints sent to the oracle.
+pub const HINTS_POSTED: &str = "hints_posted";
+/// the syscalls of the guest, the counter of each syscall is named `syscall_<number>`.
+pub const SYSCALL_PREFIX: &str = "syscall_";
+
+/// the steps between two reports of the step loop, so the sink is not called on every step.
+pub const REPORT_INTERVAL: u64 = 1 << 16;
+
+/// MetricsSink receives the counters of the emulator, to export them to a monitoring system.
+/// See `InstrumentedState::set_metrics_sink`.
+pub trait MetricsSink {
+    /// Adds `value` to the counter `name`.
+    fn inc_counter(&mut self, name: &str, value: u64);
+
+    /// Records a sample of the distribution `name`.
+    fn observe(&mut self, name: &str, value: f64);
+}
+
+/// NoopSink drops the metrics, the sink of a state without one set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn inc_counter(&mut self, _name: &str, _value: u64) {}
+
+    fn observe(&mut self, _name: &str, _value: f64) {}
+}
+
+#[derive(Debug, Default)]
+struct TestValues {
+    counters: HashMap<String, u64>,
+    observations: HashMap<String, Vec<f64>>,
+}
+
+/// TestSink keeps the metrics it receives, its clones share them so a test can read the values
+/// reported to the clone it gave to the state.
+#[derive(Debug, Default, Clone)]
+pub struct TestSink {
+    values: Arc<Mutex<TestValues>>,
+}
+
+impl TestSink {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The sum of the increments of the counter, zero if it was never reported.
+    pub fn counter(&self, name: &str) -> u64 {
+        self.values.lock().unwrap().counters.get(name).copied().unwrap_or(0)
+    }
+
+    /// The samples of the distribution, in the order they were observed.
+    pub fn observations(&self, name: &str) -> Vec<f64> {
+        self.values.lock().unwrap().observations.get(name).cloned().unwrap_or_default()
+    }
+}
+
+impl MetricsSink for TestSink {
+    fn inc_counter(&mut self, name: &str, value: u64) {
+        *self.values.lock().unwrap().counters.entry(name.to_string()).or_default() += value;
+    }
+
+    fn observe(&mut self, name: &str, value: f64) {
+        self.values.lock().unwrap().observations.entry(name.to_string()).or_default().push(value);
+    }
+}
+
+/// PendingMetrics accumulates the metrics of the step loop between two reports.
+#[derive(Debug)]
+pub(crate) struct PendingMetrics {
+    /// the step and the page allocations of the memory at the last report.
+    step: u64,
+    page_allocations: u64,
+    reported_at: Instant,
+    syscalls: BTreeMap<u32, u64>,
+}
+
+impl PendingMetrics {
+    pub(crate) fn new(step: u64, page_allocations: u64) -> Self {
+        Self { step, page_allocations, reported_at: Instant::now(), syscalls: BTreeMap::new() }
+    }
+
+    pub(crate) fn syscall(&mut self, num: u32) {
+        *self.syscalls.entry(num).or_default() += 1;
+    }
+
+    /// Reports the steps and the page allocations since the last report, and the syscalls.
+    pub(crate) fn report(&mut self, sink: &mut dyn MetricsSink, step: u64, page_allocations: u64) {
+        let steps = step.saturating_sub(self.step);
+        let now = Instant::now();
+        if steps > 0 {
+            sink.inc_counter(STEPS, steps);
+            let elapsed = now.duration_since(self.reported_at).as_secs_f64();
+            if elapsed > 0.0 {
+                sink.observe(STEPS_PER_SECOND, steps as f64 / elapsed);
+            }
+        }
+        let pages = page_allocations.saturating_sub(self.page_allocations);
+        if pages > 0 {
+            sink.inc_counter(PAGES_ALLOCATED, pages);
+        }
+        for (num, count) in std::mem::take(&mut self.syscalls) {
+            sink.inc_counter(&format!("{}{}", SYSCALL_PREFIX, num), count);
+        }
+        self.step = step;
+        self.page_allocations = page_allocations;
+        self.reported_at = now;
+    }
+}
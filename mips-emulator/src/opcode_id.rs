@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpcodeId {
     // Arithmetic Logic Unit
     ADD,
@@ -18,6 +19,10 @@ pub enum OpcodeId {
     SLTI,
     SLTIU,
     SLTU,
+    MOVZ,
+    MOVN,
+    CLZ,
+    CLO,
 
     // Shifter
     SLL,
@@ -26,10 +31,13 @@ pub enum OpcodeId {
     SRAV,
     SRL,
     SRLV,
+    ROTR,
+    ROTRV,
 
     // Multiply
     MULT,
     MULTU,
+    MUL,
     DIV,
     DIVU,
     MFHI,
@@ -40,17 +48,17 @@ pub enum OpcodeId {
     // Branch
     BEQ,
     BGEZ,
-    BGEZAL,
     BGTZ,
     BLEZ,
     BLTZ,
-    BLTZAL,
     BNE,
     J,
     JAL,
     JALR,
     JR,
     SYSCALL,
+    BREAK,
+    SYNC,
 
     // Memory Access
     LB,
@@ -58,7 +66,33 @@ pub enum OpcodeId {
     LH,
     LHU,
     LW,
+    LWL,
+    LWR,
+    LL,
     SB,
     SH,
     SW,
+    SWL,
+    SWR,
+    SC,
+    CACHE,
+
+    // Hardware registers
+    RDHWR,
+
+    // Bitfield
+    EXT,
+    INS,
+
+    // Byte shuffle
+    SEB,
+    SEH,
+    WSBH,
+}
+
+/// Returns whether the emulator executes `insn`, it rejects any other instruction and the
+/// circuits flag them in the opcode table. The decoder of `decode::opcode_id` is the one list
+/// of the supported encodings.
+pub fn is_supported(insn: u32) -> bool {
+    crate::decode::opcode_id(insn).is_some()
 }
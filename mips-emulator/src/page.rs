@@ -1,9 +1,6 @@
-use std::convert::TryInto;
 use std::ops::{Index, IndexMut, Range, RangeFrom};
-use lazy_static::lazy_static;
-use sha3::{Digest, Sha3_256};
-use sha3::digest::{FixedOutput};
 use log::debug;
+use crate::hash::HashFunction;
 
 /// Note: 2**12 = 4 KiB, the minimum page-size in Unicorn for mmap
 pub const PAGE_ADDR_SIZE: usize = 12;
@@ -11,29 +8,7 @@ pub const PAGE_KEY_SIZE: usize = 32 - PAGE_ADDR_SIZE;
 pub const PAGE_SIZE: usize = 1 << PAGE_ADDR_SIZE;
 pub const PAGE_ADDR_MASK: usize = PAGE_SIZE - 1;
 const MAX_PAGE_COUNT: usize = 1 << PAGE_KEY_SIZE;
-const PAGE_KEY_MASK: usize = MAX_PAGE_COUNT - 1;
-
-pub fn hash_pair(data_l: &[u8; 32], data_r: &[u8; 32]) -> [u8; 32] {
-    let mut hasher = Sha3_256::default();
-    hasher.update([&data_l[..], data_r].concat());
-    return hasher.finalize_fixed().try_into().unwrap();
-}
-
-fn zero_hash() -> Box<[[u8; 32]; 29]> {
-    let mut out = Box::new(
-        [[0; 32]; 29]
-    );
-
-    for i in 1..29 {
-        out[i] = hash_pair(&out[i-1], &out[i-1]);
-    }
-
-    out
-}
-
-lazy_static! {
-    pub static ref ZERO_HASHS: [[u8; 32]; 29] = *zero_hash();
-}
+pub const PAGE_KEY_MASK: usize = MAX_PAGE_COUNT - 1;
 
 #[derive(Debug, Clone)]
 pub struct Page([u8; PAGE_SIZE]);
@@ -96,6 +71,9 @@ pub struct CachedPage {
 
     // true if the above intermediate node is valid
     pub ok: [bool; PAGE_SIZE / 32],
+
+    // the hash function of the valid nodes
+    hash: Option<HashFunction>,
 }
 
 impl CachedPage {
@@ -104,9 +82,15 @@ impl CachedPage {
             data: Page::new(),
             cache: [[0; 32]; PAGE_SIZE / 32],
             ok: [false; PAGE_SIZE / 32],
+            hash: None,
         }
     }
 
+    /// Returns whether the root of the page is cached for `hash`.
+    pub fn is_hashed(&self, hash: HashFunction) -> bool {
+        self.ok[1] && self.hash == Some(hash)
+    }
+
     pub fn invalidate(&mut self, page_addr: u32) {
         if page_addr as usize >= PAGE_SIZE {
             panic!("CachedPage invalidate page: invalid page addr")
@@ -128,7 +112,14 @@ impl CachedPage {
         self.ok.fill(false);
     }
 
-    pub fn merkle_root(&mut self) -> [u8; 32] {
+    /// Returns the root of the page under `hash`, the nodes hashed with another hash function
+    /// are computed again.
+    pub fn merkle_root(&mut self, hash: HashFunction) -> [u8; 32] {
+        if self.hash != Some(hash) {
+            self.invalidate_full();
+            self.hash = Some(hash);
+        }
+        let hasher = hash.hasher();
         // hash the bottom layer
         debug!("hash the bottom layer");
         for i in (0..PAGE_SIZE).step_by(64) {
@@ -137,9 +128,7 @@ impl CachedPage {
                 continue
             }
             debug!("j: {} <- {}, {}", j, i, i+64);
-            let mut hasher = Sha3_256::default();
-            hasher.update(&self.data[i..i+64]);
-            self.cache[j] = hasher.finalize_fixed().try_into().unwrap();
+            self.cache[j] = hasher.hash(&self.data[i..i+64]);
             self.ok[j] = true;
         }
 
@@ -151,24 +140,31 @@ impl CachedPage {
                 continue
             }
             debug!("j: {} <- {}, {}", j, i, i+1);
-            self.cache[j] = hash_pair(&self.cache[i], &self.cache[i+1]);
+            self.cache[j] = hasher.hash_pair(&self.cache[i], &self.cache[i+1]);
             self.ok[j] = true
         }
 
         self.cache[1]
     }
 
-    pub fn merklelize_subtree(&mut self, generalized_index: usize) -> [u8; 32] {
-        self.merkle_root();
+    pub fn merklelize_subtree(&mut self, generalized_index: usize, hash: HashFunction) -> [u8; 32] {
+        self.merkle_root(hash);
+        self.subtree_node(generalized_index)
+    }
+
+    /// Returns a node of the page subtree, the caches must be valid, i.e. `ok[1]` is set.
+    pub fn subtree_node(&self, generalized_index: usize) -> [u8; 32] {
         if generalized_index >= PAGE_SIZE/32 {
             if generalized_index >= PAGE_SIZE/32*2 {
                 panic!("generalized_index too deep");
             }
             // it's pointing to a bottom node
             let node_index = generalized_index & (PAGE_ADDR_MASK >> 5);
-            [0; 32].clone_from_slice(
+            let mut node = [0; 32];
+            node.clone_from_slice(
                 &self.data[(node_index <<5).. ((node_index <<5)+32)]
-            )
+            );
+            return node;
         }
         self.cache[generalized_index]
     }
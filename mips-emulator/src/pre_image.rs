@@ -1,6 +1,48 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use log::{debug, warn};
+use crate::error::EmulatorError;
+use crate::hash::{Hasher32, Keccak256Hasher, Sha256Hasher};
+
+/// the bytes of the length prefixed preimage the emulator fetches at once from an oracle serving
+/// parts, at offsets aligned to it. The emulator keeps a single window, such a preimage is never
+/// fetched whole to be read, see `PreimageOracle::serves_parts`.
+pub const PREIMAGE_WINDOW_SIZE: usize = 4096;
+
+/// the bytes a window holds past its end, so the 32 bytes part a read copies from never
+/// straddles two windows.
+pub const PREIMAGE_WINDOW_OVERLAP: usize = 32;
+
 pub trait PreimageOracle {
     fn hint(&mut self, v: &[u8]);
-    fn get_preimage(&self, k: [u8; 32]) -> Vec<u8>;
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError>;
+
+    /// Whether the oracle serves `preimage_len` and `get_preimage_part` without fetching the
+    /// whole preimage. The emulator reads the preimages of such an oracle window by window, and
+    /// fetches those of the others whole with `get_preimage`, once per key read.
+    fn serves_parts(&self) -> bool {
+        false
+    }
+
+    /// Returns the length of the preimage of `k`, without its length prefix. The default
+    /// implementations of `preimage_len` and `get_preimage_part` fetch the whole preimage: an
+    /// oracle serving large preimages overrides both, and `serves_parts`.
+    fn preimage_len(&mut self, k: [u8; 32]) -> Result<u64, EmulatorError> {
+        Ok(self.get_preimage(k)?.len() as u64)
+    }
+
+    /// Returns the `len` bytes of the preimage of `k` from `offset`, fewer past its end.
+    fn get_preimage_part(
+        &mut self,
+        k: [u8; 32],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        let data = self.get_preimage(k)?;
+        let part = data.get(offset as usize..).unwrap_or(&[]);
+        Ok(part[..part.len().min(len)].to_vec())
+    }
 }
 
 pub trait Key {
@@ -9,8 +51,12 @@ pub trait Key {
     fn preimage_key(&self) -> [u8; 32];
 }
 
+// the key types of the preimage oracle spec: 3 is reserved for generic global data and 5 is the
+// blob point evaluation, SHA2-256 keys are of type 4 like in op-preimage.
 const LOCAL_KEY_TYPE: u8 = 1;
 const KECCAK256KEY_TYPE: u8 = 2;
+const SHA256KEY_TYPE: u8 = 4;
+const PRECOMPILE_KEY_TYPE: u8 = 6;
 
 pub struct LocalIndexKey(pub u64);
 
@@ -34,6 +80,386 @@ impl Key for Keccak256Key {
     }
 }
 
+pub struct Sha256Key(pub [u8;32]);
+
+impl Key for Sha256Key {
+    fn preimage_key(&self) -> [u8; 32] {
+        let mut out = self.0;
+        out[0] = SHA256KEY_TYPE;
+        out
+    }
+}
+
+/// PrecompileKey is the keccak256 hash of the precompile address and its input, its preimage is
+/// the precompile result. E.g. the identity precompile (address 0x04) returns its input.
+pub struct PrecompileKey(pub [u8;32]);
+
+impl Key for PrecompileKey {
+    fn preimage_key(&self) -> [u8; 32] {
+        let mut out = self.0;
+        out[0] = PRECOMPILE_KEY_TYPE;
+        out
+    }
+}
+
+/// Checks that `data` hashes to the key under the hash of the key type. Local and precompile keys
+/// can't be checked, their data is accepted as is.
+pub fn verify_preimage(k: [u8; 32], data: &[u8]) -> Result<(), EmulatorError> {
+    let hash: [u8; 32] = match k[0] {
+        KECCAK256KEY_TYPE => Keccak256Hasher.hash(data),
+        SHA256KEY_TYPE => Sha256Hasher.hash(data),
+        LOCAL_KEY_TYPE | PRECOMPILE_KEY_TYPE => return Ok(()),
+        _ => return Err(EmulatorError::UnsupportedKeyType { key: k }),
+    };
+    if hash[1..] != k[1..] {
+        return Err(EmulatorError::PreimageHashMismatch { key: k });
+    }
+    Ok(())
+}
+
+/// PreimageCacheStats counts the preimage reads of the guest, see
+/// `InstrumentedState::preimage_cache_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreimageCacheStats {
+    /// the windows of preimages fetched from the oracle.
+    pub fetches: u64,
+    /// the reads served from the cached window.
+    pub hits: u64,
+    /// the distinct keys fetched.
+    pub keys: usize,
+    /// the fetches of a window fetched before, checked against the digest of the first fetch.
+    pub refetches: u64,
+    /// the most bytes of preimage the emulator kept at once, the length prefix included.
+    pub peak_retained: usize,
+}
+
+/// PreimageDigests keeps the keccak256 digest of every window of preimage fetched from the
+/// oracle, with the length of the preimage. The emulator only keeps the last window, a window
+/// read again is fetched again, and an oracle returning different data for it would silently
+/// change the trace.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PreimageDigests {
+    /// the digest and the size of the window at each offset of each key.
+    digests: HashMap<([u8; 32], u32), ([u8; 32], usize)>,
+    keys: HashSet<[u8; 32]>,
+    stats: PreimageCacheStats,
+}
+
+impl PreimageDigests {
+    /// Records the fetch of the window at `start` of the preimage of `key`, `len` bytes long.
+    /// Fails with `OracleInconsistent` if the window was fetched before with other data.
+    pub(crate) fn check(
+        &mut self,
+        key: [u8; 32],
+        start: u32,
+        len: u64,
+        window: &[u8],
+    ) -> Result<(), EmulatorError> {
+        let digest = Keccak256Hasher.hash(&[len.to_be_bytes().as_slice(), window].concat());
+        self.stats.fetches += 1;
+        self.retain(window.len());
+        match self.digests.get(&(key, start)) {
+            Some(previous) if *previous != (digest, window.len()) => {
+                Err(EmulatorError::OracleInconsistent { key })
+            }
+            Some(_) => {
+                self.stats.refetches += 1;
+                Ok(())
+            }
+            None => {
+                self.digests.insert((key, start), (digest, window.len()));
+                self.keys.insert(key);
+                self.stats.keys = self.keys.len();
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks the whole length prefixed `preimage` of `key`, fetched for a witness, against the
+    /// windows of it fetched before. Fails with `OracleInconsistent` if one differs.
+    pub(crate) fn check_whole(
+        &mut self,
+        key: [u8; 32],
+        preimage: &[u8],
+    ) -> Result<(), EmulatorError> {
+        self.retain(preimage.len());
+        let len = (preimage.len() as u64 - 8).to_be_bytes();
+        let consistent = self.digests.iter()
+            .filter(|((k, _), _)| *k == key)
+            .all(|((_, start), (digest, size))| {
+                let window = preimage.get(*start as usize..*start as usize + size);
+                window.is_some_and(|window| {
+                    Keccak256Hasher.hash(&[len.as_slice(), window].concat()) == *digest
+                })
+            });
+        if !consistent {
+            return Err(EmulatorError::OracleInconsistent { key });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn hit(&mut self) {
+        self.stats.hits += 1;
+    }
+
+    /// Records that `len` bytes of preimage are kept.
+    pub(crate) fn retain(&mut self, len: usize) {
+        self.stats.peak_retained = self.stats.peak_retained.max(len);
+    }
+
+    pub(crate) fn stats(&self) -> PreimageCacheStats {
+        self.stats
+    }
+}
+
+/// ServedPreimage is the part `data` of the length prefixed preimage of `key` from `offset`,
+/// fetched from the oracle by the emulator, see `InstrumentedState::preimage_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServedPreimage {
+    pub key: [u8; 32],
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// PreimageLogOracle serves the parts of the preimages logged by a run, so a run of the same
+/// program fetching the same parts replays it without the oracle. A part the log doesn't hold
+/// fails with `PreimageNotFound`. Hints are ignored.
+///
+/// The log is not trusted: a keccak256 or sha256 key is only served once a single logged part
+/// holds its whole preimage, and that preimage hashes to the key. The runs fetching preimages
+/// whole or taking proofs log them whole.
+pub struct PreimageLogOracle {
+    parts: HashMap<[u8; 32], Vec<(u64, Vec<u8>)>>,
+    /// the keys checked against their preimage, none if the log is trusted.
+    verified: Option<HashSet<[u8; 32]>>,
+    /// the keys of the parts in the order they were pushed.
+    order: VecDeque<[u8; 32]>,
+    /// the bytes of the parts held, the oldest parts are dropped past `limit`.
+    bytes: usize,
+    limit: usize,
+}
+
+impl PreimageLogOracle {
+    pub fn new(log: &[ServedPreimage]) -> Self {
+        let mut oracle = Self::trusted(usize::MAX);
+        oracle.verified = Some(HashSet::new());
+        for served in log {
+            oracle.push(served.clone());
+        }
+        oracle
+    }
+
+    /// An empty log serving its parts unchecked, for the parts of a live oracle. It holds
+    /// `limit` bytes of parts at most.
+    pub(crate) fn trusted(limit: usize) -> Self {
+        Self {
+            parts: HashMap::new(),
+            verified: None,
+            order: VecDeque::new(),
+            bytes: 0,
+            limit,
+        }
+    }
+
+    /// Serves the part `served` too, the oldest parts are dropped if the log holds more than
+    /// its limit.
+    pub fn push(&mut self, served: ServedPreimage) {
+        self.bytes += served.data.len();
+        self.order.push_back(served.key);
+        self.parts.entry(served.key).or_default().push((served.offset, served.data));
+        while self.bytes > self.limit {
+            let Some(key) = self.order.pop_front() else { break };
+            let parts = self.parts.get_mut(&key).unwrap();
+            let (_, data) = parts.remove(0);
+            self.bytes -= data.len();
+            if parts.is_empty() {
+                self.parts.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the bytes `start..end` of the length prefixed preimage of `k`, held by a single
+    /// logged part.
+    fn part(&self, k: [u8; 32], start: u64, end: u64) -> Result<&[u8], EmulatorError> {
+        let parts = self.parts.get(&k).map(Vec::as_slice).unwrap_or_default();
+        parts
+            .iter()
+            .find_map(|(offset, data)| {
+                let from = start.checked_sub(*offset)? as usize;
+                data.get(from..(end - offset) as usize)
+            })
+            .ok_or(EmulatorError::PreimageNotFound { key: k })
+    }
+
+    /// Checks the logged preimage of `k` against the key, once, unless the log is trusted.
+    fn verify(&mut self, k: [u8; 32]) -> Result<(), EmulatorError> {
+        let unchecked = self.verified.as_ref().is_some_and(|verified| !verified.contains(&k));
+        if !unchecked || matches!(k[0], LOCAL_KEY_TYPE | PRECOMPILE_KEY_TYPE) {
+            return Ok(());
+        }
+        let len = u64::from_be_bytes(self.part(k, 0, 8)?.try_into().unwrap());
+        verify_preimage(k, self.part(k, 8, len.saturating_add(8))?)?;
+        self.verified.get_or_insert_with(HashSet::new).insert(k);
+        Ok(())
+    }
+}
+
+impl PreimageOracle for PreimageLogOracle {
+    fn hint(&mut self, _v: &[u8]) {}
+
+    fn serves_parts(&self) -> bool {
+        true
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        let len = self.preimage_len(k)?;
+        Ok(self.part(k, 8, len.saturating_add(8))?.to_vec())
+    }
+
+    fn preimage_len(&mut self, k: [u8; 32]) -> Result<u64, EmulatorError> {
+        self.verify(k)?;
+        let prefix = self.part(k, 0, 8)?;
+        Ok(u64::from_be_bytes(prefix.try_into().unwrap()))
+    }
+
+    fn get_preimage_part(
+        &mut self,
+        k: [u8; 32],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        // checks the preimage too
+        let total = self.preimage_len(k)?.saturating_add(8);
+        let start = offset.saturating_add(8).min(total);
+        let end = start.saturating_add(len as u64).min(total);
+        Ok(self.part(k, start, end)?.to_vec())
+    }
+}
+
 pub trait Hint {
     fn hint() -> String;
 }
+
+/// TypedPreimageOracle dispatches on the key type, the first byte of the key: local keys are
+/// served from the injected local data, keccak256, sha256 and precompile keys from the inserted
+/// data or else the hash-backed store. Data of hash keys is checked against the key before it is
+/// served, the data of the store is kept once checked. Hints are forwarded to the store.
+pub struct TypedPreimageOracle {
+    images: HashMap<[u8; 32], Vec<u8>>,
+    store: Box<dyn PreimageOracle>,
+}
+
+impl TypedPreimageOracle {
+    pub fn new(store: Box<dyn PreimageOracle>) -> Self {
+        Self {
+            images: HashMap::new(),
+            store,
+        }
+    }
+
+    /// Serves `data` for the local key of `index`, returns the key.
+    pub fn insert_local(&mut self, index: u64, data: Vec<u8>) -> [u8; 32] {
+        let key = LocalIndexKey(index).preimage_key();
+        self.images.insert(key, data);
+        key
+    }
+
+    /// Serves `data` for `k`, fails if `data` does not hash to the key.
+    pub fn insert(&mut self, k: [u8; 32], data: Vec<u8>) -> Result<(), EmulatorError> {
+        verify_preimage(k, &data)?;
+        self.images.insert(k, data);
+        Ok(())
+    }
+}
+
+impl PreimageOracle for TypedPreimageOracle {
+    fn hint(&mut self, v: &[u8]) {
+        self.store.hint(v);
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        if let Some(data) = self.images.get(&k) {
+            return Ok(data.clone());
+        }
+        match k[0] {
+            LOCAL_KEY_TYPE => Err(EmulatorError::PreimageNotFound { key: k }),
+            KECCAK256KEY_TYPE | SHA256KEY_TYPE | PRECOMPILE_KEY_TYPE => {
+                // verified once, then served like the inserted data
+                let data = self.store.get_preimage(k)?;
+                verify_preimage(k, &data)?;
+                self.images.insert(k, data.clone());
+                Ok(data)
+            }
+            _ => Err(EmulatorError::UnsupportedKeyType { key: k }),
+        }
+    }
+}
+
+/// EmptyPreimageOracle serves no preimage and ignores the hints.
+pub struct EmptyPreimageOracle;
+
+impl PreimageOracle for EmptyPreimageOracle {
+    fn hint(&mut self, _v: &[u8]) {}
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError::PreimageNotFound { key: k })
+    }
+}
+
+/// FilePreimageOracle serves preimages from a directory holding one file per preimage, named by
+/// the hex encoded 32 bytes key (without `0x` prefix) and containing the raw preimage bytes.
+/// Loaded preimages are cached in memory.
+pub struct FilePreimageOracle {
+    dir: PathBuf,
+    /// if set, every hint is written to `<hint_dir>/<index>.hint`.
+    hint_dir: Option<PathBuf>,
+    hint_count: usize,
+    cache: HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl FilePreimageOracle {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            hint_dir: None,
+            hint_count: 0,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Writes the received hints to files in `dir`, in the order they are received.
+    pub fn with_hint_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.hint_dir = Some(dir.into());
+        self
+    }
+
+    pub fn preimage_path(&self, k: [u8; 32]) -> PathBuf {
+        self.dir.join(hex::encode(k))
+    }
+}
+
+impl PreimageOracle for FilePreimageOracle {
+    fn hint(&mut self, v: &[u8]) {
+        debug!("hint {}", String::from_utf8_lossy(v));
+        if let Some(dir) = &self.hint_dir {
+            let path = dir.join(format!("{}.hint", self.hint_count));
+            if let Err(e) = fs::write(&path, v) {
+                warn!("failed to write hint to {}: {}", path.display(), e);
+            }
+        }
+        self.hint_count += 1;
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        if let Some(data) = self.cache.get(&k) {
+            return Ok(data.clone());
+        }
+
+        let path = self.preimage_path(k);
+        let data = fs::read(&path).map_err(|err| {
+            EmulatorError::PreimageRead { key: k, path, err }
+        })?;
+        self.cache.insert(k, data.clone());
+        Ok(data)
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::io::Read;
+use serde::Deserialize;
+use crate::decode;
+use crate::opcode_id::OpcodeId;
+use crate::symbols::SymbolMap;
+
+/// InsnKind is the class of an instruction, as far as the circuit rows it needs are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InsnKind {
+    Alu,
+    Load,
+    Store,
+    Branch,
+    /// j/jal and jr/jalr.
+    Jump,
+    /// the instructions reading or writing the hi/lo registers.
+    HiLo,
+    Syscall,
+}
+
+impl InsnKind {
+    pub fn of(insn: u32) -> Self {
+        let opcode = insn >> 26;
+        match (opcode, insn & 0x3f) {
+            (2 | 3, _) | (0, 0x8 | 0x9) => InsnKind::Jump,
+            (1 | 4..=7, _) => InsnKind::Branch,
+            (0, 0xc) => InsnKind::Syscall,
+            (0, 0x10..=0x1b) => InsnKind::HiLo,
+            _ if (0x20..0x28).contains(&opcode) || opcode == 0x30 => InsnKind::Load,
+            _ if opcode >= 0x28 => InsnKind::Store,
+            _ => InsnKind::Alu,
+        }
+    }
+}
+
+/// CostModel gives the circuit rows a step takes by its instruction kind, syscalls by their
+/// number. It is read from a JSON file like
+/// `{"default_rows": 1, "kinds": {"load": 4, "store": 4}, "syscalls": {"4003": 40}}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct CostModel {
+    /// the rows of the kinds missing from `kinds`.
+    #[serde(default)]
+    pub default_rows: u64,
+    #[serde(default)]
+    pub kinds: HashMap<InsnKind, u64>,
+    /// the rows of the syscalls by number, the others take the rows of the syscall kind.
+    #[serde(default)]
+    pub syscalls: HashMap<u32, u64>,
+}
+
+impl CostModel {
+    pub fn from_json(reader: impl Read) -> serde_json::Result<Self> {
+        serde_json::from_reader(reader)
+    }
+
+    pub fn rows(&self, kind: InsnKind) -> u64 {
+        self.kinds.get(&kind).copied().unwrap_or(self.default_rows)
+    }
+
+    pub fn syscall_rows(&self, num: u32) -> u64 {
+        self.syscalls.get(&num).copied().unwrap_or_else(|| self.rows(InsnKind::Syscall))
+    }
+}
+
+/// ProfileReport counts the executed instructions per opcode and per pc.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileReport {
+    pub steps: u64,
+    /// the 6 bits primary opcode of the instruction -> executed count.
+    pub per_opcode: HashMap<u32, u64>,
+    pub per_pc: HashMap<u32, u64>,
+    pub per_kind: HashMap<InsnKind, u64>,
+    /// the supported instructions -> executed count.
+    pub per_instruction: HashMap<OpcodeId, u64>,
+    /// the syscall number -> executed count.
+    pub per_syscall: HashMap<u32, u64>,
+}
+
+impl ProfileReport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&mut self, pc: u32, insn: u32) {
+        self.steps += 1;
+        *self.per_opcode.entry(insn >> 26).or_default() += 1;
+        *self.per_pc.entry(pc).or_default() += 1;
+        *self.per_kind.entry(InsnKind::of(insn)).or_default() += 1;
+        if let Some(id) = decode::opcode_id(insn) {
+            *self.per_instruction.entry(id).or_default() += 1;
+        }
+    }
+
+    pub fn record_syscall(&mut self, num: u32) {
+        *self.per_syscall.entry(num).or_default() += 1;
+    }
+
+    /// Adds the counts of `other`, the report of another chunk of the run.
+    pub fn merge(&mut self, other: &ProfileReport) {
+        fn add<K: Copy + Eq + std::hash::Hash>(into: &mut HashMap<K, u64>, from: &HashMap<K, u64>) {
+            for (k, count) in from {
+                *into.entry(*k).or_default() += count;
+            }
+        }
+        self.steps += other.steps;
+        add(&mut self.per_opcode, &other.per_opcode);
+        add(&mut self.per_pc, &other.per_pc);
+        add(&mut self.per_kind, &other.per_kind);
+        add(&mut self.per_instruction, &other.per_instruction);
+        add(&mut self.per_syscall, &other.per_syscall);
+    }
+
+    /// The `n` most executed instructions, sorted by count, then name.
+    pub fn top_instructions(&self, n: usize) -> Vec<(OpcodeId, u64)> {
+        let mut out: Vec<(OpcodeId, u64)> = self.per_instruction.iter()
+            .map(|(id, count)| (*id, *count))
+            .collect();
+        out.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| format!("{:?}", a.0).cmp(&format!("{:?}", b.0))));
+        out.truncate(n);
+        out
+    }
+
+    /// Estimates the circuit rows of the profiled steps under `model`.
+    pub fn estimate_rows(&self, model: &CostModel) -> u64 {
+        let mut rows = 0;
+        for (kind, count) in &self.per_kind {
+            if *kind != InsnKind::Syscall {
+                rows += count * model.rows(*kind);
+            }
+        }
+        for (num, count) in &self.per_syscall {
+            rows += count * model.syscall_rows(*num);
+        }
+        rows
+    }
+
+    /// Aggregates the executed instructions per function of `symbols`, sorted by count, then
+    /// name. Instructions outside of any function are counted as `<unknown>`.
+    pub fn by_function(&self, symbols: &SymbolMap) -> Vec<(String, u64)> {
+        let ranges = symbols.function_ranges();
+        let mut counts = HashMap::<&str, u64>::new();
+        for (pc, count) in &self.per_pc {
+            let i = ranges.partition_point(|(_, range)| range.start <= *pc);
+            let name = match i.checked_sub(1).map(|i| &ranges[i]) {
+                Some((name, range)) if range.contains(pc) => name,
+                _ => "<unknown>",
+            };
+            *counts.entry(name).or_default() += count;
+        }
+
+        let mut out: Vec<(String, u64)> = counts.into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect();
+        out.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        out
+    }
+}
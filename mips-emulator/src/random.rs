@@ -0,0 +1,85 @@
+//! Deterministic random stream of the guest: the ChaCha20 keystream of the seed with a zero nonce.
+//! The stream is addressed by byte position, so a VM can resume it from the position saved in
+//! its state.
+
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// Returns the 64 bytes keystream block `counter`.
+fn chacha20_block(key: &[u8; 32], counter: u64) -> [u8; 64] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    for i in 0..8 {
+        input[4 + i] = u32::from_le_bytes(key[4 * i..4 * i + 4].try_into().unwrap());
+    }
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut s = input;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        out[4 * i..4 * i + 4].copy_from_slice(&s[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    out
+}
+
+/// Fills `out` with the stream of `seed` starting at byte `position`.
+pub fn fill(seed: &[u8; 32], position: u64, out: &mut [u8]) {
+    let mut filled = 0;
+    while filled < out.len() {
+        let pos = position + filled as u64;
+        let block = chacha20_block(seed, pos / 64);
+        let start = (pos % 64) as usize;
+        let n = (64 - start).min(out.len() - filled);
+        out[filled..filled + n].copy_from_slice(&block[start..start + n]);
+        filled += n;
+    }
+}
+
+/// Returns the 16 bytes AT_RANDOM points to in the initial stack of `seed`, the end of its stream,
+/// far past the bytes the guest reads with getrandom.
+pub fn at_random(seed: &[u8; 32]) -> [u8; 16] {
+    let mut out = [0; 16];
+    fill(seed, u64::MAX - 15, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fill;
+
+    #[test]
+    fn test_chacha20_zero_key() {
+        // RFC 7539 appendix A.1, test vector #1
+        let mut out = [0u8; 16];
+        fill(&[0; 32], 0, &mut out);
+        assert_eq!(hex::encode(out), "76b8e0ada0f13d90405d6ae55386bd28");
+    }
+
+    #[test]
+    fn test_fill_from_position() {
+        let seed = [7u8; 32];
+        let mut whole = [0u8; 200];
+        fill(&seed, 0, &mut whole);
+        let mut part = [0u8; 100];
+        fill(&seed, 61, &mut part);
+        assert_eq!(part, whole[61..161]);
+    }
+}
@@ -0,0 +1,84 @@
+//! The indexes of the general purpose registers by their o32 ABI name.
+
+pub const ZERO: u32 = 0;
+pub const AT: u32 = 1;
+pub const V0: u32 = 2;
+pub const V1: u32 = 3;
+pub const A0: u32 = 4;
+pub const A1: u32 = 5;
+pub const A2: u32 = 6;
+pub const A3: u32 = 7;
+pub const T0: u32 = 8;
+pub const T1: u32 = 9;
+pub const T2: u32 = 10;
+pub const T3: u32 = 11;
+pub const T4: u32 = 12;
+pub const T5: u32 = 13;
+pub const T6: u32 = 14;
+pub const T7: u32 = 15;
+pub const S0: u32 = 16;
+pub const S1: u32 = 17;
+pub const S2: u32 = 18;
+pub const S3: u32 = 19;
+pub const S4: u32 = 20;
+pub const S5: u32 = 21;
+pub const S6: u32 = 22;
+pub const S7: u32 = 23;
+pub const T8: u32 = 24;
+pub const T9: u32 = 25;
+pub const K0: u32 = 26;
+pub const K1: u32 = 27;
+pub const GP: u32 = 28;
+pub const SP: u32 = 29;
+/// the frame pointer, also named s8.
+pub const FP: u32 = 30;
+pub const RA: u32 = 31;
+
+const NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Returns the ABI name of register `i`, without `$`. Panics if `i` is not one of the 32
+/// registers.
+pub fn name(i: u32) -> &'static str {
+    NAMES[i as usize]
+}
+
+/// Returns the index of the register named `name`, with or without `$`: an ABI name, `s8` for
+/// the frame pointer, or the number of the register like `$29`.
+pub fn index(name: &str) -> Option<u32> {
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if name == "s8" {
+        return Some(FP);
+    }
+    if let Some(i) = NAMES.iter().position(|n| *n == name) {
+        return Some(i as u32);
+    }
+    match name.parse::<u32>() {
+        Ok(i) if i < 32 && !name.starts_with('+') => Some(i),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for i in 0..32 {
+            assert_eq!(index(name(i)), Some(i));
+            assert_eq!(index(&format!("${}", name(i))), Some(i));
+            assert_eq!(index(&format!("${}", i)), Some(i));
+        }
+        assert_eq!((index("sp"), index("$ra"), index("a0")), (Some(SP), Some(RA), Some(A0)));
+        assert_eq!(index("s8"), Some(FP));
+        assert_eq!(name(T9), "t9");
+        for bad in ["", "$", "$32", "+1", "SP", "r4", "$$sp"] {
+            assert_eq!(index(bad), None, "{}", bad);
+        }
+    }
+}
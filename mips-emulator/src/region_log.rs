@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use crate::witness::{MemoryAccess, MemoryOperation};
+
+/// the accesses a region log keeps by default, the oldest are dropped past it.
+pub const DEFAULT_REGION_LOG_CAPACITY: usize = 4096;
+
+/// RegionWatchConfig tells what a watched region records, see
+/// `InstrumentedState::watch_region_with`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionWatchConfig {
+    /// records the loads too, not only the stores.
+    pub loads: bool,
+    /// the latest accesses kept.
+    pub capacity: usize,
+}
+
+impl Default for RegionWatchConfig {
+    fn default() -> Self {
+        Self {
+            loads: false,
+            capacity: DEFAULT_REGION_LOG_CAPACITY,
+        }
+    }
+}
+
+/// RegionAccess is a word access of the guest to a watched region. `old` and `new` are equal
+/// for a load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionAccess {
+    /// the step of the instruction, counted like `State::step`.
+    pub step: u64,
+    pub pc: u32,
+    /// the address of the word.
+    pub addr: u32,
+    pub op: MemoryOperation,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// RegionLog keeps the latest accesses to the words intersecting a range of the guest memory.
+/// Unlike a fault, recording an access never stops the execution.
+#[derive(Debug, Clone)]
+pub struct RegionLog {
+    tag: String,
+    range: Range<u32>,
+    config: RegionWatchConfig,
+    accesses: VecDeque<RegionAccess>,
+    dropped: u64,
+}
+
+impl RegionLog {
+    pub fn new(tag: &str, range: Range<u32>, config: RegionWatchConfig) -> Self {
+        Self {
+            tag: tag.to_string(),
+            range,
+            config,
+            accesses: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn range(&self) -> Range<u32> {
+        self.range.clone()
+    }
+
+    /// Returns the kept accesses, oldest first.
+    pub fn accesses(&self) -> impl Iterator<Item = &RegionAccess> {
+        self.accesses.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    /// The accesses dropped once the log was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+        self.dropped = 0;
+    }
+
+    /// Records the access of the instruction at `pc` if it touches the region.
+    pub(crate) fn observe(&mut self, step: u64, pc: u32, access: &MemoryAccess) {
+        if access.op == MemoryOperation::Read && !self.config.loads {
+            return;
+        }
+        let word = access.addr & !3;
+        if word >= self.range.end || word.saturating_add(4) <= self.range.start {
+            return;
+        }
+        if self.config.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.accesses.len() == self.config.capacity {
+            self.accesses.pop_front();
+            self.dropped += 1;
+        }
+        self.accesses.push_back(RegionAccess {
+            step,
+            pc,
+            addr: word,
+            op: access.op,
+            old: access.value_prev,
+            new: access.value,
+        });
+    }
+}
@@ -0,0 +1,189 @@
+//! Static PIE support of the ELF loader. An ET_DYN executable without an interpreter is loaded
+//! at a load bias, then its dynamic relocations and its GOT are applied against the loaded
+//! image, the work its startup code would otherwise do before main.
+
+use std::fmt::{Display, Formatter};
+use elf::abi::{ET_DYN, PT_INTERP, SHN_ABS, STB_WEAK};
+use elf::endian::AnyEndian;
+use elf::ElfBytes;
+use crate::layout::LayoutError;
+use crate::memory::{Endianness, Memory};
+use crate::page::PAGE_SIZE;
+
+/// the load bias of a static PIE, unless another one is given to `State::try_load_elf_at`.
+pub const DEFAULT_LOAD_BIAS: u32 = 0x10000;
+
+const R_MIPS_NONE: u32 = 0;
+const R_MIPS_REL32: u32 = 3;
+
+const DT_PLTGOT: i64 = 3;
+const DT_MIPS_LOCAL_GOTNO: i64 = 0x7000000a;
+const DT_MIPS_SYMTABNO: i64 = 0x70000011;
+const DT_MIPS_GOTSYM: i64 = 0x70000013;
+
+/// LoadError is returned when the loader can not load an ELF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// the segments of the ELF overlap the heap or the stack.
+    Layout(LayoutError),
+    /// the ELF is dynamically linked, only static executables are loaded.
+    Interpreter,
+    /// the load bias is not aligned to a page.
+    MisalignedBias(u32),
+    /// the relocation at `offset`, before the bias, has a type static PIEs don't emit.
+    UnsupportedRelocation { offset: u32, kind: u32 },
+    /// the relocation or GOT entry at `offset` refers to an undefined symbol that isn't weak.
+    UndefinedSymbol { offset: u32, sym: u32 },
+    /// the relocation or the GOT at `offset` is not aligned to a word.
+    MisalignedRelocation { offset: u32 },
+    /// the word at `offset` can not be written.
+    Relocate { offset: u32, error: String },
+    /// the tables of the ELF can not be parsed.
+    Parse(String),
+    /// the byte order of the ELF is not the one of `VmConfig::endianness`.
+    Endianness { elf: Endianness, configured: Endianness },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Layout(e) => write!(f, "invalid memory layout: {}", e),
+            LoadError::Interpreter => write!(f, "dynamically linked executables are not supported"),
+            LoadError::MisalignedBias(bias) => write!(f, "load bias 0x{:x} is not page aligned", bias),
+            LoadError::UnsupportedRelocation { offset, kind } => {
+                write!(f, "unsupported relocation type {} at 0x{:x}", kind, offset)
+            }
+            LoadError::UndefinedSymbol { offset, sym } => {
+                write!(f, "relocation at 0x{:x} refers to undefined symbol {}", offset, sym)
+            }
+            LoadError::MisalignedRelocation { offset } => {
+                write!(f, "relocation at 0x{:x} is not word aligned", offset)
+            }
+            LoadError::Relocate { offset, error } => {
+                write!(f, "relocation at 0x{:x} failed: {}", offset, error)
+            }
+            LoadError::Parse(e) => write!(f, "invalid ELF: {}", e),
+            LoadError::Endianness { elf, configured } => {
+                write!(f, "the ELF is {}, the emulator is configured {}", elf, configured)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<LayoutError> for LoadError {
+    fn from(e: LayoutError) -> Self {
+        LoadError::Layout(e)
+    }
+}
+
+fn parse_error(e: elf::ParseError) -> LoadError {
+    LoadError::Parse(e.to_string())
+}
+
+/// Returns the bias the ELF is loaded at: `pie_bias` for a static PIE, 0 for an ET_EXEC.
+pub fn load_bias(f: &ElfBytes<AnyEndian>, pie_bias: u32) -> u32 {
+    if f.ehdr.e_type == ET_DYN {
+        pie_bias
+    } else {
+        0
+    }
+}
+
+/// Checks that a PIE can be loaded at `bias`: it has no interpreter and the bias keeps its
+/// segments page aligned.
+pub fn check_pie(f: &ElfBytes<AnyEndian>, bias: u32) -> Result<(), LoadError> {
+    let segments = f.segments().ok_or_else(|| LoadError::Parse(String::from("no segments")))?;
+    if segments.iter().any(|segment| segment.p_type == PT_INTERP) {
+        return Err(LoadError::Interpreter);
+    }
+    if bias as usize % PAGE_SIZE != 0 {
+        return Err(LoadError::MisalignedBias(bias));
+    }
+    Ok(())
+}
+
+/// Applies the `.rel.dyn` relocations and the MIPS GOT of the PIE loaded in `memory` at
+/// `bias`. Returns the number of words patched.
+pub fn relocate(f: &ElfBytes<AnyEndian>, memory: &mut Memory, bias: u32) -> Result<usize, LoadError> {
+    let dynsym = f.dynamic_symbol_table().map_err(parse_error)?;
+    // the biased address of the symbol `sym`, the symbol 0 is the base of the image and an
+    // undefined weak symbol is 0, as the dynamic linker resolves them
+    let symbol_value = |sym: u32, offset: u32| -> Result<u32, LoadError> {
+        if sym == 0 {
+            return Ok(bias);
+        }
+        let symbol = dynsym.as_ref()
+            .and_then(|(table, _)| table.get(sym as usize).ok())
+            .ok_or(LoadError::UndefinedSymbol { offset, sym })?;
+        if symbol.is_undefined() {
+            return match symbol.st_bind() {
+                STB_WEAK => Ok(0),
+                _ => Err(LoadError::UndefinedSymbol { offset, sym }),
+            };
+        }
+        let value = symbol.st_value as u32;
+        Ok(if symbol.st_shndx == SHN_ABS { value } else { value.wrapping_add(bias) })
+    };
+    let mut patched = 0;
+
+    if let Some(shdr) = f.section_header_by_name(".rel.dyn").map_err(parse_error)? {
+        for rel in f.section_data_as_rels(&shdr).map_err(parse_error)? {
+            let offset = rel.r_offset as u32;
+            match rel.r_type {
+                R_MIPS_NONE => {}
+                // the word at the offset is the addend of the symbol
+                R_MIPS_REL32 => {
+                    if offset & 3 != 0 {
+                        return Err(LoadError::MisalignedRelocation { offset });
+                    }
+                    add_to_word(memory, offset, bias, symbol_value(rel.r_sym, offset)?)?;
+                    patched += 1;
+                }
+                kind => return Err(LoadError::UnsupportedRelocation { offset, kind }),
+            }
+        }
+    }
+
+    // the GOT is relocated without relocations: its local entries by the bias, the entries of
+    // the global symbols from DT_MIPS_GOTSYM on by the values of their symbols
+    let dynamic = match f.dynamic().map_err(parse_error)? {
+        Some(dynamic) => dynamic,
+        None => return Ok(patched),
+    };
+    let tag = |tag: i64| dynamic.iter().find(|d| d.d_tag == tag).map(|d| d.d_val() as u32);
+    let (got, local_gotno) = match (tag(DT_PLTGOT), tag(DT_MIPS_LOCAL_GOTNO)) {
+        (Some(got), Some(local_gotno)) => (got, local_gotno),
+        _ => return Ok(patched),
+    };
+    if got & 3 != 0 {
+        return Err(LoadError::MisalignedRelocation { offset: got });
+    }
+    // the first entry is reserved for the lazy resolver, the second too if its top bit is set
+    let reserved = memory.get_memory((got + 4).wrapping_add(bias));
+    let first = if reserved & 0x80000000 != 0 { 2 } else { 1 };
+    for i in first..local_gotno {
+        add_to_word(memory, got + 4 * i, bias, bias)?;
+        patched += 1;
+    }
+    if let (Some(gotsym), Some(symtabno)) = (tag(DT_MIPS_GOTSYM), tag(DT_MIPS_SYMTABNO)) {
+        for sym in gotsym..symtabno {
+            let offset = got + 4 * (local_gotno + sym - gotsym);
+            // the entry holds the unbiased value of the symbol, replace it
+            let value = symbol_value(sym, offset)?;
+            memory.set_memory(offset.wrapping_add(bias), value)
+                .map_err(|e| LoadError::Relocate { offset, error: e.to_string() })?;
+            patched += 1;
+        }
+    }
+    Ok(patched)
+}
+
+/// Adds `value` to the word at `offset` of the image loaded at `bias`.
+fn add_to_word(memory: &mut Memory, offset: u32, bias: u32, value: u32) -> Result<(), LoadError> {
+    let addr = offset.wrapping_add(bias);
+    let word = memory.get_memory(addr);
+    memory.set_memory(addr, word.wrapping_add(value))
+        .map_err(|e| LoadError::Relocate { offset, error: e.to_string() })
+}
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use elf::ElfBytes;
+use elf::endian::AnyEndian;
+use crate::config::{ExecutionMode, UnknownSyscallPolicy, VmConfig};
+use crate::error::EmulatorError;
+use crate::hash::HashFunction;
+use crate::hint::DEFAULT_MAX_HINT_SIZE;
+use crate::layout::NULL_GUARD_END;
+use crate::memory::{Endianness, DEFAULT_MAX_READ_RANGE, DEFAULT_MEMORY_HASH};
+use crate::pre_image::PreimageOracle;
+use crate::reloc::DEFAULT_LOAD_BIAS;
+use crate::state::{InstrumentedStateBuilder, State, VmStatus};
+use crate::witness::{invalid, Reader};
+
+const MAGIC: &[u8; 8] = b"MIPSRPLY";
+/// version 2 added the max hint size, version 1 replays run with the default. Version 3 added
+/// the state and memory hash functions, older replays run with the defaults. Version 4 added the
+/// end of the null guard. Version 5 added the flag of lenient invalid opcodes, unset in older
+/// replays. Version 6 added a second byte of flags, unset in older replays. Version 7 added the unknown
+/// syscall policy, older replays fault in strict mode and fail with ENOSYS otherwise. Version 8
+/// added the flag of little endian guests, older replays are big endian. Version 9 added the max
+/// read range of the write syscalls, older replays run with the default.
+pub const REPLAY_VERSION: u32 = 9;
+
+/// ReplayImage is the program of a replay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayImage {
+    /// an ELF file, loaded like `State::load_elf`.
+    Elf(Vec<u8>),
+    /// the non-zero words of the initial memory, executed from pc 0.
+    Memory(Vec<(u32, u32)>),
+}
+
+impl ReplayImage {
+    pub fn state(&self) -> Result<Box<State>, EmulatorError> {
+        self.state_for(&VmConfig::default())
+    }
+
+    /// Returns the initial state of the image with the byte order of `config`. The AT_RANDOM
+    /// bytes of an ELF come from `VmConfig::random_seed`, its initial memory is the same on every
+    /// run.
+    pub fn state_for(&self, config: &VmConfig) -> Result<Box<State>, EmulatorError> {
+        let endianness = config.endianness;
+        match self {
+            ReplayImage::Elf(bytes) => {
+                let f = ElfBytes::<AnyEndian>::minimal_parse(bytes).map_err(|e| {
+                    EmulatorError::Io(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+                })?;
+                let loaded = State::try_load_elf_with(&f, DEFAULT_LOAD_BIAS, endianness);
+                let (mut state, _) = loaded.map_err(|e| {
+                    EmulatorError::Io(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+                })?;
+                state.patch_go(&f);
+                state.patch_stack(&config.random_seed)?;
+                Ok(state)
+            }
+            ReplayImage::Memory(words) => {
+                let mut state = State::new();
+                state.memory.set_endianness(endianness);
+                for (addr, v) in words {
+                    state.memory.set_memory(*addr, *v)?;
+                }
+                Ok(state)
+            }
+        }
+    }
+}
+
+/// Replay is a program with its inputs and the outcome of running it, a regression case that
+/// can be saved to a file and checked again with `verify`.
+///
+/// The file starts with the magic `MIPSRPLY` and the version, followed by little endian fields:
+/// the image, the config, the step budget, stdin, the preimage log, the exit code and the state
+/// hash. Only the options of the config changing the execution are kept, not the journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub image: ReplayImage,
+    pub config: VmConfig,
+    pub max_steps: u64,
+    pub stdin: Vec<u8>,
+    /// the preimages served to the guest, by key, in the order they were first read.
+    pub preimages: PreimageLog,
+    pub exit_code: u8,
+    pub state_hash: [u8; 32],
+}
+
+/// ReplayMismatch is how the execution of a replay differed from the recorded one.
+#[derive(Debug)]
+pub enum ReplayMismatch {
+    Error(EmulatorError),
+    /// the guest didn't exit within the step budget, or ran out of host memory.
+    NotExited(VmStatus),
+    ExitCode { expected: u8, got: u8 },
+    StateHash { expected: [u8; 32], got: [u8; 32] },
+}
+
+impl Display for ReplayMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayMismatch::Error(err) => write!(f, "replay failed: {}", err),
+            ReplayMismatch::NotExited(status) => write!(f, "guest did not exit: {:?}", status),
+            ReplayMismatch::ExitCode { expected, got } => {
+                write!(f, "exit code {}, expected {}", got, expected)
+            }
+            ReplayMismatch::StateHash { expected, got } => write!(
+                f,
+                "state hash 0x{}, expected 0x{}",
+                hex::encode(got),
+                hex::encode(expected)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayMismatch {}
+
+impl From<EmulatorError> for ReplayMismatch {
+    fn from(err: EmulatorError) -> Self {
+        ReplayMismatch::Error(err)
+    }
+}
+
+type PreimageLog = Vec<([u8; 32], Vec<u8>)>;
+
+/// LoggingOracle forwards to the oracle being recorded, and logs the preimages it serves.
+struct LoggingOracle {
+    inner: Box<dyn PreimageOracle>,
+    log: Arc<Mutex<PreimageLog>>,
+}
+
+impl PreimageOracle for LoggingOracle {
+    fn hint(&mut self, v: &[u8]) {
+        self.inner.hint(v);
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        let data = self.inner.get_preimage(k)?;
+        let mut log = self.log.lock().unwrap();
+        if !log.iter().any(|(key, _)| *key == k) {
+            log.push((k, data.clone()));
+        }
+        Ok(data)
+    }
+}
+
+impl Replay {
+    /// Runs `image` until it exits and records the outcome, along with the preimages `oracle`
+    /// served. Fails if the guest didn't exit within `max_steps`.
+    pub fn record(
+        image: ReplayImage,
+        config: VmConfig,
+        stdin: Vec<u8>,
+        oracle: Box<dyn PreimageOracle>,
+        max_steps: u64,
+    ) -> Result<Replay, ReplayMismatch> {
+        let config = VmConfig { journal: None, ..config };
+        let log = Arc::new(Mutex::new(PreimageLog::new()));
+        let oracle = LoggingOracle { inner: oracle, log: log.clone() };
+        let mut is = InstrumentedStateBuilder::new(image.state_for(&config)?)
+            .with_config(config.clone())
+            .with_oracle(Box::new(oracle))
+            .with_stdin(stdin.clone())
+            .with_output_discarded()
+            .build()?;
+
+        let exit_code = match is.run(max_steps)?.status {
+            VmStatus::Exited(code) => code,
+            status => return Err(ReplayMismatch::NotExited(status)),
+        };
+        let preimages = log.lock().unwrap().clone();
+        Ok(Replay {
+            image,
+            config,
+            max_steps,
+            stdin,
+            preimages,
+            exit_code,
+            state_hash: is.state_hash(),
+        })
+    }
+
+    /// Runs the replay again, serving the logged preimages only, and compares the exit code and
+    /// the final state hash with the recorded ones.
+    pub fn verify(self) -> Result<(), ReplayMismatch> {
+        let state = self.image.state_for(&self.config)?;
+        let mut is = InstrumentedStateBuilder::new(state)
+            .with_config(self.config)
+            .with_preimages(self.preimages.into_iter().collect::<HashMap<_, _>>())
+            .with_stdin(self.stdin)
+            .with_output_discarded()
+            .build()?;
+
+        let got = match is.run(self.max_steps)?.status {
+            VmStatus::Exited(code) => code,
+            status => return Err(ReplayMismatch::NotExited(status)),
+        };
+        if got != self.exit_code {
+            return Err(ReplayMismatch::ExitCode { expected: self.exit_code, got });
+        }
+        let got = is.state_hash();
+        if got != self.state_hash {
+            return Err(ReplayMismatch::StateHash { expected: self.state_hash, got });
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Replay> {
+        Replay::decode(&fs::read(path)?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(REPLAY_VERSION.to_le_bytes());
+        match &self.image {
+            ReplayImage::Elf(bytes) => {
+                out.push(0);
+                put_bytes(&mut out, bytes);
+            }
+            ReplayImage::Memory(words) => {
+                out.push(1);
+                out.extend((words.len() as u32).to_le_bytes());
+                for (addr, v) in words {
+                    out.extend(addr.to_le_bytes());
+                    out.extend(v.to_le_bytes());
+                }
+            }
+        }
+
+        let config = &self.config;
+        out.extend(config.random_seed);
+        out.extend(config.max_host_pages.map_or(u64::MAX, |pages| pages as u64).to_le_bytes());
+        let flags = [
+            config.mode == ExecutionMode::Strict,
+            config.wide_preimage_io,
+            config.strict_delay_slots,
+            config.stack_guard,
+            config.protect_text,
+            config.hilo_hazards,
+            config.null_guard,
+            config.invalid_opcodes == ExecutionMode::Lenient,
+        ];
+        out.push(flags.iter().rev().fold(0, |acc, flag| (acc << 1) | *flag as u8));
+        let flags = [config.eager_pc_check, config.endianness == Endianness::Little];
+        out.push(flags.iter().rev().fold(0, |acc, flag| (acc << 1) | *flag as u8));
+        out.extend((config.max_hint_size as u64).to_le_bytes());
+        out.extend([config.state_hash.id(), config.memory_hash.id()]);
+        out.extend(config.null_guard_end.to_le_bytes());
+        out.push(unknown_syscall_id(config.unknown_syscall));
+        out.extend(config.max_read_range.to_le_bytes());
+
+        out.extend(self.max_steps.to_le_bytes());
+        put_bytes(&mut out, &self.stdin);
+        out.extend((self.preimages.len() as u32).to_le_bytes());
+        for (key, data) in &self.preimages {
+            out.extend(key);
+            put_bytes(&mut out, data);
+        }
+        out.push(self.exit_code);
+        out.extend(self.state_hash);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Replay> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a replay file"));
+        }
+        let version = r.u32()?;
+        if version == 0 || version > REPLAY_VERSION {
+            return Err(invalid(&format!("unsupported replay version {}", version)));
+        }
+        let image = match r.take(1)?[0] {
+            0 => ReplayImage::Elf(r.bytes()?.to_vec()),
+            1 => {
+                let count = r.u32()?;
+                let words = (0..count)
+                    .map(|_| Ok((r.u32()?, r.u32()?)))
+                    .collect::<io::Result<_>>()?;
+                ReplayImage::Memory(words)
+            }
+            tag => return Err(invalid(&format!("unknown image kind {}", tag))),
+        };
+
+        let random_seed = r.array()?;
+        let max_host_pages = match r.u64()? {
+            u64::MAX => None,
+            pages => Some(pages as usize),
+        };
+        let flags = r.take(1)?[0];
+        let flags = match version {
+            1..=5 => flags as u16,
+            _ => flags as u16 | (r.take(1)?[0] as u16) << 8,
+        };
+        let flag = |i: u32| flags & (1 << i) != 0;
+        let max_hint_size = match version {
+            1 => DEFAULT_MAX_HINT_SIZE,
+            _ => r.u64()? as usize,
+        };
+        let (state_hash, memory_hash) = match version {
+            1 | 2 => (HashFunction::Keccak256, DEFAULT_MEMORY_HASH),
+            _ => (hash_function(r.take(1)?[0])?, hash_function(r.take(1)?[0])?),
+        };
+        let null_guard_end = match version {
+            1..=3 => NULL_GUARD_END,
+            _ => r.u32()?,
+        };
+        let unknown_syscall = match version {
+            1..=6 if flag(0) => UnknownSyscallPolicy::Fault,
+            1..=6 => UnknownSyscallPolicy::Enosys,
+            _ => unknown_syscall_policy(r.take(1)?[0])?,
+        };
+        let max_read_range = match version {
+            1..=8 => DEFAULT_MAX_READ_RANGE,
+            _ => r.u32()?,
+        };
+        let config = VmConfig {
+            random_seed,
+            max_host_pages,
+            mode: if flag(0) { ExecutionMode::Strict } else { ExecutionMode::Lenient },
+            unknown_syscall,
+            invalid_opcodes: if flag(7) { ExecutionMode::Lenient } else { ExecutionMode::Strict },
+            wide_preimage_io: flag(1),
+            strict_delay_slots: flag(2),
+            stack_guard: flag(3),
+            protect_text: flag(4),
+            hilo_hazards: flag(5),
+            null_guard: flag(6),
+            eager_pc_check: flag(8),
+            endianness: if flag(9) { Endianness::Little } else { Endianness::Big },
+            null_guard_end,
+            max_hint_size,
+            max_read_range,
+            state_hash,
+            memory_hash,
+            journal: None,
+            livelock_interval: None,
+        };
+
+        let max_steps = r.u64()?;
+        let stdin = r.bytes()?.to_vec();
+        let count = r.u32()?;
+        let preimages = (0..count)
+            .map(|_| Ok((r.array()?, r.bytes()?.to_vec())))
+            .collect::<io::Result<_>>()?;
+        let exit_code = r.take(1)?[0];
+        let state_hash = r.array()?;
+        r.finish()?;
+        Ok(Replay { image, config, max_steps, stdin, preimages, exit_code, state_hash })
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+fn hash_function(id: u8) -> io::Result<HashFunction> {
+    HashFunction::from_id(id).ok_or_else(|| invalid(&format!("unsupported hash function {}", id)))
+}
+
+fn unknown_syscall_id(policy: UnknownSyscallPolicy) -> u8 {
+    match policy {
+        UnknownSyscallPolicy::SilentZero => 0,
+        UnknownSyscallPolicy::Enosys => 1,
+        UnknownSyscallPolicy::Fault => 2,
+    }
+}
+
+fn unknown_syscall_policy(id: u8) -> io::Result<UnknownSyscallPolicy> {
+    match id {
+        0 => Ok(UnknownSyscallPolicy::SilentZero),
+        1 => Ok(UnknownSyscallPolicy::Enosys),
+        2 => Ok(UnknownSyscallPolicy::Fault),
+        _ => Err(invalid(&format!("unsupported unknown syscall policy {}", id))),
+    }
+}
@@ -1,14 +1,58 @@
-use std::io::{Read, stderr, stdout, Write};
-use crate::memory::Memory;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, sink, stderr, stdout, Write};
+use crate::memory::{copy_from_word, copy_into_word, Endianness, Memory};
 use crate::page::{PAGE_ADDR_MASK, PAGE_SIZE};
-use log::{debug, warn};
+use log::{debug, log_enabled, trace, warn, Level};
 use std::cmp::min;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::fmt::{Display, Formatter};
-use elf::abi::PT_LOAD;
+use elf::abi::{ET_DYN, PF_X, PT_LOAD};
 use elf::endian::AnyEndian;
-use rand::{Rng, thread_rng};
-use crate::pre_image::PreimageOracle;
-use crate::witness::{ExecutionRow, Instruction, MemoryAccess, MemoryOperation, Program, ProgramSegment, StepWitness};
+use serde::Serialize;
+use sha3::{Digest, Keccak256};
+use crate::config::{ExecutionMode, UnknownSyscallPolicy, VmConfig};
+use crate::decode;
+use crate::differential::StateDiff;
+use crate::opcode_id;
+#[cfg(any(test, feature = "testing"))]
+use crate::opcode_id::OpcodeId;
+use crate::reg;
+use crate::error::{BadPcReason, EmulatorError, FaultContext};
+use crate::errno::{
+    self, EAGAIN, EBADF, ECHILD, EFAULT, EINVAL, ENOMEM, ENOSYS, ESPIPE, SYSCALL_ERROR,
+};
+use crate::journal::{Event, Journal};
+use crate::livelock::{LivelockDetector, LivelockSample};
+use crate::metrics::{self, MetricsSink, NoopSink, PendingMetrics};
+use crate::memory_backend::FileBackend;
+use crate::layout::{
+    BRK_START, HEAP_START, LayoutError, MemoryLayout, Region, RegionKind, STACK_POINTER,
+};
+use crate::guest_panic::{GuestPanic, PanicDetector};
+use crate::hash::{HashFunction, Hasher32};
+use crate::hint::HintBuffer;
+use crate::pre_image::{
+    EmptyPreimageOracle, PREIMAGE_WINDOW_OVERLAP, PREIMAGE_WINDOW_SIZE, PreimageCacheStats,
+    PreimageDigests, PreimageLogOracle, PreimageOracle, ServedPreimage, TypedPreimageOracle,
+};
+use crate::profile::ProfileReport;
+use crate::random;
+use crate::region_log::{RegionLog, RegionWatchConfig};
+use crate::reloc::{self, DEFAULT_LOAD_BIAS, LoadError};
+use crate::summary::{INSTRUCTION_MIX_LEN, InstructionCount, RunSummary, SUMMARY_SCHEMA_VERSION};
+use crate::symbols::SymbolMap;
+use crate::syscall_override::{SyscallOverride, OVERRIDABLE_SYSCALLS};
+use crate::trace_export::TraceExporter;
+use crate::witness::{
+    ExecutionRow, HiLoDelta, Instruction, InstructionImage, MemoryAccess, MemoryOperation,
+    Program, ProgramSegment, RegisterDelta, StepKind, StepWitness, SyscallWitness,
+};
+use crate::witness_stream::{DEFAULT_COMPRESSION, WitnessWriter};
 
 pub const FD_STDIN: u32 = 0;
 pub const FD_STDOUT: u32 = 1;
@@ -17,8 +61,91 @@ pub const FD_HINT_READ: u32 = 3;
 pub const FD_HINT_WRITE: u32 = 4;
 pub const FD_PREIMAGE_READ: u32 = 5;
 pub const FD_PREIMAGE_WRITE: u32 = 6;
-pub const MIPS_EBADF:u32  = 9;
-
+/// the ends of the pipe the pipe syscall returns. Nothing written to it is ever read back, the
+/// guest only gets a pipe for the wake-ups of its event loop.
+pub const FD_PIPE_READ: u32 = 7;
+pub const FD_PIPE_WRITE: u32 = 8;
+
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+
+/// the iovec entries linux accepts at most for readv/writev.
+const MAX_IOVCNT: u32 = 1024;
+
+/// the syscalls the runtimes of the guests make that succeed without doing anything, like Cannon
+/// does. The other unknown syscalls follow `VmConfig::unknown_syscall`.
+const NOOP_SYSCALLS: &[u32] = &[
+    4006, // close
+    4024, // getuid
+    4047, // getgid
+    4054, // ioctl
+    4085, // readlink
+    4091, // munmap
+    4104, // setitimer
+    4162, // sched_yield
+    4166, // nanosleep
+    4194, // rt_sigaction
+    4195, // rt_sigprocmask
+    4200, // pread64
+    4206, // sigaltstack
+    4213, // stat64
+    4215, // fstat64
+    4217, // mincore
+    4218, // madvise
+    4222, // gettid
+    4238, // futex
+    4240, // sched_getaffinity
+    4249, // epoll_ctl
+    4257, // timer_create
+    4258, // timer_settime
+    4261, // timer_delete
+    4263, // clock_gettime
+    4266, // tgkill
+    4288, // openat
+    4298, // readlinkat
+    4313, // epoll_pwait
+    4326, // epoll_create1
+    4328, // pipe2
+    4338, // prlimit64
+];
+
+/// the fields of the utsname struct written by uname: sysname, nodename, release, version,
+/// machine and domainname. Fixed, so the guest doesn't depend on the host.
+const UTSNAME: [&str; 6] = ["Linux", "zkmips", "5.15.0", "#1 SMP", "mips", "(none)"];
+/// the size of each field of the utsname struct, with its NUL terminator.
+const UTSNAME_FIELD_LEN: usize = 65;
+
+/// the bytes linux returns at most for a single getrandom call.
+const MAX_GETRANDOM_SIZE: u32 = 33554431;
+
+/// the size of the witness encoding of the state: the memory root, the preimage key and offset,
+/// pc, next_pc, lo, hi, heap, exit code, exited, step and the registers. The bit 1 of the
+/// exited byte is set for a little endian memory, so the states of the two byte orders never
+/// hash alike; it is clear for big endian, the encoding of Cannon.
+pub const STATE_WITNESS_SIZE: usize = WITNESS_REGISTERS + 32 * 4;
+
+/// the offsets of the fields in the witness encoding of the state, the memory root is at 0.
+pub(crate) const WITNESS_PREIMAGE_KEY: usize = 32;
+pub(crate) const WITNESS_PREIMAGE_OFFSET: usize = 64;
+pub(crate) const WITNESS_PC: usize = 68;
+pub(crate) const WITNESS_NEXT_PC: usize = 72;
+pub(crate) const WITNESS_LO: usize = 76;
+pub(crate) const WITNESS_HI: usize = 80;
+pub(crate) const WITNESS_HEAP: usize = 84;
+pub(crate) const WITNESS_EXIT_CODE: usize = 88;
+pub(crate) const WITNESS_EXITED: usize = 89;
+pub(crate) const WITNESS_STEP: usize = 90;
+pub(crate) const WITNESS_REGISTERS: usize = 98;
+
+/// the exit code of a guest stopped by break, like a process killed by SIGTRAP.
+pub const BREAK_EXIT_CODE: u8 = 128 + 5;
+
+/// the steps after a mult/div during which mfhi/mflo are unpredictable.
+const HILO_HAZARD_STEPS: u64 = 2;
+
+/// Cloning a `State` costs O(pages-in-table): the page data is shared copy-on-write with the
+/// clone, only the page table and the merkle node cache are duplicated.
+#[derive(Clone)]
 pub struct State {
     pub memory: Box<Memory>,
 
@@ -27,7 +154,8 @@ pub struct State {
 
     /// the 32 general purpose registers of MIPS.
     pub registers: [u32; 32],
-    /// the pc register stores the current execution instruction address.
+    /// the pc register stores the current execution instruction address. Like the hardware, the
+    /// pc and the next pc wrap at 2^32: the instruction after 0xfffffffc is at 0.
     pub pc: u32,
     /// the next pc stores the next execution instruction address.
     next_pc: u32,
@@ -43,16 +171,41 @@ pub struct State {
 
     pub exited: bool,
     exit_code: u8,
+    /// the code of the break that stopped the guest, it then exited with `BREAK_EXIT_CODE`.
+    /// Not part of the VM state witness.
+    break_code: Option<u32>,
+
+    /// the bytes of the random stream already served by getrandom, the stream itself is
+    /// seeded by `VmConfig::random_seed`.
+    random_position: u64,
+
+    /// the TLS base read by `rdhwr $29`. Not part of the VM state witness.
+    thread_pointer: u32,
+    /// the previous instruction was a branch or jump, so the next one is in its delay slot.
+    /// Only checked by `VmConfig::strict_delay_slots`, a taken branch or jump is also seen from
+    /// `next_pc`.
+    in_delay_slot: bool,
+    /// the step of the last mult/div, hi and lo read within `HILO_HAZARD_STEPS` of it are
+    /// unpredictable. Only checked by `VmConfig::hilo_hazards`.
+    hilo_written_step: Option<u64>,
+
+    /// where the program, heap and stack live, checked by the mmap/brk syscalls. Like
+    /// `last_hint`, it is not part of the VM state witness.
+    pub layout: MemoryLayout,
+    /// the symbols of the loaded ELF, shared with the clones of the state. Like `layout`, not
+    /// part of the VM state witness.
+    symbols: Option<Arc<SymbolMap>>,
 
     // last_hint is optional metadata, and not part of the VM state itself.
-    // It is used to remember the last pre-image hint,
+    // It is used to remember the pre-image hints,
     // so a VM can start from any state without fetching prior pre-images,
-    // and instead just repeat the last hint on setup,
+    // and instead just repeat the hints on setup,
     // to make sure pre-image requests can be served.
     // The first 4 bytes are a uin32 length prefix.
     // Warning: the hint MAY NOT BE COMPLETE. I.e. this is buffered,
-    // and should only be read when len(LastHint) > 4 && uint32(LastHint[:4]) >= len(LastHint[4:])
-    last_hint: Vec<u8>,
+    // and the complete hints are dispatched by the `HintBuffer` as they arrive.
+    // A host saves it next to the witness with `HintBuffer::encode`.
+    last_hint: HintBuffer,
 }
 
 impl Display for State {
@@ -82,35 +235,399 @@ impl State {
             step: 0,
             exited: false,
             exit_code: 0,
+            break_code: None,
+            random_position: 0,
+            thread_pointer: 0,
+            in_delay_slot: false,
+            hilo_written_step: None,
+            layout: MemoryLayout { heap_base: 0, ..Default::default() },
+            symbols: None,
             last_hint: Default::default(),
         })
     }
 
+    /// Writes `val` to the general purpose register `idx`, the writes to $zero are dropped.
+    /// Fails if `idx` is not one of the 32 registers.
+    pub fn write_reg(&mut self, idx: u32, val: u32) -> Result<(), EmulatorError> {
+        match self.registers.get_mut(idx as usize) {
+            None => Err(EmulatorError::InvalidRegister { reg: idx, pc: self.pc }),
+            Some(_) if idx == 0 => Ok(()),
+            Some(reg) => {
+                *reg = val;
+                Ok(())
+            }
+        }
+    }
+
+    /// Encodes the state for the witness, see `STATE_WITNESS_SIZE`.
     pub fn encode_witness(&mut self) -> Vec<u8> {
-        let mut out = Vec::<u8>::new();
-        let mem_root = self.memory.merkle_root();
-        out.extend(mem_root);
-        out.extend(self.preimage_key.clone());
-        out.extend(self.preimage_offset.to_be_bytes());
-        out.extend(self.pc.to_be_bytes());
-        out.extend(self.next_pc.to_be_bytes());
-        out.extend(self.lo.to_be_bytes());
-        out.extend(self.hi.to_be_bytes());
-        out.extend(self.heap.to_be_bytes());
-        out.push(self.exit_code);
-        if self.exited {
-            out.push(1);
-        } else {
-            out.push(0);
+        let mut out = Vec::with_capacity(STATE_WITNESS_SIZE);
+        self.witness_fields(|field| out.extend_from_slice(field));
+        out
+    }
+
+    /// Rebuilds the state encoded by `encode_witness` around `memory`, which the witness only
+    /// commits to by its root. Returns none if the witness is not `STATE_WITNESS_SIZE` bytes.
+    pub(crate) fn decode_witness(witness: &[u8], memory: Memory) -> Option<Box<Self>> {
+        if witness.len() != STATE_WITNESS_SIZE {
+            return None;
         }
-        out.extend(self.step.to_be_bytes());
+        let word = |offset: usize| u32::from_be_bytes(witness[offset..offset + 4].try_into().unwrap());
+        let mut state = Self::new();
+        state.memory = Box::new(memory);
+        state.preimage_key.copy_from_slice(&witness[WITNESS_PREIMAGE_KEY..WITNESS_PREIMAGE_OFFSET]);
+        state.preimage_offset = word(WITNESS_PREIMAGE_OFFSET);
+        state.pc = word(WITNESS_PC);
+        state.next_pc = word(WITNESS_NEXT_PC);
+        state.lo = word(WITNESS_LO);
+        state.hi = word(WITNESS_HI);
+        state.heap = word(WITNESS_HEAP);
+        state.exit_code = witness[WITNESS_EXIT_CODE];
+        state.exited = witness[WITNESS_EXITED] & 1 != 0;
+        let little = witness[WITNESS_EXITED] & 2 != 0;
+        state.memory.set_endianness(if little { Endianness::Little } else { Endianness::Big });
+        let step = &witness[WITNESS_STEP..WITNESS_REGISTERS];
+        state.step = u64::from_be_bytes(step.try_into().unwrap());
+        for (i, register) in state.registers.iter_mut().enumerate() {
+            *register = word(WITNESS_REGISTERS + 4 * i);
+        }
+        Some(state)
+    }
+
+    /// The exited byte of the witness encoding, with the byte order of the memory in bit 1.
+    fn exited_byte(&self) -> u8 {
+        self.exited as u8 | ((self.memory.endianness() == Endianness::Little) as u8) << 1
+    }
+
+    /// Calls `put` with the fields of the witness encoding of the state in order, without
+    /// building it. The memory root is cached by the memory until a page is written.
+    fn witness_fields(&mut self, mut put: impl FnMut(&[u8])) {
+        put(&self.memory.merkle_root());
+        put(&self.preimage_key);
+        put(&self.preimage_offset.to_be_bytes());
+        put(&self.pc.to_be_bytes());
+        put(&self.next_pc.to_be_bytes());
+        put(&self.lo.to_be_bytes());
+        put(&self.hi.to_be_bytes());
+        put(&self.heap.to_be_bytes());
+        put(&[self.exit_code, self.exited_byte()]);
+        put(&self.step.to_be_bytes());
         for register in self.registers {
-            out.extend(register.to_be_bytes());
+            put(&register.to_be_bytes());
+        }
+    }
+
+    /// Feeds the witness encoding of the state into `hasher` field by field, without building
+    /// it. The memory root is cached by the memory until a page is written.
+    pub fn hash_into(&mut self, hasher: &mut impl Digest) {
+        self.witness_fields(|field| hasher.update(field));
+    }
+
+    /// Returns the hash of the witness encoding of the state under `hasher`, the encoding is
+    /// built on the stack. `InstrumentedState::state_hash` hashes with the hasher of its config.
+    pub fn state_hash(&mut self, hasher: &dyn Hasher32) -> [u8; 32] {
+        let mut witness = [0; STATE_WITNESS_SIZE];
+        let mut len = 0;
+        self.witness_fields(|field| {
+            witness[len..len + field.len()].copy_from_slice(field);
+            len += field.len();
+        });
+        hasher.hash(&witness)
+    }
+
+    /// Returns the fields, registers and memory words of the state differing from `other`.
+    pub fn diff(&self, other: &State) -> StateDiff {
+        let fields = [
+            ("pc", self.pc as u64, other.pc as u64),
+            ("next_pc", self.next_pc as u64, other.next_pc as u64),
+            ("hi", self.hi as u64, other.hi as u64),
+            ("lo", self.lo as u64, other.lo as u64),
+            ("heap", self.heap as u64, other.heap as u64),
+            ("step", self.step, other.step),
+            ("exited", self.exited as u64, other.exited as u64),
+            ("exit_code", self.exit_code as u64, other.exit_code as u64),
+            ("preimage_offset", self.preimage_offset as u64, other.preimage_offset as u64),
+        ];
+        StateDiff {
+            fields: fields.into_iter().filter(|(_, a, b)| a != b).collect(),
+            preimage_key: (self.preimage_key != other.preimage_key)
+                .then_some((self.preimage_key, other.preimage_key)),
+            registers: (0..32)
+                .filter(|&i| self.registers[i] != other.registers[i])
+                .map(|i| (i, self.registers[i], other.registers[i]))
+                .collect(),
+            memory: self.memory.diff(&other.memory),
+        }
+    }
+
+    /// Returns the keccak256 hash of the big-endian registers.
+    pub fn registers_hash(&self) -> [u8; 32] {
+        let mut hasher = Keccak256::new();
+        for register in self.registers {
+            hasher.update(register.to_be_bytes());
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn random_position(&self) -> u64 {
+        self.random_position
+    }
+
+    /// The part of the state the witness doesn't commit to, saved next to it to resume the state.
+    pub fn metadata(&self) -> StateMetadata {
+        StateMetadata { random_position: self.random_position, break_code: self.break_code }
+    }
+
+    /// Restores the metadata of a serialized state, see `State::metadata`.
+    pub fn set_metadata(&mut self, metadata: StateMetadata) {
+        self.random_position = metadata.random_position;
+        self.break_code = metadata.break_code;
+    }
+
+    /// Saves the state to `path`, referring to the page file of its memory instead of inlining
+    /// the pages: the witness encoding, the id of the memory hash, the u32 length of the
+    /// metadata and the metadata, then the path of the page file. The pages are flushed to the
+    /// page file first, the memory must be held by a `FileBackend`.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let page_file = self.memory.page_file()
+            .ok_or_else(|| invalid("the memory has no page file"))?
+            .to_str()
+            .ok_or_else(|| invalid("the path of the page file is not utf-8"))?
+            .to_string();
+        self.memory.flush()?;
+        let mut out = self.encode_witness();
+        out.push(self.memory.hash_function().id());
+        let metadata = self.metadata().encode();
+        out.extend((metadata.len() as u32).to_le_bytes());
+        out.extend(metadata);
+        out.extend(page_file.as_bytes());
+        fs::write(path, out)
+    }
+
+    /// Loads a state saved by `save`, its memory reopened from the page file with at most
+    /// `capacity` pages in RAM, see `FileBackend::open`. The memory must have the root of the
+    /// witness.
+    pub fn load(path: impl AsRef<Path>, capacity: usize) -> io::Result<Box<Self>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let data = fs::read(path)?;
+        if data.len() < STATE_WITNESS_SIZE + 5 {
+            return Err(invalid("truncated state"));
+        }
+        let (witness, rest) = data.split_at(STATE_WITNESS_SIZE);
+        let hash = HashFunction::from_id(rest[0])
+            .ok_or_else(|| invalid("unsupported memory hash"))?;
+        let len = u32::from_le_bytes(rest[1..5].try_into().unwrap()) as usize;
+        if rest.len() < 5 + len {
+            return Err(invalid("truncated state metadata"));
+        }
+        let (metadata, page_file) = rest[5..].split_at(len);
+        let metadata = StateMetadata::decode(metadata)
+            .ok_or_else(|| invalid("invalid state metadata"))?;
+        let page_file = std::str::from_utf8(page_file)
+            .map_err(|_| invalid("the path of the page file is not utf-8"))?;
+
+        let mut memory = Memory::with_backend(Box::new(FileBackend::open(page_file, capacity)?));
+        memory.set_hash_function(hash);
+        let mut state = Self::decode_witness(witness, memory).unwrap();
+        state.set_metadata(metadata);
+        if state.memory.merkle_root()[..] != witness[..32] {
+            return Err(invalid("the page file does not have the memory of the state"));
+        }
+        Ok(state)
+    }
+
+    /// The steps executed, the instruction executing is the step after it.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    pub fn preimage_key(&self) -> [u8; 32] {
+        self.preimage_key
+    }
+
+    pub fn set_preimage_key(&mut self, key: [u8; 32]) {
+        self.preimage_key = key;
+    }
+
+    pub fn preimage_offset(&self) -> u32 {
+        self.preimage_offset
+    }
+
+    pub fn set_preimage_offset(&mut self, offset: u32) {
+        self.preimage_offset = offset;
+    }
+
+    /// The hint being written and the complete ones, see `InstrumentedState::from_state`.
+    pub fn last_hint(&self) -> &HintBuffer {
+        &self.last_hint
+    }
+
+    /// Restores the hints of a serialized state, the max hint size is set by the config of the
+    /// `InstrumentedState`.
+    pub fn set_last_hint(&mut self, last_hint: HintBuffer) {
+        self.last_hint = last_hint;
+    }
+
+    pub fn thread_pointer(&self) -> u32 {
+        self.thread_pointer
+    }
+
+    /// The code of the break that stopped the guest, if it did.
+    pub fn break_code(&self) -> Option<u32> {
+        self.break_code
+    }
+
+    pub fn set_thread_pointer(&mut self, thread_pointer: u32) {
+        self.thread_pointer = thread_pointer;
+    }
+
+    /// Sets the registers, the pc and hi/lo at once, to start a run from a saved point or from
+    /// the starting state of a reference implementation. The next pc follows the pc, the first
+    /// instruction is not in a delay slot.
+    pub fn set_initial(&mut self, regs: [u32; 32], pc: u32, hi: u32, lo: u32) {
+        self.registers = regs;
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.hi = hi;
+        self.lo = lo;
+        self.in_delay_slot = false;
+    }
+
+    /// Captures the text of the program, the executable segments of the ELF. Empty for states
+    /// not loaded from an ELF.
+    pub fn instruction_image(&mut self) -> InstructionImage {
+        match self.layout.text {
+            Some((start, end)) => InstructionImage::capture(&mut self.memory, start..end),
+            None => InstructionImage::default(),
         }
-        out
+    }
+
+    /// Puts the state back to `State::new` with the pc at `entry_pc`, the pages of the memory are
+    /// dropped but its allocations and settings are kept.
+    fn reset(&mut self, entry_pc: u32) {
+        self.memory.clear();
+        self.preimage_key = Default::default();
+        self.preimage_offset = 0;
+        self.registers = Default::default();
+        self.pc = entry_pc;
+        self.next_pc = entry_pc.wrapping_add(4);
+        self.hi = 0;
+        self.lo = 0;
+        self.heap = 0;
+        self.step = 0;
+        self.exited = false;
+        self.exit_code = 0;
+        self.break_code = None;
+        self.random_position = 0;
+        self.thread_pointer = 0;
+        self.in_delay_slot = false;
+        self.hilo_written_step = None;
+        self.layout = MemoryLayout { heap_base: 0, ..Default::default() };
+        self.symbols = None;
+        self.last_hint.restore(Vec::new(), Vec::new());
+    }
+
+    /// Makes the state a clone of `other`, reusing the allocations of the memory, see
+    /// `Memory::restore`.
+    fn restore(&mut self, other: &State) {
+        let State {
+            memory,
+            preimage_key,
+            preimage_offset,
+            registers,
+            pc,
+            next_pc,
+            hi,
+            lo,
+            heap,
+            step,
+            exited,
+            exit_code,
+            break_code,
+            random_position,
+            thread_pointer,
+            in_delay_slot,
+            hilo_written_step,
+            layout,
+            symbols,
+            last_hint,
+        } = other;
+        self.memory.restore(memory);
+        self.preimage_key = *preimage_key;
+        self.preimage_offset = *preimage_offset;
+        self.registers = *registers;
+        self.pc = *pc;
+        self.next_pc = *next_pc;
+        self.hi = *hi;
+        self.lo = *lo;
+        self.heap = *heap;
+        self.step = *step;
+        self.exited = *exited;
+        self.exit_code = *exit_code;
+        self.break_code = *break_code;
+        self.random_position = *random_position;
+        self.thread_pointer = *thread_pointer;
+        self.in_delay_slot = *in_delay_slot;
+        self.hilo_written_step = *hilo_written_step;
+        self.layout.clone_from(layout);
+        self.symbols.clone_from(symbols);
+        self.last_hint.clone_from(last_hint);
+    }
+
+    /// The symbols of the ELF the state was loaded from, `None` for the other states.
+    pub fn symbols(&self) -> Option<&SymbolMap> {
+        self.symbols.as_deref()
+    }
+
+    /// Checks that the program, heap and stack regions of `layout` don't overlap.
+    pub fn validate_layout(&self) -> Result<(), LayoutError> {
+        self.layout.validate()
     }
 
     pub fn load_elf(f: &elf::ElfBytes<AnyEndian>) -> (Box<Self>, Box<Program>) {
+        Self::try_load_elf(f).unwrap_or_else(|e| panic!("failed to load ELF: {}", e))
+    }
+
+    /// Loads the ELF like `load_elf`, but returns the error if its segments overlap the heap or
+    /// stack regions, or if it is a static PIE that can't be relocated.
+    pub fn try_load_elf(
+        f: &elf::ElfBytes<AnyEndian>,
+    ) -> Result<(Box<Self>, Box<Program>), LoadError> {
+        Self::try_load_elf_at(f, DEFAULT_LOAD_BIAS)
+    }
+
+    /// Loads the ELF like `try_load_elf`, a static PIE at `pie_bias`: its segments, entry and
+    /// dynamic relocations are moved by the bias. The entry of a PIE finds the bias in $t9, like
+    /// a PIC function called through it.
+    pub fn try_load_elf_at(
+        f: &elf::ElfBytes<AnyEndian>,
+        pie_bias: u32,
+    ) -> Result<(Box<Self>, Box<Program>), LoadError> {
+        Self::try_load_elf_with(f, pie_bias, Endianness::Big)
+    }
+
+    /// Loads the ELF like `try_load_elf_at` into a memory of the byte order `endianness`, the
+    /// one of `VmConfig::endianness`. Fails with `LoadError::Endianness` if the ELF is of the
+    /// other byte order.
+    pub fn try_load_elf_with(
+        f: &elf::ElfBytes<AnyEndian>,
+        pie_bias: u32,
+        endianness: Endianness,
+    ) -> Result<(Box<Self>, Box<Program>), LoadError> {
+        let elf_endianness = match f.ehdr.endianness {
+            AnyEndian::Big => Endianness::Big,
+            AnyEndian::Little => Endianness::Little,
+        };
+        if elf_endianness != endianness {
+            return Err(LoadError::Endianness { elf: elf_endianness, configured: endianness });
+        }
+        let bias = reloc::load_bias(f, pie_bias);
+        let is_pie = f.ehdr.e_type == ET_DYN;
+        if is_pie {
+            reloc::check_pie(f, bias)?;
+        }
+        let entry = (f.ehdr.e_entry as u32).wrapping_add(bias);
         let mut s = Box::new(Self {
             memory: Box::new(Memory::new()),
             registers: Default::default(),
@@ -118,17 +635,25 @@ impl State {
             preimage_key: Default::default(),
             preimage_offset: 0,
 
-            pc: f.ehdr.e_entry as u32,
-            next_pc: f.ehdr.e_entry as u32 + 4,
+            pc: entry,
+            next_pc: entry.wrapping_add(4),
 
             hi: 0,
             lo: 0,
-            heap: 0x20000000,
+            heap: HEAP_START,
             step: 0,
             exited: false,
             exit_code: 0,
+            break_code: None,
+            random_position: 0,
+            thread_pointer: 0,
+            in_delay_slot: false,
+            hilo_written_step: None,
+            layout: MemoryLayout::default(),
+            symbols: Some(Arc::new(SymbolMap::load_elf_at(f, pie_bias))),
             last_hint: Default::default(),
         });
+        s.memory.set_endianness(endianness);
 
         let mut program = Box::from(Program::new());
 
@@ -157,28 +682,44 @@ impl State {
                 }
             }
 
-            if segment.p_vaddr + segment.p_memsz >= 1u64 << 32 {
+            let vaddr = segment.p_vaddr + bias as u64;
+            if vaddr + segment.p_memsz >= 1u64 << 32 {
                 panic!("program %d out of 32-bit mem range: {:x} -{:x} (size: {:x})",
-                       segment.p_vaddr, segment.p_memsz, segment.p_memsz);
+                       vaddr, segment.p_memsz, segment.p_memsz);
             }
 
             let n = r.len();
             let r: Box<&[u8]>= Box::new(r.as_slice());
-            s.memory.set_memory_range(segment.p_vaddr as u32, r).expect(
+            s.memory.set_memory_range(vaddr as u32, r).expect(
                 "failed to set memory range"
             );
 
             if n != 0 {
-                program.segments.push(
-                    ProgramSegment {
-                        start_addr: segment.p_vaddr as u32,
-                        segment_size: n as u32,
-                        instructions: vec![],
-                    }
-                )
+                let (start, end) = (vaddr as u32, (vaddr + segment.p_memsz) as u32);
+                let extend = |extent: Option<(u32, u32)>| Some(match extent {
+                    Some((lo, hi)) => (lo.min(start), hi.max(end)),
+                    None => (start, end),
+                });
+                s.layout.program = extend(s.layout.program);
+                // the program, whose code the circuits commit to, is the executable segments only
+                if segment.p_flags & PF_X != 0 {
+                    s.layout.text = extend(s.layout.text);
+                    program.segments.push(
+                        ProgramSegment {
+                            start_addr: vaddr as u32,
+                            segment_size: n as u32,
+                            instructions: vec![],
+                        }
+                    )
+                }
             }
         }
-        (s, program)
+        if is_pie {
+            reloc::relocate(f, &mut s.memory, bias)?;
+            s.registers[25] = entry;
+        }
+        s.validate_layout()?;
+        Ok((s, program))
     }
 
     pub fn patch_go(&mut self, f: &elf::ElfBytes<AnyEndian>) {
@@ -201,7 +742,9 @@ impl State {
                         "github.com/prometheus/client_model/go.init.1" |
                         "flag.init" |
                         "runtime.check" => {
-                            let r:Vec<u8> = vec![0x03, 0xe0, 0x00, 0x08, 0, 0, 0, 0];
+                            // jr $ra, then a nop in its delay slot
+                            let mut r = self.memory.endianness().word_to_bytes(0x03e00008).to_vec();
+                            r.extend([0; 4]);
                             let r = Box::new(r.as_slice());
                             self.memory.set_memory_range(symbol.st_value as u32, r)
                                 .expect("set memory range failed");
@@ -223,43 +766,48 @@ impl State {
         }
     }
 
-    pub fn patch_stack(&mut self) {
+    /// Sets up the initial stack, AT_RANDOM points to the 16 bytes `random::at_random` derives
+    /// from `random_seed`, the `VmConfig::random_seed` of the run. Fails if the initial stack
+    /// doesn't fit the layout, or on the page limit of the memory.
+    pub fn patch_stack(&mut self, random_seed: &[u8; 32]) -> Result<(), EmulatorError> {
+        self.patch_stack_with_random(random::at_random(random_seed))
+    }
+
+    /// Like `patch_stack`, with the 16 bytes AT_RANDOM points to.
+    pub fn patch_stack_with_random(&mut self, random: [u8; 16]) -> Result<(), EmulatorError> {
         // setup stack pointer
-        let sp: u32 = 0x7fFFd000;
+        let sp: u32 = STACK_POINTER;
+        let initial = Region::new(RegionKind::Stack, sp - 4 * PAGE_SIZE as u32, sp + PAGE_SIZE as u32);
+        self.layout.check_alloc(initial, Some(self.layout.stack()))?;
 
         // allocate 1 page for the initial stack data, and 16kb = 4 pages for the stack to grow
         let r: Vec<u8> = vec![0; 5 * PAGE_SIZE];
         let r: Box<&[u8]> = Box::new(r.as_slice());
 
         let addr = sp - 4 * PAGE_SIZE as u32;
-        self.memory.set_memory_range(addr, r)
-            .expect("failed to set memory range");
+        self.memory.set_memory_range(addr, r)?;
 
-        self.registers[29] = sp;
+        self.registers[reg::SP as usize] = sp;
 
+        let endianness = self.memory.endianness();
         let mut store_mem = |addr: u32, v: u32| {
-            let mut dat = [0u8; 4];
-            dat.copy_from_slice(&v.to_be_bytes());
+            let dat = endianness.word_to_bytes(v);
             let r = Box::new(dat.as_slice());
             self.memory.set_memory_range(addr, r)
-                .expect("failed to set memory range");
         };
 
         // init argc,  argv, aux on stack
-        store_mem(sp+4*1, 0x42); // argc = 0 (argument count)
-        store_mem(sp+4*2, 0x35); // argv[n] = 0 (terminating argv)
-        store_mem(sp+4*3, 0x00); // envp[term] = 0 (no env vars)
-        store_mem(sp+4*4, 0x06); // auxv[0] = _AT_PAGESZ = 6 (key)
-        store_mem(sp+4*5, 0x1000); // auxv[1] = page size of 4 KiB (value) - (== minPhysPageSize)
-        store_mem(sp+4*6, 0x1A); // auxv[2] = AT_RANDOM
-        store_mem(sp+4*7, sp+4*9); // auxv[3] = address of 16 bytes containing random value
-        store_mem(sp+4*8, 0); // auxv[term] = 0
-
-        let mut rng = thread_rng();
-        let r: [u8; 16] = rng.gen();
-        let r: Box<&[u8]> = Box::new(r.as_slice());
+        store_mem(sp+4*1, 0x42)?; // argc = 0 (argument count)
+        store_mem(sp+4*2, 0x35)?; // argv[n] = 0 (terminating argv)
+        store_mem(sp+4*3, 0x00)?; // envp[term] = 0 (no env vars)
+        store_mem(sp+4*4, 0x06)?; // auxv[0] = _AT_PAGESZ = 6 (key)
+        store_mem(sp+4*5, 0x1000)?; // auxv[1] = page size of 4 KiB (value) - (== minPhysPageSize)
+        store_mem(sp+4*6, 0x1A)?; // auxv[2] = AT_RANDOM
+        store_mem(sp+4*7, sp+4*9)?; // auxv[3] = address of 16 bytes containing random value
+        store_mem(sp+4*8, 0)?; // auxv[term] = 0
+
+        let r: Box<&[u8]> = Box::new(random.as_slice());
         self.memory.set_memory_range(sp+4*9, r)
-            .expect("failed to set memory range");
     }
 }
 
@@ -271,259 +819,1515 @@ pub struct InstrumentedState {
     stdout_writer: Box<dyn Write>,
     /// writer for stderr
     stderr_writer: Box<dyn Write>,
+    /// the writes to stdout are counted without reading them from memory, see `new_headless`.
+    discard_stdout: bool,
 
     /// track the memory address last time accessed.
     last_mem_access: u32,
     /// indicates whether enable memory proof.
     mem_proof_enabled: bool,
+    /// the step builds its witness, with the proofs if `mem_proof_enabled`.
+    witness_enabled: bool,
     /// merkle proof for memory, depth is 28.
     mem_proof: [u8; 28*32],
+    /// the words accessed after `last_mem_access` by wide preimage reads, and their proofs.
+    extra_mem_accesses: Vec<u32>,
+    extra_mem_proofs: Vec<u8>,
+    /// the memory accessed by the syscall of the step, and its witness, tracked with proofs.
+    syscall_mem_ops: Vec<MemoryAccess>,
+    syscall_witness: Option<SyscallWitness>,
+    /// the registers written by the instruction of the step, for its witness.
+    register_delta: Option<RegisterDelta>,
+    hilo_delta: Option<HiLoDelta>,
+    /// the counter of the last memory access, each access of a step gets the next one.
+    rw_counter: u64,
 
     preimage_oracle: Box<dyn PreimageOracle>,
 
+    /// the cached window of the length prefixed preimage of `last_preimage_key`, from the offset
+    /// `last_preimage_start`, see `PREIMAGE_WINDOW_SIZE`.
     last_preimage: Vec<u8>,
     last_preimage_key: [u8; 32],
+    last_preimage_start: u32,
+    /// the length of the preimage of `last_preimage_key`, none before the first fetch.
+    last_preimage_len: Option<u64>,
     last_preimage_offset: u32,
+    /// the digests of the windows fetched from the oracle, to catch an inconsistent oracle.
+    preimage_digests: PreimageDigests,
+    /// the length prefixed preimage of the key, fetched whole for the witnesses when the cached
+    /// window doesn't hold all of it, see `whole_last_preimage`.
+    witness_preimage: Option<([u8; 32], Vec<u8>)>,
+    /// the parts of preimages fetched from the oracle, in order, if logging them is enabled.
+    preimage_log: Option<Vec<ServedPreimage>>,
+
+    /// watches stderr for the panic message of Rust guests.
+    panic_detector: PanicDetector,
+
+    config: VmConfig,
+    /// records the interesting events, if enabled by `VmConfig::journal`.
+    journal: Option<Journal>,
+    /// receives the metrics, reported by the step loop every `metrics::REPORT_INTERVAL` steps.
+    metrics: Box<dyn MetricsSink>,
+    pending_metrics: PendingMetrics,
+
+    /// instruction counts, collected when profiling is enabled.
+    profile: Option<ProfileReport>,
+    /// receives the executed instructions, see `set_trace_exporter`.
+    trace_exporter: Option<Box<dyn TraceExporter>>,
+    /// handles the process syscalls before their stubs, see `set_syscall_override`.
+    syscall_override: Option<Box<dyn SyscallOverride>>,
+    /// receives the witness of each step, see `witness_to`.
+    witness_stream: Option<WitnessWriter<BufWriter<File>>>,
+    /// the streamed witnesses have their proofs, see `witness_to`.
+    witness_stream_proofs: bool,
+    /// the addresses loads and stores must not touch, if `VmConfig::stack_guard` is enabled.
+    stack_guard: Option<Range<u32>>,
+    /// loads and stores below it fail with `NullAccess`, zero unless `VmConfig::null_guard` is
+    /// enabled.
+    null_guard_end: u32,
+    /// the text of the program when execution started.
+    instruction_image: InstructionImage,
+
+    /// the input of the guest on stdin, and the bytes of it already read.
+    stdin: Vec<u8>,
+    stdin_offset: usize,
+    /// the hints sent by the guest, if capturing them is enabled.
+    captured_hints: Option<Vec<Vec<u8>>>,
+    /// the preimage bytes read by the guest and the hints it sent, for `RunSummary`.
+    preimage_bytes_served: u64,
+    hints_posted: u64,
+    /// the instruction executed as a nop, see `disable_instruction`.
+    #[cfg(any(test, feature = "testing"))]
+    disabled_instruction: Option<OpcodeId>,
+    /// the (pc, insn) of the invalid instructions skipped, once each, see
+    /// `VmConfig::invalid_opcodes`, and how many times each was skipped.
+    skipped_instructions: Vec<(u32, u32)>,
+    skip_counts: HashMap<(u32, u32), u64>,
+    /// the audit logs of the watched regions, see `watch_region`.
+    region_logs: Vec<RegionLog>,
+}
+
+/// The range guarded by `VmConfig::stack_guard` and the end of the one guarded by
+/// `VmConfig::null_guard`, for a program with `layout`.
+fn guards(config: &VmConfig, layout: &MemoryLayout) -> (Option<Range<u32>>, u32) {
+    let stack_guard = config.stack_guard.then(|| {
+        let guard = layout.stack_guard();
+        guard.start..guard.end
+    });
+    // a program linked at a low address may use the memory below the guard
+    let null_guard_end = match (config.null_guard, layout.program) {
+        (false, _) => 0,
+        (true, Some((start, _))) => config.null_guard_end.min(start),
+        (true, None) => config.null_guard_end,
+    };
+    (stack_guard, null_guard_end)
+}
+
+/// InstrumentedStateBuilder configures an `InstrumentedState` with its inputs in one expression.
+pub struct InstrumentedStateBuilder {
+    state: Box<State>,
+    config: VmConfig,
+    oracle: Option<Box<dyn PreimageOracle>>,
+    preimages: HashMap<[u8; 32], Vec<u8>>,
+    stdin: Vec<u8>,
+    capture_hints: bool,
+    log_preimages: bool,
+    discard_output: bool,
+}
+
+impl InstrumentedStateBuilder {
+    pub fn new(state: Box<State>) -> Self {
+        Self {
+            state,
+            config: VmConfig::default(),
+            oracle: None,
+            preimages: HashMap::new(),
+            stdin: Vec::new(),
+            capture_hints: false,
+            log_preimages: false,
+            discard_output: false,
+        }
+    }
+
+    pub fn with_config(mut self, config: VmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Serves the preimages missing from `with_preimages` from `oracle`, which also receives
+    /// the hints.
+    pub fn with_oracle(mut self, oracle: Box<dyn PreimageOracle>) -> Self {
+        self.oracle = Some(oracle);
+        self
+    }
+
+    /// The bytes the guest reads from stdin.
+    pub fn with_stdin(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.stdin = bytes.into();
+        self
+    }
+
+    /// Serves the preimages of `map`, checked against their keys when the state is built.
+    pub fn with_preimages(mut self, map: HashMap<[u8; 32], Vec<u8>>) -> Self {
+        self.preimages.extend(map);
+        self
+    }
+
+    /// Keeps the hints sent by the guest, see `InstrumentedState::captured_hints`.
+    pub fn with_hints_captured(mut self) -> Self {
+        self.capture_hints = true;
+        self
+    }
+
+    /// Logs the parts of preimages fetched from the oracle, see
+    /// `InstrumentedState::preimage_log`.
+    pub fn with_preimage_log(mut self) -> Self {
+        self.log_preimages = true;
+        self
+    }
+
+    /// Discards the output of the guest, see `InstrumentedState::new_headless`.
+    pub fn with_output_discarded(mut self) -> Self {
+        self.discard_output = true;
+        self
+    }
+
+    /// Fails if a preimage does not hash to its key.
+    pub fn build(self) -> Result<Box<InstrumentedState>, EmulatorError> {
+        let store = self.oracle.unwrap_or_else(|| Box::new(EmptyPreimageOracle));
+        let oracle: Box<dyn PreimageOracle> = if self.preimages.is_empty() {
+            store
+        } else {
+            let mut oracle = TypedPreimageOracle::new(store);
+            for (k, data) in self.preimages {
+                oracle.insert(k, data)?;
+            }
+            Box::new(oracle)
+        };
+
+        let mut is = InstrumentedState::new_with_config(self.state, oracle, self.config);
+        is.stdin = self.stdin;
+        if self.capture_hints {
+            is.captured_hints = Some(Vec::new());
+        }
+        if self.log_preimages {
+            is.preimage_log = Some(Vec::new());
+        }
+        if self.discard_output {
+            is.discard_output();
+        }
+        Ok(is)
+    }
+}
+
+/// VmStatus is the reason `InstrumentedState::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VmStatus {
+    /// the guest called exit_group with the exit code.
+    Exited(u8),
+    /// the step budget ran out before the guest exited.
+    StepLimitReached,
+    /// the guest touched more pages than `VmConfig::max_host_pages`.
+    HostOom { addr: u32, pages: usize },
+    /// the guest executed break with the code, like a panic. It exited with `BREAK_EXIT_CODE`.
+    Panic { code: u32 },
+    /// the run was cancelled or timed out before `step`, the state can be resumed.
+    Cancelled { step: u64 },
+    /// the state at `pc` was sampled twice `period` steps apart with no memory write in
+    /// between, so the guest loops forever, see `VmConfig::livelock_interval`. The period is a
+    /// multiple of the period of the loop. The state can be resumed.
+    LivelockSuspected { pc: u32, period: u64 },
+}
+
+/// RunResult summarizes a call to `InstrumentedState::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    pub status: VmStatus,
+    /// the steps executed by this run.
+    pub steps: u64,
+    /// the panic message the guest printed to stderr, set when it exited with a non-zero code.
+    pub guest_panic: Option<GuestPanic>,
 }
 
 impl Display for InstrumentedState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "state: {}, last_mem_access: {}, proof_enabled: {}",
-            self.state, self.last_mem_access, self.mem_proof_enabled
+            "state: {}, pc: {}, last_mem_access: {}, proof_enabled: {}",
+            self.state, self.annotate_pc(self.state.pc), self.last_mem_access, self.mem_proof_enabled
         )
     }
 }
 
+/// StateMetadata is the part of the state a host saves with its witness to resume it, which the
+/// witness doesn't commit to. The hints are saved apart, see `HintBuffer::encode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateMetadata {
+    /// the bytes of the random stream already served by getrandom.
+    pub random_position: u64,
+    /// the code of the break the guest exited by, see `State::break_code`.
+    pub break_code: Option<u32>,
+}
+
+/// the size of the encoding of `StateMetadata`.
+const STATE_METADATA_SIZE: usize = 8 + 1 + 4;
+
+impl StateMetadata {
+    /// Encodes the metadata: the big-endian random position, then a byte set if there is a
+    /// break code and the big-endian code, zero if there is none.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.random_position.to_be_bytes().to_vec();
+        out.push(self.break_code.is_some() as u8);
+        out.extend(self.break_code.unwrap_or(0).to_be_bytes());
+        out
+    }
+
+    /// Decodes the metadata encoded by `encode`. Returns none if `data` is not of its size.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != STATE_METADATA_SIZE {
+            return None;
+        }
+        let random_position = u64::from_be_bytes(data[..8].try_into().unwrap());
+        let code = u32::from_be_bytes(data[9..].try_into().unwrap());
+        let break_code = match data[8] {
+            0 => None,
+            1 => Some(code),
+            _ => return None,
+        };
+        Some(Self { random_position, break_code })
+    }
+}
+
+/// StateSnapshot is a state `InstrumentedState::reset_to` rewinds to, for the many short runs
+/// of fuzzing and differential testing. The runs share its memory pages until they write them.
+#[derive(Clone)]
+pub struct StateSnapshot {
+    state: Box<State>,
+    instruction_image: InstructionImage,
+    /// the bytes of stdin the guest read, stdin itself is kept by the `InstrumentedState`.
+    stdin_offset: usize,
+}
+
+impl StateSnapshot {
+    /// Snapshots `state` before the guest read stdin.
+    pub fn new(mut state: Box<State>) -> Self {
+        let instruction_image = state.instruction_image();
+        Self { state, instruction_image, stdin_offset: 0 }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+}
+
 impl InstrumentedState {
     pub fn new(
         state: Box<State>,
         preimage_oracle: Box<dyn PreimageOracle>
     ) -> Box<Self> {
+        Self::new_with_config(state, preimage_oracle, VmConfig::default())
+    }
+
+    pub fn new_with_config(
+        state: Box<State>,
+        preimage_oracle: Box<dyn PreimageOracle>,
+        mut config: VmConfig,
+    ) -> Box<Self> {
+        let mut state = state;
+        state.memory.set_max_pages(config.max_host_pages);
+        state.memory.set_hash_function(config.memory_hash);
+        // the byte order is the one of the state, which its ELF or witness decided
+        config.endianness = state.memory.endianness();
+        state.last_hint.set_max_hint_size(config.max_hint_size);
+        if config.protect_text {
+            let text = state.layout.text.map(|(start, end)| start..end);
+            state.memory.set_read_only(text);
+        }
+        let instruction_image = state.instruction_image();
+        let (stack_guard, null_guard_end) = guards(&config, &state.layout);
+        let pending_metrics = PendingMetrics::new(state.step, state.memory.stats().page_allocations);
         let is = Box::new(Self{
             state,
             stdout_writer: Box::new(stdout()),
             stderr_writer: Box::new(stderr()),
+            discard_stdout: false,
             last_mem_access: !(0u32),
             mem_proof_enabled: true,
+            witness_enabled: true,
             mem_proof: [0; 28*32],
+            extra_mem_accesses: Vec::new(),
+            extra_mem_proofs: Vec::new(),
+            syscall_mem_ops: Vec::new(),
+            syscall_witness: None,
+            register_delta: None,
+            hilo_delta: None,
+            rw_counter: 0,
             preimage_oracle,
             last_preimage: Vec::<u8>::new(),
             last_preimage_key: [0; 32],
+            last_preimage_start: 0,
+            last_preimage_len: None,
             last_preimage_offset: 0,
+            preimage_digests: PreimageDigests::default(),
+            witness_preimage: None,
+            preimage_log: None,
+            panic_detector: PanicDetector::new(),
+            journal: config.journal.as_ref().map(Journal::new),
+            metrics: Box::new(NoopSink),
+            pending_metrics,
+            config,
+            profile: None,
+            trace_exporter: None,
+            syscall_override: None,
+            witness_stream: None,
+            witness_stream_proofs: false,
+            stack_guard,
+            null_guard_end,
+            instruction_image,
+            stdin: Vec::new(),
+            stdin_offset: 0,
+            captured_hints: None,
+            preimage_bytes_served: 0,
+            hints_posted: 0,
+            #[cfg(any(test, feature = "testing"))]
+            disabled_instruction: None,
+            skipped_instructions: Vec::new(),
+            skip_counts: HashMap::new(),
+            region_logs: Vec::new(),
         });
         is
     }
 
-    fn track_memory_access(&mut self, addr: u32) {
-        if self.mem_proof_enabled && self.last_mem_access != addr {
-            if self.last_mem_access != !(0u32) {
-                panic!("unexpected different memory access at {:x?}, \
-                    already have access at {:x?} buffered", addr, self.last_mem_access);
+    /// Resets the state to run another program from `entry_pc`, reusing the allocations of the
+    /// memory. The registers, hi/lo, the step counter, the exit status and the memory pages are
+    /// zeroed, along with the layout and the symbols of the previous program; the new program is
+    /// written with `set_memory`. The oracle, the writers, the config and the stdin are kept,
+    /// stdin is read again from its start. The trace exporter starts a new run.
+    pub fn reset(&mut self, entry_pc: u32) {
+        self.report_metrics();
+        self.state.reset(entry_pc);
+        self.state.memory.set_read_only(None);
+        self.instruction_image = InstructionImage::default();
+        self.clear_run();
+    }
+
+    /// Returns a snapshot of the state, to rewind to with `reset_to`.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            state: self.state.clone(),
+            instruction_image: self.instruction_image.clone(),
+            stdin_offset: self.stdin_offset,
+        }
+    }
+
+    /// Rewinds to `snapshot` like a state created from it, reusing the allocations of the
+    /// memory and of the page table: the pages of the snapshot are shared until written. The
+    /// witness buffers, the preimage cache, the journal events and the region logs are cleared.
+    /// The oracle, the writers, the config and the stdin are kept, stdin is read again from the
+    /// offset of the snapshot; the symbols and the byte order are the ones of the snapshot. The
+    /// trace exporter starts a new run.
+    pub fn reset_to(&mut self, snapshot: &StateSnapshot) {
+        self.report_metrics();
+        self.state.restore(&snapshot.state);
+        // the config applies to the memory like in `new_with_config`
+        self.state.memory.set_max_pages(self.config.max_host_pages);
+        self.state.memory.set_hash_function(self.config.memory_hash);
+        self.config.endianness = self.state.memory.endianness();
+        self.state.last_hint.set_max_hint_size(self.config.max_hint_size);
+        if self.config.protect_text {
+            let text = self.state.layout.text.map(|(start, end)| start..end);
+            self.state.memory.set_read_only(text);
+        }
+        self.instruction_image.clone_from(&snapshot.instruction_image);
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+        self.clear_run();
+        self.stdin_offset = snapshot.stdin_offset.min(self.stdin.len());
+    }
+
+    /// Clears what the emulator keeps of the run of the state, after the state was reset.
+    fn clear_run(&mut self) {
+        self.last_mem_access = !(0u32);
+        self.mem_proof = [0; 28*32];
+        self.extra_mem_accesses.clear();
+        self.extra_mem_proofs.clear();
+        self.syscall_mem_ops.clear();
+        self.syscall_witness = None;
+        self.register_delta = None;
+        self.hilo_delta = None;
+        self.rw_counter = 0;
+        self.last_preimage.clear();
+        self.last_preimage_key = [0; 32];
+        self.last_preimage_start = 0;
+        self.last_preimage_len = None;
+        self.last_preimage_offset = 0;
+        self.preimage_digests = PreimageDigests::default();
+        self.witness_preimage = None;
+        self.panic_detector = PanicDetector::new();
+        let page_allocations = self.state.memory.stats().page_allocations;
+        self.pending_metrics = PendingMetrics::new(self.state.step, page_allocations);
+        if let Some(profile) = &mut self.profile {
+            *profile = ProfileReport::new();
+        }
+        (self.stack_guard, self.null_guard_end) = guards(&self.config, &self.state.layout);
+        self.stdin_offset = 0;
+        if let Some(log) = &mut self.preimage_log {
+            log.clear();
+        }
+        if let Some(hints) = &mut self.captured_hints {
+            hints.clear();
+        }
+        self.preimage_bytes_served = 0;
+        self.hints_posted = 0;
+        self.skipped_instructions.clear();
+        self.skip_counts.clear();
+        for log in &mut self.region_logs {
+            log.clear();
+        }
+        if let Some(exporter) = &mut self.trace_exporter {
+            exporter.reset();
+        }
+    }
+
+    /// Resumes a state saved by a host, the complete hints are repeated to `preimage_oracle` so
+    /// a fresh oracle can serve the pending preimage read.
+    pub fn from_state(
+        state: Box<State>,
+        preimage_oracle: Box<dyn PreimageOracle>,
+    ) -> Box<Self> {
+        let mut is = Self::new(state, preimage_oracle);
+        is.replay_last_hint();
+        is
+    }
+
+    /// Sends the complete hints of the state to the oracle again, in order.
+    pub fn replay_last_hint(&mut self) {
+        for hint in self.state.last_hint.hints() {
+            self.preimage_oracle.hint(hint);
+        }
+    }
+
+    /// The key of the preimage cached by the last read, and the cached window of the preimage with
+    /// its length prefix, with its offset.
+    pub fn last_preimage(&self) -> ([u8; 32], u32, &[u8]) {
+        (self.last_preimage_key, self.last_preimage_start, &self.last_preimage)
+    }
+
+    /// Returns the counts of the windows of preimages fetched from the oracle and of the reads
+    /// served from the cached one.
+    pub fn preimage_cache_stats(&self) -> PreimageCacheStats {
+        self.preimage_digests.stats()
+    }
+
+    /// Caches `preimage` as the preimage of `key`, so a resumed state reads it without asking the
+    /// oracle. The length prefix is added like for the preimages from the oracle.
+    pub fn set_last_preimage(&mut self, key: [u8; 32], preimage: &[u8]) {
+        self.last_preimage_key = key;
+        self.last_preimage_start = 0;
+        self.last_preimage_len = Some(preimage.len() as u64);
+        self.last_preimage = length_prefixed(preimage);
+        self.preimage_digests.retain(self.last_preimage.len());
+    }
+
+    /// Creates a state discarding the output of the guest, for programs that don't print or
+    /// whose output doesn't matter. Writes to stdout and stderr still return the bytes written.
+    pub fn new_headless(
+        state: Box<State>,
+        preimage_oracle: Box<dyn PreimageOracle>,
+    ) -> Box<Self> {
+        let mut is = Self::new(state, preimage_oracle);
+        is.discard_output();
+        is
+    }
+
+    /// Creates a state serving the preimages of `log` instead of a live oracle. A run of the
+    /// program the log was recorded from, with the same config, fetches the same parts of the
+    /// preimages and replays the recorded run. The preimages of the log are checked against their
+    /// keys, see `PreimageLogOracle`.
+    pub fn from_preimage_log(
+        state: Box<State>,
+        log: &[ServedPreimage],
+        config: VmConfig,
+    ) -> Box<Self> {
+        Self::new_with_config(state, Box::new(PreimageLogOracle::new(log)), config)
+    }
+
+    /// Replaces the oracle, returns the previous one.
+    pub(crate) fn replace_oracle(
+        &mut self,
+        oracle: Box<dyn PreimageOracle>,
+    ) -> Box<dyn PreimageOracle> {
+        std::mem::replace(&mut self.preimage_oracle, oracle)
+    }
+
+    /// Discards the output of the guest from now on, see `new_headless`. The panic message of a
+    /// guest is still caught from stderr.
+    pub fn discard_output(&mut self) {
+        self.stdout_writer = Box::new(sink());
+        self.stderr_writer = Box::new(sink());
+        self.discard_stdout = true;
+    }
+
+    pub fn set_stdout_writer(&mut self, writer: Box<dyn Write>) {
+        self.stdout_writer = writer;
+        self.discard_stdout = false;
+    }
+
+    pub fn set_stderr_writer(&mut self, writer: Box<dyn Write>) {
+        self.stderr_writer = writer;
+    }
+
+    /// The pc and the instruction of each invalid instruction skipped when
+    /// `VmConfig::invalid_opcodes` is lenient, once each, in the order of their first skip. A
+    /// loop over an invalid instruction adds it once, see `skip_count`.
+    pub fn skipped_instructions(&self) -> &[(u32, u32)] {
+        &self.skipped_instructions
+    }
+
+    /// How many times the instruction `insn` at `pc` was skipped, 0 if it never was.
+    pub fn skip_count(&self, pc: u32, insn: u32) -> u64 {
+        self.skip_counts.get(&(pc, insn)).copied().unwrap_or(0)
+    }
+
+    /// Records the stores to the words intersecting `range` in a log named `tag`, replacing the
+    /// log of the same tag. The regions may overlap, each access goes to every region it touches.
+    pub fn watch_region(&mut self, range: Range<u32>, tag: &str) {
+        self.watch_region_with(range, tag, RegionWatchConfig::default());
+    }
+
+    /// Watches the region like `watch_region`, recording the loads too if `config.loads`.
+    pub fn watch_region_with(&mut self, range: Range<u32>, tag: &str, config: RegionWatchConfig) {
+        self.region_logs.retain(|log| log.tag() != tag);
+        self.region_logs.push(RegionLog::new(tag, range, config));
+    }
+
+    /// Stops watching the region `tag`, returns its log.
+    pub fn unwatch_region(&mut self, tag: &str) -> Option<RegionLog> {
+        let i = self.region_logs.iter().position(|log| log.tag() == tag)?;
+        Some(self.region_logs.remove(i))
+    }
+
+    pub fn region_log(&self, tag: &str) -> Option<&RegionLog> {
+        self.region_logs.iter().find(|log| log.tag() == tag)
+    }
+
+    /// Drops the accesses recorded for the region `tag`, it stays watched.
+    pub fn clear_region_log(&mut self, tag: &str) {
+        if let Some(log) = self.region_logs.iter_mut().find(|log| log.tag() == tag) {
+            log.clear();
+        }
+    }
+
+    /// Returns the parts of preimages fetched from the oracle, in order, if enabled by
+    /// `InstrumentedStateBuilder::with_preimage_log`. `from_preimage_log` replays the run
+    /// without the oracle.
+    pub fn preimage_log(&self) -> Option<&[ServedPreimage]> {
+        self.preimage_log.as_deref()
+    }
+
+    /// Returns the hints sent by the guest, if enabled by
+    /// `InstrumentedStateBuilder::with_hints_captured`.
+    pub fn captured_hints(&self) -> Option<&[Vec<u8>]> {
+        self.captured_hints.as_deref()
+    }
+
+    /// The bytes of the incomplete hint buffered, for monitoring.
+    pub fn hint_buffer_len(&self) -> usize {
+        self.state.last_hint.len()
+    }
+
+    /// The text of the program captured when the state was created, for the fetch lookups.
+    pub fn instruction_image(&self) -> &InstructionImage {
+        &self.instruction_image
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// Returns the hash of the witness encoding of the state, under `VmConfig::state_hash`.
+    pub fn state_hash(&mut self) -> [u8; 32] {
+        self.state.state_hash(self.config.state_hash.hasher())
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    pub fn journal_mut(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    /// Reports the metrics of the emulator to `sink`, from the steps executed after this call.
+    pub fn set_metrics_sink(&mut self, sink: Box<dyn MetricsSink>) {
+        self.metrics = sink;
+        self.pending_metrics =
+            PendingMetrics::new(self.state.step, self.state.memory.stats().page_allocations);
+    }
+
+    /// Reports the steps, page allocations and syscalls since the last report to the metrics
+    /// sink. `run` reports them when it returns, a host stepping the state itself calls it.
+    pub fn report_metrics(&mut self) {
+        let page_allocations = self.state.memory.stats().page_allocations;
+        self.pending_metrics.report(self.metrics.as_mut(), self.state.step, page_allocations);
+    }
+
+    fn record_event(&mut self, event: Event) -> Result<(), EmulatorError> {
+        if let Some(journal) = &mut self.journal {
+            journal.record(event)?;
+        }
+        Ok(())
+    }
+
+    /// Attaches the symbols of the program, pc values in traces are annotated with them. The
+    /// states loaded from an ELF already carry its symbols, see `State::symbols`.
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.state.symbols = Some(Arc::new(symbols));
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolMap> {
+        self.state.symbols()
+    }
+
+    /// Formats `pc` as `function+0x12` if symbols are attached.
+    pub fn annotate_pc(&self, pc: u32) -> String {
+        match self.symbols() {
+            Some(symbols) => symbols.annotate(pc),
+            None => format!("0x{:x}", pc),
+        }
+    }
+
+    /// Starts counting the executed instructions, the counts are reset.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(ProfileReport::new());
+    }
+
+    pub fn profile(&self) -> Option<&ProfileReport> {
+        self.profile.as_ref()
+    }
+
+    /// Exports the instructions executed after this call to `exporter`, until
+    /// `finish_trace_export`.
+    pub fn set_trace_exporter(&mut self, exporter: Box<dyn TraceExporter>) {
+        self.trace_exporter = Some(exporter);
+    }
+
+    /// Handles the syscalls of `OVERRIDABLE_SYSCALLS` with `hook` before their built-in stubs,
+    /// the ones it returns no outcome for keep the stub.
+    pub fn set_syscall_override(&mut self, hook: Box<dyn SyscallOverride>) {
+        self.syscall_override = Some(hook);
+    }
+
+    /// Completes the output of the trace exporter and detaches it.
+    pub fn finish_trace_export(&mut self) -> Result<(), EmulatorError> {
+        if let Some(mut exporter) = self.trace_exporter.take() {
+            exporter.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Streams the witness of each step executed after this call to the file at `path`, until
+    /// `finish_witness_stream`, those of `run` included. With `proofs`, every step takes its
+    /// proofs while the stream is open. Without, only the steps asked for proofs have them, the
+    /// others are streamed without the state, the memory proofs, the preimage and the memory
+    /// writes of their syscall. The stream is zstd compressed with the `zstd` feature.
+    pub fn witness_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        proofs: bool,
+    ) -> Result<(), EmulatorError> {
+        let file = BufWriter::new(File::create(path)?);
+        self.witness_stream = Some(WitnessWriter::with_compression(file, DEFAULT_COMPRESSION)?);
+        self.witness_stream_proofs = proofs;
+        Ok(())
+    }
+
+    /// Completes the witness stream and closes its file.
+    pub fn finish_witness_stream(&mut self) -> Result<(), EmulatorError> {
+        if let Some(stream) = self.witness_stream.take() {
+            stream.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Overwrites register `i` with `v`, $zero included, to simulate a cheating prover.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn corrupt_register(&mut self, i: u32, v: u32) {
+        self.state.registers[i as usize] = v;
+    }
+
+    /// Overwrites the word at `addr` with `v`, regardless of the memory layout, the stack
+    /// guard and the text protection, to simulate a cheating prover.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn corrupt_memory(&mut self, addr: u32, v: u32) -> Result<(), EmulatorError> {
+        self.state.memory.set_memory(addr & 0xFFffFFfc, v)
+    }
+
+    /// Executes `id` as a nop from now on, to break the emulator on purpose.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn disable_instruction(&mut self, id: OpcodeId) {
+        self.disabled_instruction = Some(id);
+    }
+
+    fn track_memory_access(&mut self, addr: u32) {
+        if self.mem_proof_enabled && self.last_mem_access != addr {
+            if self.last_mem_access != !(0u32) {
+                panic!("unexpected different memory access at {:x?}, \
+                    already have access at {:x?} buffered", addr, self.last_mem_access);
+            }
+        }
+        self.last_mem_access = addr;
+        self.mem_proof = self.state.memory.merkle_proof(addr);
+    }
+
+    /// tracks the words accessed by a step after the first one, each proof is taken against the
+    /// memory with the previous words already written.
+    fn track_extra_memory_access(&mut self, addr: u32) {
+        self.extra_mem_accesses.push(addr);
+        self.extra_mem_proofs.extend(self.state.memory.merkle_proof(addr));
+    }
+
+    /// observes the word accessed by a load or store, before the access.
+    fn observe_data_access(&mut self, insn: u32, effective_addr: u32) -> Result<(), EmulatorError> {
+        let addr = effective_addr & 0xFFffFFfc;
+        if self.stack_guard.as_ref().is_some_and(|guard| guard.contains(&addr)) {
+            let ctx = Some(self.fault_context(insn, effective_addr));
+            return Err(EmulatorError::StackOverflow { addr, pc: self.state.pc, ctx });
+        }
+        if addr < self.null_guard_end {
+            let ctx = Some(self.fault_context(insn, effective_addr));
+            return Err(EmulatorError::NullAccess { addr: effective_addr, pc: self.state.pc, ctx });
+        }
+        Ok(())
+    }
+
+    /// Returns the context of the load or store `insn` at the pc faulting on `effective_addr`.
+    fn fault_context(&mut self, insn: u32, effective_addr: u32) -> Box<FaultContext> {
+        let dump_addr = effective_addr & !0x1f;
+        let mut dump = [0; 32];
+        let endianness = self.state.memory.endianness();
+        for (i, word) in dump.chunks_mut(4).enumerate() {
+            let addr = dump_addr + 4 * i as u32;
+            word.copy_from_slice(&endianness.word_to_bytes(self.state.memory.get_memory(addr)));
+        }
+        Box::new(FaultContext {
+            pc: self.state.pc,
+            step: self.state.step,
+            insn,
+            opcode: decode::opcode_id(insn),
+            addr: effective_addr,
+            base_reg: (insn >> 21) & 0x1f,
+            offset: insn as u16 as i16,
+            dump_addr,
+            dump,
+        })
+    }
+
+    /// Attaches the context of the store `insn` to the memory error `e` it failed with.
+    fn with_fault_context(&mut self, e: EmulatorError, insn: u32, effective_addr: u32) -> EmulatorError {
+        match e {
+            EmulatorError::HostOom { addr, pages, .. } => {
+                let ctx = Some(self.fault_context(insn, effective_addr));
+                EmulatorError::HostOom { addr, pages, ctx }
+            }
+            EmulatorError::WriteToReadOnly { addr, pc, .. } => {
+                let ctx = Some(self.fault_context(insn, effective_addr));
+                EmulatorError::WriteToReadOnly { addr, pc, ctx }
+            }
+            e => e,
+        }
+    }
+
+    /// returns the memory access `op` at `addr` with the next rw counter, and records it in the
+    /// logs of the watched regions.
+    fn next_mem_access(&mut self, addr: u32, op: MemoryOperation, value: u32, value_prev: u32) -> MemoryAccess {
+        let access = self.count_mem_access(addr, op, value, value_prev);
+        for log in &mut self.region_logs {
+            log.observe(self.state.step, self.state.pc, &access);
+        }
+        access
+    }
+
+    /// returns the memory access like `next_mem_access`, without recording it.
+    fn count_mem_access(&mut self, addr: u32, op: MemoryOperation, value: u32, value_prev: u32) -> MemoryAccess {
+        self.rw_counter += 1;
+        MemoryAccess { rw_counter: self.rw_counter, addr, op, value, value_prev }
+    }
+
+    /// records a memory access of the syscall being executed for its witness.
+    fn track_syscall_mem_op(&mut self, addr: u32, op: MemoryOperation, value: u32, value_prev: u32) {
+        let access = self.next_mem_access(addr, op, value, value_prev);
+        if self.mem_proof_enabled {
+            self.syscall_mem_ops.push(access);
+        }
+    }
+
+    /// Copies up to `count` bytes of the preimage to `addr`, returns the bytes copied. The
+    /// preimage is copied window by window.
+    fn read_preimage_wide(&mut self, addr: u32, count: u32) -> Result<u32, EmulatorError> {
+        let (key, offset) = (self.state.preimage_key, self.state.preimage_offset);
+        self.last_preimage_offset = offset;
+        let mut copied = 0;
+        while copied < count {
+            let window = self.preimage_window(key, offset + copied)?;
+            let remaining = (count - copied) as usize;
+            let byte_addr = addr.wrapping_add(copied);
+            let mut n = min(window.len(), remaining);
+            if n == 0 {
+                break;
+            }
+            // the chunks but the last end on a word boundary, no word is written twice
+            let aligned = (byte_addr.wrapping_add(n as u32) & !3).wrapping_sub(byte_addr) as usize;
+            if n < remaining && aligned > 0 {
+                n = aligned;
+            }
+            let chunk = window.start..window.start + n;
+
+            if self.mem_proof_enabled {
+                // write word by word, so every touched word has a proof to go with it
+                let mut written = 0;
+                while written < n {
+                    let byte_addr = byte_addr.wrapping_add(written as u32);
+                    let word_addr = byte_addr & 0xFFffFFfc;
+                    if copied == 0 && written == 0 {
+                        self.track_memory_access(word_addr);
+                    } else {
+                        self.track_extra_memory_access(word_addr);
+                    }
+                    let prev = self.state.memory.get_memory(word_addr);
+                    let bytes = &self.last_preimage[chunk.start + written..chunk.end];
+                    let endianness = self.state.memory.endianness();
+                    let (word, len) = copy_into_word(prev, byte_addr & 3, bytes, endianness);
+                    self.state.memory.store(word_addr, word, self.state.pc)?;
+                    self.track_syscall_mem_op(word_addr, MemoryOperation::Write, word, prev);
+                    written += len;
+                }
+            } else {
+                let bytes = &self.last_preimage[chunk];
+                self.state.memory.set_memory_range(byte_addr, Box::new(bytes))?;
+            }
+            copied += n as u32;
+        }
+
+        self.state.preimage_offset += copied;
+        Ok(copied)
+    }
+
+    /// Writes `bytes` at `addr` for the syscall being executed. With the proofs enabled or a
+    /// region watched, the words are written one by one like the wide preimage reads, so each
+    /// has a proof and an access record; `first` tells the first word is the first access of
+    /// the step.
+    fn write_syscall_bytes(
+        &mut self,
+        addr: u32,
+        bytes: &[u8],
+        first: bool,
+    ) -> Result<(), EmulatorError> {
+        if !self.mem_proof_enabled && self.region_logs.is_empty() {
+            return self.state.memory.set_memory_range(addr, Box::new(bytes));
+        }
+        let mut written = 0;
+        while written < bytes.len() {
+            let byte_addr = addr.wrapping_add(written as u32);
+            let word_addr = byte_addr & !3;
+            if self.mem_proof_enabled {
+                if first && written == 0 {
+                    self.track_memory_access(word_addr);
+                } else {
+                    self.track_extra_memory_access(word_addr);
+                }
+            }
+            let prev = self.state.memory.get_memory(word_addr);
+            let endianness = self.state.memory.endianness();
+            let (word, len) = copy_into_word(prev, byte_addr & 3, &bytes[written..], endianness);
+            self.state.memory.store(word_addr, word, self.state.pc)?;
+            self.track_syscall_mem_op(word_addr, MemoryOperation::Write, word, prev);
+            written += len;
+        }
+        Ok(())
+    }
+
+    // (data, data_len) = self.read_preimage(self.state.preimage_key, self.state.preimage_offset)
+    fn read_preimage(&mut self, key: [u8; 32], offset: u32) -> Result<([u8; 32], u32), EmulatorError> {
+        let window = self.preimage_window(key, offset)?;
+        self.last_preimage_offset = offset;
+
+        let mut data = [0; 32];
+        // the offset may be past the end after an lseek, then there is nothing left to read
+        let bytes_to_copy = &self.last_preimage[window];
+        let copy_size = bytes_to_copy.len().min(data.len()); // length: 32 - offset
+
+        data[..copy_size].copy_from_slice(&bytes_to_copy[..copy_size]); // equal length
+        Ok((data, copy_size as u32))
+    }
+
+    /// Caches the window of the length prefixed preimage of `key` holding `offset`, unless it is
+    /// cached already. The window of an oracle not serving parts is the whole preimage, fetched
+    /// once. Returns the range of `last_preimage` from `offset` to the end of the window, empty
+    /// past the end of the preimage.
+    fn preimage_window(
+        &mut self,
+        key: [u8; 32],
+        offset: u32,
+    ) -> Result<Range<usize>, EmulatorError> {
+        let same_key = key == self.last_preimage_key;
+        // like Cannon, the zero key read before any fetch serves nothing
+        if same_key && self.last_preimage_len.is_none() {
+            return Ok(0..0);
+        }
+        let whole = !self.preimage_oracle.serves_parts();
+        let (len, fetched) = match self.last_preimage_len.filter(|_| same_key) {
+            Some(len) => (len, None),
+            None if whole => {
+                let preimage = length_prefixed(&self.preimage_oracle.get_preimage(key)?);
+                self.log_served_preimage(key, 0, &preimage);
+                (preimage.len() as u64 - 8, Some(preimage))
+            }
+            None => {
+                let len = self.preimage_oracle.preimage_len(key)?;
+                self.log_served_preimage(key, 0, &len.to_be_bytes());
+                (len, None)
+            }
+        };
+        let (start, end) = if whole {
+            (0, len + 8)
+        } else {
+            let start = offset & !(PREIMAGE_WINDOW_SIZE as u32 - 1);
+            let end = (len + 8)
+                .min(start as u64 + (PREIMAGE_WINDOW_SIZE + PREIMAGE_WINDOW_OVERLAP) as u64)
+                .max(start as u64);
+            (start, end)
+        };
+
+        let cached_end = self.last_preimage_start as u64 + self.last_preimage.len() as u64;
+        if same_key && self.last_preimage_start <= start && cached_end >= end {
+            self.preimage_digests.hit();
+        } else {
+            let window = match fetched {
+                Some(preimage) => preimage,
+                None => self.fetch_preimage_window(key, len, start as u64, end)?,
+            };
+            self.preimage_digests.check(key, start, len, &window)?;
+            if !same_key {
+                self.metrics.inc_counter(metrics::PREIMAGES_FETCHED, 1);
+                self.last_preimage_key = key;
+                self.record_event(Event::PreimageKey { step: self.state.step, key })?;
+            }
+            self.last_preimage_len = Some(len);
+            self.last_preimage_start = start;
+            self.last_preimage = window;
+        }
+
+        let to = (end - self.last_preimage_start as u64) as usize;
+        let from = min((offset - self.last_preimage_start) as usize, to);
+        Ok(from..to)
+    }
+
+    /// Fetches the bytes `start..end` of the length prefixed preimage of `key`, `len` bytes long.
+    fn fetch_preimage_window(
+        &mut self,
+        key: [u8; 32],
+        len: u64,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        let mut window = Vec::with_capacity((end - start) as usize);
+        if start < 8 {
+            window.extend(&len.to_be_bytes()[start as usize..end.min(8) as usize]);
+        }
+        if end > 8 {
+            let from = start.max(8) - 8;
+            let part_len = (end - 8 - from) as usize;
+            let part = self.preimage_oracle.get_preimage_part(key, from, part_len)?;
+            // a part shorter than the length says is another preimage
+            if part.len() != part_len {
+                return Err(EmulatorError::OracleInconsistent { key });
+            }
+            window.extend(part);
+        }
+        self.log_served_preimage(key, start, &window);
+        Ok(window)
+    }
+
+    /// Logs the part `data` of the length prefixed preimage of `key` from `offset`, if enabled.
+    fn log_served_preimage(&mut self, key: [u8; 32], offset: u64, data: &[u8]) {
+        if let Some(log) = &mut self.preimage_log {
+            log.push(ServedPreimage { key, offset, data: data.to_vec() });
+        }
+    }
+
+    /// Returns the length prefixed preimage of the last read for the witness: the cached window
+    /// if it holds all of it, else the preimage fetched whole once per key and checked against
+    /// the windows read from it.
+    fn whole_last_preimage(&mut self) -> Result<Vec<u8>, EmulatorError> {
+        let total = self.last_preimage_len.map_or(0, |len| len + 8);
+        if self.last_preimage_start == 0 && self.last_preimage.len() as u64 == total {
+            return Ok(self.last_preimage.clone());
+        }
+        let key = self.last_preimage_key;
+        match &self.witness_preimage {
+            Some((cached, preimage)) if *cached == key => return Ok(preimage.clone()),
+            _ => {}
+        }
+        let preimage = length_prefixed(&self.preimage_oracle.get_preimage(key)?);
+        self.preimage_digests.check_whole(key, &preimage)?;
+        self.log_served_preimage(key, 0, &preimage);
+        self.witness_preimage = Some((key, preimage.clone()));
+        Ok(preimage)
+    }
+
+    /// read syscall of `count` bytes from `fd` to `addr`, returns (v0, v1).
+    fn sys_read(&mut self, fd: u32, addr: u32, count: u32) -> Result<(u32, u32), EmulatorError> {
+        let mut v0 = 0u32;
+        let mut v1 = 0u32;
+        match fd {
+            FD_STDIN => {
+                // reads at most to the end of the word at addr, like the preimage reads,
+                // v0 is zero at the end of the input
+                let remaining = self.stdin.len() - self.stdin_offset;
+                let len = min(remaining, count as usize);
+                if len > 0 {
+                    let effective_addr = addr & 0xFFffFFfc;
+                    self.track_memory_access(effective_addr);
+                    let mem = self.state.memory.get_memory(effective_addr);
+                    let input = &self.stdin[self.stdin_offset..self.stdin_offset + len];
+                    let endianness = self.state.memory.endianness();
+                    let (out_mem, n) = copy_into_word(mem, addr & 3, input, endianness);
+                    self.state.memory.store(effective_addr, out_mem, self.state.pc)?;
+                    self.stdin_offset += n;
+                    v0 = n as u32;
+                }
+            }
+            FD_PREIMAGE_READ if self.config.wide_preimage_io => {
+                v0 = self.read_preimage_wide(addr, count)?;
+                self.preimage_bytes_served += v0 as u64;
+            }
+            // todo: track memory write
+            FD_PREIMAGE_READ => { // pre-image oracle
+                let effective_addr = addr & 0xFFffFFfc; // align memory
+                self.track_memory_access(effective_addr);
+                let mem = self.state.memory.get_memory(effective_addr);
+                let (data, data_len) =
+                    self.read_preimage(self.state.preimage_key, self.state.preimage_offset)?;
+
+                // at most to the end of the word
+                let len = min(data_len, count) as usize;
+                let endianness = self.state.memory.endianness();
+                let (out_mem, n) = copy_into_word(mem, addr & 3, &data[..len], endianness);
+                let data_len = n as u32;
+                self.state.memory.store(effective_addr, out_mem, self.state.pc)?;
+                self.track_syscall_mem_op(effective_addr, MemoryOperation::Write, out_mem, mem);
+                self.state.preimage_offset += data_len;
+                self.preimage_bytes_served += data_len as u64;
+                v0 = data_len;
+            }
+            FD_HINT_READ => { // hint response
+                // don't actually read into memory,
+                // just say we read it all, we ignore the result anyway
+                v0 = count;
+            }
+            FD_PIPE_READ => {
+                // poll never reports the pipe readable, so it stays empty
+                (v0, v1) = errno::fail(EAGAIN);
+            }
+            _ => {
+                (v0, v1) = errno::fail(EBADF);
             }
         }
-        self.last_mem_access = addr;
-        self.mem_proof = self.state.memory.merkle_proof(addr);
+        Ok((v0, v1))
     }
 
-    // (data, data_len) = self.read_preimage(self.state.preimage_key, self.state.preimage_offset)
-    fn read_preimage(&mut self, key: [u8; 32], offset: u32) -> ([u8; 32], u32) {
-        if key != self.last_preimage_key {
-            self.last_preimage_key = key;
-            let data = self.preimage_oracle.get_preimage(key);
-            // add the length prefix
-            let mut preimage = Vec::new();
-            preimage.extend(data.len().to_be_bytes());
-            preimage.extend(data);
-            self.last_preimage = preimage;
+    /// write syscall of `count` bytes at `addr` to `fd`, returns (v0, v1).
+    fn sys_write(&mut self, fd: u32, addr: u32, count: u32) -> Result<(u32, u32), EmulatorError> {
+        // the fds copying the buffer, the buffer is checked even if the output is discarded. A
+        // buffer over the cap is a short write like Linux may do, the guest writes the rest next.
+        let mut count = count;
+        if matches!(fd, FD_STDOUT | FD_STDERR | FD_HINT_WRITE) {
+            if addr as u64 + count as u64 > 1 << 32 {
+                return Ok(errno::fail(EFAULT));
+            }
+            count = min(count, self.config.max_read_range);
         }
-        self.last_preimage_offset = offset;
+        let v0;
+        let mut v1 = 0u32;
+        match fd {
+            // todo: track memory read
+            FD_STDOUT if self.discard_stdout => {
+                v0 = count;
+            }
+            FD_STDOUT => {
+                self.state.memory.read_memory_range(addr, count);
+                match std::io::copy(self.state.memory.as_mut(), self.stdout_writer.as_mut()) {
+                    Err(e) => {
+                        panic!("read range from memory failed {}", e);
+                    }
+                    Ok(_) => {}
+                }
+                v0 = count;
+            }
+            FD_STDERR => {
+                self.state.memory.read_memory_range(addr, count);
+                let mut data = Vec::<u8>::new();
+                self.state.memory.read_to_end(&mut data).unwrap();
+                self.panic_detector.feed(&data);
+                match self.stderr_writer.write_all(&data) {
+                    Err(e) => {
+                        panic!("read range from memory failed {}", e);
+                    }
+                    Ok(_) => {}
+                }
+                v0 = count;
+            }
+            FD_HINT_WRITE => {
+                self.state.memory.read_memory_range(addr, count);
+                let mut data = Vec::<u8>::new();
+                self.state.memory.read_to_end(&mut data).unwrap();
+                // sends every complete hint to the oracle
+                let (oracle, captured) = (&mut self.preimage_oracle, &mut self.captured_hints);
+                let (metrics_sink, hints_posted) = (&mut self.metrics, &mut self.hints_posted);
+                self.state.last_hint.feed(&data, |hint| {
+                    oracle.hint(hint);
+                    metrics_sink.inc_counter(metrics::HINTS_POSTED, 1);
+                    *hints_posted += 1;
+                    if let Some(captured) = captured {
+                        captured.push(hint.to_vec());
+                    }
+                })?;
+                v0 = count;
+            }
+            FD_PREIMAGE_WRITE => {
+                let effective_addr = addr & 0xFFffFFfc;
+                self.track_memory_access(effective_addr);
+                let out_mem = self.state.memory.get_memory(effective_addr);
+                self.track_syscall_mem_op(effective_addr, MemoryOperation::Read, out_mem, out_mem);
+
+                // at most to the end of the word
+                let mut written = [0u8; 4];
+                let endianness = self.state.memory.endianness();
+                let n = copy_from_word(
+                    out_mem, addr & 3, &mut written[..min(count, 4) as usize], endianness,
+                );
+
+                // shift the key left and append the written bytes
+                let mut key = [0; 32];
+                key[..32-n].copy_from_slice(&self.state.preimage_key[n..]);
+                key[32-n..].copy_from_slice(&written[..n]);
+
+                self.state.preimage_key = key;
+                self.state.preimage_offset = 0;
+                v0 = n as u32;
+            }
+            FD_PIPE_WRITE => {
+                v0 = count;
+            }
+            _ => {
+                (v0, v1) = errno::fail(EBADF);
+            }
+        }
+        Ok((v0, v1))
+    }
 
-        let mut data = [0; 32];
-        let bytes_to_copy = &self.last_preimage[(offset as usize)..]; // length: 32 - offset
-        let copy_size = bytes_to_copy.len().min(data.len()); // length: 32 - offset
+    /// readv/writev syscall: runs `sys` for the (base, len) entries of the iovec array at `iov`,
+    /// returns (v0, v1). The transfer stops at the first short or failed entry, and after the
+    /// first entry on the preimage fds, which touch a memory word at most per step.
+    fn sys_vectored(
+        &mut self,
+        fd: u32,
+        iov: u32,
+        iovcnt: u32,
+        sys: fn(&mut Self, u32, u32, u32) -> Result<(u32, u32), EmulatorError>,
+    ) -> Result<(u32, u32), EmulatorError> {
+        if iov & 3 != 0 {
+            return Ok(errno::fail(EFAULT));
+        }
+        if iovcnt > MAX_IOVCNT {
+            return Ok(errno::fail(EINVAL));
+        }
 
-        data[..copy_size].copy_from_slice(&bytes_to_copy[..copy_size]); // equal length
-        return (data, copy_size as u32);
+        let mut total = 0u32;
+        for i in 0..iovcnt {
+            let entry = iov.wrapping_add(8 * i);
+            let base = self.state.memory.get_memory(entry);
+            let len = self.state.memory.get_memory(entry.wrapping_add(4));
+            if len == 0 {
+                continue;
+            }
+            let (n, err) = sys(self, fd, base, len)?;
+            if n == SYSCALL_ERROR {
+                // report the error only if nothing was transferred
+                if total == 0 {
+                    return Ok((n, err));
+                }
+                break;
+            }
+            total = total.wrapping_add(n);
+            if n < len || fd == FD_PREIMAGE_READ || fd == FD_PREIMAGE_WRITE {
+                break;
+            }
+        }
+        Ok((total, 0))
+    }
+
+    /// Only the preimage fd is seekable, it moves the offset of the next preimage read. The
+    /// other fds are streams and fail like pipes do, so stdio doesn't try to seek them.
+    fn sys_lseek(&mut self, fd: u32, offset: i64, whence: u32) -> (u32, u32) {
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => self.state.preimage_offset as i64,
+            _ => return errno::fail(EINVAL),
+        };
+        match fd {
+            FD_PREIMAGE_READ => {
+                let new_offset = base + offset;
+                if new_offset < 0 || new_offset > u32::MAX as i64 {
+                    return errno::fail(EINVAL);
+                }
+                self.state.preimage_offset = new_offset as u32;
+                (new_offset as u32, 0)
+            }
+            FD_STDIN | FD_STDOUT | FD_STDERR | FD_HINT_READ | FD_HINT_WRITE | FD_PREIMAGE_WRITE |
+            FD_PIPE_READ | FD_PIPE_WRITE => {
+                errno::fail(ESPIPE)
+            }
+            _ => errno::fail(EBADF),
+        }
     }
 
-    fn handle_syscall(&mut self) {
+    fn handle_syscall(&mut self) -> Result<(), EmulatorError> {
         let syscall_num = self.state.registers[2]; // v0
+        self.pending_metrics.syscall(syscall_num);
         let mut v0 = 0u32;
         let mut v1 = 0u32;
 
         let a0 = self.state.registers[4];
         let a1 = self.state.registers[5];
-        let mut a2 = self.state.registers[6];
+        let a2 = self.state.registers[6];
+        let a3 = self.state.registers[7];
+
+        let outcome = match &mut self.syscall_override {
+            Some(hook) if OVERRIDABLE_SYSCALLS.contains(&syscall_num) => {
+                hook.handle(syscall_num, [a0, a1, a2, a3])
+            }
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            for (i, (addr, bytes)) in outcome.writes.iter().enumerate() {
+                self.write_syscall_bytes(*addr, bytes, i == 0)?;
+            }
+            return self.finish_syscall(outcome.v0, outcome.v1);
+        }
 
         match syscall_num {
             4090 => { // mmap
                 // args: a0 = heap/hint, indicates mmap heap or hint. a1 = size
-                let mut size = a1;
-                if size&(PAGE_ADDR_MASK as u32) != 0 {
-                    // adjust size to align with page size
-                    size += PAGE_SIZE as u32 - (size & (PAGE_ADDR_MASK as u32));
-                }
-                if a0 == 0 {
-                    v0 = self.state.heap;
-                    self.state.heap += size;
-                    debug!("mmap heap {:x?} size {:x?}", v0, size);
-                } else {
-                    v0 = a0;
-                    debug!("mmap hint {:x?} size {:x?}", v0, size);
+                // adjust size to align with page size
+                let size = a1.checked_add(PAGE_ADDR_MASK as u32).map(|size| size & !(PAGE_ADDR_MASK as u32));
+                // a mapping the page limit can't hold fails like on a host out of memory, rather
+                // than with `HostOom` once the guest touches its pages
+                let available = self.state.memory.available_pages().unwrap_or(usize::MAX);
+                match size.filter(|size| *size as usize / PAGE_SIZE <= available) {
+                    None => {
+                        debug!("mmap size {:x?} exceeds the memory limit", a1);
+                        (v0, v1) = errno::fail(ENOMEM);
+                    }
+                    Some(size) if a0 == 0 => {
+                        let region = Region::new(RegionKind::Mmap, self.state.heap, self.state.heap.saturating_add(size));
+                        self.state.layout.check_alloc(region, Some(self.state.layout.heap()))?;
+                        v0 = self.state.heap;
+                        self.state.heap += size;
+                        debug!("mmap heap {:x?} size {:x?}", v0, size);
+                    }
+                    Some(size) => {
+                        let region = Region::new(RegionKind::Mmap, a0, a0.saturating_add(size));
+                        self.state.layout.check_alloc(region, None)?;
+                        v0 = a0;
+                        debug!("mmap hint {:x?} size {:x?}", v0, size);
+                    }
                 }
             }
             4045 => { // brk
-                v0 = 0x40000000;
+                let region = Region::new(RegionKind::Brk, BRK_START, BRK_START + PAGE_SIZE as u32);
+                self.state.layout.check_alloc(region, None)?;
+                v0 = BRK_START;
             }
             4120 => { // clone
                 v0 = 1;
             }
+            4020 => { // getpid
+                // the guest is the init process of its own namespace
+                v0 = 1;
+            }
+            4064 => { // getppid
+                v0 = 0;
+            }
+            4114 => { // wait4
+                // clone doesn't create processes, there is no child to wait for
+                (v0, v1) = errno::fail(ECHILD);
+            }
+            4122 => { // uname
+                // args: a0 = the utsname struct
+                if a0 == 0 {
+                    (v0, v1) = errno::fail(EFAULT);
+                } else {
+                    let mut utsname = [0u8; UTSNAME.len() * UTSNAME_FIELD_LEN];
+                    for (field, value) in utsname.chunks_mut(UTSNAME_FIELD_LEN).zip(UTSNAME) {
+                        field[..value.len()].copy_from_slice(value.as_bytes());
+                    }
+                    self.write_syscall_bytes(a0, &utsname, true)?;
+                }
+            }
+            4283 => { // set_thread_area
+                // args: a0 = the TLS base, read back by rdhwr $29
+                self.state.thread_pointer = a0;
+            }
+            4353 => { // getrandom
+                // args: a0 = buf, a1 = buflen, a2 = flags
+                // returns: v0 = the number of bytes written
+                let len = min(a1, MAX_GETRANDOM_SIZE);
+                let mut data = vec![0u8; len as usize];
+                random::fill(&self.config.random_seed, self.state.random_position, &mut data);
+                self.state.random_position += len as u64;
+                if len > 0 {
+                    self.state.memory.set_memory_range(a0, Box::new(data.as_slice()))?;
+                }
+                v0 = len;
+            }
             4246 => { // exit group
                 self.state.exited = true;
                 self.state.exit_code = a0 as u8;
-                return;
+                // the host may not see buffered output of the guest after it exited
+                self.stdout_writer.flush()?;
+                self.stderr_writer.flush()?;
+                return Ok(());
             }
             4003 => { // read
                 // args: a0 = fd, a1 = addr, a2 = count
                 // returns: v0 = read, v1 = err code
-                match a0 {
-                    FD_STDIN => {
-                        // leave v0 and v1 zero: read nothing, no error
-                    }
-                    // todo: track memory write
-                    FD_PREIMAGE_READ => { // pre-image oracle
-                        let addr = a1 & 0xFFffFFfc; // align memory
-                        self.track_memory_access(addr);
-                        let mem = self.state.memory.get_memory(addr);
-                        let (data, mut data_len) =
-                            self.read_preimage(self.state.preimage_key, self.state.preimage_offset);
-
-                        let alignment = a1 & 3;
-                        let space = 4 - alignment;
-                        data_len = min(min(data_len, space), a2); // at most 4
-
-                        let mut out_mem = mem.to_be_bytes().clone();
-                        out_mem[(alignment as usize)..].copy_from_slice(&data[..(data_len as usize)]);
-                        self.state.memory.set_memory(addr, u32::from_be_bytes(out_mem));
-                        self.state.preimage_offset += data_len;
-                        v0 = data_len;
-                    }
-                    FD_HINT_READ => { // hint response
-                        // don't actually read into memory,
-                        // just say we read it all, we ignore the result anyway
-                        v0 = a2;
-                    }
-                    _ => {
-                        v0 = 0xFFffFFff;
-                        v1 = MIPS_EBADF;
-                    }
-                }
+                (v0, v1) = self.sys_read(a0, a1, a2)?;
             }
             4004 => { // write
                 // args: a0 = fd, a1 = addr, a2 = count
                 // returns: v0 = written, v1 = err code
-                match a0 {
-                    // todo: track memory read
-                    FD_STDOUT => {
-                        self.state.memory.read_memory_range(a1, a2);
-                        match std::io::copy(self.state.memory.as_mut(), self.stdout_writer.as_mut()) {
-                            Err(e) => {
-                                panic!("read range from memory failed {}", e);
-                            }
-                            Ok(_) => {}
-                        }
-                        v0 = a2;
-                    }
-                    FD_STDERR => {
-                        self.state.memory.read_memory_range(a1, a2);
-                        match std::io::copy(self.state.memory.as_mut(), self.stderr_writer.as_mut()) {
-                            Err(e) => {
-                                panic!("read range from memory failed {}", e);
-                            }
-                            Ok(_) => {}
-                        }
-                        v0 = a2;
-                    }
-                    FD_HINT_WRITE => {
-                        self.state.memory.read_memory_range(a1, a2);
-                        let mut hint_data = Vec::<u8>::new();
-                        self.state.memory.read_to_end(&mut hint_data).unwrap();
-                        self.state.last_hint.extend(&hint_data);
-                        while self.state.last_hint.len() > 4 {
-                            // process while there is enough data to check if there are any hints.
-                            let mut hint_len_bytes = [0u8; 4];
-                            hint_len_bytes.copy_from_slice(&self.state.last_hint[..4]);
-                            let hint_len = u32::from_be_bytes(hint_len_bytes) as usize;
-                            if hint_len >= self.state.last_hint[4..].len() {
-                                let mut hint = Vec::<u8>::new();
-                                self.state.last_hint[4..(4 + hint_len)].clone_into(&mut hint);
-                                self.state.last_hint = self.state.last_hint.split_off(4+hint_len);
-                                self.preimage_oracle.hint(hint.as_slice());
-                            }
-                        }
-                        v0 = a2;
-                    }
-                    FD_PREIMAGE_WRITE => {
-                        let addr = a1 & 0xFFffFFfc;
-                        self.track_memory_access(addr);
-                        let out_mem = self.state.memory.get_memory(addr);
-
-                        let alignment = a1 & 3;
-                        let space = 4 - alignment;
-                        a2 = min(a2, space); // at most write to 4 bytes
-
-                        let mut key = [0; 32];
-                        for i in (a2 as usize)..32 {
-                            key[i-(a2 as usize)] = self.state.preimage_key[i];
-                        }
-                        let out_mem_be = out_mem.to_be_bytes();
-                        for i in (32-a2 as usize)..32 {
-                            key[i] = out_mem_be[i+(a2 as usize)-32];
-                        }
-
-                        self.state.preimage_key = key;
-                        self.state.preimage_offset = 0;
-                        v0 = a2;
-                    }
-                    _ => {
-                        v0 = 0xFFffFFff;
-                        v1 = MIPS_EBADF;
-                    }
+                (v0, v1) = self.sys_write(a0, a1, a2)?;
+            }
+            4145 => { // readv
+                // args: a0 = fd, a1 = iov, a2 = iovcnt
+                // returns: v0 = read, v1 = err code
+                (v0, v1) = self.sys_vectored(a0, a1, a2, Self::sys_read)?;
+            }
+            4146 => { // writev
+                // args: a0 = fd, a1 = iov, a2 = iovcnt
+                // returns: v0 = written, v1 = err code
+                (v0, v1) = self.sys_vectored(a0, a1, a2, Self::sys_write)?;
+            }
+            4019 => { // lseek
+                // args: a0 = fd, a1 = offset, a2 = whence
+                // returns: v0 = the new offset, v1 = err code
+                (v0, v1) = self.sys_lseek(a0, a1 as i32 as i64, a2);
+            }
+            4140 => { // _llseek
+                // args: a0 = fd, a1 = offset high, a2 = offset low, a3 = result, whence on the stack
+                // returns: v0 = 0, the new 64 bits offset at result, v1 = err code
+                let sp = self.state.registers[reg::SP as usize];
+                let whence = self.state.memory.get_memory(sp.wrapping_add(16));
+                let offset = ((a1 as u64) << 32 | a2 as u64) as i64;
+                let (offset, err) = self.sys_lseek(a0, offset, whence);
+                if err != 0 {
+                    (v0, v1) = (offset, err);
+                } else {
+                    let offset = match self.state.memory.endianness() {
+                        Endianness::Big => (offset as u64).to_be_bytes(),
+                        Endianness::Little => (offset as u64).to_le_bytes(),
+                    };
+                    self.state.memory.set_memory_range(a3, Box::new(offset.as_slice()))?;
                 }
             }
+            4042 => { // pipe
+                // returns: v0 = the read end, $v1 = the write end, like the MIPS kernel does
+                v0 = FD_PIPE_READ;
+                self.state.write_reg(3, FD_PIPE_WRITE)?;
+            }
+            4188 => { // poll
+                // args: a0 = fds, a1 = nfds, a2 = timeout
+                // returns: v0 = 0, the timeout expired without events. The guest can't wait for
+                // anything that happens outside of it, and the result must not depend on the host.
+                v0 = 0;
+            }
             4055 => { // fcntl
                 // args: a0 = fd, a1 = cmd
                 if a1 == 3 { // F_GETFL: get file descriptor flags
                     match a0 {
-                        FD_STDIN | FD_PREIMAGE_READ | FD_HINT_READ => {
+                        FD_STDIN | FD_PREIMAGE_READ | FD_HINT_READ | FD_PIPE_READ => {
                             v0 = 0 // O_RDONLY
                         }
-                        FD_STDOUT | FD_STDERR | FD_PREIMAGE_WRITE | FD_HINT_WRITE => {
+                        FD_STDOUT | FD_STDERR | FD_PREIMAGE_WRITE | FD_HINT_WRITE | FD_PIPE_WRITE => {
                             v0 = 1 // O_WRONLY
                         }
                         _ => {
-                            v0 = 0xFFffFFff;
-                            v1 = MIPS_EBADF;
+                            (v0, v1) = errno::fail(EBADF);
                         }
                     }
                 } else {
-                    v0 = 0xFFffFFff;
-                    v1 = MIPS_EBADF;
+                    // the other commands are not supported
+                    (v0, v1) = errno::fail(EINVAL);
+                }
+            }
+            num if NOOP_SYSCALLS.contains(&num) => {}
+            num => {
+                let (step, pc) = (self.state.step, self.state.pc);
+                debug!("unknown syscall {} at 0x{:x}", num, pc);
+                self.record_event(Event::UnknownSyscall { step, pc, num })?;
+                match self.config.unknown_syscall {
+                    UnknownSyscallPolicy::SilentZero => {}
+                    UnknownSyscallPolicy::Enosys => (v0, v1) = errno::fail(ENOSYS),
+                    UnknownSyscallPolicy::Fault => return Err(EmulatorError::UnknownSyscall { num, pc }),
                 }
             }
-            _ => {}
         }
 
-        self.state.registers[2] = v0;
-        self.state.registers[7] = v1;
+        self.finish_syscall(v0, v1)
+    }
+
+    /// Returns v0 and v1 to the guest and moves past the syscall.
+    fn finish_syscall(&mut self, v0: u32, v1: u32) -> Result<(), EmulatorError> {
+        self.state.write_reg(2, v0)?;
+        self.state.write_reg(7, v1)?;
 
         self.state.pc = self.state.next_pc;
-        self.state.next_pc = self.state.next_pc + 4;
+        self.state.next_pc = self.state.next_pc.wrapping_add(4);
+        Ok(())
+    }
+
+    /// Why the instruction at `pc` can't be fetched, if it can't. The pc must be in the text of
+    /// the program when it is known, else in strict mode in a page of the memory.
+    fn bad_pc(&self, pc: u32) -> Option<BadPcReason> {
+        if pc & 3 != 0 {
+            return Some(BadPcReason::Misaligned);
+        }
+        match self.state.layout.text {
+            Some((start, end)) if !(start..end).contains(&pc) => Some(BadPcReason::NotExecutable),
+            None if self.config.mode == ExecutionMode::Strict && !self.state.memory.is_mapped(pc) => {
+                Some(BadPcReason::Unmapped)
+            }
+            _ => None,
+        }
     }
 
-    fn handle_branch(&mut self, opcode: u32, insn: u32, rt_reg: u32, rs: u32) {
+    /// Fails the branch or jump at the pc with `BadPc` if `target` can't be fetched, when
+    /// `VmConfig::eager_pc_check` is enabled.
+    fn check_jump_target(&self, target: u32) -> Result<(), EmulatorError> {
+        if !self.config.eager_pc_check {
+            return Ok(());
+        }
+        match self.bad_pc(target) {
+            Some(reason) => Err(EmulatorError::BadPc { pc: target, reason, jump: Some(self.state.pc) }),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_branch(&mut self, opcode: u32, insn: u32, rt_reg: u32, rs: u32) -> Result<(), EmulatorError> {
         let should_branch = match opcode {
             4 | 5 => { // beq/bne
                 let rt = self.state.registers[rt_reg as usize];
@@ -542,7 +2346,7 @@ impl InstrumentedState {
                 } else if rtv == 1 { // 1 -> bgez
                     (rs as i32) >= 0
                 } else {
-                    false
+                    unreachable!("regimm instruction {} is not supported", rtv);
                 }
             }
             _ => {
@@ -551,27 +2355,54 @@ impl InstrumentedState {
         };
 
         let prev_pc = self.state.pc;
-        self.state.pc = self.state.next_pc; // execute the delay slot first
         if should_branch  {
+            let target = prev_pc.wrapping_add(4).wrapping_add(sign_extension(insn & 0xFFFF, 16) << 2);
+            self.check_jump_target(target)?;
+            self.state.pc = self.state.next_pc; // execute the delay slot first
             // then continue with the instruction the branch jumps to.
-            self.state.next_pc = (prev_pc as u64 + 4u64 + (sign_extension(insn & 0xFFFF, 16) << 2) as u64) as u32;
+            self.state.next_pc = target;
         } else {
-            self.state.next_pc = self.state.next_pc + 4;
+            self.state.pc = self.state.next_pc;
+            self.state.next_pc = self.state.next_pc.wrapping_add(4);
         }
+        Ok(())
     }
 
-    fn handle_jump(&mut self, link_reg: u32, dest: u32) {
+    fn handle_jump(&mut self, link_reg: u32, dest: u32) -> Result<(), EmulatorError> {
+        self.check_jump_target(dest)?;
         let prev_pc = self.state.pc;
         self.state.pc = self.state.next_pc;
         self.state.next_pc = dest;
 
-        if link_reg != 0 {
-            // set the link-register to the instr after the delay slot instruction.
-            self.state.registers[link_reg as usize] = prev_pc + 8;
-        }
+        // set the link-register to the instr after the delay slot instruction.
+        self.write_register(link_reg, prev_pc.wrapping_add(8))
     }
 
-    fn handle_hilo(&mut self, fun: u32, rs: u32, rt: u32, store_reg: u32) {
+    fn handle_hilo(&mut self, fun: u32, rs: u32, rt: u32, store_reg: u32) -> Result<(), EmulatorError> {
+        if self.config.hilo_hazards && (fun == 0x10 || fun == 0x12) {
+            if let Some(step) = self.state.hilo_written_step {
+                if self.state.step - step <= HILO_HAZARD_STEPS {
+                    return Err(EmulatorError::HiLoHazard { pc: self.state.pc });
+                }
+            }
+        }
+        if (0x18..0x1c).contains(&fun) {
+            self.state.hilo_written_step = Some(self.state.step);
+        }
+
+        let (old_hi, old_lo) = (self.state.hi, self.state.lo);
+        if (fun == 0x1a || fun == 0x1b) && rt == 0 {
+            if self.config.mode == ExecutionMode::Strict {
+                return Err(EmulatorError::DivideByZero { pc: self.state.pc });
+            }
+            // the result is UNPREDICTABLE in MIPS32, like Cannon hi and lo are left unchanged
+            self.hilo_delta = Some(HiLoDelta { old_hi, old_lo, hi: old_hi, lo: old_lo });
+            self.state.pc = self.state.next_pc;
+            self.state.next_pc = self.state.next_pc.wrapping_add(4);
+            return Ok(());
+        }
+
+
         let mut val = 0u32;
         match fun {
             0x10 => { // mfhi
@@ -587,7 +2418,7 @@ impl InstrumentedState {
                 self.state.lo = rs;
             }
             0x18 => { // mult
-                let acc = (rs as i64 * rt as i64) as u64;
+                let acc = (rs as i32 as i64 * rt as i32 as i64) as u64;
                 self.state.hi = (acc >> 32) as u32;
                 self.state.lo = acc as u32;
             }
@@ -597,8 +2428,8 @@ impl InstrumentedState {
                 self.state.lo = acc as u32;
             }
             0x1a => { // div
-                self.state.hi = ((rs as i32) % (rt as i32)) as u32;
-                self.state.lo = ((rs as i32) / (rt as i32)) as u32;
+                self.state.hi = (rs as i32).wrapping_rem(rt as i32) as u32;
+                self.state.lo = (rs as i32).wrapping_div(rt as i32) as u32;
             }
             0x1b => { // divu
                 self.state.hi = rs % rt;
@@ -609,41 +2440,126 @@ impl InstrumentedState {
             }
         }
 
-        if store_reg != 0 {
-            self.state.registers[store_reg as usize] = val;
+        if fun == 0x10 || fun == 0x12 {
+            self.write_register(store_reg, val)?;
+        } else {
+            let (hi, lo) = (self.state.hi, self.state.lo);
+            self.hilo_delta = Some(HiLoDelta { old_hi, old_lo, hi, lo });
         }
 
         self.state.pc = self.state.next_pc;
-        self.state.next_pc = self.state.next_pc + 4;
+        self.state.next_pc = self.state.next_pc.wrapping_add(4);
+        Ok(())
     }
 
-    fn handle_rd(&mut self, store_reg: u32, val: u32, conditional: bool) {
-        if store_reg >=32 {
-            panic!("invalid register");
-        }
-        if store_reg != 0 && conditional {
-            self.state.registers[store_reg as usize] = val;
+    fn handle_rd(
+        &mut self,
+        store_reg: u32,
+        val: u32,
+        conditional: bool,
+    ) -> Result<(), EmulatorError> {
+        if conditional {
+            self.write_register(store_reg, val)?;
         }
 
         self.state.pc = self.state.next_pc;
-        self.state.next_pc = self.state.next_pc + 4;
+        self.state.next_pc = self.state.next_pc.wrapping_add(4);
+        Ok(())
     }
 
-    // returns a ExecutionRow and MemoryAccess struct
+    /// Writes the general purpose register `reg` of the instruction with `State::write_reg`, and
+    /// records the write for the witness.
+    fn write_register(&mut self, reg: u32, val: u32) -> Result<(), EmulatorError> {
+        let old = self.state.registers.get(reg as usize).copied().unwrap_or_default();
+        self.state.write_reg(reg, val)?;
+        if reg != 0 {
+            self.register_delta = Some(RegisterDelta { reg, old, new: val });
+        }
+        Ok(())
+    }
+
+    // returns a ExecutionRow and the memory accesses of the instruction, in order
     // this method executes a single mips instruction
-    fn mips_step(&mut self) -> (Option<ExecutionRow>, Option<MemoryAccess>) {
+    fn mips_step(&mut self) -> Result<(Option<ExecutionRow>, Vec<MemoryAccess>), EmulatorError> {
         if self.state.exited {
-            return (None, None);
+            return Ok((None, vec![]));
         }
 
+        // the instruction sees its own step, which only counts once it commits
         self.state.step += 1;
+        let mut result = self.execute_instruction();
+        // a page the backend failed to read or write makes the step fail
+        if let Some(err) = self.state.memory.take_backend_error() {
+            result = Err(EmulatorError::Io(err));
+        }
+        if result.is_err() {
+            self.state.step -= 1;
+        }
+        result
+    }
 
+    fn execute_instruction(
+        &mut self,
+    ) -> Result<(Option<ExecutionRow>, Vec<MemoryAccess>), EmulatorError> {
         let mut execution_row = ExecutionRow::default();
 
-        // fetch instruction
+        // fetch instruction, the first access of the step. Unlike the data accesses, the
+        // fetches are not recorded by the region logs.
         let insn = self.state.memory.get_memory(self.state.pc);
+        let fetch = self.count_mem_access(self.state.pc, MemoryOperation::Fetch, insn, insn);
         let opcode = insn >> 26; // 6-bits
 
+        if log_enabled!(Level::Trace) {
+            trace!("step: {}, pc: {}, insn: {:08x}", self.state.step, self.annotate_pc(self.state.pc), insn);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record(self.state.pc, insn);
+        }
+        if let Some(exporter) = &mut self.trace_exporter {
+            exporter.record(self.state.step, self.state.pc, insn)?;
+        }
+        #[cfg(test)]
+        decode::coverage::record(insn);
+        if !opcode_id::is_supported(insn) {
+            if self.config.invalid_opcodes == ExecutionMode::Strict {
+                let pc = self.state.pc;
+                if let Some(coprocessor) = decode::coprocessor(insn) {
+                    let (fmt, funct) = ((insn >> 21) & 0x1f, insn & 0x3f);
+                    return Err(EmulatorError::UnsupportedCoprocessor {
+                        pc, insn, coprocessor, fmt, funct,
+                    });
+                }
+                return Err(EmulatorError::InvalidOpcode { pc, insn });
+            }
+            warn!("skipping invalid instruction {:08x} at {}", insn, self.annotate_pc(self.state.pc));
+            let count = self.skip_counts.entry((self.state.pc, insn)).or_insert(0);
+            if *count == 0 {
+                self.skipped_instructions.push((self.state.pc, insn));
+            }
+            *count += 1;
+            self.state.in_delay_slot = false;
+            self.handle_rd(0, 0, false)?;
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+        let is_control_transfer = decode::is_control_transfer(insn);
+        // like Cannon, the delay slot of a taken branch or jump can't transfer control
+        let taken = self.state.next_pc != self.state.pc.wrapping_add(4);
+        let checked = taken || self.config.strict_delay_slots && self.state.in_delay_slot;
+        if is_control_transfer && checked {
+            return Err(EmulatorError::UnpredictableDelaySlot { pc: self.state.pc, insn });
+        }
+        self.state.in_delay_slot = is_control_transfer;
+        #[cfg(any(test, feature = "testing"))]
+        if self.disabled_instruction.is_some() && decode::opcode_id(insn) == self.disabled_instruction {
+            self.handle_rd(0, 0, false)?;
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+        if let Some(journal) = &self.journal {
+            if journal.pc_interval() != 0 && self.state.step % journal.pc_interval() == 0 {
+                self.record_event(Event::Pc { step: self.state.step, pc: self.state.pc })?;
+            }
+        }
+
         // set the instruction to execution row.
         execution_row.instruction = Instruction {
             addr: self.state.pc,
@@ -659,6 +2575,77 @@ impl InstrumentedState {
         execution_row.hi = self.state.hi;
         execution_row.lo = self.state.lo;
 
+        // beq is the hottest branch, it skips the operand fetch below
+        if opcode == 4 {
+            let rs = self.state.registers[((insn >> 21) & 0x1f) as usize];
+            self.handle_branch(opcode, insn, (insn >> 16) & 0x1f, rs)?;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
+        // rdhwr
+        if opcode == 0x1f && insn & 0x3f == 0x3b {
+            let val = match (insn >> 11) & 0x1f {
+                0 => 0, // CPU number
+                3 => self.state.step as u32, // cycle counter
+                29 => self.state.thread_pointer, // user local, the TLS base
+                hwr => unreachable!("rdhwr of hardware register {} is not supported", hwr),
+            };
+            self.handle_rd((insn >> 16) & 0x1f, val, true)?;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            execution_row.registers = self.state.registers.clone();
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
+        // ext/ins
+        if opcode == 0x1f && matches!(insn & 0x3f, 0x00 | 0x04) {
+            let rs = self.state.registers[((insn >> 21) & 0x1f) as usize];
+            let rt_reg = (insn >> 16) & 0x1f;
+            let val = decode::bitfield(insn, rs, self.state.registers[rt_reg as usize])
+                .ok_or(EmulatorError::UnpredictableBitfield { pc: self.state.pc, insn })?;
+            self.handle_rd(rt_reg, val, true)?;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            execution_row.registers = self.state.registers.clone();
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
+        // wsbh/seb/seh
+        if opcode == 0x1f && insn & 0x3f == 0x20 {
+            let rt = self.state.registers[((insn >> 16) & 0x1f) as usize];
+            let val = match (insn >> 6) & 0x1f {
+                0x02 => ((rt & 0x00ff00ff) << 8) | ((rt >> 8) & 0x00ff00ff),
+                0x10 => sign_extension(rt, 8),
+                _ => sign_extension(rt, 16),
+            };
+            self.handle_rd((insn >> 11) & 0x1f, val, true)?;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            execution_row.registers = self.state.registers.clone();
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
+        // break stops the guest at the break, the code is in bits 6-25
+        if opcode == 0 && insn & 0x3f == 0x0d {
+            self.state.exited = true;
+            self.state.exit_code = BREAK_EXIT_CODE;
+            self.state.break_code = Some((insn >> 6) & 0xfffff);
+            execution_row.exited = true;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
+        // sync, the memory is always consistent for a single thread
+        if opcode == 0 && insn & 0x3f == 0x0f {
+            self.handle_rd(0, 0, false)?;
+            execution_row.pc = self.state.pc;
+            execution_row.next_pc = self.state.next_pc;
+            return Ok((Some(execution_row), vec![fetch]));
+        }
+
         // j-type j/jal
         if opcode == 2 || opcode == 3 {
             let link_reg = match opcode {
@@ -666,10 +2653,10 @@ impl InstrumentedState {
                 _ => { 0 }
             };
 
-            self.handle_jump(link_reg, sign_extension(insn & 0x03ffFFff, 26)<<2);
+            self.handle_jump(link_reg, decode::jump_target(self.state.next_pc, insn))?;
             execution_row.pc = self.state.pc;
             execution_row.next_pc = self.state.next_pc;
-            return (Some(execution_row), None);
+            return Ok((Some(execution_row), vec![fetch]));
         }
 
         // fetch register
@@ -684,14 +2671,8 @@ impl InstrumentedState {
             rt = self.state.registers[rt_reg as usize];
             rd_reg = (insn >> 11) & 0x1f;
         } else if opcode < 0x20 {
-            // rt is SignExtImm
-            // don't sign extend for andi, ori, xori
-            if opcode == 0xC || opcode == 0xD || opcode == 0xE {
-                // ZeroExtImm
-                rt = insn & 0xFFFF;
-            } else {
-                rt = sign_extension(insn&0xffFF, 16);
-            }
+            // rt is the extended immediate
+            rt = decode::imm(insn);
         } else if opcode >= 0x28 || opcode == 0x22 || opcode == 0x26 {
             // store rt value with store
             rt = self.state.registers[rt_reg as usize];
@@ -701,13 +2682,13 @@ impl InstrumentedState {
         }
 
         if (opcode >= 4 && opcode < 8) || opcode == 1 {
-            self.handle_branch(opcode, insn, rt_reg, rs);
+            self.handle_branch(opcode, insn, rt_reg, rs)?;
             execution_row.pc = self.state.pc;
             execution_row.next_pc = self.state.next_pc;
-            return (Some(execution_row), None);
+            return Ok((Some(execution_row), vec![fetch]));
         }
 
-        let mut mem_access: Option<MemoryAccess> = None;
+        let mut mem_ops: Vec<MemoryAccess> = vec![fetch];
 
         let mut store_addr: u32 = 0xffFFffFF;
         // memory fetch (all I-type)
@@ -716,28 +2697,37 @@ impl InstrumentedState {
         if opcode >= 0x20 {
             // M[R[rs]+SignExtImm]
             rs = (rs as u64 + sign_extension(insn&0xffFF, 16) as u64) as u32;
+            if self.config.mode == ExecutionMode::Strict && rs & decode::alignment_mask(opcode) != 0 {
+                let ctx = Some(self.fault_context(insn, rs));
+                return Err(EmulatorError::MisalignedAccess { addr: rs, pc: self.state.pc, ctx });
+            }
             let addr = rs & 0xFFffFFfc;
+            self.observe_data_access(insn, rs)?;
             self.track_memory_access(addr);
             mem = self.state.memory.get_memory(addr);
-            if opcode >= 0x28 && opcode != 0x30 {
+            if opcode == 0x2f {
+                // cache only forms the address, it writes neither memory nor a register
+                rd_reg = 0;
+            } else if opcode >= 0x28 && opcode != 0x30 {
                 // store
                 store_addr = addr;
                 // store opcodes don't write back to a register
                 rd_reg = 0;
             }
 
-            // create the memory access operation
-            mem_access = Some(MemoryAccess {
-                rw_counter: self.state.step,
-                addr,
-                op: MemoryOperation::Read,
-                value: mem,
-                value_prev: mem,
-            });
+            // create the memory access operation, stores read the word they modify first. That
+            // read is not a load of the watched regions, the write records the old word.
+            let access = if store_addr != 0xffFFffFF {
+                self.count_mem_access(addr, MemoryOperation::Read, mem, mem)
+            } else {
+                self.next_mem_access(addr, MemoryOperation::Read, mem, mem)
+            };
+            mem_ops.push(access);
         }
 
-        // ALU
-        let val = self.execute(insn, rs, rt, mem);
+        // ALU, the loads and stores compute their byte lanes like on a big endian memory
+        let lanes = if opcode >= 0x20 { self.state.memory.endianness().lane_addr(rs) } else { rs };
+        let val = self.execute(insn, lanes, rt, mem);
 
         let fun = insn & 0x3f; // 6-bits
         if opcode == 0 && fun >= 8 && fun < 0x1c {
@@ -747,112 +2737,128 @@ impl InstrumentedState {
                     _=> {0}
                 };
 
-                self.handle_jump(link_reg, rs);
+                self.handle_jump(link_reg, rs)?;
                 execution_row.pc = self.state.pc;
                 execution_row.next_pc = self.state.next_pc;
-                return (Some(execution_row), mem_access);
+                return Ok((Some(execution_row), mem_ops));
             }
 
             if fun == 0xa {
-                self.handle_rd(rd_reg, rs, rt == 0);
+                self.handle_rd(rd_reg, rs, rt == 0)?;
                 execution_row.pc = self.state.pc;
                 execution_row.next_pc = self.state.next_pc;
                 execution_row.registers = self.state.registers.clone();
-                return (Some(execution_row), mem_access);
+                return Ok((Some(execution_row), mem_ops));
             }
             if fun == 0xb {
-                self.handle_rd(rd_reg, rs, rt != 0);
+                self.handle_rd(rd_reg, rs, rt != 0)?;
                 execution_row.pc = self.state.pc;
                 execution_row.next_pc = self.state.next_pc;
                 execution_row.registers = self.state.registers.clone();
-                return (Some(execution_row), mem_access);
+                return Ok((Some(execution_row), mem_ops));
             }
 
             // syscall (can read/write)
             if fun == 0xc {
-                self.handle_syscall();
+                let pc = self.state.pc;
+                let num = self.state.registers[2];
+                let args = [self.state.registers[4], self.state.registers[5], self.state.registers[6]];
+                let a3 = self.state.registers[7];
+                if let Some(profile) = &mut self.profile {
+                    profile.record_syscall(num);
+                }
+                self.handle_syscall()?;
+                if self.witness_enabled {
+                    self.syscall_witness = Some(self.syscall_witness(num, [args[0], args[1], args[2], a3]));
+                }
+                if self.journal.is_some() {
+                    let (step, v0, v1) = (self.state.step, self.state.registers[2], self.state.registers[7]);
+                    self.record_event(Event::Syscall { step, pc, num, args, v0, v1 })?;
+                    if self.state.exited {
+                        self.record_event(Event::Exit { step, code: self.state.exit_code })?;
+                    }
+                }
                 execution_row.heap = self.state.heap;
                 execution_row.exited = self.state.exited;
                 execution_row.pc = self.state.pc;
                 execution_row.next_pc = self.state.next_pc;
                 execution_row.registers = self.state.registers.clone();
                 // todo: trace the memory access
-                return (Some(execution_row), mem_access);
+                return Ok((Some(execution_row), mem_ops));
             }
 
             // lo and hi registers
             // can write back
             if fun >= 0x10 && fun < 0x1c {
-                self.handle_hilo(fun, rs, rt, rd_reg);
+                self.handle_hilo(fun, rs, rt, rd_reg)?;
                 execution_row.pc = self.state.pc;
                 execution_row.next_pc = self.state.next_pc;
                 execution_row.registers = self.state.registers.clone();
                 execution_row.hi = self.state.hi;
                 execution_row.lo = self.state.lo;
-                return (Some(execution_row), mem_access);
+                return Ok((Some(execution_row), mem_ops));
             }
         }
 
         // stupid sc, write a 1 to rt
-        if opcode == 0x38 && rt_reg != 0 {
-            self.state.registers[rt_reg as usize] = 1;
+        if opcode == 0x38 {
+            self.write_register(rt_reg, 1)?;
         }
 
         // write memory
         if store_addr != 0xffFFffFF {
-            let value_prev = self.state.memory.get_memory(store_addr);
             self.track_memory_access(store_addr);
-            self.state.memory.set_memory(store_addr, val);
+            if let Err(e) = self.state.memory.store(store_addr, val, self.state.pc) {
+                return Err(self.with_fault_context(e, insn, rs));
+            }
 
-            mem_access = Some(MemoryAccess {
-                rw_counter: self.state.step,
-                addr: store_addr,
-                op: MemoryOperation::Write,
-                value: val,
-                value_prev,
-            });
+            let access = self.next_mem_access(store_addr, MemoryOperation::Write, val, mem);
+            mem_ops.push(access);
         }
 
         // write back the value to the destination register
-        self.handle_rd(rd_reg, val, true);
+        self.handle_rd(rd_reg, val, true)?;
         execution_row.pc = self.state.pc;
         execution_row.next_pc = self.state.next_pc;
         execution_row.registers = self.state.registers.clone();
-        return (Some(execution_row), mem_access);
+        return Ok((Some(execution_row), mem_ops));
+    }
+
+    /// Returns the witness of the syscall `num` just executed on `args`.
+    fn syscall_witness(&mut self, num: u32, args: [u32; 4]) -> SyscallWitness {
+        let on_preimage_fd = matches!(num, 4003 | 4004 | 4019 | 4145 | 4146)
+            && (args[0] == FD_PREIMAGE_READ || args[0] == FD_PREIMAGE_WRITE);
+        SyscallWitness {
+            step: self.state.step,
+            num,
+            args,
+            ret: (self.state.registers[2], self.state.registers[7]),
+            preimage_key: on_preimage_fd.then_some(self.state.preimage_key),
+            preimage_offset: on_preimage_fd.then_some(self.state.preimage_offset),
+            mem_ops: std::mem::take(&mut self.syscall_mem_ops),
+        }
+    }
+
+    fn execute(&mut self, insn: u32, rs: u32, rt: u32, mem: u32) -> u32 {
+        // the hot instructions skip the remapping and the dispatch of the general path
+        match insn >> 26 {
+            0x09 => rs.wrapping_add(rt), // addiu, rt is the sign extended immediate
+            0x23 => mem, // lw
+            0x2b => rt, // sw
+            _ => self.execute_general(insn, rs, rt, mem),
+        }
     }
 
-    fn execute(&mut self, insn: u32, mut rs: u32, rt: u32, mem: u32) -> u32 {
+    /// The ALU of all the instructions, `execute` takes fast paths for the hot ones first.
+    fn execute_general(&mut self, insn: u32, mut rs: u32, rt: u32, mem: u32) -> u32 {
         // implement alu
         let mut opcode = insn >> 26;
         let mut fun = insn & 0x3F;
 
         if opcode < 0x20 {
             // transform ArithLogI
-            if opcode >= 8 && opcode < 0xf {
-                match opcode {
-                    8 => {
-                        fun = 0x20; // addi
-                    }
-                    9=> {
-                        fun = 0x21; // addiu
-                    }
-                    0xa => {
-                        fun = 0x2a; // slti
-                    }
-                    0xb => {
-                        fun = 0x2b; // sltiu
-                    }
-                    0xc => {
-                        fun = 0x24; // andi
-                    }
-                    0xd => {
-                        fun = 0x25; // ori
-                    }
-                    0xe => {
-                        fun = 0x26; // xori
-                    }
-                    _ => {}
-                }
+            if let Some(imm_fun) = decode::arith_imm_fun(opcode) {
+                fun = imm_fun;
                 opcode = 0;
             }
 
@@ -864,12 +2870,16 @@ impl InstrumentedState {
                         return rs; // jr/jalr/div + others
                     } else if fun == 0x00 {
                         return rt << shamt; // sll
+                    } else if fun == 0x02 && insn & (1 << 21) != 0 {
+                        return rt.rotate_right(shamt); // rotr
                     } else if fun == 0x02 {
                         return rt >> shamt; // srl
                     } else if fun == 0x03 {
                         return sign_extension(rt >> shamt, 32-shamt); // sra
                     } else if fun == 0x04 {
                         return rt << (rs & 0x1f); // sllv
+                    } else if fun == 0x06 && insn & (1 << 6) != 0 {
+                        return rt.rotate_right(rs & 0x1f); // rotrv
                     } else if fun == 0x06 {
                         return rt >> (rs & 0x1f); // srlv
                     } else if fun == 0x07 {
@@ -918,7 +2928,7 @@ impl InstrumentedState {
                 return rt << 16; // lui
             } else if opcode == 0x1c { // SPECIAL2
                 if fun == 2 { // mul
-                    return ((rs as i64) * (rt as i64)) as u32;
+                    return (rs as i32).wrapping_mul(rt as i32) as u32;
                 }
                 if fun == 0x20 || fun == 0x21 { // clo
                     if fun == 0x20 {
@@ -979,21 +2989,41 @@ impl InstrumentedState {
             let val = rt << (24 - (rs & 3) *8 );
             let mask = 0xffFFffFFu32 << (24 - (rs & 3) *8 );
             return (mem & (!mask)) | val;
+        } else if opcode == 0x2f { // cache
+            return mem;
         } else if opcode == 0x30 { // ll
             return mem;
         } else if opcode == 0x38 { // sc
             return rt;
         }
 
-        panic!("invalid instruction, opcode: {}", opcode);
+        unreachable!("instruction 0x{:08x} is not supported", insn);
     }
 
-    pub fn step(&mut self, proof: bool) -> (Box<StepWitness>, Option<ExecutionRow>, Option<MemoryAccess>) {
+    pub fn step(
+        &mut self,
+        proof: bool,
+    ) -> Result<(Box<StepWitness>, Option<ExecutionRow>, Vec<MemoryAccess>), EmulatorError> {
+        let streamed = self.witness_stream.is_some();
+        let proof = proof || streamed && self.witness_stream_proofs;
         self.mem_proof_enabled = proof;
+        self.witness_enabled = proof || streamed;
         self.last_mem_access = !(0u32);
+        self.extra_mem_accesses.clear();
+        self.extra_mem_proofs.clear();
         self.last_preimage_offset = !(0u32);
+        self.syscall_mem_ops.clear();
+        self.syscall_witness = None;
+        self.register_delta = None;
+        self.hilo_delta = None;
 
         let mut wit: Box<StepWitness> = Default::default();
+        let pc = self.state.pc;
+        // checked before the fetch, which can't read a misaligned pc
+        if let Some(reason) = self.bad_pc(pc).filter(|_| !self.state.exited) {
+            return Err(EmulatorError::BadPc { pc, reason, jump: None });
+        }
+        let insn = self.state.memory.get_memory(pc);
 
         if proof {
             let insn_proof = self.state.memory.merkle_proof(self.state.pc);
@@ -1001,29 +3031,267 @@ impl InstrumentedState {
             wit.mem_proof = insn_proof.to_vec();
         }
 
-        let (execution_row, mem_access) = self.mips_step();
+        let (execution_row, mem_ops) = match self.mips_step() {
+            Ok(v) => v,
+            Err(e) => {
+                if self.journal.is_some() {
+                    // the step of the failed instruction, not counted
+                    let (step, pc) = (self.state.step + 1, self.state.pc);
+                    self.record_event(Event::Error { step, pc, message: e.to_string() })?;
+                }
+                return Err(e);
+            }
+        };
 
         if proof {
             wit.mem_proof.extend(self.mem_proof.clone());
+            wit.mem_proof.extend(&self.extra_mem_proofs);
+            wit.extra_mem_accesses.clone_from(&self.extra_mem_accesses);
             if self.last_preimage_offset != !(0u32) {
                 wit.preimage_offset = self.last_preimage_offset;
                 wit.preimage_key = self.last_preimage_key;
-                wit.preimage_value.clone_from(&self.last_preimage);
+                wit.preimage_value = self.whole_last_preimage()?;
+            }
+        }
+        if self.witness_enabled {
+            wit.kind = StepKind::new(insn, self.syscall_witness.take());
+            wit.step = self.state.step;
+            wit.instruction = Instruction { addr: pc, bytecode: insn };
+            wit.mem_ops.clone_from(&mem_ops);
+            wit.register_delta = self.register_delta;
+            wit.hilo_delta = self.hilo_delta;
+            if let Some(stream) = &mut self.witness_stream {
+                stream.write(&wit)?;
             }
         }
 
-        (wit, execution_row, mem_access)
+        Ok((wit, execution_row, mem_ops))
+    }
+
+    /// Runs the program without proofs until it exits or `max_steps` steps were executed.
+    /// Host resource limits of the guest end the run with a status, other errors are returned.
+    pub fn run(&mut self, max_steps: u64) -> Result<RunResult, EmulatorError> {
+        self.run_checked(max_steps, u64::MAX, || false)
+    }
+
+    /// Runs the program like `run`, and summarizes the run with the state after it.
+    pub fn run_with_summary(&mut self, max_steps: u64) -> Result<RunSummary, EmulatorError> {
+        let start = Instant::now();
+        let result = self.run(max_steps)?;
+        Ok(self.summary(&result, start.elapsed()))
+    }
+
+    /// Summarizes the run of `result`, which took `wall_time`, with the state after it.
+    pub fn summary(&mut self, result: &RunResult, wall_time: Duration) -> RunSummary {
+        let instruction_mix = self.profile.as_ref().map(|profile| {
+            profile.top_instructions(INSTRUCTION_MIX_LEN).into_iter()
+                .map(|(id, count)| InstructionCount {
+                    instruction: format!("{:?}", id).to_lowercase(),
+                    count,
+                })
+                .collect()
+        });
+        RunSummary {
+            schema_version: SUMMARY_SCHEMA_VERSION,
+            steps: self.state.step,
+            status: result.status,
+            exit_code: self.state.exited.then_some(self.state.exit_code),
+            wall_time_secs: wall_time.as_secs_f64(),
+            pages_allocated: self.state.memory.stats().page_allocations,
+            preimage_bytes_served: self.preimage_bytes_served,
+            hints_posted: self.hints_posted,
+            state_hash: hex::encode(self.state_hash()),
+            guest_panic: result.guest_panic.as_ref().map(|panic| panic.message.clone()),
+            instruction_mix,
+        }
+    }
+
+    /// Runs the program like `run` without a step limit, until it exits or `cancel` is set.
+    /// The flag is read every `check_every` steps, a cancelled run ends with `Cancelled`.
+    pub fn run_with_cancel(
+        &mut self,
+        cancel: &AtomicBool,
+        check_every: u64,
+    ) -> Result<RunResult, EmulatorError> {
+        self.run_checked(u64::MAX, check_every, || cancel.load(Ordering::Relaxed))
+    }
+
+    /// Runs the program like `run_with_cancel`, the run is also cancelled once `timeout` has
+    /// elapsed. The clock is read every `check_every` steps too.
+    pub fn run_with_timeout(
+        &mut self,
+        cancel: &AtomicBool,
+        timeout: Duration,
+        check_every: u64,
+    ) -> Result<RunResult, EmulatorError> {
+        let deadline = Instant::now() + timeout;
+        self.run_checked(u64::MAX, check_every, || {
+            cancel.load(Ordering::Relaxed) || Instant::now() >= deadline
+        })
+    }
+
+    /// Samples the state for the livelock detector of `run`.
+    fn livelock_sample(&self) -> LivelockSample {
+        let state = &self.state;
+        LivelockSample {
+            pc: state.pc,
+            next_pc: state.next_pc,
+            registers: state.registers,
+            hi: state.hi,
+            lo: state.lo,
+            heap: state.heap,
+            preimage_key: state.preimage_key,
+            preimage_offset: state.preimage_offset,
+            random_position: state.random_position,
+            thread_pointer: state.thread_pointer,
+            in_delay_slot: state.in_delay_slot,
+            hilo_age: state.hilo_written_step
+                .map(|written| (state.step - written).min(HILO_HAZARD_STEPS + 1)),
+            stdin_offset: self.stdin_offset,
+            hints_posted: self.hints_posted,
+            pending_hint: state.last_hint.len(),
+        }
+    }
+
+    /// Runs like `run`, calling `cancelled` every `check_every` steps.
+    fn run_checked(
+        &mut self,
+        max_steps: u64,
+        check_every: u64,
+        mut cancelled: impl FnMut() -> bool,
+    ) -> Result<RunResult, EmulatorError> {
+        let start = self.state.step;
+        let check_every = check_every.max(1);
+        let mut next_check = start.saturating_add(check_every);
+        let mut next_report = start.saturating_add(metrics::REPORT_INTERVAL);
+        let livelock_interval = self.config.livelock_interval.map(|interval| interval.max(1));
+        let mut livelock = LivelockDetector::default();
+        let mut status = None;
+        while !self.state.exited && self.state.step - start < max_steps {
+            if self.state.step >= next_report {
+                self.report_metrics();
+                next_report = self.state.step.saturating_add(metrics::REPORT_INTERVAL);
+            }
+            if self.state.step >= next_check {
+                if cancelled() {
+                    status = Some(VmStatus::Cancelled { step: self.state.step });
+                    break;
+                }
+                next_check = self.state.step.saturating_add(check_every);
+            }
+            if livelock_interval.map_or(false, |interval| self.state.step % interval == 0) {
+                let (step, generation) = (self.state.step, self.state.memory.generation());
+                if let Some(period) = livelock.observe(self.livelock_sample(), step, generation) {
+                    status = Some(VmStatus::LivelockSuspected { pc: self.state.pc, period });
+                    break;
+                }
+            }
+            match self.step(false) {
+                Ok(_) => {}
+                Err(EmulatorError::HostOom { addr, pages, .. }) => {
+                    status = Some(VmStatus::HostOom { addr, pages });
+                    break;
+                }
+                Err(e) => {
+                    self.report_metrics();
+                    return Err(e);
+                }
+            }
+        }
+        self.report_metrics();
+
+        let status = match status {
+            Some(status) => status,
+            None if self.state.exited => match self.state.break_code {
+                Some(code) => VmStatus::Panic { code },
+                None => VmStatus::Exited(self.state.exit_code),
+            },
+            None => VmStatus::StepLimitReached,
+        };
+        let guest_panic = match status {
+            VmStatus::Exited(code) if code != 0 => self.panic_detector.panic(),
+            _ => None,
+        };
+        Ok(RunResult {
+            status,
+            steps: self.state.step - start,
+            guest_panic,
+        })
     }
 }
 
 /// se extends the number to 32 bit with sign.
+/// Sign extends the low `idx` bits of `dat`, its upper bits are ignored. `idx` 32 returns `dat`.
 fn sign_extension(dat: u32, idx: u32) -> u32 {
-    let is_signed = (dat >> (idx-1)) != 0;
-    let signed = ((1u32 << (32-idx)) - 1) << idx;
-    let mask = (1u32 << idx) - 1;
-    if is_signed {
-        dat & mask | signed
-    } else {
-        dat & mask
+    if idx == 0 {
+        return 0;
+    }
+    let shift = 32 - idx.min(32);
+    (((dat << shift) as i32) >> shift) as u32
+}
+
+/// Prefixes the preimage with its length, as the guest reads it.
+fn length_prefixed(preimage: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + preimage.len());
+    out.extend((preimage.len() as u64).to_be_bytes());
+    out.extend(preimage);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstrumentedState, State};
+    use crate::decode;
+    use crate::pre_image::EmptyPreimageOracle;
+    use crate::random;
+
+    /// Returns `n` pseudo random words of the stream of `seed`.
+    fn random_words(seed: u8, n: usize) -> Vec<u32> {
+        let mut bytes = vec![0; 4 * n];
+        random::fill(&[seed; 32], 0, &mut bytes);
+        bytes.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn test_hot_paths_match_general_path() {
+        let mut is = InstrumentedState::new(State::new(), Box::new(EmptyPreimageOracle));
+
+        // addiu, lw and sw on the operands mips_step fetches: the extended immediate of addiu,
+        // the effective address, the value of rt and the memory word of the loads and stores
+        for (i, op) in random_words(1, 4 * 3000).chunks(4).enumerate() {
+            let opcode = [0x09, 0x23, 0x2b][i % 3];
+            let insn = opcode << 26 | op[0] & 0x03ffFFff;
+            let rt = if opcode == 0x09 { decode::imm(insn) } else { op[2] };
+            assert_eq!(
+                is.execute(insn, op[1], rt, op[3]),
+                is.execute_general(insn, op[1], rt, op[3]),
+                "insn 0x{:08x} rs 0x{:08x} rt 0x{:08x} mem 0x{:08x}", insn, op[1], rt, op[3],
+            );
+        }
+    }
+
+    #[test]
+    fn test_pc_wraps() {
+        let mut is = InstrumentedState::new(State::new(), Box::new(EmptyPreimageOracle));
+        for addr in [0xfffffff8, 0xfffffffc, 0, 4] {
+            is.state.memory.set_memory(addr, 0).unwrap();
+        }
+        is.state.pc = 0xfffffff8;
+        is.state.next_pc = 0xfffffffc;
+        is.step(false).unwrap();
+        assert_eq!((is.state.pc, is.state.next_pc), (0xfffffffc, 0));
+        is.step(false).unwrap();
+        assert_eq!((is.state.pc, is.state.next_pc), (0, 4));
+
+        // the return address of a jalr in the last two words wraps too
+        let jalr = 8 << 21 | 31 << 11 | 0x09;
+        is.state.memory.set_memory(0xfffffff8, jalr).unwrap();
+        is.state.registers[8] = 0x100;
+        is.state.pc = 0xfffffff8;
+        is.state.next_pc = 0xfffffffc;
+        is.step(false).unwrap();
+        assert_eq!(is.state.registers[31], 0);
+        is.step(false).unwrap();
+        assert_eq!(is.state.pc, 0x100);
     }
 }
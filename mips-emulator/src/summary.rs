@@ -0,0 +1,54 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde::Serialize;
+use crate::state::VmStatus;
+
+/// the version of the schema of `RunSummary`, bumped when a field changes meaning or goes away.
+pub const SUMMARY_SCHEMA_VERSION: u32 = 1;
+
+/// the instructions `RunSummary::instruction_mix` lists at most.
+pub const INSTRUCTION_MIX_LEN: usize = 10;
+
+/// InstructionCount is an entry of the instruction mix, the mnemonic and its executed count.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstructionCount {
+    pub instruction: String,
+    pub count: u64,
+}
+
+/// RunSummary is the outcome of a run as a single JSON object, for pipelines running guests.
+/// The fields of optional subsystems are none when the subsystem is off, rather than zero.
+/// See `InstrumentedState::run_with_summary`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    /// `SUMMARY_SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// the steps executed since the state started, not only by the last run.
+    pub steps: u64,
+    pub status: VmStatus,
+    /// none until the guest exits.
+    pub exit_code: Option<u8>,
+    pub wall_time_secs: f64,
+    /// see `MemoryStats::page_allocations`.
+    pub pages_allocated: u64,
+    /// the preimage bytes the guest read, their length prefixes included.
+    pub preimage_bytes_served: u64,
+    pub hints_posted: u64,
+    /// the hex of `InstrumentedState::state_hash` after the run.
+    pub state_hash: String,
+    /// the panic message the guest printed to stderr, see `RunResult::guest_panic`.
+    pub guest_panic: Option<String>,
+    /// the most executed instructions, if profiling is enabled.
+    pub instruction_mix: Option<Vec<InstructionCount>>,
+}
+
+impl RunSummary {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn write_json(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
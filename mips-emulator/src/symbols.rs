@@ -0,0 +1,124 @@
+use std::ops::Range;
+use elf::abi::{SHN_ABS, STT_FUNC, STT_NOTYPE, STT_OBJECT};
+use elf::endian::AnyEndian;
+use log::warn;
+use crate::reloc::{self, DEFAULT_LOAD_BIAS};
+
+/// Symbol is a named address range of the guest program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    /// the size of the symbol, zero if unknown (e.g. labels of hand written assembly).
+    pub size: u32,
+    pub is_function: bool,
+}
+
+/// SymbolMap maps guest addresses to the symbols of the ELF symbol table (`.symtab`/`.strtab`).
+/// The map of a stripped binary is empty.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    /// sorted by address, at most one symbol per address.
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn load_elf(f: &elf::ElfBytes<AnyEndian>) -> Self {
+        Self::load_elf_at(f, DEFAULT_LOAD_BIAS)
+    }
+
+    /// Loads the symbols like `load_elf`, those of a static PIE moved by `pie_bias` like
+    /// `State::try_load_elf_at` moves its segments.
+    pub fn load_elf_at(f: &elf::ElfBytes<AnyEndian>, pie_bias: u32) -> Self {
+        let bias = reloc::load_bias(f, pie_bias);
+        let (table, strtab) = match f.symbol_table() {
+            Ok(Some(table)) => table,
+            Ok(None) => return Self::new(),
+            Err(e) => {
+                warn!("failed to parse symbols table, {}", e);
+                return Self::new();
+            }
+        };
+
+        let mut symbols = Vec::new();
+        for symbol in table {
+            let symtype = symbol.st_symtype();
+            if symbol.is_undefined() || !matches!(symtype, STT_FUNC | STT_OBJECT | STT_NOTYPE) {
+                continue;
+            }
+            match strtab.get(symbol.st_name as usize) {
+                Ok("") => {}
+                Ok(name) => {
+                    symbols.push(Symbol {
+                        name: name.to_string(),
+                        addr: if symbol.st_shndx == SHN_ABS {
+                            symbol.st_value as u32
+                        } else {
+                            (symbol.st_value as u32).wrapping_add(bias)
+                        },
+                        size: symbol.st_size as u32,
+                        is_function: symtype == STT_FUNC,
+                    });
+                }
+                Err(e) => {
+                    warn!("parse symbol failed, {}", e);
+                }
+            }
+        }
+        Self::from_symbols(symbols)
+    }
+
+    pub fn from_symbols(mut symbols: Vec<Symbol>) -> Self {
+        // keep the most descriptive symbol of every address: functions first, then the largest.
+        symbols.sort_by(|a, b| {
+            a.addr.cmp(&b.addr)
+                .then(b.is_function.cmp(&a.is_function))
+                .then(b.size.cmp(&a.size))
+        });
+        symbols.dedup_by_key(|s| s.addr);
+        Self { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Returns the symbol containing `addr` and the offset of `addr` in it.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.symbols.partition_point(|s| s.addr <= addr);
+        if i == 0 {
+            return None;
+        }
+        let symbol = &self.symbols[i - 1];
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+
+    /// Returns the address ranges of the functions with a known size, sorted by address.
+    pub fn function_ranges(&self) -> Vec<(&str, Range<u32>)> {
+        self.symbols.iter()
+            .filter(|s| s.is_function && s.size != 0)
+            .map(|s| (s.name.as_str(), s.addr..s.addr.saturating_add(s.size)))
+            .collect()
+    }
+
+    /// Formats `addr` as `0x400120 <main+0x12>`, or just the address if no symbol contains it.
+    pub fn annotate(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("0x{:x} <{}>", addr, name),
+            Some((name, offset)) => format!("0x{:x} <{}+0x{:x}>", addr, name, offset),
+            None => format!("0x{:x}", addr),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Overrides of the built-in stubs of the process syscalls, see
+//! `InstrumentedState::set_syscall_override`.
+
+/// the syscalls an override may handle: getpid, getppid, wait4 and uname. The others keep
+/// their built-in handlers, which the preimage, hint and exit state depend on.
+pub const OVERRIDABLE_SYSCALLS: [u32; 4] = [4020, 4064, 4114, 4122];
+
+/// SyscallOutcome is what an overridden syscall returns to the guest: its v0 and v1, and the
+/// bytes it writes at each address. The bytes are written through the same path as the ones of
+/// the built-in syscalls, so they have proofs and the watched regions see them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyscallOutcome {
+    pub v0: u32,
+    pub v1: u32,
+    pub writes: Vec<(u32, Vec<u8>)>,
+}
+
+/// SyscallOverride replaces the built-in handlers of `OVERRIDABLE_SYSCALLS`.
+pub trait SyscallOverride {
+    /// Handles the syscall `num` with the arguments a0 to a3, or returns none to leave it to
+    /// the built-in handler.
+    fn handle(&mut self, num: u32, args: [u32; 4]) -> Option<SyscallOutcome>;
+}
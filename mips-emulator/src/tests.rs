@@ -1,10 +1,12 @@
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         fs,
         iter::zip,
         path::{PathBuf, Path},
+        sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
+        time::{Duration, Instant},
     };
     use elf::{
         ElfBytes,
@@ -15,11 +17,457 @@ mod tests {
         Keccak256,
         digest::{FixedOutputReset, Reset}
     };
-    use crate::pre_image::{Keccak256Key, Key, LocalIndexKey, PreimageOracle};
-    use crate::state::{InstrumentedState, State};
+    use crate::memory::{copy_from_word, copy_into_word, Endianness, Memory, DEFAULT_MEMORY_HASH};
+    use crate::memory_backend::FileBackend;
+    use crate::symbols::{Symbol, SymbolMap};
+    use crate::trace_export::{ChromeTraceExporter, QemuExporter};
+    use crate::syscall_override::{SyscallOutcome, SyscallOverride};
+    use crate::profile::{CostModel, InsnKind, ProfileReport};
+    use crate::region_log::{RegionAccess, RegionWatchConfig};
+    use crate::config::{ExecutionMode, UnknownSyscallPolicy, VmConfig};
+    use crate::error::{BadPcReason, EmulatorError};
+    use crate::errno::{
+        EAGAIN, EBADF, ECHILD, EFAULT, EINVAL, ENOMEM, ENOSYS, ESPIPE, SYSCALL_ERROR,
+    };
+    use crate::hash::{HashFunction, Hasher32, Keccak256Hasher};
+    use crate::hint::HintBuffer;
+    use crate::layout::{HEAP_START, LayoutError, MemoryLayout, Region, RegionKind, STACK_POINTER};
+    use crate::reloc::{DEFAULT_LOAD_BIAS, LoadError};
+    use crate::livelock::DEFAULT_LIVELOCK_INTERVAL;
+    use crate::journal::{Event, EventKind, JournalConfig, JsonlSink, read_jsonl};
+    use crate::metrics::{self, TestSink};
+    use crate::summary::{INSTRUCTION_MIX_LEN, RunSummary, SUMMARY_SCHEMA_VERSION};
+    use crate::pre_image::{
+        EmptyPreimageOracle, FilePreimageOracle, Keccak256Key, Key, LocalIndexKey, PrecompileKey,
+        PREIMAGE_WINDOW_OVERLAP, PREIMAGE_WINDOW_SIZE, PreimageCacheStats, PreimageLogOracle,
+        PreimageOracle, Sha256Key, TypedPreimageOracle, verify_preimage,
+    };
+    use crate::guest_panic::GuestPanic;
+    use crate::compat::cannon::{self, OneStepError, OneStepInput, PROOF_SIZE};
+    use crate::verifier::{self, PreimagePart, StateWitness, StepProofs};
+    use crate::time_travel::TimeTravel;
+    use crate::replay::{Replay, ReplayImage, ReplayMismatch, REPLAY_VERSION};
+    #[cfg(feature = "ffi")]
+    use std::{ffi::{c_char, c_int, c_void, CStr, CString}, ptr};
+    #[cfg(feature = "ffi")]
+    use crate::ffi;
+    use crate::differential::{run_lockstep, run_lockstep_every};
+    use crate::opcode_id::OpcodeId;
+    use crate::decode::coverage::{self, assert_full_coverage};
+    use crate::witness::{
+        CODE_HASH_DOMAIN, ExecutionRow, HiLoDelta, Instruction, MemoryAccess, MemoryOperation,
+        OpcodeRow, Program, RegisterDelta, StepKind, StepWitness, SyscallWitness, Trace,
+        WitnessTables,
+    };
+    #[cfg(feature = "zstd")]
+    use crate::witness_stream::Compression;
+    use crate::witness_stream::{WitnessReader, WitnessWriter};
+    use crate::state::{
+        FD_HINT_READ, FD_HINT_WRITE, FD_PIPE_READ, FD_PIPE_WRITE, FD_PREIMAGE_READ, FD_PREIMAGE_WRITE,
+        FD_STDERR, FD_STDIN, FD_STDOUT, InstrumentedState, SEEK_CUR, SEEK_SET,
+        InstrumentedStateBuilder, State, StateMetadata, VmStatus,
+    };
 
     const END_ADDR: u32 = 0xa7ef00d0;
 
+    /// Encoders for the instructions of the hand assembled test programs.
+    mod asm {
+        pub fn r_type(rs: u32, rt: u32, rd: u32, shamt: u32, fun: u32) -> u32 {
+            (rs << 21) | (rt << 16) | (rd << 11) | (shamt << 6) | fun
+        }
+
+        pub fn i_type(opcode: u32, rs: u32, rt: u32, imm: u32) -> u32 {
+            (opcode << 26) | (rs << 21) | (rt << 16) | (imm & 0xffff)
+        }
+
+        pub fn syscall() -> u32 {
+            0xc
+        }
+
+        pub fn addiu(rt: u32, rs: u32, imm: i16) -> u32 {
+            i_type(9, rs, rt, imm as u32)
+        }
+
+        pub fn ori(rt: u32, rs: u32, imm: u16) -> u32 {
+            i_type(0xd, rs, rt, imm as u32)
+        }
+
+        pub fn lui(rt: u32, imm: u16) -> u32 {
+            i_type(0xf, 0, rt, imm as u32)
+        }
+
+        /// loads a 32 bits constant into `rt` with lui/ori.
+        pub fn li(rt: u32, v: u32) -> [u32; 2] {
+            [lui(rt, (v >> 16) as u16), ori(rt, rt, v as u16)]
+        }
+
+        pub fn lw(rt: u32, base: u32, offset: i16) -> u32 {
+            i_type(0x23, base, rt, offset as u32)
+        }
+
+        pub fn sw(rt: u32, base: u32, offset: i16) -> u32 {
+            i_type(0x2b, base, rt, offset as u32)
+        }
+
+        pub fn sb(rt: u32, base: u32, offset: i16) -> u32 {
+            i_type(0x28, base, rt, offset as u32)
+        }
+
+        /// `offset` is in instructions, relative to the delay slot.
+        pub fn bne(rs: u32, rt: u32, offset: i16) -> u32 {
+            i_type(5, rs, rt, offset as u32)
+        }
+
+        pub fn beq(rs: u32, rt: u32, offset: i16) -> u32 {
+            i_type(4, rs, rt, offset as u32)
+        }
+
+        pub fn j(target: u32) -> u32 {
+            (2 << 26) | ((target >> 2) & 0x03ffFFff)
+        }
+
+        pub fn jal(target: u32) -> u32 {
+            (3 << 26) | ((target >> 2) & 0x03ffFFff)
+        }
+
+        pub fn addu(rd: u32, rs: u32, rt: u32) -> u32 {
+            r_type(rs, rt, rd, 0, 0x21)
+        }
+
+        pub fn jr(rs: u32) -> u32 {
+            r_type(rs, 0, 0, 0, 8)
+        }
+
+        pub fn rdhwr(rt: u32, rd: u32) -> u32 {
+            (0x1f << 26) | r_type(0, rt, rd, 0, 0x3b)
+        }
+
+        /// ext rt, rs, pos, size, the rd bits hold size - 1.
+        pub fn ext(rt: u32, rs: u32, pos: u32, size: u32) -> u32 {
+            (0x1f << 26) | r_type(rs, rt, size.wrapping_sub(1) & 0x1f, pos, 0x00)
+        }
+
+        /// ins rt, rs, pos, size, the rd bits hold the msb pos + size - 1.
+        pub fn ins(rt: u32, rs: u32, pos: u32, size: u32) -> u32 {
+            (0x1f << 26) | r_type(rs, rt, (pos + size).wrapping_sub(1) & 0x1f, pos, 0x04)
+        }
+
+        /// rotr rd, rt, sa, srl with the R bit set in rs.
+        pub fn rotr(rd: u32, rt: u32, sa: u32) -> u32 {
+            r_type(1, rt, rd, sa, 0x02)
+        }
+
+        /// rotrv rd, rt, rs, srlv with the R bit set in shamt.
+        pub fn rotrv(rd: u32, rt: u32, rs: u32) -> u32 {
+            r_type(rs, rt, rd, 1, 0x06)
+        }
+
+        /// wsbh rd, rt
+        pub fn wsbh(rd: u32, rt: u32) -> u32 {
+            (0x1f << 26) | r_type(0, rt, rd, 0x02, 0x20)
+        }
+
+        /// seb rd, rt
+        pub fn seb(rd: u32, rt: u32) -> u32 {
+            (0x1f << 26) | r_type(0, rt, rd, 0x10, 0x20)
+        }
+
+        /// seh rd, rt
+        pub fn seh(rd: u32, rt: u32) -> u32 {
+            (0x1f << 26) | r_type(0, rt, rd, 0x18, 0x20)
+        }
+
+        /// cache op, offset(base)
+        pub fn cache(op: u32, base: u32, offset: i16) -> u32 {
+            i_type(0x2f, base, op, offset as u32)
+        }
+
+        /// break with the 20 bits code
+        pub fn brk(code: u32) -> u32 {
+            (code << 6) | 0xd
+        }
+
+        pub fn nop() -> u32 {
+            0
+        }
+
+        pub fn to_bytes(program: &[u32]) -> Vec<u8> {
+            program.iter().flat_map(|insn| insn.to_be_bytes()).collect()
+        }
+    }
+
+    /// Writer of minimal MIPS32 ELF executables, big endian unless `little_endian`: one PT_LOAD
+    /// segment and section per segment, and a symbol table if any symbol is added. The first
+    /// segment is the executable text, the others are data. A static PIE has a `.rel.dyn`
+    /// section of its relocations, and a `.dynsym` of its undefined weak symbols if it has any,
+    /// numbered from 1.
+    #[derive(Default)]
+    struct ElfWriter {
+        entry: u32,
+        segments: Vec<(u32, Vec<u8>)>,
+        /// (name, addr, size, is_function)
+        symbols: Vec<(String, u32, u32, bool)>,
+        pie: bool,
+        /// (offset, symbol, type)
+        relocations: Vec<(u32, u32, u32)>,
+        weak_symbols: Vec<String>,
+        little_endian: bool,
+    }
+
+    impl ElfWriter {
+        fn new(entry: u32) -> Self {
+            Self { entry, ..Default::default() }
+        }
+
+        fn segment(mut self, vaddr: u32, data: Vec<u8>) -> Self {
+            self.segments.push((vaddr, data));
+            self
+        }
+
+        fn function(mut self, name: &str, addr: u32, size: u32) -> Self {
+            self.symbols.push((name.to_string(), addr, size, true));
+            self
+        }
+
+        fn object(mut self, name: &str, addr: u32, size: u32) -> Self {
+            self.symbols.push((name.to_string(), addr, size, false));
+            self
+        }
+
+        /// Makes the executable an ET_DYN static PIE.
+        fn pie(mut self) -> Self {
+            self.pie = true;
+            self
+        }
+
+        fn relocation(mut self, offset: u32, sym: u32, kind: u32) -> Self {
+            self.relocations.push((offset, sym, kind));
+            self
+        }
+
+        /// Adds an undefined weak dynamic symbol.
+        fn weak(mut self, name: &str) -> Self {
+            self.weak_symbols.push(name.to_string());
+            self
+        }
+
+        /// Makes the executable a mipsel one, the segments are written as given.
+        fn little_endian(mut self) -> Self {
+            self.little_endian = true;
+            self
+        }
+
+        fn build(&self) -> Vec<u8> {
+            fn strtab(names: &[&str]) -> (Vec<u8>, Vec<u32>) {
+                let mut out = vec![0u8];
+                let mut offsets = vec![];
+                for name in names {
+                    offsets.push(out.len() as u32);
+                    out.extend(name.as_bytes());
+                    out.push(0);
+                }
+                (out, offsets)
+            }
+            fn align(out: &mut Vec<u8>) {
+                out.resize((out.len() + 3) & !3, 0);
+            }
+            let little_endian = self.little_endian;
+            let u16e = |v: u32| {
+                if little_endian { (v as u16).to_le_bytes() } else { (v as u16).to_be_bytes() }
+            };
+            let u32e = |v: u32| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+
+            let has_symbols = !self.symbols.is_empty();
+            let mut section_names = vec![];
+            for i in 0..self.segments.len() {
+                section_names.push(if i == 0 { ".text".to_string() } else { format!(".data{}", i) });
+            }
+            if has_symbols {
+                section_names.push(".symtab".to_string());
+                section_names.push(".strtab".to_string());
+            }
+            if !self.weak_symbols.is_empty() {
+                section_names.push(".dynsym".to_string());
+                section_names.push(".dynstr".to_string());
+            }
+            if self.pie {
+                section_names.push(".rel.dyn".to_string());
+            }
+            section_names.push(".shstrtab".to_string());
+            let (shstrtab, shstr_offsets) =
+                strtab(&section_names.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+
+            let phoff = 52;
+            let mut out = vec![0u8; phoff + 32 * self.segments.len()];
+            // (name, type, addr, offset, size, link, info, entsize)
+            let mut sections = vec![];
+            let mut phdrs = vec![];
+            for (i, (vaddr, data)) in self.segments.iter().enumerate() {
+                align(&mut out);
+                let offset = out.len() as u32;
+                out.extend(data);
+                let flags = if i == 0 { 5 } else { 6 }; // R+X or R+W
+                phdrs.push([1, offset, *vaddr, *vaddr, data.len() as u32, data.len() as u32, flags, 4]);
+                sections.push([shstr_offsets[i], 1, *vaddr, offset, data.len() as u32, 0, 0, 0]);
+            }
+            if has_symbols {
+                let names: Vec<&str> = self.symbols.iter().map(|s| s.0.as_str()).collect();
+                let (strtab, name_offsets) = strtab(&names);
+                let mut symtab = vec![0u8; 16];
+                for ((_, addr, size, is_function), name) in zip(&self.symbols, name_offsets) {
+                    let shndx = self.segments.iter()
+                        .position(|(vaddr, data)| *addr >= *vaddr && *addr < vaddr + data.len() as u32)
+                        .map_or(0xfff1, |i| i + 1) as u32; // SHN_ABS if not in any segment
+                    symtab.extend(u32e(name));
+                    symtab.extend(u32e(*addr));
+                    symtab.extend(u32e(*size));
+                    symtab.push(0x10 | if *is_function { 2 } else { 1 }); // STB_GLOBAL
+                    symtab.push(0);
+                    symtab.extend(u16e(shndx));
+                }
+                let n = self.segments.len() as u32;
+                align(&mut out);
+                sections.push([shstr_offsets[n as usize], 2, 0, out.len() as u32, symtab.len() as u32, n + 2, 1, 16]);
+                out.extend(symtab);
+                sections.push([shstr_offsets[n as usize + 1], 3, 0, out.len() as u32, strtab.len() as u32, 0, 0, 0]);
+                out.extend(strtab);
+            }
+            if !self.weak_symbols.is_empty() {
+                let names: Vec<&str> = self.weak_symbols.iter().map(|s| s.as_str()).collect();
+                let (dynstr, name_offsets) = strtab(&names);
+                let mut dynsym = vec![0u8; 16];
+                for name in name_offsets {
+                    dynsym.extend(u32e(name));
+                    dynsym.extend([0; 8]);
+                    dynsym.extend([0x20, 0]); // STB_WEAK, STT_NOTYPE
+                    dynsym.extend(u16e(0)); // SHN_UNDEF
+                }
+                let i = section_names.iter().position(|name| name == ".dynsym").unwrap();
+                let (link, size) = (sections.len() as u32 + 2, dynsym.len() as u32);
+                align(&mut out);
+                sections.push([shstr_offsets[i], 11, 0, out.len() as u32, size, link, 1, 16]);
+                out.extend(dynsym);
+                let size = dynstr.len() as u32;
+                sections.push([shstr_offsets[i + 1], 3, 0, out.len() as u32, size, 0, 0, 0]);
+                out.extend(dynstr);
+            }
+            if self.pie {
+                let rels: Vec<u8> = self.relocations.iter()
+                    .flat_map(|(offset, sym, kind)| [*offset, sym << 8 | kind])
+                    .flat_map(u32e)
+                    .collect();
+                let name = shstr_offsets[shstr_offsets.len() - 2];
+                align(&mut out);
+                sections.push([name, 9, 0, out.len() as u32, rels.len() as u32, 0, 0, 8]); // SHT_REL
+                out.extend(rels);
+            }
+            sections.push([*shstr_offsets.last().unwrap(), 3, 0, out.len() as u32, shstrtab.len() as u32, 0, 0, 0]);
+            out.extend(shstrtab);
+
+            align(&mut out);
+            let shoff = out.len() as u32;
+            out.extend([0u8; 40]);
+            for [name, sh_type, addr, offset, size, link, info, entsize] in &sections {
+                for v in [*name, *sh_type, 0, *addr, *offset, *size, *link, *info, 4, *entsize] {
+                    out.extend(u32e(v));
+                }
+            }
+            for (i, phdr) in phdrs.iter().enumerate() {
+                let bytes: Vec<u8> = phdr.iter().flat_map(|v| u32e(*v)).collect();
+                out[phoff + 32 * i..phoff + 32 * (i + 1)].copy_from_slice(&bytes);
+            }
+
+            let ei_data = if little_endian { 1 } else { 2 };
+            let mut ehdr = vec![0x7f, b'E', b'L', b'F', 1, ei_data, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            ehdr.extend(u16e(if self.pie { 3 } else { 2 })); // ET_DYN or ET_EXEC
+            ehdr.extend(u16e(8)); // EM_MIPS
+            ehdr.extend(u32e(1));
+            ehdr.extend(u32e(self.entry));
+            ehdr.extend(u32e(phoff as u32));
+            ehdr.extend(u32e(shoff));
+            ehdr.extend(u32e(0));
+            for v in [52, 32, phdrs.len() as u32, 40, sections.len() as u32 + 1, sections.len() as u32] {
+                ehdr.extend(u16e(v));
+            }
+            out[..52].copy_from_slice(&ehdr);
+            out
+        }
+    }
+
+    /// Creates a state executing `program` from address 0.
+    fn load_program(program: &[u32]) -> Box<State> {
+        let mut state = State::new();
+        for (i, insn) in program.iter().enumerate() {
+            state.memory.set_memory(4 * i as u32, *insn).unwrap();
+        }
+        state
+    }
+
+    /// Executes a single syscall at the current pc, returns (v0, v1).
+    fn do_syscall(is: &mut InstrumentedState, num: u32, a0: u32, a1: u32, a2: u32) -> (u32, u32) {
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = num;
+        is.state.registers[4] = a0;
+        is.state.registers[5] = a1;
+        is.state.registers[6] = a2;
+        is.step(false).unwrap();
+        (is.state.registers[2], is.state.registers[7])
+    }
+
+    /// Sets the preimage key through the syscalls, returns the length prefixed preimage the
+    /// guest reads back.
+    fn read_preimage_via_syscalls(is: &mut InstrumentedState, key: [u8; 32]) -> Vec<u8> {
+        let buf = 0x10000;
+        is.state.memory.set_memory_range(buf, Box::new(key.as_slice())).unwrap();
+        for i in 0..8 {
+            let (v0, _) = do_syscall(is, 4004, FD_PREIMAGE_WRITE, buf + 4 * i, 4);
+            assert_eq!(v0, 4);
+        }
+
+        let mut out = vec![];
+        loop {
+            let (v0, _) = do_syscall(is, 4003, FD_PREIMAGE_READ, buf, 4);
+            if v0 == 0 {
+                break;
+            }
+            out.extend(&is.state.memory.get_memory(buf).to_be_bytes()[..v0 as usize]);
+        }
+        out
+    }
+
+    /// Writer appending to a buffer shared with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Oracle serving a fixed set of pre-images and recording the hints it receives.
+    #[derive(Default)]
+    struct RecordingOracle {
+        images: HashMap<[u8; 32], Vec<u8>>,
+        hints: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl PreimageOracle for RecordingOracle {
+        fn hint(&mut self, v: &[u8]) {
+            self.hints.lock().unwrap().push(v.to_vec());
+        }
+
+        fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+            self.images.get(&k).cloned().ok_or(EmulatorError::PreimageNotFound { key: k })
+        }
+    }
+
     struct TestOracle {
         images: HashMap<[u8; 32], Vec<u8>>,
         pre_hash: [u8; 32],
@@ -113,13 +561,13 @@ mod tests {
             }
         }
 
-        fn get_preimage(&self, k: [u8; 32]) -> Vec<u8> {
+        fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
             match self.images.get(&k) {
                 None => {
                     panic!("missing pre-image {:?}", k);
                 },
                 Some(preimage) => {
-                    preimage.to_vec()
+                    Ok(preimage.to_vec())
                 }
             }
         }
@@ -189,7 +637,7 @@ mod tests {
             if instrumented_state.state.pc == END_ADDR {
                 break;
             }
-            instrumented_state.step(true);
+            instrumented_state.step(true).unwrap();
         }
     }
 
@@ -215,7 +663,7 @@ mod tests {
         let (mut state, mut program) = State::load_elf(&file);
 
         state.patch_go(&file);
-        state.patch_stack();
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
 
         program.load_instructions(&mut state);
         let hash_of_program = program.compute_hash();
@@ -228,7 +676,7 @@ mod tests {
             if instrumented_state.state.exited {
                 break;
             }
-            instrumented_state.step(true);
+            instrumented_state.step(true).unwrap();
         }
     }
 
@@ -242,7 +690,7 @@ mod tests {
         let (mut state, mut program) = State::load_elf(&file);
 
         state.patch_go(&file);
-        state.patch_stack();
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
 
         program.load_instructions(&mut state);
         let hash_of_program = program.compute_hash();
@@ -255,7 +703,5044 @@ mod tests {
             if instrumented_state.state.exited {
                 break;
             }
-            instrumented_state.step(true);
+            instrumented_state.step(true).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_clone_state_shares_pages() {
+        let mut state = State::new();
+        for i in 0..10_000u32 {
+            state.memory.set_memory(i << 12, i).unwrap();
+        }
+        let root = state.memory.merkle_root();
+        let stats = state.memory.stats();
+        assert_eq!(stats.pages, 10_000);
+
+        let mut cloned = state.clone();
+        cloned.memory.set_memory(0x1000, 0xdeadbeef).unwrap();
+        let cloned_root = cloned.memory.merkle_root();
+
+        assert_eq!(state.memory.merkle_root(), root);
+        assert_ne!(cloned_root, root);
+        assert_eq!(state.memory.get_memory(0x1000), 1);
+        assert_eq!(cloned.memory.get_memory(0x1000), 0xdeadbeef);
+
+        let cloned_stats = cloned.memory.stats();
+        assert_eq!(cloned_stats.pages, stats.pages);
+        assert_eq!(cloned_stats.page_allocations, stats.page_allocations + 1);
+        assert_eq!(cloned_stats.page_copies, 1);
+        assert_eq!(state.memory.stats(), stats);
+    }
+
+    #[test]
+    fn test_hash_cloned_memory_in_parallel() {
+        let mut memory = Memory::new();
+        for i in 0..64u32 {
+            memory.set_memory(i << 12, i).unwrap();
+        }
+        let mut expected = memory.clone();
+        expected.set_memory(0x2000, 7).unwrap();
+        let expected = expected.merkle_root();
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let mut memory = memory.clone();
+            std::thread::spawn(move || {
+                memory.set_memory(0x2000, 7).unwrap();
+                memory.merkle_root()
+            })
+        }).collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_merkle_root_tracks_memory_content() {
+        let mut memory = Memory::new();
+        let empty_root = memory.merkle_root();
+        memory.set_memory(0x1000, 5).unwrap();
+        let root = memory.merkle_root();
+        assert_ne!(root, empty_root);
+        memory.set_memory(0x1000, 6).unwrap();
+        assert_ne!(memory.merkle_root(), root);
+        memory.set_memory(0x1000, 5).unwrap();
+        assert_eq!(memory.merkle_root(), root);
+    }
+
+    #[test]
+    fn test_merkle_proof_depth() {
+        // as in cannon the tree has 28 levels, down to the leaves of 32 bytes
+        let mut memory = Memory::new();
+        let zero_hashes = DEFAULT_MEMORY_HASH.zero_hashes();
+        assert_eq!(memory.merkle_root(), zero_hashes[27]);
+
+        let addr = 0x1234_5678;
+        memory.set_memory(addr, 0xdeadbeef).unwrap();
+        let root = memory.merkle_root();
+        let proof = memory.merkle_proof(addr);
+        let hasher = DEFAULT_MEMORY_HASH.hasher();
+        let mut node: [u8; 32] = proof[..32].try_into().unwrap();
+        assert_eq!(node[0x18..0x1c], 0xdeadbeefu32.to_be_bytes());
+        for i in 1..28 {
+            let sibling: [u8; 32] = proof[i * 32..(i + 1) * 32].try_into().unwrap();
+            node = if (addr >> (4 + i)) & 1 != 0 {
+                hasher.hash_pair(&sibling, &node)
+            } else {
+                hasher.hash_pair(&node, &sibling)
+            };
+        }
+        assert_eq!(node, root);
+        // the sibling leaf is empty, the last sibling is the empty upper half of the memory
+        assert_eq!(proof[32..64], zero_hashes[0]);
+        assert_eq!(proof[27 * 32..], zero_hashes[26]);
+    }
+
+    #[test]
+    fn test_words_in_range_across_pages() {
+        let mut memory = Memory::new();
+        memory.set_memory(0x1ff8, 1).unwrap();
+        memory.set_memory(0x1ffc, 2).unwrap();
+        let stats = memory.stats();
+
+        // unaligned at both ends, the second page is unmapped
+        let words: Vec<_> = memory.words_in_range(0x1ffa, 0xb).collect();
+        assert_eq!(words, [(0x1ff8, 1), (0x1ffc, 2), (0x2000, 0), (0x2004, 0)]);
+        assert_eq!(memory.stats(), stats);
+        assert_eq!(memory.words_in_range(0x1ffa, 0).count(), 0);
+        assert_eq!(memory.words_in_range(0xffff_fffc, 4).collect::<Vec<_>>(), [(0xffff_fffc, 0)]);
+    }
+
+    #[test]
+    fn test_unaligned_u32() {
+        let mut memory = Memory::new();
+        let bytes: Vec<u8> = (1..=12).collect();
+        memory.set_memory_range(0xffc, Box::new(bytes.as_slice())).unwrap();
+        for offset in 0..8 {
+            let i = offset as usize;
+            let expected = u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+            assert_eq!(memory.read_u32(0xffc + offset), expected, "offset {}", offset);
+        }
+
+        // straddling the words at 0x1000 and 0x1004, the bytes around are kept
+        memory.write_u32(0x1002, 0xaabbccdd).unwrap();
+        assert_eq!(memory.get_memory(0x1000), 0x0506aabb);
+        assert_eq!(memory.get_memory(0x1004), 0xccdd0b0c);
+        assert_eq!(memory.read_u32(0x1002), 0xaabbccdd);
+        memory.write_u32(0x1004, 0x01020304).unwrap();
+        assert_eq!(memory.get_memory(0x1004), 0x01020304);
+
+        // the address wraps at 2^32
+        memory.write_u32(0xffff_fffe, 0x11223344).unwrap();
+        assert_eq!(memory.get_memory(0xffff_fffc), 0x00001122);
+        assert_eq!(memory.get_memory(0), 0x33440000);
+        assert_eq!(memory.read_u32(0xffff_fffe), 0x11223344);
+
+        // a little endian memory reads the same bytes the other way round
+        let mut memory = Memory::new();
+        memory.set_endianness(Endianness::Little);
+        memory.set_memory_range(0xffc, Box::new(bytes.as_slice())).unwrap();
+        for offset in 0..8 {
+            let i = offset as usize;
+            let expected = u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+            assert_eq!(memory.read_u32(0xffc + offset), expected, "offset {}", offset);
+        }
+        memory.write_u32(0x1002, 0xaabbccdd).unwrap();
+        assert_eq!(memory.get_memory(0x1000), 0xccdd0605);
+        assert_eq!(memory.get_memory(0x1004), 0x0c0baabb);
+        assert_eq!(memory.read_u32(0x1002), 0xaabbccdd);
+    }
+
+    #[test]
+    fn test_memory_eq() {
+        let mut written = Memory::new();
+        written.set_memory(0x1000, 7).unwrap();
+        written.set_memory(0x5000, 9).unwrap();
+        let mut other = Memory::new();
+        other.set_memory(0x5000, 9).unwrap();
+        assert_ne!(written, other);
+        assert_ne!(written.content_hash(), other.content_hash());
+
+        // the zeroed page stays allocated, but equals the page never written
+        written.set_memory(0x1000, 0).unwrap();
+        assert_eq!(written.page_count(), 2);
+        assert_eq!(other.page_count(), 1);
+        assert_eq!(written, other);
+        assert_eq!(other, written);
+        assert_eq!(written.content_hash(), other.content_hash());
+        assert_eq!(Memory::new().content_hash(), Memory::new().content_hash());
+
+        // clones share their pages until written
+        let mut clone = written.clone();
+        assert_eq!(clone, written);
+        clone.set_memory(0x5004, 1).unwrap();
+        assert_ne!(clone, written);
+        assert_ne!(clone.content_hash(), written.content_hash());
+    }
+
+    #[test]
+    fn test_many_small_hints() {
+        let mut hints = Vec::<u8>::new();
+        for i in 0..1000 {
+            let payload = format!("hint-{}", i);
+            hints.extend((payload.len() as u32).to_be_bytes());
+            hints.extend(payload.as_bytes());
+        }
+
+        let mut state = State::new();
+        let hints_addr = 0x10000;
+        state.memory.set_memory_range(hints_addr, Box::new(hints.as_slice())).unwrap();
+        let oracle = RecordingOracle::default();
+        let received = oracle.hints.clone();
+        let mut is = InstrumentedState::new(state, Box::new(oracle));
+
+        // odd sized writes, so hints and their length prefixes are split across writes
+        let mut offset = 0;
+        while offset < hints.len() {
+            let count = 7.min(hints.len() - offset) as u32;
+            let (v0, v1) = do_syscall(&mut is, 4004, FD_HINT_WRITE, hints_addr + offset as u32, count);
+            assert_eq!((v0, v1), (count, 0));
+            offset += count as usize;
+        }
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1000);
+        for (i, hint) in received.iter().enumerate() {
+            assert_eq!(hint.as_slice(), format!("hint-{}", i).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_headless_output() {
+        let mut state = State::new();
+        state.memory.set_memory_range(0x10000, Box::new(&b"hello\n"[..])).unwrap();
+        let mut is = InstrumentedState::new_headless(state, Box::new(RecordingOracle::default()));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x10000, 6), (6, 0));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDERR, 0x10000, 6), (6, 0));
+
+        // the builder discards the output the same way
+        let mut is = InstrumentedStateBuilder::new(State::new()).with_output_discarded().build().unwrap();
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x10000, 1000), (1000, 0));
+    }
+
+    #[test]
+    fn test_write_rejects_bad_ranges() {
+        let oracle = Box::new(RecordingOracle::default());
+        let mut is = InstrumentedState::new_headless(State::new(), oracle);
+        for fd in [FD_STDOUT, FD_STDERR, FD_HINT_WRITE] {
+            // wraps the address space
+            assert_eq!(do_syscall(&mut is, 4004, fd, 0xfffffff0, 0x100), (SYSCALL_ERROR, EFAULT));
+            assert_eq!(do_syscall(&mut is, 4004, fd, 2, u32::MAX), (SYSCALL_ERROR, EFAULT));
+        }
+        // ends at the end of the address space, a short write of the cap
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0xfffffff0, 0x10), (0x10, 0));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 1, u32::MAX), (64 << 20, 0));
+        assert!(!is.state.exited);
+
+        // a short write over the cap, even if the output is discarded
+        let config = VmConfig { max_read_range: 16, ..Default::default() };
+        let mut is = InstrumentedStateBuilder::new(State::new())
+            .with_config(config)
+            .with_output_discarded()
+            .build()
+            .unwrap();
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x10000, 17), (16, 0));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x10000, 16), (16, 0));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0xfffffff0, 0x20), (SYSCALL_ERROR, EFAULT));
+
+        // the guest writing the rest sends the whole hint
+        let mut state = State::new();
+        let hint = [0, 0, 0, 4, 1, 2, 3, 4];
+        state.memory.set_memory_range(0x10000, Box::new(hint.as_slice())).unwrap();
+        let oracle = RecordingOracle::default();
+        let received = oracle.hints.clone();
+        let config = VmConfig { max_read_range: 5, ..Default::default() };
+        let mut is = InstrumentedStateBuilder::new(state)
+            .with_config(config)
+            .with_oracle(Box::new(oracle))
+            .build()
+            .unwrap();
+        assert_eq!(do_syscall(&mut is, 4004, FD_HINT_WRITE, 0x10000, 8), (5, 0));
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(do_syscall(&mut is, 4004, FD_HINT_WRITE, 0x10005, 3), (3, 0));
+        assert_eq!(*received.lock().unwrap(), vec![vec![1, 2, 3, 4]]);
+    }
+
+    /// Oracle serving a pre-image only once the hint naming its key was received, like a host
+    /// fetching the pre-images on demand.
+    #[derive(Default)]
+    struct HintedOracle {
+        source: HashMap<[u8; 32], Vec<u8>>,
+        fetched: HashMap<[u8; 32], Vec<u8>>,
+    }
+
+    impl PreimageOracle for HintedOracle {
+        fn hint(&mut self, v: &[u8]) {
+            let key: [u8; 32] = v.try_into().expect("the hint is the key of a pre-image");
+            if let Some(image) = self.source.get(&key) {
+                self.fetched.insert(key, image.clone());
+            }
+        }
+
+        fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+            self.fetched.get(&k).cloned().ok_or(EmulatorError::PreimageNotFound { key: k })
+        }
+    }
+
+    /// Oracle returning different data on every fetch, like a flaky remote oracle.
+    #[derive(Default)]
+    struct InconsistentOracle {
+        fetches: u8,
+    }
+
+    impl PreimageOracle for InconsistentOracle {
+        fn hint(&mut self, _v: &[u8]) {}
+
+        fn get_preimage(&mut self, _k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+            self.fetches += 1;
+            Ok(vec![self.fetches; 4])
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_oracle() {
+        let (a, b) = ([1; 32], [2; 32]);
+        let images = HashMap::from([(a, b"aaaa".to_vec()), (b, b"bbbb".to_vec())]);
+        let oracle = RecordingOracle { images, ..Default::default() };
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        for (key, image) in [(a, b"aaaa"), (b, b"bbbb"), (a, b"aaaa")] {
+            assert_eq!(read_preimage_via_syscalls(&mut is, key)[8..], *image);
+        }
+        // each key read is a fetch, then 3 more reads up to the end of the 12 bytes
+        let stats = PreimageCacheStats {
+            fetches: 3,
+            hits: 9,
+            keys: 2,
+            refetches: 1,
+            peak_retained: 12,
+        };
+        assert_eq!(is.preimage_cache_stats(), stats);
+
+        // a whole-preimage oracle is asked once per key read
+        let mut is = InstrumentedState::new(State::new(), Box::new(InconsistentOracle::default()));
+        assert_eq!(read_preimage_via_syscalls(&mut is, a)[8..], [1; 4]);
+        assert_eq!(read_preimage_via_syscalls(&mut is, b)[8..], [2; 4]);
+        // the key is set, the first read fetches its preimage again
+        is.state.memory.set_memory_range(0x10000, Box::new(a.as_slice())).unwrap();
+        for i in 0..8 {
+            assert_eq!(do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4).0, 4);
+        }
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = 4003;
+        is.state.registers[4] = FD_PREIMAGE_READ;
+        is.state.registers[5] = 0x10000;
+        is.state.registers[6] = 4;
+        let err = is.step(false).unwrap_err();
+        assert!(matches!(err, EmulatorError::OracleInconsistent { key } if key == a), "{}", err);
+    }
+
+    #[test]
+    fn test_resume_mid_preimage_read() {
+        let image = b"the pre-image read across a resume".to_vec();
+        let key = Keccak256Key([7; 32]).preimage_key();
+        let source = HashMap::from([(key, image)]);
+
+        let mut hint = 32u32.to_be_bytes().to_vec();
+        hint.extend(key);
+        let (hint_addr, key_addr, buf) = (0x10000, 0x11000, 0x12000);
+        let mut state = State::new();
+        state.memory.set_memory_range(hint_addr, Box::new(hint.as_slice())).unwrap();
+        state.memory.set_memory_range(key_addr, Box::new(key.as_slice())).unwrap();
+        let oracle = HintedOracle { source: source.clone(), ..Default::default() };
+        let mut is = InstrumentedState::new(state, Box::new(oracle));
+
+        assert_eq!(do_syscall(&mut is, 4004, FD_HINT_WRITE, hint_addr, hint.len() as u32).0, 36);
+        for i in 0..8 {
+            assert_eq!(do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, key_addr + 4 * i, 4).0, 4);
+        }
+        let read = |is: &mut InstrumentedState| {
+            let (v0, _) = do_syscall(is, 4003, FD_PREIMAGE_READ, buf, 4);
+            is.state.memory.get_memory(buf).to_be_bytes()[..v0 as usize].to_vec()
+        };
+        let mut head = vec![];
+        for _ in 0..3 {
+            head.extend(read(&mut is));
+        }
+
+        // the witness and the hints are what a host saves of the state
+        let witness = is.state.encode_witness();
+        let mut saved = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        saved.set_last_hint(is.state.last_hint().clone());
+        assert_eq!(saved.preimage_key(), key);
+        assert_eq!(saved.preimage_offset(), 12);
+        assert_eq!(saved.last_hint().last(), Some(&key[..]));
+
+        let oracle = HintedOracle { source, ..Default::default() };
+        let mut resumed = InstrumentedState::from_state(saved, Box::new(oracle));
+        let (mut tail, mut expected) = (vec![], vec![]);
+        loop {
+            let (a, b) = (read(&mut resumed), read(&mut is));
+            assert_eq!(a.len(), b.len());
+            if a.is_empty() {
+                break;
+            }
+            tail.extend(a);
+            expected.extend(b);
         }
+        assert_eq!(tail, expected);
+        head.extend(tail);
+        assert_eq!(head, is.last_preimage().2);
+        assert_eq!(resumed.last_preimage(), is.last_preimage());
+    }
+
+    #[test]
+    fn test_resume_with_partial_hint() {
+        let (a, b) = (Keccak256Key([1; 32]).preimage_key(), Keccak256Key([2; 32]).preimage_key());
+        let source = HashMap::from([(a, b"first".to_vec()), (b, b"second".to_vec())]);
+        let mut hints = 32u32.to_be_bytes().to_vec();
+        hints.extend(a);
+        hints.extend(32u32.to_be_bytes());
+        hints.extend(b);
+
+        let hints_addr = 0x20000;
+        let mut state = State::new();
+        state.memory.set_memory_range(hints_addr, Box::new(hints.as_slice())).unwrap();
+        let oracle = HintedOracle { source: source.clone(), ..Default::default() };
+        let mut is = InstrumentedState::new(state, Box::new(oracle));
+        // the first hint and the start of the second one
+        assert_eq!(do_syscall(&mut is, 4004, FD_HINT_WRITE, hints_addr, 56).0, 56);
+
+        let witness = is.state.encode_witness();
+        let encoded = is.state.last_hint().encode();
+        let mut saved = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        saved.set_last_hint(HintBuffer::decode(&encoded).unwrap());
+        assert_eq!(saved.last_hint().pending(), &hints[36..56]);
+        assert_eq!(saved.last_hint().last(), Some(&a[..]));
+
+        // the first hint is repeated on resume, the rest of the second one completes it
+        let oracle = HintedOracle { source: source.clone(), ..Default::default() };
+        let mut resumed = InstrumentedState::from_state(saved, Box::new(oracle));
+        assert_eq!(read_preimage_via_syscalls(&mut resumed, a)[8..], *b"first");
+        assert_eq!(do_syscall(&mut resumed, 4004, FD_HINT_WRITE, hints_addr + 56, 16).0, 16);
+        assert_eq!(read_preimage_via_syscalls(&mut resumed, b)[8..], *b"second");
+        assert!(resumed.state.last_hint().is_empty());
+
+        // every complete hint is repeated, not only the last one
+        let mut saved = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        saved.set_last_hint(resumed.state.last_hint().clone());
+        assert_eq!(saved.last_hint().hints(), [a.to_vec(), b.to_vec()]);
+        let oracle = HintedOracle { source: source.clone(), ..Default::default() };
+        let mut resumed = InstrumentedState::from_state(saved, Box::new(oracle));
+        assert_eq!(read_preimage_via_syscalls(&mut resumed, a)[8..], *b"first");
+        assert_eq!(read_preimage_via_syscalls(&mut resumed, b)[8..], *b"second");
+    }
+
+    #[test]
+    fn test_oversized_hint() {
+        let mut hints = Vec::<u8>::new();
+        hints.extend(2u32.to_be_bytes());
+        hints.extend(b"ok");
+        hints.extend(0xFFffFFffu32.to_be_bytes());
+
+        let mut state = State::new();
+        let hints_addr = 0x10000;
+        state.memory.set_memory_range(hints_addr, Box::new(hints.as_slice())).unwrap();
+        let oracle = RecordingOracle::default();
+        let received = oracle.hints.clone();
+        let config = VmConfig { max_hint_size: 16, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(oracle), config);
+
+        // the first write ends in the middle of the oversized prefix
+        let (v0, _) = do_syscall(&mut is, 4004, FD_HINT_WRITE, hints_addr, 8);
+        assert_eq!(v0, 8);
+        assert_eq!(is.hint_buffer_len(), 2);
+
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = 4004;
+        is.state.registers[4] = FD_HINT_WRITE;
+        is.state.registers[5] = hints_addr + 8;
+        is.state.registers[6] = 2;
+        let result = is.step(false);
+        assert!(matches!(result, Err(EmulatorError::OversizedHint { declared: 0xFFffFFff })));
+        assert_eq!(is.hint_buffer_len(), 4);
+        assert_eq!(*received.lock().unwrap(), [b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn test_run_with_cancel() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        // the state is not Send, it stays on the thread running it
+        let handle = std::thread::spawn(move || {
+            let program = [asm::addiu(8, 8, 1), asm::j(0), asm::nop()];
+            let mut is = InstrumentedState::new(load_program(&program), Box::new(EmptyPreimageOracle));
+            let cancelled = is.run_with_cancel(&flag, 1000).unwrap();
+            let resumed = is.run(300).unwrap();
+            (cancelled, resumed, is.state.step(), is.state.registers[8])
+        });
+
+        std::thread::sleep(Duration::from_millis(20));
+        let cancelled_at = Instant::now();
+        cancel.store(true, Ordering::Relaxed);
+        let (cancelled, resumed, step, counter) = handle.join().unwrap();
+        assert!(cancelled_at.elapsed() < Duration::from_secs(5));
+
+        let VmStatus::Cancelled { step: cancel_step } = cancelled.status else {
+            panic!("unexpected status {:?}", cancelled.status);
+        };
+        assert_eq!(cancel_step % 1000, 0);
+        assert_eq!(cancelled.steps, cancel_step);
+        assert_eq!(resumed.status, VmStatus::StepLimitReached);
+        assert_eq!(resumed.steps, 300);
+        assert_eq!(step, cancel_step + 300);
+        // addiu, j and its delay slot
+        assert_eq!(counter as u64, (step + 2) / 3);
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let program = [asm::addiu(8, 8, 1), asm::j(0), asm::nop()];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(EmptyPreimageOracle));
+        let cancel = AtomicBool::new(false);
+        let result = is.run_with_timeout(&cancel, Duration::from_millis(10), 100).unwrap();
+        assert!(matches!(result.status, VmStatus::Cancelled { step } if step == result.steps));
+        assert!(!is.state.exited);
+    }
+
+    #[test]
+    fn test_run_detects_livelock() {
+        // while (1);
+        let program = [asm::beq(0, 0, -1), asm::nop()];
+        let config =
+            VmConfig { livelock_interval: Some(DEFAULT_LIVELOCK_INTERVAL), ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program),
+            Box::new(EmptyPreimageOracle),
+            config,
+        );
+        let result = is.run(1_000_000).unwrap();
+        assert_eq!(result.status, VmStatus::LivelockSuspected { pc: 0, period: 64 });
+        assert!(result.steps < 1000);
+        assert!(!is.state.exited);
+
+        // the detector is off by default
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(EmptyPreimageOracle));
+        assert_eq!(is.run(10_000).unwrap().status, VmStatus::StepLimitReached);
+    }
+
+    #[test]
+    fn test_livelock_ignores_progressing_loop() {
+        // sums an array of 1M words, the registers differ at every sample
+        let (array, len) = (0x100000u32, 1u32 << 20);
+        let words: Vec<u8> = (0..len).flat_map(|i| i.to_be_bytes()).collect();
+        let mut program = vec![];
+        program.extend(asm::li(8, array));
+        program.extend(asm::li(11, array + 4 * len));
+        program.extend([
+            asm::lw(9, 8, 0),
+            asm::addiu(8, 8, 4),
+            asm::bne(8, 11, -3),
+            asm::addu(10, 10, 9),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(), // exit_group(0)
+        ]);
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(array, Box::new(words.as_slice())).unwrap();
+        let config =
+            VmConfig { livelock_interval: Some(DEFAULT_LIVELOCK_INTERVAL), ..Default::default() };
+        let mut is =
+            InstrumentedState::new_with_config(state, Box::new(EmptyPreimageOracle), config);
+        let result = is.run(5_000_000).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(0));
+        assert_eq!(is.state.registers[10], (0..len).fold(0u32, |sum, i| sum.wrapping_add(i)));
+    }
+
+    #[test]
+    fn test_run_reports_guest_panic() {
+        let first = "thread 'main' panicked at src/main.rs:7:5:\nsomething we";
+        let second = "nt wrong\nwith two lines\nnote: run with `RUST_BACKTRACE=1` \
+            environment variable to display a backtrace\n";
+        let data_addr = 0x10000;
+
+        let mut program = vec![];
+        program.extend(asm::li(5, data_addr));
+        program.extend([
+            asm::addiu(4, 0, 2),
+            asm::addiu(6, 0, first.len() as i16),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(), // write(stderr, first)
+            asm::addiu(5, 5, first.len() as i16),
+            asm::addiu(6, 0, second.len() as i16),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(), // write(stderr, second)
+            asm::addiu(4, 0, 101),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(), // exit_group(101)
+        ]);
+        let mut state = load_program(&program);
+        let data = [first, second].concat();
+        state.memory.set_memory_range(data_addr, Box::new(data.as_bytes())).unwrap();
+
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let result = is.run(100).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(101));
+        assert_eq!(result.steps, program.len() as u64);
+        assert_eq!(result.guest_panic, Some(GuestPanic {
+            message: String::from("something went wrong\nwith two lines"),
+            location: Some(String::from("src/main.rs:7:5")),
+        }));
+    }
+
+    #[test]
+    fn test_file_preimage_oracle() {
+        let dir = std::env::temp_dir().join(format!("preimages-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = b"hello preimage oracle, longer than one word".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        fs::write(dir.join(hex::encode(key)), &data).unwrap();
+
+        let mut is = InstrumentedState::new(State::new(), Box::new(FilePreimageOracle::new(&dir)));
+        let out = read_preimage_via_syscalls(&mut is, key);
+        assert_eq!(out[..8], (data.len() as u64).to_be_bytes());
+        assert_eq!(out[8..], data);
+
+        // an unknown key is reported with the file it was looked up in
+        let mut missing = key;
+        missing[31] ^= 1;
+        let mut oracle = FilePreimageOracle::new(&dir);
+        match oracle.get_preimage(missing) {
+            Err(EmulatorError::PreimageRead { key, path, .. }) => {
+                assert_eq!(key, missing);
+                assert_eq!(path, dir.join(hex::encode(missing)));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// main copies 8 bytes from `src` to `dst` with memcpy, then exits.
+    fn memcpy_program() -> ElfWriter {
+        let (main, memcpy, dst, src) = (0x400000, 0x400100, 0x10000, 0x10100);
+        let mut main_code = vec![];
+        main_code.extend(asm::li(4, dst));
+        main_code.extend(asm::li(5, src));
+        main_code.extend([
+            asm::addiu(6, 0, 8),
+            asm::jal(memcpy),
+            asm::nop(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let memcpy_code = [
+            asm::lw(8, 5, 0),
+            asm::sw(8, 4, 0),
+            asm::addiu(4, 4, 4),
+            asm::addiu(5, 5, 4),
+            asm::addiu(6, 6, -4),
+            asm::bne(6, 0, -6),
+            asm::nop(),
+            asm::jr(31),
+            asm::nop(),
+        ];
+        let mut text = asm::to_bytes(&main_code);
+        text.resize((memcpy - main) as usize, 0);
+        text.extend(asm::to_bytes(&memcpy_code));
+
+        ElfWriter::new(main)
+            .segment(main, text)
+            .segment(src, b"8 bytes!".to_vec())
+            .function("main", main, 4 * main_code.len() as u32)
+            .function("memcpy", memcpy, 4 * memcpy_code.len() as u32)
+            .object("src", src, 8)
+    }
+
+    #[test]
+    fn test_trace_bytes() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, mut program) = State::load_elf(&file);
+        program.load_instructions(&mut state);
+        assert!(!program.image.is_empty());
+
+        let mut registers = [0; 32];
+        registers[29] = 0x7fff0000;
+        let exec = vec![
+            ExecutionRow {
+                instruction: Instruction { addr: 0x400000, bytecode: asm::lw(8, 9, 4) },
+                step: 1,
+                registers,
+                pc: 0x400004,
+                next_pc: 0x400008,
+                heap: HEAP_START,
+                exited: false,
+                hi: 1,
+                lo: 2,
+            },
+            ExecutionRow { step: 2, exited: true, ..Default::default() },
+        ];
+        let mem = vec![
+            MemoryAccess { rw_counter: 1, addr: 0x400000, op: MemoryOperation::Fetch, value: 7, value_prev: 7 },
+            MemoryAccess { rw_counter: 2, addr: 0x10000, op: MemoryOperation::Write, value: 8, value_prev: 7 },
+            MemoryAccess { rw_counter: u64::MAX, addr: u32::MAX, op: MemoryOperation::Read, value: 0, value_prev: 0 },
+        ];
+        let trace = Trace { prog: *program.clone(), exec, mem };
+
+        let bytes = trace.to_bytes();
+        let decoded = Trace::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, trace);
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(Program::from_bytes(&program.to_bytes()).unwrap(), *program);
+        // the position of the bit iterator is not encoded, nor compared
+        let mut iterated = program.clone();
+        iterated.nth(40);
+        assert_eq!(Program::from_bytes(&iterated.to_bytes()).unwrap(), *iterated);
+
+        // truncated, trailing and corrupted encodings are rejected
+        assert!(Trace::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Trace::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        let mut corrupted = bytes.clone();
+        // the operation of the second to last access, after its rw counter and address
+        let op = bytes.len() - 2 * 21 + 12;
+        assert_eq!(corrupted[op], 1);
+        corrupted[op] = 3;
+        assert!(Trace::from_bytes(&corrupted).is_err());
+        assert!(Trace::from_bytes(b"MIPSRPLY").is_err());
+    }
+
+    #[test]
+    fn test_witness_stream_size() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let mut witnesses = vec![];
+        while !is.state.exited {
+            witnesses.push(*is.step(true).unwrap().0);
+        }
+
+        let mut writer = WitnessWriter::new(vec![]).unwrap();
+        for wit in &witnesses {
+            writer.write(wit).unwrap();
+        }
+        assert_eq!(writer.records(), witnesses.len() as u64);
+        let stream = writer.finish().unwrap();
+        let naive: usize = witnesses.iter().map(|wit| serde_json::to_vec(wit).unwrap().len()).sum();
+        assert!(naive >= 5 * stream.len(), "{} bytes, {} as json", stream.len(), naive);
+
+        let read = WitnessReader::new(stream.as_slice()).unwrap()
+            .collect::<std::io::Result<Vec<StepWitness>>>()
+            .unwrap();
+        assert_eq!(read, witnesses);
+        assert!(matches!(read.last().unwrap().kind, StepKind::Syscall(_)));
+
+        // a truncated record is an error rather than the end of the stream
+        let mut reader = WitnessReader::new(&stream[..stream.len() - 1]).unwrap();
+        assert!(reader.find(|wit| wit.is_err()).is_some());
+        assert!(WitnessReader::new(b"MIPSTRCE\x01\0\0\0\0".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_witness_stream() {
+        // stores a counter to consecutive words, forever
+        let program = [
+            asm::lui(8, 0x1000),
+            asm::addiu(9, 0, 0),
+            asm::sw(9, 8, 0),
+            asm::addiu(8, 8, 4),
+            asm::addiu(9, 9, 1),
+            asm::bne(9, 0, -4),
+            asm::nop(),
+        ];
+        let steps = 100_000;
+        let path = std::env::temp_dir().join(format!("witnesses-{}", std::process::id()));
+        let light_path = path.with_extension("light");
+        let new_state = || {
+            InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()))
+        };
+        for (path, proofs) in [(&path, true), (&light_path, false)] {
+            let mut is = new_state();
+            is.witness_to(path, proofs).unwrap();
+            assert_eq!(is.run(steps).unwrap().status, VmStatus::StepLimitReached);
+            is.finish_witness_stream().unwrap();
+        }
+
+        // the same steps again, each against its records, the light ones have no proofs
+        let mut is = new_state();
+        let mut reader = WitnessReader::open(&path).unwrap();
+        let mut light_reader = WitnessReader::open(&light_path).unwrap();
+        for _ in 0..steps {
+            let (mut wit, _, _) = is.step(true).unwrap();
+            assert_eq!(reader.next_witness().unwrap().as_ref(), Some(&*wit));
+            wit.state.clear();
+            wit.mem_proof.clear();
+            assert_eq!(light_reader.next_witness().unwrap().as_ref(), Some(&*wit));
+        }
+        assert!(reader.next_witness().unwrap().is_none());
+        assert!(light_reader.next_witness().unwrap().is_none());
+        assert!(fs::metadata(&light_path).unwrap().len() < fs::metadata(&path).unwrap().len());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&light_path).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_witness_stream_zstd() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let mut witnesses = vec![];
+        while !is.state.exited {
+            witnesses.push(*is.step(true).unwrap().0);
+        }
+
+        let write = |compression| {
+            let mut writer = WitnessWriter::with_compression(vec![], compression).unwrap();
+            for wit in &witnesses {
+                writer.write(wit).unwrap();
+            }
+            writer.finish().unwrap()
+        };
+        let plain = write(Compression::None);
+        let compressed = write(Compression::Zstd(3));
+        let (compressed_len, plain_len) = (compressed.len(), plain.len());
+        assert!(compressed_len < plain_len, "{} bytes, {} plain", compressed_len, plain_len);
+        let read = WitnessReader::new(compressed.as_slice()).unwrap()
+            .collect::<std::io::Result<Vec<StepWitness>>>()
+            .unwrap();
+        assert_eq!(read, witnesses);
+
+        // a truncated compressed stream is an error rather than the end of the stream
+        for cut in [1, 8, compressed.len() / 2] {
+            let mut reader = WitnessReader::new(&compressed[..compressed.len() - cut]).unwrap();
+            assert!(reader.find(|wit| wit.is_err()).is_some(), "cut {}", cut);
+        }
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let symbols = SymbolMap::load_elf(&file);
+        assert_eq!(symbols.lookup(0x400000), Some(("main", 0)));
+        assert_eq!(symbols.lookup(0x400108), Some(("memcpy", 8)));
+        assert_eq!(symbols.lookup(0x10104), Some(("src", 4)));
+        assert_eq!(symbols.lookup(0x400100 + 4 * 9), None);
+        assert_eq!(symbols.lookup(0x1000), None);
+        assert_eq!(symbols.annotate(0x400108), "0x400108 <memcpy+0x8>");
+        let functions: Vec<&str> = symbols.function_ranges().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(functions, ["main", "memcpy"]);
+
+        // the loaded state carries the symbols of its ELF
+        let (state, _) = State::load_elf(&file);
+        assert_eq!(state.symbols().unwrap().symbols(), symbols.symbols());
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.enable_profiling();
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.state.memory.get_memory(0x10000).to_be_bytes(), *b"8 by");
+        assert_eq!(is.state.memory.get_memory(0x10004).to_be_bytes(), *b"tes!");
+
+        // memcpy runs its 7 instructions loop twice, then returns
+        let report = is.profile().unwrap().by_function(is.symbols().unwrap());
+        assert_eq!(report, [(String::from("memcpy"), 16), (String::from("main"), 10)]);
+    }
+
+    #[test]
+    fn test_symbol_map_of_stripped_binary() {
+        let mut program = memcpy_program();
+        program.symbols.clear();
+        let data = program.build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let symbols = SymbolMap::load_elf(&file);
+        assert!(symbols.is_empty());
+        assert_eq!(symbols.lookup(0x400000), None);
+        assert_eq!(symbols.annotate(0x400000), "0x400000");
+
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert!(is.symbols().unwrap().is_empty());
+        assert_eq!(is.annotate_pc(0x400000), "0x400000");
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+    }
+
+    /// main calls outer, which calls inner; 13 steps run main up to after the call.
+    fn nested_calls_program() -> (Box<State>, SymbolMap) {
+        let mut program = vec![asm::nop(); 19];
+        program[0] = asm::jal(0x20);
+        program[2] = asm::addiu(8, 0, 1);
+        // outer saves $ra in $s0 around the call
+        program[8] = asm::addiu(29, 29, -8);
+        program[9] = asm::addu(16, 31, 0);
+        program[10] = asm::jal(0x40);
+        program[12] = asm::addu(31, 16, 0);
+        program[13] = asm::jr(31);
+        program[16] = asm::addiu(9, 0, 2);
+        program[17] = asm::jr(31);
+        let symbol = |name: &str, addr, size| Symbol { name: name.to_string(), addr, size, is_function: true };
+        let symbols = SymbolMap::from_symbols(vec![
+            symbol("main", 0, 0x20),
+            symbol("outer", 0x20, 0x20),
+            symbol("inner", 0x40, 0x10),
+        ]);
+        (load_program(&program), symbols)
+    }
+
+    #[test]
+    fn test_trace_export() {
+        let (state, symbols) = nested_calls_program();
+        let out = SharedBuffer::default();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.set_trace_exporter(Box::new(ChromeTraceExporter::new(out.clone(), symbols.clone())));
+        for _ in 0..13 {
+            is.step(false).unwrap();
+        }
+        is.finish_trace_export().unwrap();
+
+        let slices = |out: &SharedBuffer| -> Vec<String> {
+            let json: serde_json::Value = serde_json::from_slice(&out.0.lock().unwrap()).unwrap();
+            json["traceEvents"].as_array().unwrap().iter()
+                .map(|event| {
+                    let name = event["name"].as_str().unwrap();
+                    format!("{} {} {}", event["ph"].as_str().unwrap(), name, event["ts"])
+                })
+                .collect()
+        };
+        // the slices begin at the first instruction of the callee, and end after the delay
+        // slot of its return
+        assert_eq!(slices(&out), [
+            "B main 1", "B outer 3", "B inner 7", "E inner 10", "E outer 13", "E main 14",
+        ]);
+
+        // after a rewind, the slices of the first run end and the second run follows it
+        let (state, symbols) = nested_calls_program();
+        let out = SharedBuffer::default();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let snapshot = is.snapshot();
+        is.set_trace_exporter(Box::new(ChromeTraceExporter::new(out.clone(), symbols.clone())));
+        for _ in 0..9 {
+            is.step(false).unwrap();
+        }
+        is.reset_to(&snapshot);
+        for _ in 0..13 {
+            is.step(false).unwrap();
+        }
+        is.finish_trace_export().unwrap();
+        assert_eq!(slices(&out), [
+            "B main 1", "B outer 3", "B inner 7", "E inner 10", "E outer 10", "E main 10",
+            "B main 10", "B outer 12", "B inner 16", "E inner 19", "E outer 22", "E main 23",
+        ]);
+
+        let (state, symbols) = nested_calls_program();
+        let out = SharedBuffer::default();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.set_trace_exporter(Box::new(QemuExporter::new(out.clone(), symbols)));
+        for _ in 0..13 {
+            is.step(false).unwrap();
+        }
+        is.finish_trace_export().unwrap();
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let instructions = text.lines().filter(|line| line.starts_with("0x")).count();
+        assert_eq!(instructions as u64, is.state.step());
+        let blocks: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix("IN: ")).collect();
+        assert_eq!(blocks, ["main", "outer", "inner", "outer", "main"]);
+
+        // a reset starts a new block, even at the pc after the last one
+        let (state, symbols) = nested_calls_program();
+        let out = SharedBuffer::default();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.set_trace_exporter(Box::new(QemuExporter::new(out.clone(), symbols)));
+        for _ in 0..3 {
+            is.step(false).unwrap();
+        }
+        let (mut state, _) = nested_calls_program();
+        is.reset(0x24);
+        for addr in (0..0x4c).step_by(4) {
+            is.state.memory.set_memory(addr, state.memory.get_memory(addr)).unwrap();
+        }
+        is.step(false).unwrap();
+        is.finish_trace_export().unwrap();
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let blocks: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix("IN: ")).collect();
+        assert_eq!(blocks, ["main", "outer", "outer"]);
+        assert!(text.starts_with("----------------\nIN: main\n0x00000000:  0c000008  jal\n0x00000004:  00000000  nop\n\n"));
+    }
+
+    #[test]
+    fn test_typed_preimage_oracle() {
+        let global = b"global data".to_vec();
+        let keccak_key = Keccak256Key(Keccak256::digest(&global).into()).preimage_key();
+        let mut store = RecordingOracle::default();
+        store.images.insert(keccak_key, global.clone());
+
+        let mut oracle = TypedPreimageOracle::new(Box::new(store));
+        let local = b"local data".to_vec();
+        let local_key = oracle.insert_local(1, local.clone());
+        assert_eq!(local_key[0], 1);
+        assert_eq!(keccak_key[0], 2);
+
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        assert_eq!(read_preimage_via_syscalls(&mut is, local_key)[8..], local);
+        assert_eq!(read_preimage_via_syscalls(&mut is, keccak_key)[8..], global);
+
+        // the local data is not served for a keccak key with the same content
+        let mut oracle = TypedPreimageOracle::new(Box::new(RecordingOracle::default()));
+        let mut key = oracle.insert_local(1, local);
+        key[0] = 2;
+        assert!(matches!(oracle.get_preimage(key), Err(EmulatorError::PreimageNotFound { .. })));
+        key[0] = 9;
+        assert!(matches!(oracle.get_preimage(key), Err(EmulatorError::UnsupportedKeyType { .. })));
+    }
+
+    /// Runs a guest reading 5 and 16 random bytes to unaligned buffers, returns the bytes and
+    /// the final state hash.
+    fn run_getrandom(seed: [u8; 32]) -> (Vec<u8>, [u8; 32]) {
+        let mut program = vec![];
+        for (buf, len) in [(0x10001, 5), (0x10103, 16)] {
+            program.extend(asm::li(4, buf));
+            program.extend([
+                asm::addiu(5, 0, len),
+                asm::addiu(6, 0, 0),
+                asm::addiu(2, 0, 4353),
+                asm::syscall(),
+            ]);
+        }
+        program.extend([asm::addiu(4, 0, 0), asm::addiu(2, 0, 4246), asm::syscall()]);
+
+        let config = VmConfig { random_seed: seed, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.state.random_position(), 21);
+
+        let mut bytes = vec![];
+        for addr in [0x10000, 0x10004, 0x10100, 0x10104, 0x10108, 0x1010c, 0x10110] {
+            bytes.extend(is.state.memory.get_memory(addr).to_be_bytes());
+        }
+        (bytes, is.state_hash())
+    }
+
+    #[test]
+    fn test_getrandom_is_deterministic() {
+        let (bytes, hash) = run_getrandom([1; 32]);
+        assert_eq!(run_getrandom([1; 32]), (bytes.clone(), hash));
+        // the bytes around the buffers are untouched
+        assert_eq!(bytes[0], 0);
+        assert_eq!(bytes[6..11], [0; 5]);
+        assert_eq!(bytes[27], 0);
+
+        let (other_bytes, other_hash) = run_getrandom([2; 32]);
+        assert_ne!(bytes, other_bytes);
+        assert_ne!(hash, other_hash);
+    }
+
+    #[test]
+    fn test_getrandom_spans_pages() {
+        let config = VmConfig { random_seed: [3; 32], ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            State::new(), Box::new(RecordingOracle::default()), config);
+        is.state.memory.set_memory(0x20ffc, 0xaabbccdd).unwrap();
+        is.state.memory.set_memory(0x21ffc, 0x11223344).unwrap();
+
+        assert_eq!(do_syscall(&mut is, 4353, 0x20ffe, 4097, 0), (4097, 0));
+        assert_eq!(is.state.random_position(), 4097);
+
+        let mut expected = vec![0u8; 4097];
+        crate::random::fill(&[3; 32], 0, &mut expected);
+        let mut written = vec![];
+        written.extend(&is.state.memory.get_memory(0x20ffc).to_be_bytes()[2..]);
+        for addr in (0x21000..0x21ffc).step_by(4) {
+            written.extend(is.state.memory.get_memory(addr).to_be_bytes());
+        }
+        written.extend(&is.state.memory.get_memory(0x21ffc).to_be_bytes()[..3]);
+        assert_eq!(written, expected);
+        // the bytes around the buffer are untouched
+        assert_eq!(is.state.memory.get_memory(0x20ffc) >> 16, 0xaabb);
+        assert_eq!(is.state.memory.get_memory(0x21ffc) & 0xff, 0x44);
+    }
+
+    #[test]
+    fn test_getrandom_resumes_with_metadata() {
+        let config = VmConfig { random_seed: [4; 32], ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            State::new(), Box::new(RecordingOracle::default()), config.clone());
+        assert_eq!(do_syscall(&mut is, 4353, 0x10000, 8, 0), (8, 0));
+
+        // the metadata is saved next to the witness, the resumed state continues the stream
+        let witness = is.state.encode_witness();
+        let metadata = StateMetadata::decode(&is.state.metadata().encode()).unwrap();
+        assert_eq!(metadata, StateMetadata { random_position: 8, break_code: None });
+        assert!(StateMetadata::decode(&[0; 7]).is_none());
+        let mut saved = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        saved.set_metadata(metadata);
+        let mut resumed = InstrumentedState::new_with_config(
+            saved, Box::new(RecordingOracle::default()), config);
+        assert_eq!(do_syscall(&mut resumed, 4353, 0x10008, 8, 0), (8, 0));
+
+        let mut expected = [0; 16];
+        crate::random::fill(&[4; 32], 0, &mut expected);
+        assert_eq!(resumed.state.memory.read_u32(0x10008).to_be_bytes(), expected[8..12]);
+        assert_eq!(resumed.state.memory.read_u32(0x1000c).to_be_bytes(), expected[12..16]);
+    }
+
+    #[test]
+    fn test_patch_stack_is_seeded() {
+        let at_random = |seed: &[u8; 32]| {
+            let mut state = State::new();
+            state.patch_stack(seed).unwrap();
+            let addr = state.memory.read_u32(STACK_POINTER + 4 * 7);
+            let words = (0..4).map(|i| state.memory.read_u32(addr + 4 * i));
+            words.flat_map(u32::to_be_bytes).collect::<Vec<_>>()
+        };
+        assert_eq!(at_random(&[1; 32]), crate::random::at_random(&[1; 32]));
+        assert_ne!(at_random(&[1; 32]), at_random(&[2; 32]));
+    }
+
+    #[test]
+    fn test_patch_stack_out_of_layout() {
+        let mut state = State::new();
+        state.layout.stack_base = STACK_POINTER;
+        match state.patch_stack(&[0; 32]) {
+            Err(EmulatorError::Layout(LayoutError::OutOfRegion { .. })) => {}
+            r => panic!("expected the stack out of its region, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_sha256_preimage_key() {
+        let data = b"sha256 data".to_vec();
+        let key = Sha256Key(sha2::Sha256::digest(&data).into()).preimage_key();
+        // the type byte of the spec, 5 is the one of the blob keys
+        assert_eq!(key[0], 4);
+        assert_eq!(key[1..], sha2::Sha256::digest(&data)[1..]);
+        let mut oracle = TypedPreimageOracle::new(Box::new(RecordingOracle::default()));
+        oracle.insert(key, data.clone()).unwrap();
+
+        // the data does not match the key under keccak256, nor other data under sha256
+        let keccak_key = Keccak256Key(sha2::Sha256::digest(&data).into()).preimage_key();
+        assert!(matches!(oracle.insert(keccak_key, data.clone()),
+            Err(EmulatorError::PreimageHashMismatch { .. })));
+        let mut wrong_key = key;
+        wrong_key[31] ^= 1;
+        assert!(matches!(oracle.insert(wrong_key, data.clone()),
+            Err(EmulatorError::PreimageHashMismatch { .. })));
+
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        assert_eq!(read_preimage_via_syscalls(&mut is, key)[8..], data);
+    }
+
+    #[test]
+    fn test_replay_from_preimage_log() {
+        // the first preimage is longer than a window
+        let long: Vec<u8> = (0..PREIMAGE_WINDOW_SIZE as u32 + 100).map(|i| i as u8).collect();
+        let mut oracle = RecordingOracle::default();
+        let keys = [long, b"short".to_vec()].map(|data| {
+            let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+            oracle.images.insert(key, data);
+            key
+        });
+        let read_all = |is: &mut InstrumentedState| {
+            keys.map(|key| read_preimage_via_syscalls(is, key))
+        };
+
+        let mut is = InstrumentedStateBuilder::new(State::new())
+            .with_oracle(Box::new(oracle))
+            .with_preimage_log()
+            .build()
+            .unwrap();
+        let read = read_all(&mut is);
+        assert_eq!(read[1], [5u64.to_be_bytes().as_slice(), b"short"].concat());
+        let log = is.preimage_log().unwrap().to_vec();
+        assert!(log.iter().all(|served| keys.contains(&served.key)));
+        // the preimages of an oracle not serving parts are fetched and logged whole, once
+        assert_eq!(log.len(), 2);
+        let whole = |(served, read): (&crate::pre_image::ServedPreimage, &Vec<u8>)| {
+            served.offset == 0 && served.data == *read
+        };
+        assert!(log.iter().zip(&read).all(whole));
+
+        let config = VmConfig::default();
+        let mut replayed = InstrumentedState::from_preimage_log(State::new(), &log, config);
+        assert_eq!(read_all(&mut replayed), read);
+        assert_eq!(replayed.state_hash(), is.state_hash());
+        assert_eq!(replayed.state.registers, is.state.registers);
+
+        // the log serves only the parts the run fetched
+        let mut oracle = PreimageLogOracle::new(&log);
+        assert_eq!(oracle.preimage_len(keys[1]).unwrap(), 5);
+        assert_eq!(oracle.get_preimage(keys[1]).unwrap(), b"short");
+        assert_eq!(oracle.get_preimage_part(keys[0], 4000, 10).unwrap(), read[0][4008..4018]);
+        let missing = oracle.preimage_len([7; 32]);
+        assert!(matches!(missing, Err(EmulatorError::PreimageNotFound { .. })));
+
+        // a forged log is refused, and so is a hash key whose whole preimage it doesn't hold
+        let mut forged = log.clone();
+        forged[1].data = [5u64.to_be_bytes().as_slice(), b"forge"].concat();
+        let mut oracle = PreimageLogOracle::new(&forged);
+        assert!(matches!(oracle.get_preimage_part(keys[1], 0, 5),
+            Err(EmulatorError::PreimageHashMismatch { .. })));
+        let mut partial = log.clone();
+        partial[0].data.truncate(100);
+        let mut oracle = PreimageLogOracle::new(&partial);
+        assert!(matches!(oracle.get_preimage_part(keys[0], 0, 10),
+            Err(EmulatorError::PreimageNotFound { .. })));
+        // logging is off unless enabled
+        assert!(replayed.preimage_log().is_none());
+    }
+
+    #[test]
+    fn test_store_preimages_are_verified() {
+        let data = b"stored data".to_vec();
+        let sha256_key = Sha256Key(sha2::Sha256::digest(&data).into()).preimage_key();
+        let mut wrong_key = sha256_key;
+        wrong_key[1] ^= 1;
+        // the identity precompile returns its input
+        let mut precompile_input = vec![0u8; 20];
+        precompile_input[19] = 4;
+        precompile_input.extend(&data);
+        let precompile_key = PrecompileKey(Keccak256::digest(&precompile_input).into()).preimage_key();
+
+        let mut store = RecordingOracle::default();
+        store.images.insert(sha256_key, data.clone());
+        store.images.insert(wrong_key, data.clone());
+        store.images.insert(precompile_key, data.clone());
+        let mut oracle = TypedPreimageOracle::new(Box::new(store));
+
+        assert_eq!(oracle.get_preimage(sha256_key).unwrap(), data);
+        assert_eq!(oracle.get_preimage(precompile_key).unwrap(), data);
+        assert!(matches!(oracle.get_preimage(wrong_key),
+            Err(EmulatorError::PreimageHashMismatch { .. })));
+    }
+
+    #[test]
+    fn test_journal_records_syscalls() {
+        let data = b"preimage".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let (key_addr, buf) = (0x10000, 0x10100);
+
+        // write the key to the preimage fd word by word, then read the 16 bytes of the
+        // length prefixed preimage and exit
+        let mut program = vec![];
+        let mut syscall = |num: i16, a0: i16, a1: u32| {
+            program.extend(asm::li(5, a1));
+            program.extend([
+                asm::addiu(4, 0, a0),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, num),
+                asm::syscall(),
+            ]);
+        };
+        for i in 0..8 {
+            syscall(4004, FD_PREIMAGE_WRITE as i16, key_addr + 4 * i);
+        }
+        for i in 0..4 {
+            syscall(4003, FD_PREIMAGE_READ as i16, buf + 4 * i);
+        }
+        syscall(4246, 0, 0);
+
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(key_addr, Box::new(key.as_slice())).unwrap();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+        let config = VmConfig {
+            journal: Some(JournalConfig { capacity: 100, pc_interval: 10 }),
+            ..Default::default()
+        };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(oracle), config);
+
+        let path = std::env::temp_dir().join(format!("journal-{}.jsonl", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        is.journal_mut().unwrap().set_sink(Box::new(JsonlSink::new(file)));
+        let result = is.run(1000).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(0));
+
+        let journal = is.journal().unwrap();
+        let syscalls: Vec<(u32, u32, u32)> = journal.replay_filter(EventKind::Syscall)
+            .map(|e| match e {
+                Event::Syscall { num, args, v0, .. } => (*num, args[0], *v0),
+                _ => unreachable!(),
+            })
+            .collect();
+        let mut expected = vec![(4004, FD_PREIMAGE_WRITE, 4); 8];
+        expected.extend([(4003, FD_PREIMAGE_READ, 4); 4]);
+        expected.push((4246, 0, 4246));
+        assert_eq!(syscalls, expected);
+
+        let keys: Vec<&Event> = journal.replay_filter(EventKind::PreimageKey).collect();
+        assert_eq!(keys, [&Event::PreimageKey { step: 54, key }]);
+        let exits: Vec<&Event> = journal.replay_filter(EventKind::Exit).collect();
+        assert_eq!(exits, [&Event::Exit { step: result.steps, code: 0 }]);
+        assert_eq!(journal.replay_filter(EventKind::Pc).count() as u64, result.steps / 10);
+
+        // both the file sink and the exporter round trip through the reader
+        let events: Vec<Event> = journal.events().cloned().collect();
+        let file = fs::File::open(&path).unwrap();
+        assert_eq!(read_jsonl(std::io::BufReader::new(file)).unwrap(), events);
+        let mut out = vec![];
+        journal.to_jsonl(&mut out).unwrap();
+        assert_eq!(read_jsonl(out.as_slice()).unwrap(), events);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_flushes_writers() {
+        let message = b"written right before exit\n";
+        let mut program = asm::li(5, 0x10000).to_vec();
+        for fd in [1, 2] {
+            program.extend([
+                asm::addiu(4, 0, fd),
+                asm::addiu(6, 0, message.len() as i16),
+                asm::addiu(2, 0, 4004),
+                asm::syscall(),
+            ]);
+        }
+        program.extend([asm::addiu(4, 0, 0), asm::addiu(2, 0, 4246), asm::syscall()]);
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(0x10000, Box::new(message.as_slice())).unwrap();
+
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.set_stdout_writer(Box::new(std::io::BufWriter::with_capacity(1024, stdout.clone())));
+        is.set_stderr_writer(Box::new(std::io::BufWriter::with_capacity(1024, stderr.clone())));
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), message);
+        assert_eq!(stderr.0.lock().unwrap().as_slice(), message);
+    }
+
+    #[test]
+    fn test_max_host_pages() {
+        // store a word to every page of 100 MiB
+        let (base, pages) = (0x10000000, 100 << 8);
+        let mut program = vec![];
+        program.extend(asm::li(8, base));
+        program.extend(asm::li(9, pages));
+        program.extend([
+            asm::sw(9, 8, 0),
+            asm::addiu(8, 8, 4096),
+            asm::addiu(9, 9, -1),
+            asm::bne(9, 0, -4),
+            asm::nop(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+
+        // 10 MiB, including the page of the program
+        let config = VmConfig { max_host_pages: Some(10 << 8), ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        let result = is.run(1_000_000).unwrap();
+        let addr = base + (2559 << 12);
+        assert_eq!(result.status, VmStatus::HostOom { addr, pages: 2560 });
+        assert_eq!(is.state.memory.page_count(), 2560);
+
+        // the store keeps failing without allocating
+        match is.step(false) {
+            Err(e @ EmulatorError::HostOom { .. }) => {
+                let message = "out of host memory at 0x109ff000, 2560 pages allocated by sw $9, 0($8) at 0x10";
+                assert!(e.to_string().starts_with(message), "{}", e);
+            }
+            _ => panic!("expected host oom"),
+        }
+        assert_eq!(is.state.memory.page_count(), 2560);
+    }
+
+    #[test]
+    fn test_writev() {
+        let mut is = InstrumentedState::new(State::new(), Box::new(RecordingOracle::default()));
+        let stdout = SharedBuffer::default();
+        is.set_stdout_writer(Box::new(stdout.clone()));
+        is.state.memory.set_memory_range(0x10000, Box::new(b"hello ".as_slice())).unwrap();
+        // the second buffer spans two pages
+        is.state.memory.set_memory_range(0x10ffa, Box::new(b"vectored io".as_slice())).unwrap();
+        let iov = [0x10000, 6, 0x10100, 0, 0x10ffa, 11];
+        for (i, v) in iov.iter().enumerate() {
+            is.state.memory.set_memory(0x20000 + 4 * i as u32, *v).unwrap();
+        }
+
+        assert_eq!(do_syscall(&mut is, 4146, 1, 0x20000, 3), (17, 0));
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"hello vectored io");
+
+        // a bad fd fails before anything is written
+        assert_eq!(do_syscall(&mut is, 4146, 9, 0x20000, 3), (0xFFffFFff, 9));
+        // readv from the hint response reads everything
+        assert_eq!(do_syscall(&mut is, 4145, 3, 0x20000, 3), (17, 0));
+    }
+
+    /// Reads the whole length prefixed preimage of `data` to 0x100000, returns the steps and the
+    /// bytes read.
+    fn run_preimage_reader(data: &[u8], wide_preimage_io: bool) -> (u64, Vec<u8>) {
+        let key = Keccak256Key(Keccak256::digest(data).into()).preimage_key();
+        let (key_addr, buf) = (0x10000, 0x100000);
+
+        let mut program = vec![];
+        for i in 0..8 {
+            program.extend(asm::li(5, key_addr + 4 * i));
+            program.extend([
+                asm::addiu(4, 0, FD_PREIMAGE_WRITE as i16),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, 4004),
+                asm::syscall(),
+            ]);
+        }
+        // read until the end of the preimage, in requests of the whole preimage
+        program.extend(asm::li(5, buf));
+        program.extend(asm::li(16, data.len() as u32 + 8));
+        program.extend([
+            asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+            asm::addu(6, 16, 0),
+            asm::addiu(2, 0, 4003),
+            asm::syscall(),
+            asm::addu(5, 5, 2),
+            asm::bne(2, 0, -6),
+            asm::nop(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(key_addr, Box::new(key.as_slice())).unwrap();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data.to_vec());
+        let config = VmConfig { wide_preimage_io, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(oracle), config);
+        let result = is.run(1_000_000).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(0));
+
+        let len = data.len() as u32 + 8;
+        let out = (buf..buf + len).step_by(4)
+            .flat_map(|addr| is.state.memory.get_memory(addr).to_be_bytes())
+            .collect();
+        (result.steps, out)
+    }
+
+    #[test]
+    fn test_wide_preimage_reads() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 7 + i / 256) as u8).collect();
+        let (narrow_steps, narrow) = run_preimage_reader(&data, false);
+        let (wide_steps, wide) = run_preimage_reader(&data, true);
+        assert_eq!(narrow, wide);
+        assert_eq!(narrow[..8], (data.len() as u64).to_be_bytes());
+        assert_eq!(narrow[8..], data);
+
+        // 8 key writes, then one read per word, plus the final empty read
+        let setup = 8 * 6 + 4;
+        assert_eq!(narrow_steps, setup + 7 * (16386 + 1) + 3);
+        assert_eq!(wide_steps, setup + 7 * 2 + 3);
+    }
+
+    #[test]
+    fn test_wide_preimage_read_proofs() {
+        let data = b"twenty bytes of data".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+        let config = VmConfig { wide_preimage_io: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(State::new(), Box::new(oracle), config);
+        is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        for i in 0..8 {
+            do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4);
+        }
+
+        // read the 28 bytes to an unaligned address with proofs
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = 4003;
+        is.state.registers[4] = FD_PREIMAGE_READ;
+        is.state.registers[5] = 0x20002;
+        is.state.registers[6] = 100;
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(is.state.registers[2], 28);
+
+        // the words 0x20000..0x2001c, 0x2001c is partially written
+        let extra: Vec<u32> = (1..8).map(|i| 0x20000 + 4 * i).collect();
+        assert_eq!(wit.extra_mem_accesses, extra);
+        assert_eq!(wit.mem_proof.len(), 28 * 32 * (2 + extra.len()));
+        assert_eq!(wit.preimage_key, key);
+        assert_eq!(wit.preimage_offset, 0);
+        assert_eq!(is.state.memory.get_memory(0x20000) & 0xffff, 0);
+        assert_eq!(is.state.memory.get_memory(0x2001c).to_be_bytes(), [b't', b'a', 0, 0]);
+    }
+
+    #[test]
+    fn test_lseek_preimage() {
+        let data = b"0123456789abcdef".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        for i in 0..8 {
+            do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4);
+        }
+
+        // skip the length prefix
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, 8, SEEK_SET), (8, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+        assert_eq!(&is.state.memory.get_memory(0x20000).to_be_bytes(), b"0123");
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, 4, SEEK_CUR), (16, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+        assert_eq!(&is.state.memory.get_memory(0x20000).to_be_bytes(), b"89ab");
+
+        // past the end reads nothing
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, 100, SEEK_SET), (100, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (0, 0));
+
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, 0, 2), (0xFFffFFff, EINVAL));
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, -1i32 as u32, SEEK_SET),
+                   (0xFFffFFff, EINVAL));
+        assert_eq!(do_syscall(&mut is, 4019, FD_STDIN, 0, SEEK_SET), (0xFFffFFff, ESPIPE));
+        assert_eq!(do_syscall(&mut is, 4019, 9, 0, SEEK_SET), (0xFFffFFff, EBADF));
+    }
+
+    /// Returns the byte `i` of the synthetic preimage of `PartOracle`.
+    fn synthetic_byte(i: u64) -> u8 {
+        (i * 7 + i / 251) as u8
+    }
+
+    /// Oracle serving a synthetic preimage of `len` bytes by parts, never whole.
+    struct PartOracle {
+        key: [u8; 32],
+        len: u64,
+    }
+
+    impl PreimageOracle for PartOracle {
+        fn hint(&mut self, _v: &[u8]) {}
+
+        fn get_preimage(&mut self, _k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+            panic!("the preimage is only served by parts");
+        }
+
+        fn serves_parts(&self) -> bool {
+            true
+        }
+
+        fn preimage_len(&mut self, k: [u8; 32]) -> Result<u64, EmulatorError> {
+            if k != self.key {
+                return Err(EmulatorError::PreimageNotFound { key: k });
+            }
+            Ok(self.len)
+        }
+
+        fn get_preimage_part(
+            &mut self,
+            k: [u8; 32],
+            offset: u64,
+            len: usize,
+        ) -> Result<Vec<u8>, EmulatorError> {
+            assert_eq!(k, self.key);
+            let end = self.len.min(offset + len as u64);
+            Ok((offset..end).map(synthetic_byte).collect())
+        }
+    }
+
+    #[test]
+    fn test_streamed_preimage() {
+        let len: u64 = 64 << 20;
+        let key = LocalIndexKey(1).preimage_key();
+        let prefixed_byte = |i: u64| {
+            if i < 8 { len.to_be_bytes()[i as usize] } else { synthetic_byte(i - 8) }
+        };
+        let new_state = |config: VmConfig| {
+            let oracle = PartOracle { key, len };
+            let mut is = InstrumentedState::new_with_config(State::new(), Box::new(oracle), config);
+            is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+            for i in 0..8 {
+                do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4);
+            }
+            is
+        };
+
+        // the whole preimage, in reads of 1 MiB
+        let mut is = new_state(VmConfig { wide_preimage_io: true, ..Default::default() });
+        let (buf, mut offset) = (0x100000, 0);
+        loop {
+            let (n, _) = do_syscall(&mut is, 4003, FD_PREIMAGE_READ, buf, 1 << 20);
+            if n == 0 {
+                break;
+            }
+            let read = is.state.memory.words_in_range(buf, n)
+                .flat_map(|(_, word)| word.to_be_bytes())
+                .take(n as usize);
+            assert!(read.eq((offset..offset + n as u64).map(prefixed_byte)), "at {}", offset);
+            offset += n as u64;
+        }
+        assert_eq!(offset, len + 8);
+
+        // every window is fetched once, only one is kept. The 8 bytes past the last full window
+        // are in its overlap.
+        let stats = is.preimage_cache_stats();
+        let windows = len / PREIMAGE_WINDOW_SIZE as u64;
+        assert_eq!((stats.fetches, stats.refetches, stats.keys), (windows, 0, 1));
+        assert!(stats.peak_retained <= PREIMAGE_WINDOW_SIZE + PREIMAGE_WINDOW_OVERLAP);
+        let (cached_key, start, window) = is.last_preimage();
+        assert_eq!((cached_key, start as u64), (key, len - PREIMAGE_WINDOW_SIZE as u64));
+        assert_eq!(window.len(), PREIMAGE_WINDOW_SIZE + 8);
+
+        // a word read across two windows still reads the whole word
+        let mut is = new_state(VmConfig::default());
+        let boundary = 3 * PREIMAGE_WINDOW_SIZE as u32;
+        let offset = boundary - 2;
+        assert_eq!(do_syscall(&mut is, 4019, FD_PREIMAGE_READ, offset, SEEK_SET), (offset, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+        let expected: Vec<u8> = (offset as u64..offset as u64 + 4).map(prefixed_byte).collect();
+        assert_eq!(is.state.memory.get_memory(0x20000).to_be_bytes().to_vec(), expected);
+        assert_eq!(is.preimage_cache_stats().fetches, 1);
+    }
+
+    #[test]
+    fn test_whole_preimage_fetched_once() {
+        let data: Vec<u8> = (0..3 * PREIMAGE_WINDOW_SIZE as u32).map(|i| i as u8).collect();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let new_state = |parts: bool| {
+            let images = HashMap::from([(key, data.clone())]);
+            let oracle = CountingOracle { images, calls: calls.clone(), parts };
+            InstrumentedState::new(State::new(), Box::new(oracle))
+        };
+
+        // read word by word, an oracle serving whole preimages is asked once
+        let mut is = new_state(false);
+        assert_eq!(read_preimage_via_syscalls(&mut is, key)[8..], data);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(is.preimage_cache_stats().fetches, 1);
+
+        // the witnesses of the reads from an oracle serving parts share one whole copy
+        calls.store(0, Ordering::Relaxed);
+        let mut is = new_state(true);
+        is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        for i in 0..8 {
+            do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4);
+        }
+        let prefixed = [(data.len() as u64).to_be_bytes().as_slice(), &data].concat();
+        for offset in [0, 4, 8] {
+            let pc = is.state.pc;
+            is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+            is.state.registers[2] = 4003;
+            is.state.registers[4] = FD_PREIMAGE_READ;
+            is.state.registers[5] = 0x20000;
+            is.state.registers[6] = 4;
+            let (wit, _, _) = is.step(true).unwrap();
+            assert_eq!((wit.preimage_offset, &wit.preimage_value), (offset, &prefixed));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    /// Executes the I-type `opcode` on `rs` and `imm`, returns the result in rt.
+    fn exec_imm(opcode: u32, rs: u32, imm: u32) -> u32 {
+        let mut state = load_program(&[asm::i_type(opcode, 8, 9, imm)]);
+        state.registers[8] = rs;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        is.state.registers[9]
+    }
+
+    #[test]
+    fn test_imm_boundaries() {
+        let (slti, sltiu, andi, ori, xori) = (0xa, 0xb, 0xc, 0xd, 0xe);
+
+        // slti compares signed with the sign extended immediate, 0x8000 is -32768
+        assert_eq!(exec_imm(slti, 0, 0x8000), 0);
+        assert_eq!(exec_imm(slti, 0xffff7fff, 0x8000), 1);
+        assert_eq!(exec_imm(slti, 0, 0x7fff), 1);
+        assert_eq!(exec_imm(slti, 0x7fff, 0x7fff), 0);
+
+        // sltiu compares unsigned with the sign extended immediate, 0x8000 is 0xffff8000
+        assert_eq!(exec_imm(sltiu, 0x8000, 0x8000), 1);
+        assert_eq!(exec_imm(sltiu, 0xffff8000, 0x8000), 0);
+        assert_eq!(exec_imm(sltiu, 0x7ffe, 0x7fff), 1);
+        assert_eq!(exec_imm(sltiu, 0xffffffff, 0x7fff), 0);
+
+        // the logical immediates are zero extended
+        assert_eq!(exec_imm(andi, 0xffffffff, 0x8000), 0x8000);
+        assert_eq!(exec_imm(andi, 0xffffffff, 0x7fff), 0x7fff);
+        assert_eq!(exec_imm(ori, 0, 0x8000), 0x8000);
+        assert_eq!(exec_imm(ori, 0x12340000, 0x7fff), 0x12347fff);
+        assert_eq!(exec_imm(xori, 0xffffffff, 0x8000), 0xffff7fff);
+        assert_eq!(exec_imm(xori, 0xffffffff, 0x7fff), 0xffff8000);
+    }
+
+    #[test]
+    fn test_sltiu_sign_extended_immediate() {
+        // sltiu $t, $s, 0xffff compares with 0xffffffff, the largest unsigned value, rather
+        // than with 0xffff or -1
+        let sltiu = 0xb;
+        for rs in [0, 1, 0xffff, 0x10000, 0x7fffffff, 0x80000000, 0xfffffffe] {
+            assert_eq!(exec_imm(sltiu, rs, 0xffff), 1, "0x{:x}", rs);
+        }
+        assert_eq!(exec_imm(sltiu, 0xffffffff, 0xffff), 0);
+        // slti compares the same immediate as -1
+        assert_eq!(exec_imm(0xa, 0, 0xffff), 0);
+        assert_eq!(exec_imm(0xa, 0xfffffffe, 0xffff), 1);
+    }
+
+    #[test]
+    fn test_strict_delay_slots() {
+        let program = [asm::beq(0, 0, 2), asm::j(0x100), asm::nop()];
+
+        let config = VmConfig { strict_delay_slots: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config.clone());
+        is.step(false).unwrap();
+        match is.step(false) {
+            Err(EmulatorError::UnpredictableDelaySlot { pc, insn }) => {
+                assert_eq!(pc, 4);
+                assert_eq!(insn, asm::j(0x100));
+            }
+            _ => panic!("expected UnpredictableDelaySlot"),
+        }
+
+        // the delay slot of a branch not taken is only checked in strict mode
+        let program = [asm::bne(0, 0, 2), asm::j(0x100), asm::nop()];
+        let oracle = Box::new(RecordingOracle::default());
+        let mut is = InstrumentedState::new(load_program(&program), oracle);
+        is.step(false).unwrap();
+        is.step(false).unwrap();
+        assert_eq!(is.state.pc, 0x8);
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        is.step(false).unwrap();
+        assert!(matches!(is.step(false), Err(EmulatorError::UnpredictableDelaySlot { pc: 4, .. })));
+
+        // a branch following a delay slot is fine
+        let program = [asm::beq(0, 0, 1), asm::nop(), asm::j(0x100), asm::nop()];
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), VmConfig { strict_delay_slots: true, ..Default::default() });
+        for _ in 0..4 {
+            is.step(false).unwrap();
+        }
+        assert_eq!(is.state.pc, 0x100);
+    }
+
+    #[test]
+    fn test_elf_overlapping_heap_is_rejected() {
+        let text = asm::to_bytes(&[asm::nop()]);
+        let data = ElfWriter::new(0x400000)
+            .segment(0x400000, text)
+            .segment(HEAP_START - 0x1000, vec![1u8; 0x2000])
+            .build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let err = State::try_load_elf(&file).err().unwrap();
+        let stack_limit = MemoryLayout::default().stack_limit;
+        assert_eq!(err, LoadError::Layout(LayoutError::Overlap {
+            first: Region::new(RegionKind::Program, 0x400000, HEAP_START + 0x1000),
+            second: Region::new(RegionKind::Heap, HEAP_START, stack_limit),
+        }));
+    }
+
+    /// A static PIE linked at 0 printing "hello\n" through a pointer relocated by the loader.
+    fn static_pie_hello() -> ElfWriter {
+        let text = asm::to_bytes(&[
+            asm::lw(5, 25, 0x1000), // the relocated pointer to the message
+            asm::lw(6, 25, 0x1004),
+            asm::addiu(4, 0, FD_STDOUT as i16),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let mut data = vec![];
+        data.extend(0x1008u32.to_be_bytes());
+        data.extend(6u32.to_be_bytes());
+        data.extend(b"hello\n\0\0");
+        ElfWriter::new(0)
+            .pie()
+            .segment(0, text)
+            .segment(0x1000, data)
+            .function("main", 0, 32)
+            .object("msg", 0x1008, 6)
+            .relocation(0x1000, 0, 3) // R_MIPS_REL32
+    }
+
+    #[test]
+    fn test_static_pie() {
+        let data = static_pie_hello().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, program) = State::try_load_elf(&file).unwrap();
+        let base = DEFAULT_LOAD_BIAS;
+        assert_eq!(state.pc, base);
+        assert_eq!(state.registers[25], base);
+        assert_eq!(state.layout.text, Some((base, base + 32)));
+        assert_eq!(program.segments.len(), 1);
+        assert_eq!(program.segments[0].start_addr, base);
+        // the relocation adds the bias to the pointer, the length is left alone
+        assert_eq!(state.memory.get_memory(base + 0x1000), base + 0x1008);
+        assert_eq!(state.memory.get_memory(base + 0x1004), 6);
+        let symbols = SymbolMap::load_elf(&file);
+        assert_eq!(symbols.lookup(base), Some(("main", 0)));
+        assert_eq!(symbols.lookup(base + 0x100a), Some(("msg", 2)));
+
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let stdout = SharedBuffer::default();
+        is.set_stdout_writer(Box::new(stdout.clone()));
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"hello\n");
+
+        // the bias is configurable, and must keep the segments page aligned
+        let (mut state, _) = State::try_load_elf_at(&file, 0x200000).unwrap();
+        assert_eq!(state.pc, 0x200000);
+        assert_eq!(state.memory.get_memory(0x201000), 0x201008);
+        assert_eq!(SymbolMap::load_elf_at(&file, 0x200000).lookup(0x201008), Some(("msg", 0)));
+        assert_eq!(State::try_load_elf_at(&file, 0x200010).err(), Some(LoadError::MisalignedBias(0x200010)));
+
+        // the relocations a static PIE doesn't emit are rejected
+        let data = static_pie_hello().relocation(0x1004, 0, 2).build(); // R_MIPS_32
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        assert_eq!(
+            State::try_load_elf(&file).err(),
+            Some(LoadError::UnsupportedRelocation { offset: 0x1004, kind: 2 })
+        );
+
+        // an undefined weak symbol resolves to 0, the word keeps its addend
+        let data = static_pie_hello().weak("__gmon_start__").relocation(0x1004, 1, 3).build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, _) = State::try_load_elf(&file).unwrap();
+        assert_eq!(state.memory.get_memory(base + 0x1000), base + 0x1008);
+        assert_eq!(state.memory.get_memory(base + 0x1004), 6);
+
+        // a symbol missing from the dynamic symbols is undefined
+        let data = static_pie_hello().relocation(0x1004, 1, 3).build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        assert_eq!(
+            State::try_load_elf(&file).err(),
+            Some(LoadError::UndefinedSymbol { offset: 0x1004, sym: 1 })
+        );
+
+        // a REL32 that isn't word aligned has an error of its own
+        let data = static_pie_hello().relocation(0x1002, 0, 3).build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        assert_eq!(
+            State::try_load_elf(&file).err(),
+            Some(LoadError::MisalignedRelocation { offset: 0x1002 })
+        );
+    }
+
+    /// A mipsel executable printing "hello, world\n", the pointer to the message and its length
+    /// are little endian words.
+    fn mipsel_hello() -> Vec<u8> {
+        let mut text = asm::li(8, 0x410000).to_vec();
+        text.extend([
+            asm::lw(5, 8, 0),
+            asm::lw(6, 8, 4),
+            asm::addiu(4, 0, 1),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let text: Vec<u8> = text.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut data = vec![];
+        data.extend(0x410008u32.to_le_bytes());
+        data.extend(13u32.to_le_bytes());
+        data.extend(b"hello, world\n");
+        ElfWriter::new(0x400000)
+            .little_endian()
+            .segment(0x400000, text)
+            .segment(0x410000, data)
+            .function("main", 0x400000, 40)
+            .build()
+    }
+
+    #[test]
+    fn test_mipsel_hello() {
+        let data = mipsel_hello();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (big, little) = (Endianness::Big, Endianness::Little);
+        assert_eq!(
+            State::try_load_elf(&file).err(),
+            Some(LoadError::Endianness { elf: little, configured: big })
+        );
+        let (mut state, _) = State::try_load_elf_with(&file, DEFAULT_LOAD_BIAS, little).unwrap();
+        assert_eq!(state.memory.get_memory(0x400000), asm::lui(8, 0x41));
+        assert_eq!(state.memory.get_memory(0x410004), 13);
+        assert_eq!(SymbolMap::load_elf(&file).lookup(0x400004), Some(("main", 4)));
+
+        // the emulator keeps the byte order of the state, whatever the config says
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert_eq!(is.config().endianness, little);
+        let start = is.snapshot();
+        let stdout = SharedBuffer::default();
+        is.set_stdout_writer(Box::new(stdout.clone()));
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"hello, world\n");
+        is.reset_to(&start);
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.state.memory.endianness(), little);
+
+        // the witness records the byte order, the same pages read big endian hash differently
+        let witness = is.state.encode_witness();
+        assert_eq!(witness[89], 3);
+        let decoded = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        assert_eq!(decoded.memory.endianness(), little);
+        let mut other = is.state.clone();
+        other.memory.set_endianness(big);
+        assert_eq!(other.encode_witness()[89], 1);
+        assert_ne!(other.state_hash(&Keccak256Hasher), is.state_hash());
+
+        // the byte order is part of the config of a replay
+        let oracle = Box::new(RecordingOracle::default());
+        let config = VmConfig { endianness: little, ..Default::default() };
+        let replay = Replay::record(ReplayImage::Elf(data), config, vec![], oracle, 100).unwrap();
+        let decoded = Replay::decode(&replay.encode()).unwrap();
+        assert_eq!(decoded.config.endianness, little);
+        decoded.verify().unwrap();
+    }
+
+    #[cfg(feature = "ffi")]
+    extern "C" {
+        /// the C program of tests/ffi/ffi_test.c, compiled by the build script. Returns 0, or
+        /// the line of the failed check.
+        fn mips_ffi_test(elf_path: *const c_char, expected_hash: *const u8) -> c_int;
+        /// like `mips_ffi_test`, reading the preimage of `mipsel_preimage_read` from the C host.
+        fn mips_ffi_preimage_test(elf_path: *const c_char) -> c_int;
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_ffi_c_program() {
+        let data = mipsel_hello();
+        let little = Endianness::Little;
+        let mode = ExecutionMode::Strict;
+        let config = VmConfig { endianness: little, mode, ..Default::default() };
+        let state = ReplayImage::Elf(data.clone()).state_for(&config).unwrap();
+        let mut is = InstrumentedStateBuilder::new(state)
+            .with_config(config)
+            .with_output_discarded()
+            .build()
+            .unwrap();
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        let expected = is.state_hash();
+
+        let path = std::env::temp_dir().join(format!("ffi-{}.elf", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let line = unsafe { mips_ffi_test(c_path.as_ptr(), expected.as_ptr()) };
+        assert_eq!(line, 0, "the check of line {} of ffi_test.c failed", line);
+
+        // the preimage is longer than the first buffer of the callback
+        fs::write(&path, mipsel_preimage_read()).unwrap();
+        let line = unsafe { mips_ffi_preimage_test(c_path.as_ptr()) };
+        fs::remove_file(&path).unwrap();
+        assert_eq!(line, 0, "the check of line {} of ffi_test.c failed", line);
+    }
+
+    /// A mipsel executable writing the keccak256 key at 0x410000 a word at a time, reading the
+    /// length prefixed preimage of 5000 bytes `i * 7 + 1` into 0x420000 and exiting.
+    #[cfg(feature = "ffi")]
+    fn mipsel_preimage_read() -> Vec<u8> {
+        let text = [
+            asm::lui(16, 0x41),
+            asm::addiu(18, 16, 32),
+            asm::addiu(4, 0, FD_PREIMAGE_WRITE as i16),
+            asm::addiu(5, 16, 0),
+            asm::addiu(6, 0, 4),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            asm::addu(16, 16, 2),
+            asm::bne(16, 18, -7),
+            asm::nop(),
+            asm::lui(17, 0x42),
+            asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+            asm::addiu(5, 17, 0),
+            asm::addiu(6, 0, 4),
+            asm::addiu(2, 0, 4003),
+            asm::syscall(),
+            asm::addu(17, 17, 2),
+            asm::bne(2, 0, -7),
+            asm::nop(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ];
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7 + 1) as u8).collect();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let text: Vec<u8> = text.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let len = text.len() as u32;
+        ElfWriter::new(0x400000)
+            .little_endian()
+            .segment(0x400000, text)
+            .segment(0x410000, key.to_vec())
+            .segment(0x420000, vec![0; 0x1400])
+            .function("main", 0x400000, len)
+            .build()
+    }
+
+    /// A mipsel executable sending the empty hint, then setting the key to the word after it and
+    /// reading a word of its preimage into the word.
+    #[cfg(feature = "ffi")]
+    fn mipsel_hint_and_read() -> Vec<u8> {
+        let mut text = asm::li(5, 0x410000).to_vec();
+        text.extend([
+            asm::addiu(4, 0, FD_HINT_WRITE as i16),
+            asm::addiu(6, 0, 4),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            // the zero key read before any fetch serves nothing, without asking the oracle
+            asm::addiu(4, 0, FD_PREIMAGE_WRITE as i16),
+            asm::addiu(5, 5, 4),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+            asm::addiu(2, 0, 4003),
+            asm::syscall(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let text: Vec<u8> = text.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        ElfWriter::new(0x400000)
+            .little_endian()
+            .segment(0x400000, text)
+            .segment(0x410000, vec![0, 0, 0, 0, 1, 2, 3, 4])
+            .function("main", 0x400000, 64)
+            .build()
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_ffi_failing_callbacks() {
+        unsafe extern "C" fn get(
+            ctx: *mut c_void, _key: *const u8, _buf: *mut u8, _cap: usize, _len: *mut usize,
+        ) -> c_int {
+            *(ctx as *mut u32) += 1;
+            7
+        }
+        unsafe extern "C" fn failing_hint(ctx: *mut c_void, _hint: *const u8, len: usize) -> c_int {
+            assert_eq!(len, 0);
+            *(ctx as *mut u32) += 1;
+            3
+        }
+        unsafe extern "C" fn hint(ctx: *mut c_void, _hint: *const u8, _len: usize) -> c_int {
+            *(ctx as *mut u32) += 1;
+            0
+        }
+        let last_error = || {
+            unsafe { CStr::from_ptr(ffi::mips_vm_last_error_msg()) }.to_str().unwrap().to_string()
+        };
+
+        let data = mipsel_hint_and_read();
+        let config = CString::new(r#"{"endianness": "little"}"#).unwrap();
+        let mut calls = 0u32;
+        let ctx = &mut calls as *mut u32 as *mut c_void;
+        unsafe {
+            let vm = ffi::mips_vm_new(data.as_ptr(), data.len(), config.as_ptr());
+            assert!(!vm.is_null());
+            let status = ffi::mips_vm_register_oracle(vm, Some(get), Some(failing_hint), ctx);
+            assert_eq!(status, ffi::MIPS_VM_OK);
+            assert_eq!(ffi::mips_vm_step(vm, 100), ffi::MIPS_VM_ERR_ORACLE);
+            assert_eq!(last_error(), "the hint callback failed with code 3");
+
+            // the step of the hint was executed, the hint is not sent again
+            let status = ffi::mips_vm_register_oracle(vm, Some(get), Some(hint), ctx);
+            assert_eq!(status, ffi::MIPS_VM_OK);
+            assert_eq!(ffi::mips_vm_step(vm, 100), ffi::MIPS_VM_ERR_ORACLE);
+            let message = last_error();
+            assert!(message.starts_with("the preimage callback failed with code 7"), "{}", message);
+            assert_eq!(calls, 2);
+
+            assert_eq!(ffi::mips_vm_register_oracle(vm, None, None, ctx), ffi::MIPS_VM_ERR_NULL);
+            assert_eq!(ffi::mips_vm_step(ptr::null_mut(), 1), ffi::MIPS_VM_ERR_NULL);
+            assert_eq!(last_error(), "vm is null");
+            ffi::mips_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn test_mmap_into_stack() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, _) = State::load_elf(&file);
+        state.validate_layout().unwrap();
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+
+        // fits below the stack
+        let stack_limit = is.state.layout.stack_limit;
+        assert_eq!(do_syscall(&mut is, 4090, 0, 0x10000, 0), (HEAP_START, 0));
+
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = 4090;
+        is.state.registers[4] = 0;
+        is.state.registers[5] = stack_limit - HEAP_START;
+        match is.step(false) {
+            Err(EmulatorError::Layout(LayoutError::Overlap { first, second })) => {
+                assert_eq!(first, Region::new(RegionKind::Mmap, HEAP_START + 0x10000, stack_limit + 0x10000));
+                assert_eq!(second, is.state.layout.stack());
+            }
+            _ => panic!("expected the mmap to overlap the stack"),
+        }
+    }
+
+    #[test]
+    fn test_process_syscalls() {
+        let mut is = InstrumentedState::new(State::new(), Box::new(RecordingOracle::default()));
+        assert_eq!(do_syscall(&mut is, 4020, 0, 0, 0), (1, 0)); // getpid
+        assert_eq!(do_syscall(&mut is, 4064, 0, 0, 0), (0, 0)); // getppid
+        assert_eq!(do_syscall(&mut is, 4114, 0xFFffFFff, 0, 0), (SYSCALL_ERROR, ECHILD)); // wait4
+
+        // the utsname struct crosses a page, its 65 bytes fields are NUL padded
+        let addr = 0x10f80;
+        is.state.memory.set_memory_range(addr, Box::new([0xaa; 392].as_slice())).unwrap();
+        assert_eq!(do_syscall(&mut is, 4122, addr, 0, 0), (0, 0));
+        let read = |is: &mut InstrumentedState| -> Vec<u8> {
+            (addr..addr + 392).step_by(4)
+                .flat_map(|word| is.state.memory.get_memory(word).to_be_bytes())
+                .collect()
+        };
+        let bytes = read(&mut is);
+        let field = |value: &str| {
+            let mut field = value.as_bytes().to_vec();
+            field.resize(65, 0);
+            field
+        };
+        let expected: Vec<u8> = ["Linux", "zkmips", "5.15.0", "#1 SMP", "mips", "(none)"].iter()
+            .flat_map(|value| field(value))
+            .chain([0xaa; 2])
+            .collect();
+        assert_eq!(bytes, expected);
+        assert_eq!(do_syscall(&mut is, 4122, 0, 0, 0), (SYSCALL_ERROR, EFAULT));
+
+        // with proofs, each word of the struct has its proof, and the watched regions see them
+        is.watch_region(addr..addr + 390, "utsname");
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = 4122;
+        is.state.registers[4] = addr;
+        let (wit, _, _) = is.step(true).unwrap();
+        let words: Vec<u32> = (addr..addr + 390).step_by(4).collect();
+        assert_eq!(wit.extra_mem_accesses, words[1..]);
+        assert_eq!(wit.mem_proof.len(), 28 * 32 * (1 + words.len()));
+        assert_eq!(is.region_log("utsname").unwrap().len(), words.len());
+        assert_eq!(read(&mut is), expected);
+
+        // an override replaces the stubs it returns an outcome for, its writes are tracked too
+        struct Sandbox;
+        impl SyscallOverride for Sandbox {
+            fn handle(&mut self, num: u32, args: [u32; 4]) -> Option<SyscallOutcome> {
+                let nodename = (args[0] + 65, b"sandbox\0".to_vec());
+                match num {
+                    4020 => Some(SyscallOutcome { v0: 42, ..Default::default() }),
+                    4122 => Some(SyscallOutcome { writes: vec![nodename], ..Default::default() }),
+                    _ => None,
+                }
+            }
+        }
+        is.set_syscall_override(Box::new(Sandbox));
+        is.clear_region_log("utsname");
+        assert_eq!(do_syscall(&mut is, 4020, 0, 0, 0), (42, 0));
+        assert_eq!(do_syscall(&mut is, 4064, 0, 0, 0), (0, 0));
+        assert_eq!(do_syscall(&mut is, 4122, addr, 0, 0), (0, 0));
+        let bytes = read(&mut is);
+        assert_eq!(bytes[65..73], *b"sandbox\0");
+        assert_eq!(bytes[..65], expected[..65]);
+        assert_eq!(bytes[73..], expected[73..]);
+        assert_eq!(is.region_log("utsname").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_mmap_over_memory_limit() {
+        let config = VmConfig { max_host_pages: Some(16), ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            State::new(), Box::new(RecordingOracle::default()), config);
+        // the page of the syscall is allocated, 15 pages are left
+        assert_eq!(do_syscall(&mut is, 4090, 0, 15 << 12, 0), (0, 0));
+        assert_eq!(do_syscall(&mut is, 4090, 0, (15 << 12) + 1, 0), (SYSCALL_ERROR, ENOMEM));
+        assert_eq!(do_syscall(&mut is, 4090, 0x40000000, 16 << 12, 0), (SYSCALL_ERROR, ENOMEM));
+        // the size rounded up to a page overflows
+        assert_eq!(do_syscall(&mut is, 4090, 0, 0xffff_f001, 0), (SYSCALL_ERROR, ENOMEM));
+        assert_eq!(do_syscall(&mut is, 4090, 0, 0, 0), (15 << 12, 0));
+
+        // without a limit, only the layout limits the mappings
+        let mut is = InstrumentedState::new(State::new(), Box::new(RecordingOracle::default()));
+        assert_eq!(do_syscall(&mut is, 4090, 0, 64 << 12, 0), (0, 0));
+        assert_eq!(do_syscall(&mut is, 4090, 0, 0, 0), (64 << 12, 0));
+    }
+
+    /// Executes the hi/lo multiply or divide `fun` on `rs` and `rt`, returns (hi, lo).
+    fn exec_hilo(fun: u32, rs: u32, rt: u32) -> (u32, u32) {
+        let program = [
+            asm::r_type(8, 9, 0, 0, fun),
+            asm::r_type(0, 0, 10, 0, 0x10), // mfhi
+            asm::r_type(0, 0, 11, 0, 0x12), // mflo
+        ];
+        let mut state = load_program(&program);
+        state.registers[8] = rs;
+        state.registers[9] = rt;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..program.len() {
+            is.step(false).unwrap();
+        }
+        (is.state.registers[10], is.state.registers[11])
+    }
+
+    #[test]
+    fn test_mult_sign_extends() {
+        let (mult, multu) = (0x18, 0x19);
+        assert_eq!(exec_hilo(mult, -1i32 as u32, -1i32 as u32), (0, 1));
+        assert_eq!(exec_hilo(mult, -1i32 as u32, 2), (0xffffffff, 0xfffffffe));
+        assert_eq!(exec_hilo(mult, 0x80000000, 0x80000000), (0x40000000, 0));
+        assert_eq!(exec_hilo(multu, -1i32 as u32, -1i32 as u32), (0xfffffffe, 1));
+        assert_eq!(exec_hilo(multu, -1i32 as u32, 2), (1, 0xfffffffe));
+    }
+
+    #[test]
+    fn test_store_mem_ops() {
+        let mut state = State::new();
+        state.memory.set_memory(0, asm::lw(8, 9, 0)).unwrap();
+        state.memory.set_memory(4, asm::sb(10, 9, 1)).unwrap();
+        state.memory.set_memory(0x10000, 0x11223344).unwrap();
+        state.registers[9] = 0x10000;
+        state.registers[10] = 0xaabbccdd;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+
+        let (_, _, load) = is.step(true).unwrap();
+        assert_eq!(load, vec![
+            MemoryAccess {
+                rw_counter: 1,
+                addr: 0,
+                op: MemoryOperation::Fetch,
+                value: asm::lw(8, 9, 0),
+                value_prev: asm::lw(8, 9, 0),
+            },
+            MemoryAccess {
+                rw_counter: 2,
+                addr: 0x10000,
+                op: MemoryOperation::Read,
+                value: 0x11223344,
+                value_prev: 0x11223344,
+            },
+        ]);
+
+        // the partial store reads the word it merges the byte into, then writes it back
+        let (_, _, store) = is.step(true).unwrap();
+        assert_eq!(store, vec![
+            MemoryAccess {
+                rw_counter: 3,
+                addr: 4,
+                op: MemoryOperation::Fetch,
+                value: asm::sb(10, 9, 1),
+                value_prev: asm::sb(10, 9, 1),
+            },
+            MemoryAccess {
+                rw_counter: 4,
+                addr: 0x10000,
+                op: MemoryOperation::Read,
+                value: 0x11223344,
+                value_prev: 0x11223344,
+            },
+            MemoryAccess {
+                rw_counter: 5,
+                addr: 0x10000,
+                op: MemoryOperation::Write,
+                value: 0x11dd3344,
+                value_prev: 0x11223344,
+            },
+        ]);
+        assert_eq!(is.state.memory.get_memory(0x10000), 0x11dd3344);
+    }
+
+    #[test]
+    fn test_fetch_mem_ops() {
+        let program = [
+            asm::addiu(8, 0, 0x100),
+            asm::sw(8, 8, 0),
+            asm::lw(9, 8, 0),
+            asm::beq(0, 0, 1),
+            asm::nop(),
+            asm::lw(10, 8, 4),
+            asm::addiu(2, 0, 4020), // getpid
+            asm::syscall(),
+            asm::sb(9, 8, 3),
+            asm::addiu(11, 0, 1),
+        ];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        let mut rw_counter = 0;
+        for _ in 0..9 {
+            let pc = is.state.pc;
+            let insn = is.state.memory.get_memory(pc);
+            let (wit, _, accesses) = is.step(true).unwrap();
+            assert_eq!(accesses, wit.mem_ops);
+            assert_eq!(accesses[0], MemoryAccess {
+                rw_counter: rw_counter + 1,
+                addr: pc,
+                op: MemoryOperation::Fetch,
+                value: insn,
+                value_prev: insn,
+            });
+            assert!(accesses[1..].iter().all(|access| access.op != MemoryOperation::Fetch));
+            assert!(accesses.windows(2).all(|w| w[0].rw_counter < w[1].rw_counter));
+            rw_counter = accesses.last().unwrap().rw_counter;
+        }
+
+        // the fetches are not in the rw table, nor in the region logs
+        let config = RegionWatchConfig { loads: true, ..Default::default() };
+        is.watch_region_with(0..0x40, "text", config);
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.mem_ops.len(), 1);
+        assert!(WitnessTables::from_trace(&[*wit]).rw.is_empty());
+        assert!(is.region_log("text").unwrap().is_empty());
+    }
+
+    /// Executes a single syscall at the current pc with proofs, returns its witness.
+    fn syscall_witness(is: &mut InstrumentedState, num: u32, a0: u32, a1: u32, a2: u32) -> SyscallWitness {
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::syscall()).unwrap();
+        is.state.registers[2] = num;
+        is.state.registers[4] = a0;
+        is.state.registers[5] = a1;
+        is.state.registers[6] = a2;
+        match is.step(true).unwrap().0.kind {
+            StepKind::Syscall(wit) => wit,
+            kind => panic!("expected a syscall step, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_syscall_witness() {
+        let data = b"some data".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+
+        let mut witnesses = vec![];
+        for i in 0..8 {
+            witnesses.push(syscall_witness(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4));
+        }
+        is.state.memory.set_memory(0x20000, 0xaabbccdd).unwrap();
+        witnesses.push(syscall_witness(&mut is, 4003, FD_PREIMAGE_READ, 0x20002, 4));
+
+        for (i, wit) in witnesses[..8].iter().enumerate() {
+            let addr = 0x10000 + 4 * i as u32;
+            let word = u32::from_be_bytes(key[4 * i..4 * i + 4].try_into().unwrap());
+            let mut expected_key = [0u8; 32];
+            expected_key[32 - 4 * (i + 1)..].copy_from_slice(&key[..4 * (i + 1)]);
+            assert_eq!(*wit, SyscallWitness {
+                step: i as u64 + 1,
+                num: 4004,
+                args: [FD_PREIMAGE_WRITE, addr, 4, 0],
+                ret: (4, 0),
+                preimage_key: Some(expected_key),
+                preimage_offset: Some(0),
+                // after the fetch of the syscall instruction
+                mem_ops: vec![MemoryAccess {
+                    rw_counter: 2 * i as u64 + 2,
+                    addr,
+                    op: MemoryOperation::Read,
+                    value: word,
+                    value_prev: word,
+                }],
+            });
+        }
+
+        // the read fills the 2 bytes up to the word boundary, with the length prefix
+        assert_eq!(witnesses[8], SyscallWitness {
+            step: 9,
+            num: 4003,
+            args: [FD_PREIMAGE_READ, 0x20002, 4, 0],
+            ret: (2, 0),
+            preimage_key: Some(key),
+            preimage_offset: Some(2),
+            mem_ops: vec![MemoryAccess {
+                rw_counter: 18,
+                addr: 0x20000,
+                op: MemoryOperation::Write,
+                value: 0xaabb0000,
+                value_prev: 0xaabbccdd,
+            }],
+        });
+
+        // the other steps are classified by their instruction
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::addiu(8, 0, 1)).unwrap();
+        assert_eq!(is.step(true).unwrap().0.kind, StepKind::Alu);
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::r_type(0, 0, 8, 0, 0x10)).unwrap();
+        assert_eq!(is.step(true).unwrap().0.kind, StepKind::HiLo);
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::j(0x100)).unwrap();
+        assert_eq!(is.step(true).unwrap().0.kind, StepKind::Jump);
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, 0x0000000f).unwrap();
+        assert_eq!(is.step(true).unwrap().0.kind, StepKind::Sync);
+        let pc = is.state.pc;
+        is.state.memory.set_memory(pc, asm::brk(0xabcde)).unwrap();
+        assert_eq!(is.step(true).unwrap().0.kind, StepKind::Break { code: 0xabcde });
+    }
+
+    #[test]
+    fn test_witness_tables() {
+        let program = [
+            asm::addiu(8, 0, 0x100),
+            asm::addiu(9, 0, 7),
+            asm::sw(9, 8, 0),
+            asm::lw(10, 8, 0),
+            asm::addiu(9, 9, 1),
+            asm::sw(9, 8, 4),
+            asm::beq(0, 0, 1),
+            asm::nop(),
+            asm::lw(11, 8, 4),
+            asm::addu(12, 10, 11),
+            asm::sw(12, 8, 8),
+            asm::addiu(8, 8, 0x10),
+            asm::sw(12, 8, -4),
+            asm::lw(13, 8, -16),
+            asm::j(0x40),
+            asm::nop(),
+            asm::sw(13, 8, 0),
+            asm::lw(14, 8, -4),
+            asm::r_type(10, 11, 0, 0, 0x18), // mult
+            asm::r_type(0, 0, 15, 0, 0x12), // mflo
+        ];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        let trace: Vec<_> = (0..20).map(|_| *is.step(true).unwrap().0).collect();
+        let tables = WitnessTables::from_trace(&trace);
+
+        let rw: Vec<String> = tables.rw.iter()
+            .map(|row| format!("{} {} {:#x} {} {}",
+                               row.rw_counter, row.is_write as u8, row.address, row.value, row.value_prev))
+            .collect();
+        assert_eq!(rw.join("\n"), "\
+            4 0 0x100 0 0\n\
+            5 1 0x100 7 0\n\
+            7 0 0x100 7 7\n\
+            25 0 0x100 7 7\n\
+            10 0 0x104 0 0\n\
+            11 1 0x104 8 0\n\
+            15 0 0x104 8 8\n\
+            18 0 0x108 0 0\n\
+            19 1 0x108 15 0\n\
+            22 0 0x10c 0 0\n\
+            23 1 0x10c 15 0\n\
+            32 0 0x10c 15 15\n\
+            29 0 0x110 0 0\n\
+            30 1 0x110 7 0");
+        assert!(tables.rw.windows(2)
+            .all(|w| (w[0].address, w[0].rw_counter) < (w[1].address, w[1].rw_counter)));
+        let counters: HashSet<u64> = tables.rw.iter().map(|row| row.rw_counter).collect();
+        assert_eq!(counters.len(), tables.rw.len());
+
+        let steps: Vec<String> = tables.step.iter()
+            .map(|row| format!("{} {:#x} {}", row.step, row.pc, row.kind))
+            .collect();
+        assert_eq!(steps.join(" "), "1 0x0 0 2 0x4 0 3 0x8 0 4 0xc 0 5 0x10 0 6 0x14 0 7 0x18 1 \
+            8 0x1c 0 9 0x20 0 10 0x24 0 11 0x28 0 12 0x2c 0 13 0x30 0 14 0x34 0 15 0x38 2 \
+            16 0x3c 0 17 0x40 0 18 0x44 0 19 0x48 4 20 0x4c 4");
+        assert!(zip(&tables.step, program).all(|(row, insn)| row.bytecode == insn));
+
+        // every instruction was executed once
+        let expected: Vec<OpcodeRow> = zip((0..).step_by(4), program)
+            .map(|(address, bytecode)| OpcodeRow { address, bytecode, supported: true })
+            .collect();
+        assert_eq!(tables.opcode, expected);
+    }
+
+    #[test]
+    fn test_set_initial() {
+        let mut state = State::new();
+        let pc = 0x400100;
+        state.memory.set_memory(pc, asm::addu(10, 8, 9)).unwrap();
+        state.memory.set_memory(pc + 4, asm::r_type(0, 0, 11, 0, 0x10)).unwrap(); // mfhi
+        state.memory.set_memory(pc + 8, asm::r_type(0, 0, 12, 0, 0x12)).unwrap(); // mflo
+        let regs: [u32; 32] = std::array::from_fn(|i| 0x1000 * i as u32);
+        state.set_initial(regs, pc, 0xaaaa, 0xbbbb);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.instruction.addr, pc);
+        assert_eq!(is.state.registers[10], 0x8000 + 0x9000);
+        assert_eq!(is.state.registers[..10], regs[..10]);
+        assert_eq!(is.state.pc, pc + 4);
+        is.step(false).unwrap();
+        is.step(false).unwrap();
+        assert_eq!((is.state.registers[11], is.state.registers[12]), (0xaaaa, 0xbbbb));
+    }
+
+    #[test]
+    fn test_register_delta() {
+        let program = [
+            asm::addiu(8, 0, 5),
+            asm::addiu(8, 8, -7),
+            asm::beq(8, 8, 1),
+            asm::nop(),
+            asm::sw(8, 0, 0x100),
+            asm::r_type(8, 8, 0, 0, 0x19), // multu
+            asm::r_type(0, 0, 9, 0, 0x10), // mfhi
+        ];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, Some(RegisterDelta { reg: 8, old: 0, new: 5 }));
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, Some(RegisterDelta { reg: 8, old: 5, new: 0xffff_fffe }));
+        assert_eq!(wit.hilo_delta, None);
+
+        // the branch and the store write no register
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, None);
+        is.step(true).unwrap();
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, None);
+
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, None);
+        assert_eq!(wit.hilo_delta, Some(HiLoDelta { old_hi: 0, old_lo: 0, hi: 0xffff_fffc, lo: 4 }));
+        let (wit, _, _) = is.step(true).unwrap();
+        assert_eq!(wit.register_delta, Some(RegisterDelta { reg: 9, old: 0, new: 0xffff_fffc }));
+        assert_eq!(wit.hilo_delta, None);
+    }
+
+    #[test]
+    fn test_write_reg() {
+        let mut state = State::new();
+        state.pc = 0x400;
+        state.write_reg(8, 5).unwrap();
+        state.write_reg(0, 5).unwrap();
+        state.write_reg(31, 6).unwrap();
+        assert_eq!((state.registers[0], state.registers[8], state.registers[31]), (0, 5, 6));
+        for idx in [32, 0x1f00, 0xFFffFFff] {
+            match state.write_reg(idx, 1) {
+                Err(EmulatorError::InvalidRegister { reg, pc }) => {
+                    assert_eq!((reg, pc), (idx, 0x400))
+                }
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_destination_register() {
+        // the instructions writing a register, given rt and rd. rs is $1, holding a mapped and
+        // aligned address for the loads, the stores and jalr.
+        let templates: [(&str, fn(u32, u32) -> u32); 20] = [
+            ("addu", |rt, rd| asm::r_type(1, rt, rd, 0, 0x21)),
+            ("sltu", |rt, rd| asm::r_type(1, rt, rd, 0, 0x2b)),
+            ("sll", |rt, rd| asm::r_type(0, rt, rd, 3, 0x00)),
+            ("movz", |rt, rd| asm::r_type(1, rt, rd, 0, 0x0a)),
+            ("movn", |rt, rd| asm::r_type(1, rt, rd, 0, 0x0b)),
+            ("jalr", |rt, rd| asm::r_type(1, rt, rd, 0, 0x09)),
+            ("mfhi", |rt, rd| asm::r_type(0, rt, rd, 0, 0x10)),
+            ("mflo", |rt, rd| asm::r_type(0, rt, rd, 0, 0x12)),
+            ("divu", |rt, rd| asm::r_type(1, rt, rd, 0, 0x1b)),
+            ("rdhwr", |rt, _| (0x1f << 26) | asm::r_type(0, rt, 29, 0, 0x3b)),
+            ("seb", |rt, rd| (0x1f << 26) | asm::r_type(0, rt, rd, 0x10, 0x20)),
+            ("ext", |rt, rd| (0x1f << 26) | asm::r_type(1, rt, rd, 0, 0x00)),
+            ("addiu", |rt, rd| asm::i_type(0x09, 1, rt, rd)),
+            ("lui", |rt, rd| asm::i_type(0x0f, 0, rt, rd)),
+            ("lw", |rt, rd| asm::i_type(0x23, 1, rt, rd << 2)),
+            ("lwl", |rt, rd| asm::i_type(0x22, 1, rt, rd)),
+            ("lb", |rt, rd| asm::i_type(0x20, 1, rt, rd)),
+            ("ll", |rt, rd| asm::i_type(0x30, 1, rt, rd << 2)),
+            ("sc", |rt, rd| asm::i_type(0x38, 1, rt, rd << 2)),
+            ("sw", |rt, rd| asm::i_type(0x2b, 1, rt, rd << 2)),
+        ];
+        for (name, template) in templates {
+            for rt in 0..32 {
+                for rd in 0..32 {
+                    let insn = template(rt, rd);
+                    let mut state = load_program(&[insn]);
+                    for reg in 1..32 {
+                        state.registers[reg] = 0x1000 + 4 * reg as u32;
+                    }
+                    let oracle = Box::new(RecordingOracle::default());
+                    let mut is = InstrumentedState::new(state, oracle);
+                    let result = is.step(true);
+                    assert!(result.is_ok(), "{} 0x{:08x}: {:?}", name, insn, result.err());
+                    assert_eq!(is.state.registers[0], 0, "{} 0x{:08x} wrote $zero", name, insn);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_supported_instruction() {
+        // one encoding of each instruction the decoder knows executes
+        crate::decode::coverage::reset();
+        for (id, insn) in crate::decode::coverage::encodings() {
+            let mut state = load_program(&[insn]);
+            for reg in 1..32 {
+                state.registers[reg] = 0x1000 + 4 * reg as u32;
+            }
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            let result = is.step(true);
+            assert!(result.is_ok(), "{:?} 0x{:08x}: {:?}", id, insn, result.err());
+        }
+        assert_full_coverage!();
+    }
+
+    #[test]
+    fn test_break_on_division_by_zero() {
+        // the guard compilers emit for a division by a variable
+        let program = [
+            asm::r_type(8, 9, 0, 0, 0x1a), // div $8, $9
+            asm::bne(9, 0, 2),
+            asm::nop(),
+            asm::brk(7),
+            asm::r_type(0, 0, 10, 0, 0x12), // mflo $10
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ];
+        let run = |divisor: u32| {
+            let mut state = load_program(&program);
+            state.registers[8] = 12;
+            state.registers[9] = divisor;
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            let result = is.run(100).unwrap();
+            (result.status, is)
+        };
+
+        let (status, mut is) = run(0);
+        assert_eq!(status, VmStatus::Panic { code: 7 });
+        assert!(is.state.exited);
+        assert_eq!(is.state.break_code(), Some(7));
+        assert_eq!(is.state.pc, 12);
+        assert_eq!(is.state.registers[10], 0);
+
+        // the break code is saved with the metadata, a resumed state still panicked
+        let metadata = StateMetadata::decode(&is.state.metadata().encode()).unwrap();
+        assert_eq!(metadata.break_code, Some(7));
+        let witness = is.state.encode_witness();
+        let mut saved = State::decode_witness(&witness, (*is.state.memory).clone()).unwrap();
+        saved.set_metadata(metadata);
+        let mut resumed = InstrumentedState::new(saved, Box::new(RecordingOracle::default()));
+        assert_eq!(resumed.run(100).unwrap().status, VmStatus::Panic { code: 7 });
+
+        let (status, is) = run(3);
+        assert_eq!(status, VmStatus::Exited(0));
+        assert_eq!(is.state.break_code(), None);
+        assert_eq!(is.state.registers[10], 4);
+    }
+
+    #[test]
+    fn test_sync_is_nop() {
+        // the rd bits of sync are zero, stype in the shamt bits is ignored
+        let state = load_program(&[(0x10 << 6) | 0xf]);
+        let registers = state.registers;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        assert_eq!(is.state.pc, 4);
+        assert_eq!(is.state.registers, registers);
+    }
+
+    #[test]
+    fn test_mul_wraps() {
+        let mul = |rs: u32, rt: u32| {
+            let mut state = load_program(&[asm::r_type(8, 9, 10, 0, 2) | (0x1c << 26)]);
+            state.registers[8] = rs;
+            state.registers[9] = rt;
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            is.step(false).unwrap();
+            is.state.registers[10]
+        };
+        assert_eq!(mul(0x7fffffff, 0x7fffffff), 1);
+        assert_eq!(mul(0xffffffff, 0xffffffff), 1);
+        assert_eq!(mul(0x12345678, 0x9abcdef0), 0x242d2080);
+        assert_eq!(mul(-3i32 as u32, 7), -21i32 as u32);
+    }
+
+    /// Recurses `depth` times with 1 KiB stack frames, then exits.
+    fn recursion_program(depth: u16) -> Box<State> {
+        let f = 6 * 4;
+        let mut state = load_program(&[
+            asm::addiu(4, 0, depth as i16),
+            asm::jal(f),
+            asm::nop(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+            // f:
+            asm::addiu(29, 29, -1024),
+            asm::sw(31, 29, 0),
+            asm::beq(4, 0, 3),
+            asm::addiu(4, 4, -1),
+            asm::jal(f),
+            asm::nop(),
+            asm::lw(31, 29, 0),
+            asm::addiu(29, 29, 1024),
+            asm::jr(31),
+            asm::nop(),
+        ]);
+        state.registers[29] = STACK_POINTER;
+        state
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        let config = VmConfig { stack_guard: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            recursion_program(100), Box::new(RecordingOracle::default()), config.clone());
+        assert_eq!(is.run(100_000).unwrap().status, VmStatus::Exited(0));
+
+        // the frame 8189 is the first one below the stack limit
+        let mut is = InstrumentedState::new_with_config(
+            recursion_program(0x7fff), Box::new(RecordingOracle::default()), config);
+        let stack_limit = is.state.layout.stack_limit;
+        match is.run(100_000) {
+            Err(EmulatorError::StackOverflow { addr, pc, .. }) => {
+                assert_eq!(addr, STACK_POINTER - 8189 * 1024);
+                assert!(addr < stack_limit);
+                assert_eq!(pc, 7 * 4);
+            }
+            _ => panic!("expected a stack overflow"),
+        }
+
+        // unguarded, the stack grows down below the limit
+        let mut is = InstrumentedState::new(recursion_program(0x7fff), Box::new(RecordingOracle::default()));
+        assert_eq!(is.run(1_000_000).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.state.registers[29], STACK_POINTER);
+    }
+
+    #[test]
+    fn test_pipe_and_poll() {
+        let mut is = InstrumentedState::new(State::new(), Box::new(RecordingOracle::default()));
+        assert_eq!(do_syscall(&mut is, 4042, 0, 0, 0), (FD_PIPE_READ, 0));
+        assert_eq!(is.state.registers[3], FD_PIPE_WRITE);
+        // the wake-ups written to the pipe are dropped, it never has anything to read
+        assert_eq!(do_syscall(&mut is, 4004, FD_PIPE_WRITE, 0x100, 8), (8, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_PIPE_READ, 0x100, 8), (0xFFffFFff, EAGAIN));
+        assert_eq!(do_syscall(&mut is, 4055, FD_PIPE_READ, 3, 0), (0, 0)); // O_RDONLY
+        assert_eq!(do_syscall(&mut is, 4019, FD_PIPE_WRITE, 0, SEEK_SET), (0xFFffFFff, ESPIPE));
+
+        // an event loop polling the pipe with a timeout until it times out, which is at once
+        let program = [
+            asm::addiu(2, 0, 4188), // poll
+            asm::addiu(4, 0, 0x100),
+            asm::addiu(5, 0, 1),
+            asm::addiu(6, 0, 1000), // timeout in ms
+            asm::syscall(),
+            asm::bne(2, 0, -6),
+            asm::nop(),
+            asm::addiu(2, 0, 4246), // exit group
+            asm::addiu(4, 0, 0),
+            asm::syscall(),
+        ];
+        let mut state = load_program(&program);
+        state.memory.set_memory(0x100, FD_PIPE_READ).unwrap();
+        state.memory.set_memory(0x104, 1 << 16).unwrap(); // POLLIN, no revents
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let result = is.run(100).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(0));
+        assert_eq!(result.steps, program.len() as u64);
+        assert_eq!(is.state.memory.get_memory(0x104), 1 << 16);
+    }
+
+    #[test]
+    fn test_unknown_syscall_policy() {
+        let new_state = |unknown_syscall| {
+            let config = VmConfig {
+                unknown_syscall,
+                journal: Some(JournalConfig::default()),
+                ..Default::default()
+            };
+            InstrumentedState::new_with_config(State::new(), Box::new(RecordingOracle::default()), config)
+        };
+        let unknown_syscalls = |is: &InstrumentedState| -> Vec<Event> {
+            is.journal().unwrap().replay_filter(EventKind::UnknownSyscall).cloned().collect()
+        };
+
+        // Linux fails with ENOSYS, by default
+        assert_eq!(VmConfig::default().unknown_syscall, UnknownSyscallPolicy::Enosys);
+        let mut is = new_state(UnknownSyscallPolicy::Enosys);
+        assert_eq!(do_syscall(&mut is, 4999, 1, 2, 3), (0xFFffFFff, ENOSYS));
+        assert_eq!(is.state.pc, 4);
+        assert_eq!(unknown_syscalls(&is), [Event::UnknownSyscall { step: 1, pc: 0, num: 4999 }]);
+        // the ignored syscalls are not unknown
+        assert_eq!(do_syscall(&mut is, 4194, 1, 2, 3), (0, 0)); // rt_sigaction
+        assert_eq!(unknown_syscalls(&is).len(), 1);
+
+        // Cannon succeeds without doing anything
+        assert_eq!(VmConfig::cannon().unknown_syscall, UnknownSyscallPolicy::SilentZero);
+        let mut is = new_state(UnknownSyscallPolicy::SilentZero);
+        is.state.registers[7] = 5;
+        assert_eq!(do_syscall(&mut is, 4999, 1, 2, 3), (0, 0));
+        assert_eq!(is.state.pc, 4);
+        assert_eq!(unknown_syscalls(&is), [Event::UnknownSyscall { step: 1, pc: 0, num: 4999 }]);
+
+        // the step fails, the pc stays at the syscall
+        let mut is = new_state(UnknownSyscallPolicy::Fault);
+        is.state.memory.set_memory(0, asm::syscall()).unwrap();
+        is.state.registers[2] = 4999;
+        match is.step(false) {
+            Err(EmulatorError::UnknownSyscall { num: 4999, pc: 0 }) => {}
+            _ => panic!("expected an unknown syscall error"),
+        }
+        assert_eq!(is.state.pc, 0);
+        assert_eq!(unknown_syscalls(&is), [Event::UnknownSyscall { step: 1, pc: 0, num: 4999 }]);
+    }
+
+    #[test]
+    fn test_execution_mode() {
+        let strict = VmConfig { mode: ExecutionMode::Strict, ..Default::default() };
+        let new_state = |config: &VmConfig| {
+            InstrumentedState::new_with_config(State::new(), Box::new(RecordingOracle::default()), config.clone())
+        };
+
+        // the ignored syscalls succeed, fcntl fails on what it doesn't support
+        let mut is = new_state(&strict);
+        assert_eq!(do_syscall(&mut is, 4194, 1, 2, 3), (0, 0)); // rt_sigaction
+        assert_eq!(do_syscall(&mut is, 4055, FD_STDOUT, 1, 0), (0xFFffFFff, EINVAL)); // fcntl F_GETFD
+        assert_eq!(do_syscall(&mut is, 4055, 9, 3, 0), (0xFFffFFff, EBADF));
+
+        // division by zero, hi and lo keep the values of the previous mult
+        let program = [
+            asm::r_type(8, 8, 0, 0, 0x19), // multu
+            asm::r_type(8, 0, 0, 0, 0x1a), // div
+            asm::r_type(0, 0, 10, 0, 0x10), // mfhi
+            asm::r_type(0, 0, 11, 0, 0x12), // mflo
+        ];
+        let mut state = load_program(&program);
+        state.registers[8] = 0x10001;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert_eq!(is.run(4).unwrap().status, VmStatus::StepLimitReached);
+        assert_eq!((is.state.registers[10], is.state.registers[11]), (1, 0x20001));
+        assert_eq!(exec_hilo(0x1a, 7, 0), (0, 0));
+        assert_eq!(exec_hilo(0x1a, 0x80000000, -1i32 as u32), (0, 0x80000000));
+        let mut state = load_program(&[asm::r_type(8, 9, 0, 0, 0x1b)]);
+        state.registers[8] = 7;
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), strict.clone());
+        match is.step(false) {
+            Err(EmulatorError::DivideByZero { pc: 0 }) => {}
+            _ => panic!("expected a division by zero error"),
+        }
+
+        // misaligned word load
+        let program = [asm::lw(9, 8, 2)];
+        let mut state = load_program(&program);
+        state.registers[8] = 0x100;
+        let mut is = InstrumentedState::new(state.clone(), Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), strict);
+        match is.step(false) {
+            Err(EmulatorError::MisalignedAccess { addr: 0x102, pc: 0, .. }) => {}
+            _ => panic!("expected a misaligned access error"),
+        }
+    }
+
+    #[test]
+    fn test_fault_context() {
+        // a load from the unmapped guard page below the stack
+        let program = [asm::addiu(8, 8, 4), asm::lw(9, 8, -8)];
+        let mut state = load_program(&program);
+        let guard = state.layout.stack_guard();
+        state.registers[8] = guard.start + 0x100;
+        let config = VmConfig { stack_guard: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), config);
+        is.step(false).unwrap();
+        let e = is.step(false).map(|_| ()).unwrap_err();
+        match &e {
+            EmulatorError::StackOverflow { addr, pc: 4, ctx: Some(ctx) } => {
+                assert_eq!(*addr, guard.start + 0xfc);
+                assert_eq!((ctx.pc, ctx.step, ctx.insn), (4, 2, program[1]));
+                assert_eq!(ctx.opcode, Some(OpcodeId::LW));
+                assert_eq!((ctx.addr, ctx.base_reg, ctx.offset), (guard.start + 0xfc, 8, -8));
+                assert_eq!(ctx.dump_addr, guard.start + 0xe0);
+                assert_eq!(ctx.dump, [0; 32]);
+            }
+            e => panic!("expected a stack overflow, got {:?}", e),
+        }
+        let message = format!("stack overflow of 0x{0:x} at 0x4 by lw $9, -8($8) at 0x4, \
+            step 2, address 0x{0:x}", guard.start + 0xfc);
+        assert_eq!(e.to_string(), message);
+
+        // a store over the text of the program, the dump shows the store itself
+        let program = [asm::sw(9, 8, 4), asm::nop()];
+        let mut state = load_program(&program);
+        state.layout.text = Some((0, 8));
+        let config = VmConfig { protect_text: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state.clone(), Box::new(RecordingOracle::default()), config);
+        let e = is.step(false).map(|_| ()).unwrap_err();
+        match &e {
+            EmulatorError::WriteToReadOnly { addr: 4, pc: 0, ctx: Some(ctx) } => {
+                assert_eq!(ctx.opcode, Some(OpcodeId::SW));
+                assert_eq!((ctx.addr, ctx.base_reg, ctx.offset, ctx.step), (4, 8, 4, 1));
+                assert_eq!(ctx.dump_addr, 0);
+                assert_eq!(ctx.dump[..4], program[0].to_be_bytes());
+                assert_eq!(ctx.dump[4..], [0; 28]);
+            }
+            e => panic!("expected a write to read only, got {:?}", e),
+        }
+        assert_eq!(e.to_string(), "write to read only 0x4 at 0x0 by sw $9, 4($8) at 0x0, step 1, address 0x4");
+        // the store didn't commit, its step is not counted
+        assert_eq!((is.state.step(), is.state.pc), (0, 0));
+        let dump = format!("{:#}", e);
+        assert!(dump.ends_with(&format!(
+            "\n00000000: {} 00 00 00 00 00 00 00 00 00 00 00 00\n\
+            00000010: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n",
+            hex::encode(program[0].to_be_bytes()).as_bytes().chunks(2)
+                .map(|b| std::str::from_utf8(b).unwrap()).collect::<Vec<_>>().join(" "),
+        )), "{}", dump);
+
+        // the text is writable by default, the store succeeds
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+    }
+
+    #[test]
+    fn test_null_guard() {
+        // a load through a null pointer, the fetch of the program at 0 is not guarded
+        let program = [asm::nop(), asm::addiu(9, 0, 1), asm::lw(9, 8, 4)];
+        let config = VmConfig { null_guard: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config.clone());
+        match is.run(100) {
+            Err(EmulatorError::NullAccess { addr: 4, pc: 8, .. }) => {}
+            other => panic!("expected a null access, got {:?}", other),
+        }
+        assert_eq!(is.state.registers[9], 1);
+
+        // a store through a null pointer carries the context of the store
+        let program = [asm::sw(9, 8, 0x12)];
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config.clone());
+        let e = is.step(false).map(|_| ()).unwrap_err();
+        match &e {
+            EmulatorError::NullAccess { addr: 0x12, pc: 0, ctx: Some(ctx) } => {
+                assert_eq!(ctx.opcode, Some(OpcodeId::SW));
+                assert_eq!((ctx.addr, ctx.base_reg, ctx.offset, ctx.step), (0x12, 8, 0x12, 1));
+                assert_eq!(ctx.dump[..4], program[0].to_be_bytes());
+            }
+            e => panic!("expected a null access, got {:?}", e),
+        }
+        assert_eq!(e.to_string(), "null pointer access of 0x12 at 0x0 by sw $9, 18($8) at 0x0, step 1, address 0x12");
+
+        // the guard ends at the configured address
+        let program = [asm::nop(), asm::addiu(9, 0, 1), asm::lw(9, 8, 4)];
+        let mut state = load_program(&program);
+        state.registers[8] = 0x100;
+        let config = VmConfig { null_guard_end: 0x100, ..config };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), config);
+        for _ in 0..3 {
+            is.step(false).unwrap();
+        }
+
+        // a program linked in the first page still runs, the guard ends below its first segment
+        let text = asm::to_bytes(&[
+            asm::addiu(8, 0, 0x800),
+            asm::lw(9, 8, 0),
+            asm::sw(9, 8, 4),
+            asm::addiu(2, 0, 4246),
+            asm::addiu(4, 0, 0),
+            asm::syscall(),
+        ]);
+        let data = ElfWriter::new(0x400)
+            .segment(0x400, text)
+            .segment(0x800, vec![0x12, 0x34, 0x56, 0x78])
+            .build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        let config = VmConfig { null_guard: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), config);
+        assert_eq!(is.run(100).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.state.memory.get_memory(0x804), 0x12345678);
+    }
+
+    #[test]
+    fn test_seek_streams() {
+        let data = b"0123456789".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+        let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+        let stdout = SharedBuffer::default();
+        is.set_stdout_writer(Box::new(stdout.clone()));
+        is.state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        for i in 0..8 {
+            do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4);
+        }
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+
+        // the ftell/fseek of stdio around a flush of stdout
+        is.state.memory.set_memory_range(0x30000, Box::new(b"abcdef".as_slice())).unwrap();
+        is.state.registers[29] = 0x40000;
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x30000, 3), (3, 0));
+        assert_eq!(do_syscall(&mut is, 4019, FD_STDOUT, 0, SEEK_CUR), (0xFFffFFff, ESPIPE));
+        is.state.memory.set_memory(0x40010, SEEK_CUR).unwrap();
+        is.state.registers[7] = 0x50000;
+        assert_eq!(do_syscall(&mut is, 4140, FD_STDOUT, 0, 0), (0xFFffFFff, ESPIPE));
+        assert_eq!(do_syscall(&mut is, 4004, FD_STDOUT, 0x30003, 3), (3, 0));
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"abcdef");
+        for fd in [FD_STDIN, FD_STDERR, FD_HINT_READ, FD_HINT_WRITE, FD_PREIMAGE_WRITE] {
+            assert_eq!(do_syscall(&mut is, 4019, fd, 0, SEEK_SET), (0xFFffFFff, ESPIPE));
+        }
+
+        // the preimage offset is untouched, the read continues after the first word
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+        assert_eq!(is.state.memory.get_memory(0x20000), 10);
+
+        // _llseek of the preimage fd returns the 64 bits offset through the result pointer
+        is.state.memory.set_memory(0x40010, SEEK_SET).unwrap();
+        is.state.registers[7] = 0x50000;
+        assert_eq!(do_syscall(&mut is, 4140, FD_PREIMAGE_READ, 0, 12), (0, 0));
+        assert_eq!(is.state.memory.get_memory(0x50000), 0);
+        assert_eq!(is.state.memory.get_memory(0x50004), 12);
+        assert_eq!(do_syscall(&mut is, 4003, FD_PREIMAGE_READ, 0x20000, 4), (4, 0));
+        assert_eq!(&is.state.memory.get_memory(0x20000).to_be_bytes(), b"4567");
+    }
+
+    /// Assembles the syscall `num` on the arguments.
+    fn syscall_asm(num: u32, a0: u32, a1: u32, a2: u32) -> Vec<u32> {
+        let mut out = vec![];
+        out.extend(asm::li(4, a0));
+        out.extend(asm::li(5, a1));
+        out.extend(asm::li(6, a2));
+        out.extend([asm::addiu(2, 0, num as i16), asm::syscall()]);
+        out
+    }
+
+    #[test]
+    fn test_instrumented_state_builder() {
+        let data = b"preimage".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+
+        let mut program = vec![];
+        program.extend(syscall_asm(4003, FD_STDIN, 0x20000, 4));
+        program.extend(syscall_asm(4003, FD_STDIN, 0x20004, 4));
+        program.extend(syscall_asm(4003, FD_STDIN, 0x20008, 4));
+        program.extend(syscall_asm(4004, FD_HINT_WRITE, 0x30000, 6));
+        for i in 0..8 {
+            program.extend(syscall_asm(4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4));
+        }
+        for i in 0..4 {
+            program.extend(syscall_asm(4003, FD_PREIMAGE_READ, 0x20010 + 4 * i, 4));
+        }
+        program.extend(syscall_asm(4246, 0, 0, 0));
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        state.memory.set_memory_range(0x30000, Box::new([0, 0, 0, 2, b'h', b'i'].as_slice())).unwrap();
+
+        let mut is = InstrumentedStateBuilder::new(state)
+            .with_stdin(b"hello".as_slice())
+            .with_preimages(HashMap::from([(key, data)]))
+            .with_hints_captured()
+            .build()
+            .unwrap();
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+
+        assert_eq!(&is.state.memory.get_memory(0x20000).to_be_bytes(), b"hell");
+        assert_eq!(is.state.memory.get_memory(0x20004).to_be_bytes(), [b'o', 0, 0, 0]);
+        assert_eq!(is.state.memory.get_memory(0x20008), 0);
+        assert_eq!(is.state.memory.get_memory(0x20014), 8);
+        assert_eq!(&is.state.memory.get_memory(0x20018).to_be_bytes(), b"prei");
+        assert_eq!(&is.state.memory.get_memory(0x2001c).to_be_bytes(), b"mage");
+        assert_eq!(is.captured_hints().unwrap(), [b"hi".to_vec()]);
+
+        // preimages are checked against their keys
+        let result = InstrumentedStateBuilder::new(State::new())
+            .with_preimages(HashMap::from([(key, b"other".to_vec())]))
+            .build();
+        assert!(matches!(result, Err(EmulatorError::PreimageHashMismatch { .. })));
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        let data = b"preimage".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+
+        let mut program = vec![];
+        program.extend(syscall_asm(4004, FD_HINT_WRITE, 0x30000, 12));
+        for i in 0..8 {
+            program.extend(syscall_asm(4004, FD_PREIMAGE_WRITE, 0x10000 + 4 * i, 4));
+        }
+        // the preimage is fetched once and read from its cache after
+        for i in 0..4 {
+            program.extend(syscall_asm(4003, FD_PREIMAGE_READ, 0x20000 + 4 * i, 4));
+        }
+        program.extend(syscall_asm(4246, 0, 0, 0));
+        let mut state = load_program(&program);
+        state.memory.set_memory_range(0x10000, Box::new(key.as_slice())).unwrap();
+        let hints = [0, 0, 0, 2, b'h', b'i', 0, 0, 0, 2, b'h', b'o'];
+        state.memory.set_memory_range(0x30000, Box::new(hints.as_slice())).unwrap();
+
+        let mut is = InstrumentedStateBuilder::new(state)
+            .with_preimages(HashMap::from([(key, data)]))
+            .with_hints_captured()
+            .build()
+            .unwrap();
+        let sink = TestSink::new();
+        is.set_metrics_sink(Box::new(sink.clone()));
+        let pages_before = is.state.memory.stats().page_allocations;
+        // the counters add up over runs
+        let first = is.run(10).unwrap();
+        let second = is.run(1000).unwrap();
+        assert_eq!(second.status, VmStatus::Exited(0));
+
+        assert_eq!(sink.counter(metrics::STEPS), first.steps + second.steps);
+        assert_eq!(
+            sink.counter(metrics::PAGES_ALLOCATED),
+            is.state.memory.stats().page_allocations - pages_before
+        );
+        assert!(sink.counter(metrics::PAGES_ALLOCATED) > 0);
+        assert_eq!(sink.counter(metrics::PREIMAGES_FETCHED), 1);
+        assert_eq!(sink.counter(metrics::HINTS_POSTED), is.captured_hints().unwrap().len() as u64);
+        assert_eq!(sink.counter(metrics::HINTS_POSTED), 2);
+        assert_eq!(sink.counter("syscall_4004"), 9);
+        assert_eq!(sink.counter("syscall_4003"), 4);
+        assert_eq!(sink.counter("syscall_4246"), 1);
+    }
+
+    #[test]
+    fn test_run_summary() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+
+        // checks the keys and the types of the fields, returns the instruction mix
+        let check_schema = |is: &mut InstrumentedState, summary: &RunSummary| {
+            let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+            let object = json.as_object().unwrap();
+            let mut keys: Vec<&str> = object.keys().map(|k| k.as_str()).collect();
+            keys.sort();
+            assert_eq!(keys, [
+                "exit_code", "guest_panic", "hints_posted", "instruction_mix", "pages_allocated",
+                "preimage_bytes_served", "schema_version", "state_hash", "status", "steps",
+                "wall_time_secs",
+            ]);
+            assert_eq!(json["schema_version"], SUMMARY_SCHEMA_VERSION);
+            assert_eq!(json["status"], serde_json::json!({"exited": 0}));
+            assert_eq!(json["exit_code"], 0);
+            assert_eq!(json["steps"].as_u64(), Some(summary.steps));
+            assert!(json["wall_time_secs"].is_f64());
+            assert!(json["pages_allocated"].as_u64().unwrap() > 0);
+            assert_eq!(json["preimage_bytes_served"], 0);
+            assert_eq!(json["hints_posted"], 0);
+            assert_eq!(json["state_hash"], hex::encode(is.state_hash()));
+            assert!(json["guest_panic"].is_null());
+            json["instruction_mix"].clone()
+        };
+
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        let summary = is.run_with_summary(1000).unwrap();
+        assert_eq!(summary.status, VmStatus::Exited(0));
+        // the profiler is off, not empty
+        assert!(check_schema(&mut is, &summary).is_null());
+
+        let path = std::env::temp_dir().join(format!("summary-{}.json", std::process::id()));
+        summary.write_json(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), summary.to_json());
+        fs::remove_file(&path).unwrap();
+
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.enable_profiling();
+        let summary = is.run_with_summary(1000).unwrap();
+        let mix = check_schema(&mut is, &summary);
+        let mix = mix.as_array().unwrap();
+        assert!(!mix.is_empty() && mix.len() <= INSTRUCTION_MIX_LEN);
+        let counts: Vec<u64> = mix.iter().map(|entry| entry["count"].as_u64().unwrap()).collect();
+        assert!(counts.windows(2).all(|w| w[0] >= w[1]));
+        assert!(counts.iter().sum::<u64>() <= summary.steps);
+        // addiu runs most, 3 per word copied and 3 around the call
+        assert_eq!(mix[0], serde_json::json!({"instruction": "addiu", "count": 9}));
+    }
+
+    #[test]
+    fn test_copy_word_lanes() {
+        let word = 0x11223344u32;
+        let bytes = [0xaa, 0xbb, 0xcc, 0xdd];
+        for endianness in [Endianness::Big, Endianness::Little] {
+            for offset in 0..4u32 {
+                for len in 1..=4usize {
+                    let n = len.min(4 - offset as usize);
+                    let mut lanes = endianness.word_to_bytes(word);
+                    lanes[offset as usize..offset as usize + n].copy_from_slice(&bytes[..n]);
+                    assert_eq!(copy_into_word(word, offset, &bytes[..len], endianness),
+                               (endianness.word_from_bytes(lanes), n),
+                               "{} offset {} len {}", endianness, offset, len);
+
+                    let mut out = [0u8; 4];
+                    assert_eq!(copy_from_word(word, offset, &mut out[..len], endianness), n);
+                    let range = offset as usize..offset as usize + n;
+                    assert_eq!(out[..n], endianness.word_to_bytes(word)[range],
+                               "{} offset {} len {}", endianness, offset, len);
+                    assert!(out[n..].iter().all(|b| *b == 0));
+                }
+            }
+        }
+        let big = Endianness::Big;
+        assert_eq!(copy_into_word(0x11223344, 2, &[], big), (0x11223344, 0));
+        assert_eq!(copy_into_word(0, 1, &[1, 2, 3, 4], big), (0x00010203, 3));
+        assert_eq!(copy_from_word(0x01020304, 3, &mut [0u8; 4], big), 1);
+        assert_eq!(copy_into_word(0, 1, &[1, 2, 3, 4], Endianness::Little), (0x03020100, 3));
+    }
+
+    #[test]
+    fn test_preimage_io_at_every_alignment() {
+        let data: Vec<u8> = (0..40).collect();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        for alignment in 0..4u32 {
+            let mut oracle = RecordingOracle::default();
+            oracle.images.insert(key, data.clone());
+            let mut is = InstrumentedState::new(State::new(), Box::new(oracle));
+
+            // write the key from an unaligned buffer, taking the short writes into account
+            let buf = 0x10000 + alignment;
+            is.state.memory.set_memory_range(buf, Box::new(key.as_slice())).unwrap();
+            let mut written = 0;
+            while written < 32 {
+                let (n, _) = do_syscall(&mut is, 4004, FD_PREIMAGE_WRITE, buf + written, 32 - written);
+                assert!(n >= 1 && n <= 4);
+                written += n;
+            }
+
+            let out = 0x20000 + alignment;
+            let mut read = 0;
+            loop {
+                let (n, _) = do_syscall(&mut is, 4003, FD_PREIMAGE_READ, out + read, 64);
+                if n == 0 {
+                    break;
+                }
+                read += n;
+            }
+            assert_eq!(read, 48);
+            let words: Vec<u8> = (0x20000u32..0x20000 + 52).step_by(4)
+                .flat_map(|addr| is.state.memory.get_memory(addr).to_be_bytes())
+                .collect();
+            let start = alignment as usize;
+            assert_eq!(words[start..start + 8], (data.len() as u64).to_be_bytes());
+            assert_eq!(words[start + 8..start + 48], data, "alignment {}", alignment);
+        }
+    }
+
+    #[test]
+    fn test_rdhwr() {
+        let mut state = load_program(&[asm::rdhwr(8, 29), asm::rdhwr(9, 0), asm::rdhwr(10, 3)]);
+        state.set_thread_pointer(0x7ff00000);
+        state.registers[9] = 5;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..3 {
+            is.step(false).unwrap();
+        }
+        assert_eq!(is.state.registers[8], 0x7ff00000);
+        assert_eq!(is.state.registers[9], 0);
+        assert_eq!(is.state.registers[10], 3);
+        assert_eq!(is.state.pc, 12);
+
+        // the other hardware registers are reserved, lenient mode skips the read
+        let program = [asm::rdhwr(8, 2)];
+        let oracle = Box::new(RecordingOracle::default());
+        let mut is = InstrumentedState::new(load_program(&program), oracle);
+        match is.step(false) {
+            Err(EmulatorError::InvalidOpcode { pc: 0, insn }) if insn == program[0] => {}
+            r => panic!("expected an invalid opcode, got {:?}", r.map(|_| ())),
+        }
+        let config = VmConfig { invalid_opcodes: ExecutionMode::Lenient, ..Default::default() };
+        let mut state = load_program(&program);
+        state.registers[8] = 5;
+        let oracle = Box::new(RecordingOracle::default());
+        let mut is = InstrumentedState::new_with_config(state, oracle, config);
+        is.step(false).unwrap();
+        assert_eq!((is.state.registers[8], is.state.pc), (5, 4));
+        assert_eq!(is.skipped_instructions(), [(0, program[0])]);
+    }
+
+    #[test]
+    fn test_estimate_rows() {
+        let mut report = ProfileReport::new();
+        let program = [
+            (asm::addiu(8, 0, 1), 10),
+            (asm::lw(9, 8, 0), 5),
+            (asm::sw(9, 8, 0), 3),
+            (asm::bne(8, 0, 1), 2),
+            (asm::jr(31), 1),
+            (asm::syscall(), 4),
+        ];
+        for (i, (insn, count)) in program.iter().enumerate() {
+            for _ in 0..*count {
+                report.record(4 * i as u32, *insn);
+            }
+        }
+        for num in [4003, 4003, 4004, 4246] {
+            report.record_syscall(num);
+        }
+        assert_eq!(report.per_kind[&InsnKind::Load], 5);
+
+        let model = CostModel::from_json(r#"{
+            "default_rows": 1,
+            "kinds": {"load": 4, "store": 6, "syscall": 10},
+            "syscalls": {"4003": 100}
+        }"#.as_bytes()).unwrap();
+        // alu, branch and jump 13 * 1, load 5 * 4, store 3 * 6, read 2 * 100, the others 2 * 10
+        assert_eq!(report.estimate_rows(&model), 13 + 20 + 18 + 200 + 20);
+        assert_eq!(report.estimate_rows(&CostModel::default()), 0);
+    }
+
+    #[test]
+    fn test_merge_profiles() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.enable_profiling();
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+        let whole = is.profile().unwrap().clone();
+
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.enable_profiling();
+        assert_eq!(is.run(11).unwrap().status, VmStatus::StepLimitReached);
+        let mut merged = is.profile().unwrap().clone();
+        is.enable_profiling();
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+        merged.merge(is.profile().unwrap());
+
+        assert_eq!(merged, whole);
+        assert_eq!(whole.steps, 26);
+        assert_eq!(whole.per_syscall[&4246], 1);
+    }
+
+    #[test]
+    fn test_set_thread_area() {
+        let mut program = vec![asm::rdhwr(8, 29)];
+        program.extend(syscall_asm(4283, 0x7ff01000, 0, 0));
+        program.push(asm::rdhwr(9, 29));
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        for _ in 0..program.len() {
+            is.step(false).unwrap();
+        }
+        // zero before the TLS is set up
+        assert_eq!(is.state.registers[8], 0);
+        assert_eq!(is.state.registers[2], 0);
+        assert_eq!(is.state.registers[9], 0x7ff01000);
+        assert_eq!(is.state.thread_pointer(), 0x7ff01000);
+    }
+
+    #[test]
+    fn test_branch_in_delay_slot() {
+        let program = [asm::beq(0, 0, 2), asm::j(0x100), asm::nop()];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        match is.step(false) {
+            Err(EmulatorError::UnpredictableDelaySlot { pc: 4, insn }) if insn == program[1] => {}
+            _ => panic!("expected UnpredictableDelaySlot"),
+        }
+
+        // the same from a resumed copy of the state
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        let mut resumed = InstrumentedState::new(is.state.clone(), Box::new(RecordingOracle::default()));
+        let result = resumed.step(false);
+        assert!(matches!(result, Err(EmulatorError::UnpredictableDelaySlot { pc: 4, .. })));
+
+        // a jump in the delay slot of a jump
+        let program = [asm::j(0x100), asm::jal(0x200)];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        assert!(matches!(is.step(false), Err(EmulatorError::UnpredictableDelaySlot { pc: 4, .. })));
+
+        // branch, then ALU in the delay slot
+        let program = [asm::beq(0, 0, 2), asm::addiu(8, 0, 7), asm::nop(), asm::addiu(9, 8, 1)];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        for _ in 0..3 {
+            is.step(false).unwrap();
+        }
+        assert_eq!(is.state.pc, 0x10);
+        assert_eq!(is.state.registers[8], 7);
+        assert_eq!(is.state.registers[9], 8);
+    }
+
+    /// Executes `program` from address 0 with `regs` set and the words of `mem` stored.
+    fn exec_program(program: &[u32], regs: &[(usize, u32)], mem: &[(u32, u32)]) -> Box<InstrumentedState> {
+        exec_program_in(Endianness::Big, program, regs, mem)
+    }
+
+    /// Executes `program` like `exec_program` on a memory of the byte order `endianness`.
+    fn exec_program_in(
+        endianness: Endianness,
+        program: &[u32],
+        regs: &[(usize, u32)],
+        mem: &[(u32, u32)],
+    ) -> Box<InstrumentedState> {
+        let mut state = State::new();
+        state.memory.set_endianness(endianness);
+        for (i, insn) in program.iter().enumerate() {
+            state.memory.set_memory(4 * i as u32, *insn).unwrap();
+        }
+        for (i, v) in regs {
+            state.registers[*i] = *v;
+        }
+        for (addr, v) in mem {
+            state.memory.set_memory(*addr, *v).unwrap();
+        }
+        let config = VmConfig { endianness, ..Default::default() };
+        let mut is =
+            InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), config);
+        for _ in 0..program.len() {
+            is.step(false).unwrap();
+        }
+        is
+    }
+
+    /// Executes the R-type `fun` of `opcode` on rs and rt, returns rd.
+    fn exec_alu(opcode: u32, fun: u32, rs: u32, rt: u32, shamt: u32) -> u32 {
+        let insn = (opcode << 26) | asm::r_type(8, 9, 10, shamt, fun);
+        exec_program(&[insn], &[(8, rs), (9, rt), (10, 7)], &[]).state.registers[10]
+    }
+
+    /// Executes the load/store `opcode` at `offset` from 0x10008 with rt set to `rt`, returns
+    /// rt and the two words below 0x10008.
+    fn exec_mem(opcode: u32, offset: i16, rt: u32) -> (u32, u32, u32) {
+        exec_mem_in(Endianness::Big, opcode, offset, rt)
+    }
+
+    /// Executes the load/store like `exec_mem` on a memory of the byte order `endianness`.
+    fn exec_mem_in(endianness: Endianness, opcode: u32, offset: i16, rt: u32) -> (u32, u32, u32) {
+        let insn = asm::i_type(opcode, 8, 9, offset as u32);
+        let mem = [(0x10000, 0x11223344), (0x10004, 0x8899aabb)];
+        let mut is = exec_program_in(endianness, &[insn], &[(8, 0x10008), (9, rt)], &mem);
+        let memory = &mut is.state.memory;
+        let words = (memory.get_memory(0x10000), memory.get_memory(0x10004));
+        (is.state.registers[9], words.0, words.1)
+    }
+
+    /// Executes the branch `insn` with offset 3 on rs and rt, and its delay slot, returns
+    /// whether it is taken.
+    fn exec_branch(insn: u32, rs: u32, rt: u32) -> bool {
+        let is = exec_program(&[insn, asm::nop()], &[(8, rs), (9, rt)], &[]);
+        match is.state.pc {
+            0x10 => true,
+            8 => false,
+            pc => panic!("unexpected pc 0x{:x}", pc),
+        }
+    }
+
+    /// Executes the ext/ins `insn` on rs = $8 and rt = $9, returns $9.
+    fn exec_bitfield(insn: u32, rs: u32, rt: u32) -> u32 {
+        let is = exec_program(&[insn], &[(8, rs), (9, rt)], &[]);
+        is.state.registers[9]
+    }
+
+    #[test]
+    fn test_bitfield() {
+        // ext extracts the field into the low bits
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 0, 1), 0xffffffff, 0), 1);
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 0, 32), 0x89abcdef, 0), 0x89abcdef);
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 31, 1), 0x80000000, 0), 1);
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 8, 12), 0x89abcdef, 0xffffffff), 0xbcd);
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 16, 16), 0x89abcdef, 0), 0x89ab);
+
+        // ins replaces the field of rt with the low bits of rs, the rest of rt is kept
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 0, 1), 0xfffffffe, 0xffffffff), 0xfffffffe);
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 0, 32), 0x89abcdef, 0x12345678), 0x89abcdef);
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 31, 1), 1, 0), 0x80000000);
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 8, 12), 0xfffff000, 0xffffffff), 0xfff000ff);
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 8, 12), 0xffffffff, 0), 0x000fff00);
+
+        // the ext fields cross bit 31, the msb of the ins field is below its lsb
+        for insn in [asm::ext(9, 8, 16, 17), asm::ext(9, 8, 31, 2), asm::ins(9, 8, 4, 0)] {
+            let mut is = InstrumentedState::new(load_program(&[insn]), Box::new(RecordingOracle::default()));
+            match is.step(false) {
+                Err(EmulatorError::UnpredictableBitfield { pc: 0, insn: got }) => assert_eq!(got, insn),
+                other => panic!("0x{:08x}: {:?}", insn, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_extend() {
+        let exec = |insn: u32, rt: u32| exec_program(&[insn], &[(9, rt)], &[]).state.registers[8];
+        assert_eq!(exec(asm::seb(8, 9), 0x80), 0xffffff80);
+        assert_eq!(exec(asm::seh(8, 9), 0x8000), 0xffff8000);
+        // positive values are unchanged
+        assert_eq!(exec(asm::seb(8, 9), 0x7f), 0x7f);
+        assert_eq!(exec(asm::seh(8, 9), 0x7fff), 0x7fff);
+        // the upper bits of rt are ignored
+        assert_eq!(exec(asm::seb(8, 9), 0x12345681), 0xffffff81);
+        assert_eq!(exec(asm::seb(8, 9), 0xffffff01), 0x01);
+        assert_eq!(exec(asm::seh(8, 9), 0x1234f00d), 0xfffff00d);
+        assert_eq!(exec(asm::seh(8, 9), 0xffff0123), 0x0123);
+        // sra by 0 sign extends all 32 bits
+        assert_eq!(exec_alu(0, 0x03, 0, 0x80000001, 0), 0x80000001);
+    }
+
+    #[test]
+    fn test_rotate_and_swap() {
+        let exec = |insn: u32, rt: u32, rs: u32| {
+            exec_program(&[insn], &[(9, rt), (10, rs)], &[]).state.registers[8]
+        };
+        assert_eq!(exec(asm::rotr(8, 9, 8), 0x11223344, 0), 0x44112233);
+        assert_eq!(exec(asm::rotr(8, 9, 0), 0x11223344, 0), 0x11223344);
+        assert_eq!(exec(asm::rotr(8, 9, 31), 0x80000001, 0), 0x00000003);
+        // the variable amount is masked to 5 bits
+        assert_eq!(exec(asm::rotrv(8, 9, 10), 0x11223344, 8), 0x44112233);
+        assert_eq!(exec(asm::rotrv(8, 9, 10), 0x11223344, 40), 0x44112233);
+        // without the R bit they are still srl/srlv
+        assert_eq!(exec(asm::r_type(0, 9, 8, 8, 0x02), 0x11223344, 0), 0x00112233);
+        assert_eq!(exec(asm::r_type(10, 9, 8, 0, 0x06), 0x11223344, 8), 0x00112233);
+
+        assert_eq!(exec(asm::wsbh(8, 9), 0x11223344, 0), 0x22114433);
+        assert_eq!(exec(asm::wsbh(8, 9), 0xff00ff00, 0), 0x00ff00ff);
+    }
+
+    #[test]
+    fn test_cache_is_nop() {
+        // the op field is in the rt bits, the register must not be written
+        let mut state = load_program(&[asm::cache(0x15, 8, -4)]);
+        state.registers[8] = 0x10008;
+        state.registers[0x15] = 0xdeadbeef;
+        state.memory.set_memory(0x10004, 0x8899aabb).unwrap();
+        let (registers, root) = (state.registers, state.memory.merkle_root());
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+
+        let (_, _, accesses) = is.step(true).unwrap();
+        assert_eq!(is.state.pc, 4);
+        assert_eq!(is.state.registers, registers);
+        assert_eq!(is.state.memory.merkle_root(), root);
+        // the effective address is still accessed, for the witness
+        assert_eq!(accesses.len(), 2);
+        assert_eq!(accesses[1].addr, 0x10004);
+        assert_eq!(accesses[1].op, MemoryOperation::Read);
+        assert_eq!(accesses[1].value, 0x8899aabb);
+    }
+
+    #[test]
+    fn test_instruction_coverage() {
+        coverage::reset();
+
+        // shifts
+        assert_eq!(exec_alu(0, 0x00, 0, 0x80000001, 4), 0x10); // sll
+        assert_eq!(exec_alu(0, 0x02, 0, 0x80000000, 4), 0x08000000); // srl
+        assert_eq!(exec_alu(0, 0x03, 0, 0x80000000, 4), 0xf8000000); // sra
+        assert_eq!(exec_alu(0, 0x04, 36, 1, 0), 0x10); // sllv
+        assert_eq!(exec_alu(0, 0x06, 4, 0x80000000, 0), 0x08000000); // srlv
+        assert_eq!(exec_alu(0, 0x07, 4, 0x80000000, 0), 0xf8000000); // srav
+
+        // arithmetic and logic
+        assert_eq!(exec_alu(0, 0x20, 1, 2, 0), 3); // add
+        assert_eq!(exec_alu(0, 0x21, 0xffffffff, 2, 0), 1); // addu
+        assert_eq!(exec_alu(0, 0x22, 1, 2, 0), 0xffffffff); // sub
+        assert_eq!(exec_alu(0, 0x23, 5, 3, 0), 2); // subu
+        assert_eq!(exec_alu(0, 0x24, 0xff00ff00, 0x0ff00ff0, 0), 0x0f000f00); // and
+        assert_eq!(exec_alu(0, 0x25, 0xff00ff00, 0x0ff00ff0, 0), 0xfff0fff0); // or
+        assert_eq!(exec_alu(0, 0x26, 0xff00ff00, 0x0ff00ff0, 0), 0xf0f0f0f0); // xor
+        assert_eq!(exec_alu(0, 0x27, 0xff00ff00, 0x0ff00ff0, 0), 0x000f000f); // nor
+        assert_eq!(exec_alu(0, 0x2a, 0xffffffff, 1, 0), 1); // slt
+        assert_eq!(exec_alu(0, 0x2b, 0xffffffff, 1, 0), 0); // sltu
+        assert_eq!(exec_alu(0, 0x0a, 5, 0, 0), 5); // movz
+        assert_eq!(exec_alu(0, 0x0a, 5, 1, 0), 7);
+        assert_eq!(exec_alu(0, 0x0b, 5, 1, 0), 5); // movn
+        assert_eq!(exec_alu(0, 0x0b, 5, 0, 0), 7);
+        assert_eq!(exec_alu(0x1c, 0x02, -3i32 as u32, 4, 0), -12i32 as u32); // mul
+        assert_eq!(exec_alu(0x1c, 0x20, 0x00ffffff, 0, 0), 8); // clz
+        assert_eq!(exec_alu(0x1c, 0x21, 0xff000000, 0, 0), 8); // clo
+        assert_eq!(exec_bitfield(asm::ext(9, 8, 4, 8), 0x12345678, 0), 0x67); // ext
+        assert_eq!(exec_bitfield(asm::ins(9, 8, 4, 8), 0xab, 0x12345678), 0x12345ab8); // ins
+        assert_eq!(exec_bitfield(asm::seb(9, 8), 0xff, 0), 0xffffffff); // seb
+        assert_eq!(exec_bitfield(asm::seh(9, 8), 0xffff, 0), 0xffffffff); // seh
+        assert_eq!(exec_bitfield(asm::wsbh(9, 8), 0x11223344, 0), 0x22114433); // wsbh
+        let regs = [(9, 0x11223344), (10, 8)];
+        assert_eq!(exec_program(&[asm::rotr(8, 9, 8)], &regs, &[]).state.registers[8], 0x44112233); // rotr
+        assert_eq!(exec_program(&[asm::rotrv(8, 9, 10)], &regs, &[]).state.registers[8], 0x44112233); // rotrv
+
+        // immediates
+        assert_eq!(exec_imm(0x8, 1, 0xffff), 0); // addi
+        assert_eq!(exec_imm(0x9, 0x7fffffff, 1), 0x80000000); // addiu
+        assert_eq!(exec_imm(0xa, 0, 1), 1); // slti
+        assert_eq!(exec_imm(0xb, 0, 1), 1); // sltiu
+        assert_eq!(exec_imm(0xc, 0xffffffff, 0x8000), 0x8000); // andi
+        assert_eq!(exec_imm(0xd, 1, 0x8000), 0x8001); // ori
+        assert_eq!(exec_imm(0xe, 0xffff, 0x00ff), 0xff00); // xori
+        assert_eq!(exec_imm(0xf, 0, 0x1234), 0x12340000); // lui
+
+        // hi/lo, read back with mfhi/mflo
+        assert_eq!(exec_hilo(0x18, -2i32 as u32, 3), (0xffffffff, -6i32 as u32)); // mult
+        assert_eq!(exec_hilo(0x19, 0x80000000, 4), (2, 0)); // multu
+        assert_eq!(exec_hilo(0x1a, -7i32 as u32, 2), (-1i32 as u32, -3i32 as u32)); // div
+        assert_eq!(exec_hilo(0x1b, 7, 2), (1, 3)); // divu
+        let program = [
+            asm::r_type(8, 0, 0, 0, 0x11), // mthi
+            asm::r_type(9, 0, 0, 0, 0x13), // mtlo
+            asm::r_type(0, 0, 10, 0, 0x10), // mfhi
+            asm::r_type(0, 0, 11, 0, 0x12), // mflo
+        ];
+        let is = exec_program(&program, &[(8, 1), (9, 2)], &[]);
+        assert_eq!((is.state.registers[10], is.state.registers[11]), (1, 2));
+
+        // branches
+        assert!(exec_branch(asm::beq(8, 9, 3), 1, 1));
+        assert!(!exec_branch(asm::beq(8, 9, 3), 1, 2));
+        assert!(exec_branch(asm::bne(8, 9, 3), 1, 2));
+        assert!(exec_branch(asm::i_type(6, 8, 0, 3), 0, 0)); // blez
+        assert!(!exec_branch(asm::i_type(6, 8, 0, 3), 1, 0));
+        assert!(exec_branch(asm::i_type(7, 8, 0, 3), 1, 0)); // bgtz
+        assert!(!exec_branch(asm::i_type(7, 8, 0, 3), 0, 0));
+        assert!(exec_branch(asm::i_type(1, 8, 0, 3), -1i32 as u32, 0)); // bltz
+        assert!(!exec_branch(asm::i_type(1, 8, 0, 3), 0, 0));
+        assert!(exec_branch(asm::i_type(1, 8, 1, 3), 0, 0)); // bgez
+        assert!(!exec_branch(asm::i_type(1, 8, 1, 3), -1i32 as u32, 0));
+
+        // jumps, with their delay slot
+        let is = exec_program(&[asm::j(0x100), asm::nop()], &[], &[]);
+        assert_eq!(is.state.pc, 0x100);
+        let is = exec_program(&[asm::jal(0x100), asm::nop()], &[], &[]);
+        assert_eq!((is.state.pc, is.state.registers[31]), (0x100, 8));
+        let is = exec_program(&[asm::jr(8), asm::nop()], &[(8, 0x200)], &[]);
+        assert_eq!(is.state.pc, 0x200);
+        let jalr = asm::r_type(8, 0, 10, 0, 9);
+        let is = exec_program(&[jalr, asm::nop()], &[(8, 0x200)], &[]);
+        assert_eq!((is.state.pc, is.state.registers[10]), (0x200, 8));
+
+        // syscall and rdhwr
+        let mut state = load_program(&[asm::syscall()]);
+        state.registers[2] = 4246;
+        state.registers[4] = 3;
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert_eq!(is.run(1).unwrap().status, VmStatus::Exited(3));
+        let mut state = load_program(&[asm::rdhwr(10, 29)]);
+        state.set_thread_pointer(0x1234);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.step(false).unwrap();
+        assert_eq!(is.state.registers[10], 0x1234);
+
+        // loads and stores at negative offsets, the words are 0x11223344 and 0x8899aabb
+        let rt = 0xdeadbeef;
+        assert_eq!(exec_mem(0x20, -4, rt).0, 0xffffff88); // lb
+        assert_eq!(exec_mem(0x24, -3, rt).0, 0x99); // lbu
+        assert_eq!(exec_mem(0x21, -2, rt).0, 0xffffaabb); // lh
+        assert_eq!(exec_mem(0x25, -2, rt).0, 0xaabb); // lhu
+        assert_eq!(exec_mem(0x25, -8, rt).0, 0x1122);
+        assert_eq!(exec_mem(0x23, -8, rt).0, 0x11223344); // lw
+        assert_eq!(exec_mem(0x30, -4, rt).0, 0x8899aabb); // ll
+        assert_eq!(exec_mem(0x22, -7, rt).0, 0x223344ef); // lwl
+        assert_eq!(exec_mem(0x26, -6, rt).0, 0xde112233); // lwr
+        assert_eq!(exec_mem(0x26, -5, rt).0, 0x11223344);
+        let rt = 0xaabbccdd;
+        assert_eq!(exec_mem(0x28, -3, rt), (rt, 0x11223344, 0x88ddaabb)); // sb
+        assert_eq!(exec_mem(0x29, -2, rt), (rt, 0x11223344, 0x8899ccdd)); // sh
+        assert_eq!(exec_mem(0x2b, -8, rt), (rt, rt, 0x8899aabb)); // sw
+        assert_eq!(exec_mem(0x2a, -7, rt), (rt, 0x11aabbcc, 0x8899aabb)); // swl
+        assert_eq!(exec_mem(0x2a, -8, rt), (rt, rt, 0x8899aabb));
+        assert_eq!(exec_mem(0x2e, -6, rt), (rt, 0xbbccdd44, 0x8899aabb)); // swr
+        assert_eq!(exec_mem(0x38, -4, rt), (1, 0x11223344, rt)); // sc
+        assert_eq!(exec_mem(0x2f, -4, rt), (rt, 0x11223344, 0x8899aabb)); // cache
+
+        // sync and break
+        assert_eq!(exec_program(&[0x0000000f], &[], &[]).state.pc, 4);
+        assert_eq!(exec_program(&[asm::brk(1)], &[], &[]).state.break_code(), Some(1));
+
+        assert_full_coverage!();
+    }
+
+    #[test]
+    fn test_instructions_in_both_endiannesses() {
+        let r = |opcode: u32, fun: u32, shamt: u32| {
+            (opcode << 26) | asm::r_type(8, 9, 10, shamt, fun)
+        };
+        let i = |opcode: u32, imm: u32| asm::i_type(opcode, 8, 10, imm);
+        // (insn, rs, rt, $10 after), $10 is 7 before
+        let alu = [
+            (r(0, 0x00, 4), 0, 0x80000001, 0x10), // sll
+            (r(0, 0x02, 4), 0, 0x80000000, 0x08000000), // srl
+            (r(0, 0x03, 4), 0, 0x80000000, 0xf8000000), // sra
+            (r(0, 0x04, 0), 36, 1, 0x10), // sllv
+            (r(0, 0x06, 0), 4, 0x80000000, 0x08000000), // srlv
+            (r(0, 0x07, 0), 4, 0x80000000, 0xf8000000), // srav
+            (r(0, 0x20, 0), 1, 2, 3), // add
+            (r(0, 0x21, 0), 0xffffffff, 2, 1), // addu
+            (r(0, 0x22, 0), 1, 2, 0xffffffff), // sub
+            (r(0, 0x23, 0), 5, 3, 2), // subu
+            (r(0, 0x24, 0), 0xff00ff00, 0x0ff00ff0, 0x0f000f00), // and
+            (r(0, 0x25, 0), 0xff00ff00, 0x0ff00ff0, 0xfff0fff0), // or
+            (r(0, 0x26, 0), 0xff00ff00, 0x0ff00ff0, 0xf0f0f0f0), // xor
+            (r(0, 0x27, 0), 0xff00ff00, 0x0ff00ff0, 0x000f000f), // nor
+            (r(0, 0x2a, 0), 0xffffffff, 1, 1), // slt
+            (r(0, 0x2b, 0), 0xffffffff, 1, 0), // sltu
+            (r(0, 0x0a, 0), 5, 0, 5), // movz
+            (r(0, 0x0b, 0), 5, 0, 7), // movn
+            (r(0x1c, 0x02, 0), -3i32 as u32, 4, -12i32 as u32), // mul
+            (r(0x1c, 0x20, 0), 0x00ffffff, 0, 8), // clz
+            (r(0x1c, 0x21, 0), 0xff000000, 0, 8), // clo
+            (i(0x8, 0xffff), 1, 0, 0), // addi
+            (i(0x9, 1), 0x7fffffff, 0, 0x80000000), // addiu
+            (i(0xa, 1), 0, 0, 1), // slti
+            (i(0xb, 1), 0, 0, 1), // sltiu
+            (i(0xc, 0x8000), 0xffffffff, 0, 0x8000), // andi
+            (i(0xd, 0x8000), 1, 0, 0x8001), // ori
+            (i(0xe, 0x00ff), 0xffff, 0, 0xff00), // xori
+            (i(0xf, 0x1234), 0, 0, 0x12340000), // lui
+            (asm::ext(10, 8, 4, 8), 0x12345678, 0, 0x67), // ext
+            (asm::ins(10, 8, 4, 8), 0xab, 0, 0xab7), // ins
+            (asm::seb(10, 9), 0, 0xff, 0xffffffff), // seb
+            (asm::seh(10, 9), 0, 0xffff, 0xffffffff), // seh
+            (asm::wsbh(10, 9), 0, 0x11223344, 0x22114433), // wsbh
+            (asm::rotr(10, 9, 8), 0, 0x11223344, 0x44112233), // rotr
+            (asm::rotrv(10, 9, 8), 8, 0x11223344, 0x44112233), // rotrv
+        ];
+        // (fun, rs, rt, hi, lo), read back with mfhi/mflo
+        let hilo = [
+            (0x18, -2i32 as u32, 3, 0xffffffff, -6i32 as u32), // mult
+            (0x19, 0x80000000, 4, 2, 0), // multu
+            (0x1a, -7i32 as u32, 2, -1i32 as u32, -3i32 as u32), // div
+            (0x1b, 7, 2, 1, 3), // divu
+        ];
+        // (opcode, offset, rt big endian, rt little endian) from 0x10008, the words below it are
+        // 0x11223344 and 0x8899aabb, rt is 0xdeadbeef before
+        let loads = [
+            (0x20, -4, 0xffffff88, 0xffffffbb), // lb
+            (0x24, -3, 0x99, 0xaa), // lbu
+            (0x21, -2, 0xffffaabb, 0xffff8899), // lh
+            (0x25, -8, 0x1122, 0x3344), // lhu
+            (0x23, -8, 0x11223344, 0x11223344), // lw
+            (0x30, -4, 0x8899aabb, 0x8899aabb), // ll
+            (0x22, -7, 0x223344ef, 0x3344beef), // lwl
+            (0x26, -6, 0xde112233, 0xdead1122), // lwr
+        ];
+        // (opcode, offset, words big endian, words little endian), rt is 0xaabbccdd
+        let stores = [
+            (0x28, -3, (0x11223344, 0x88ddaabb), (0x11223344, 0x8899ddbb)), // sb
+            (0x29, -2, (0x11223344, 0x8899ccdd), (0x11223344, 0xccddaabb)), // sh
+            (0x2b, -8, (0xaabbccdd, 0x8899aabb), (0xaabbccdd, 0x8899aabb)), // sw
+            (0x2a, -7, (0x11aabbcc, 0x8899aabb), (0x1122aabb, 0x8899aabb)), // swl
+            (0x2e, -6, (0xbbccdd44, 0x8899aabb), (0xccdd3344, 0x8899aabb)), // swr
+            (0x38, -4, (0x11223344, 0xaabbccdd), (0x11223344, 0xaabbccdd)), // sc
+        ];
+
+        for endianness in [Endianness::Big, Endianness::Little] {
+            for (insn, rs, rt, expected) in alu {
+                let is = exec_program_in(endianness, &[insn], &[(8, rs), (9, rt), (10, 7)], &[]);
+                assert_eq!(is.state.registers[10], expected, "{} insn 0x{:08x}", endianness, insn);
+            }
+            for (fun, rs, rt, hi, lo) in hilo {
+                let program = [
+                    asm::r_type(8, 9, 0, 0, fun),
+                    asm::r_type(0, 0, 10, 0, 0x10), // mfhi
+                    asm::r_type(0, 0, 11, 0, 0x12), // mflo
+                ];
+                let is = exec_program_in(endianness, &program, &[(8, rs), (9, rt)], &[]);
+                let got = (is.state.registers[10], is.state.registers[11]);
+                assert_eq!(got, (hi, lo), "{} fun 0x{:x}", endianness, fun);
+            }
+            let regs = [(8, 1), (9, 1)];
+            let is = exec_program_in(endianness, &[asm::beq(8, 9, 3), asm::nop()], &regs, &[]);
+            assert_eq!(is.state.pc, 0x10);
+            let is = exec_program_in(endianness, &[asm::jal(0x100), asm::nop()], &[], &[]);
+            assert_eq!((is.state.pc, is.state.registers[31]), (0x100, 8));
+
+            let big = endianness == Endianness::Big;
+            for (opcode, offset, rt_big, rt_little) in loads {
+                let expected = (if big { rt_big } else { rt_little }, 0x11223344, 0x8899aabb);
+                assert_eq!(exec_mem_in(endianness, opcode, offset, 0xdeadbeef), expected,
+                           "{} opcode 0x{:x} offset {}", endianness, opcode, offset);
+            }
+            for (opcode, offset, words_big, words_little) in stores {
+                let (rt, first, second) = exec_mem_in(endianness, opcode, offset, 0xaabbccdd);
+                assert_eq!(rt, if opcode == 0x38 { 1 } else { 0xaabbccdd });
+                assert_eq!((first, second), if big { words_big } else { words_little },
+                           "{} opcode 0x{:x} offset {}", endianness, opcode, offset);
+            }
+            // the unaligned loads and stores of the whole word, from the other end of it
+            let (lwr, lwl) = if big { (-5, -8) } else { (-8, -5) };
+            assert_eq!(exec_mem_in(endianness, 0x26, lwr, 0xdeadbeef).0, 0x11223344);
+            assert_eq!(exec_mem_in(endianness, 0x22, lwl, 0xdeadbeef).0, 0x11223344);
+            assert_eq!(exec_mem_in(endianness, 0x2a, lwl, 0xaabbccdd).1, 0xaabbccdd); // swl
+            assert_eq!(exec_mem_in(endianness, 0x2e, lwr, 0xaabbccdd).1, 0xaabbccdd); // swr
+        }
+    }
+
+    #[test]
+    fn test_corrupt_state() {
+        let program = [
+            asm::addiu(8, 0, 1),
+            asm::addu(9, 8, 0),
+            asm::sw(9, 0, 0x100),
+            asm::addiu(10, 0, 0),
+            asm::nop(),
+        ];
+        let mut honest = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        let mut cheater = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        for _ in 0..program.len() {
+            honest.step(false).unwrap();
+        }
+
+        // the corrupted register flows into memory, the corrupted $zero into r10
+        for i in 0..program.len() {
+            match i {
+                1 => cheater.corrupt_register(8, 2),
+                3 => cheater.corrupt_register(0, 3),
+                _ => {}
+            }
+            cheater.step(false).unwrap();
+        }
+        assert_eq!(cheater.state.registers[10], 3);
+        assert_eq!(cheater.state.memory.get_memory(0x100), 2);
+        assert_ne!(honest.state_hash(), cheater.state_hash());
+
+        // corrupting the memory back to the honest word leaves the registers diverged
+        cheater.corrupt_memory(0x100, 1).unwrap();
+        assert_eq!(cheater.state.memory.get_memory(0x100), honest.state.memory.get_memory(0x100));
+        assert_ne!(honest.state_hash(), cheater.state_hash());
+    }
+
+    /// Returns an ELF whose code stores a word over its own text through a wild pointer, the
+    /// store is at 0x400008.
+    fn wild_store_program() -> Vec<u8> {
+        let text = asm::to_bytes(&[
+            asm::lui(8, 0x40),
+            asm::ori(8, 8, 0x10),
+            asm::sw(0, 8, 4), // overwrites the addiu at 0x400014 with a nop
+            asm::nop(),
+            asm::nop(),
+            asm::addiu(9, 0, 1),
+        ]);
+        ElfWriter::new(0x400000)
+            .segment(0x400000, text)
+            .segment(0x410000, vec![0u8; 4])
+            .build()
+    }
+
+    #[test]
+    fn test_instruction_image() {
+        let text = [
+            asm::lui(8, 0x40),
+            asm::sw(0, 8, 0), // overwrites the lui with a nop
+            asm::nop(),
+            asm::addiu(9, 0, 1),
+        ];
+        let data = ElfWriter::new(0x400000)
+            .segment(0x400000, asm::to_bytes(&text))
+            .segment(0x410000, vec![0xff; 4])
+            .build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, mut program) = State::load_elf(&file);
+        program.load_instructions(&mut state);
+
+        // the image holds the text only, not the data segment
+        let expected: Vec<(u32, u32)> = zip((0x400000..).step_by(4), text).collect();
+        assert_eq!(program.image.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(program.image.get(0x400004), Some(text[1]));
+        assert_eq!(program.image.get(0x410000), None);
+
+        // the snapshot is taken when execution starts, later stores don't change it
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..2 {
+            is.step(false).unwrap();
+        }
+        assert_eq!(is.state.memory.get_memory(0x400000), 0);
+        assert_eq!(is.instruction_image(), &program.image);
+    }
+
+    #[test]
+    fn test_code_hash() {
+        let text = asm::to_bytes(&[asm::addiu(8, 0, 1), asm::nop()]);
+        let load = |base: u32| {
+            let data = ElfWriter::new(base).segment(base, text.clone()).build();
+            let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+            let (mut state, mut program) = State::load_elf(&file);
+            program.load_instructions(&mut state);
+            program
+        };
+        let (low, high) = (load(0x400000), load(0x500000));
+        assert_eq!(low.code_hash(), load(0x400000).code_hash());
+        assert_ne!(low.code_hash(), high.code_hash());
+        assert_eq!(low.code_bytes()[CODE_HASH_DOMAIN.len()..][..8], [0, 0x40, 0, 0, 0x24, 0x08, 0, 1]);
+
+        // the data segment is not code, the guest writes it freely
+        let data = ElfWriter::new(0x400000)
+            .segment(0x400000, text.clone())
+            .segment(0x401000, vec![0; 8])
+            .build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, mut program) = State::load_elf(&file);
+        program.load_instructions(&mut state);
+        assert_eq!(program.segments.len(), 1);
+        assert_eq!(program.code_hash(), low.code_hash());
+        state.memory.set_memory(0x401000, 0x12345678).unwrap();
+        assert!(program.verify_against_memory(&state.memory));
+
+        // the program modifies its code, which is not protected
+        let data = wild_store_program();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (mut state, mut program) = State::load_elf(&file);
+        program.load_instructions(&mut state);
+        assert!(program.verify_against_memory(&state.memory));
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..3 {
+            is.step(false).unwrap();
+        }
+        assert!(!program.verify_against_memory(&is.state.memory));
+    }
+
+    #[test]
+    fn test_protect_text() {
+        let data = wild_store_program();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        assert_eq!(state.layout.text, Some((0x400000, 0x400018)));
+        assert_eq!(state.layout.program, Some((0x400000, 0x410004)));
+
+        let config = VmConfig { protect_text: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            state.clone(), Box::new(RecordingOracle::default()), config);
+        is.step(false).unwrap();
+        is.step(false).unwrap();
+        match is.step(false) {
+            Err(EmulatorError::WriteToReadOnly { addr, pc, .. }) => {
+                assert_eq!((addr, pc), (0x400014, 0x400008));
+            }
+            other => panic!("expected a write to read only, got {:?}", other.map(|_| ())),
+        }
+        // the data stays writable
+        is.state.memory.store(0x410000, 1, is.state.pc).unwrap();
+
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..6 {
+            is.step(false).unwrap();
+        }
+        assert_eq!(is.state.registers[9], 0);
+    }
+
+    #[test]
+    fn test_hilo_hazards() {
+        let (mult, mflo) = (0x18, 0x12);
+        let back_to_back = [asm::r_type(8, 9, 0, 0, mult), asm::r_type(0, 0, 10, 0, mflo)];
+        let apart = [
+            asm::r_type(8, 9, 0, 0, mult),
+            asm::nop(),
+            asm::nop(),
+            asm::r_type(0, 0, 10, 0, mflo),
+        ];
+        let run = |program: &[u32], config: VmConfig| {
+            let mut state = load_program(program);
+            state.registers[8] = 6;
+            state.registers[9] = 7;
+            let mut is = InstrumentedState::new_with_config(
+                state, Box::new(RecordingOracle::default()), config);
+            for _ in 0..program.len() {
+                is.step(false)?;
+            }
+            Ok::<_, EmulatorError>(is.state.registers[10])
+        };
+
+        let config = VmConfig { hilo_hazards: true, ..Default::default() };
+        match run(&back_to_back, config.clone()) {
+            Err(EmulatorError::HiLoHazard { pc }) => assert_eq!(pc, 4),
+            other => panic!("expected a hi/lo hazard, got {:?}", other),
+        }
+        // two instructions apart the read is fine
+        assert_eq!(run(&apart, config).unwrap(), 42);
+        // off by default
+        assert_eq!(run(&back_to_back, VmConfig::default()).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_jump_region() {
+        // jumps to (delay slot address & 0xf0000000) | (target & 0x0fffffff)
+        let run = |jump_at: u32, jump: u32| {
+            let mut state = load_program(&[asm::j(jump_at), asm::nop()]);
+            state.memory.set_memory(jump_at, jump).unwrap();
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            for _ in 0..4 {
+                is.step(false).unwrap();
+            }
+            (is.state.pc, is.state.registers[31])
+        };
+
+        // the index of the first jump has its top bit set, it isn't sign extended
+        assert_eq!(run(0x0ffffff8, asm::j(0x200)), (0x200, 0));
+        // the delay slot is in the next region
+        assert_eq!(run(0x0ffffffc, asm::j(0x200)), (0x10000200, 0));
+        assert_eq!(run(0x0ffffffc, asm::jal(0x0ffffff0)), (0x1ffffff0, 0x10000004));
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        // unassigned SPECIAL functions and an unassigned opcode
+        for insn in [0x0000000e, 0x00000005, 0xfc000000] {
+            let state = load_program(&[asm::nop(), insn]);
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            is.step(false).unwrap();
+            match is.step(false) {
+                Err(EmulatorError::InvalidOpcode { pc, insn: got }) => {
+                    assert_eq!((pc, got), (4, insn))
+                }
+                other => panic!("0x{:08x}: {:?}", insn, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported_coprocessor() {
+        // mfc1, add.s, lwc1, sdc1, movf and movt
+        for insn in [0x44000000, 0x46000000, 0xc4000000, 0xf4000000, 0x00000001, 0x00010001] {
+            let state = load_program(&[asm::nop(), insn]);
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            is.step(false).unwrap();
+            match is.step(false) {
+                Err(e @ EmulatorError::UnsupportedCoprocessor { pc: 4, coprocessor: 1, .. }) => {
+                    assert!(e.to_string().contains("COP1"), "{}", e);
+                }
+                other => panic!("0x{:08x}: {:?}", insn, other.map(|_| ())),
+            }
+        }
+
+        // skipped like the other instructions the emulator doesn't implement
+        let config = VmConfig { invalid_opcodes: ExecutionMode::Lenient, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&[0x44000000]), Box::new(RecordingOracle::default()), config);
+        is.step(false).unwrap();
+        assert_eq!(is.skipped_instructions(), [(0, 0x44000000)]);
+    }
+
+    #[test]
+    fn test_unsupported_coprocessor_context() {
+        // add.s $f0, $f1, $f2, add.d $f0, $f2, $f4 and lwc2 $0, 0($0)
+        let cases = [(0x46020800, 1, 0x10, 0), (0x46241000, 1, 0x11, 0), (0xc8000000, 2, 0, 0)];
+        for (insn, coprocessor, fmt, funct) in cases {
+            let state = load_program(&[asm::nop(), asm::nop(), insn]);
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            is.step(false).unwrap();
+            is.step(false).unwrap();
+            match is.step(false) {
+                Err(EmulatorError::UnsupportedCoprocessor {
+                    pc, insn: found, coprocessor: found_cop, fmt: found_fmt, funct: found_funct,
+                }) => {
+                    let found = (pc, found, found_cop, found_fmt, found_funct);
+                    assert_eq!(found, (8, insn, coprocessor, fmt, funct));
+                }
+                other => panic!("0x{:08x}: {:?}", insn, other.map(|_| ())),
+            }
+        }
+
+        let err = EmulatorError::UnsupportedCoprocessor {
+            pc: 8, insn: 0x46020800, coprocessor: 1, fmt: 0x10, funct: 0,
+        };
+        assert_eq!(
+            err.to_string(),
+            "instruction 0x46020800 at 0x8 uses the unsupported coprocessor COP1 \
+             (fmt 0x10, funct 0x00)",
+        );
+    }
+
+    #[test]
+    fn test_watch_region() {
+        // fills the 64 bytes at 0x10000 word by word, then touches the words around them
+        let mut program = vec![
+            asm::lui(8, 1),
+            asm::addiu(9, 8, 64),
+            asm::lui(10, 0xabcd),
+            asm::ori(10, 10, 0xef01),
+            asm::sw(10, 8, 0),
+            asm::addiu(8, 8, 4),
+            asm::bne(8, 9, -3),
+            asm::nop(),
+            asm::sw(10, 9, 0),
+            asm::lw(11, 9, 0),
+            asm::sw(10, 8, -0x100),
+        ];
+        program.extend(syscall_asm(4246, 0, 0, 0));
+        let mut state = load_program(&program);
+        for i in 0..16 {
+            state.memory.set_memory(0x10000 + 4 * i, i).unwrap();
+        }
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        is.watch_region(0x10000..0x10040, "buf");
+        // overlaps the end of the buffer, the word at 0x1003c straddles its start
+        let config = RegionWatchConfig { loads: true, ..Default::default() };
+        is.watch_region_with(0x1003e..0x10044, "tail", config);
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+
+        let buf: Vec<RegionAccess> = is.region_log("buf").unwrap().accesses().copied().collect();
+        let expected: Vec<RegionAccess> = (0..16)
+            .map(|i| RegionAccess {
+                step: 5 + 4 * i as u64,
+                pc: 16,
+                addr: 0x10000 + 4 * i,
+                op: MemoryOperation::Write,
+                old: i,
+                new: 0xabcdef01,
+            })
+            .collect();
+        assert_eq!(buf, expected);
+
+        let tail: Vec<(u32, MemoryOperation, u32, u32)> = is.region_log("tail").unwrap().accesses()
+            .map(|a| (a.addr, a.op, a.old, a.new))
+            .collect();
+        assert_eq!(tail, [
+            (0x1003c, MemoryOperation::Write, 15, 0xabcdef01),
+            (0x10040, MemoryOperation::Write, 0, 0xabcdef01),
+            (0x10040, MemoryOperation::Read, 0xabcdef01, 0xabcdef01),
+        ]);
+
+        // clearing a log keeps the others and the watch
+        is.clear_region_log("buf");
+        assert!(is.region_log("buf").unwrap().is_empty());
+        assert_eq!(is.region_log("tail").unwrap().len(), 3);
+        assert_eq!(is.unwatch_region("tail").unwrap().tag(), "tail");
+        assert!(is.region_log("tail").is_none());
+    }
+
+    #[test]
+    fn test_skip_invalid_opcode() {
+        // the invalid instructions are skipped, the ones around them still execute
+        let program = [
+            asm::addiu(9, 0, 1),
+            0xfc000000,
+            asm::addiu(9, 9, 2),
+            0x0000000e,
+            asm::addiu(2, 0, 4246),
+            asm::addu(4, 9, 0),
+            asm::syscall(),
+        ];
+        let config = VmConfig { invalid_opcodes: ExecutionMode::Lenient, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config.clone());
+        let result = is.run(100).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(3));
+        assert_eq!(result.steps, program.len() as u64);
+        assert_eq!(is.skipped_instructions(), [(4, 0xfc000000), (12, 0x0000000e)]);
+        assert_eq!((is.skip_count(4, 0xfc000000), is.skip_count(8, 0xfc000000)), (1, 0));
+
+        // an invalid instruction in a loop is listed once, with the times it was skipped
+        let program = [
+            asm::addiu(9, 0, 100),
+            0xfc000000,
+            asm::addiu(9, 9, -1),
+            asm::bne(9, 0, -3),
+            asm::nop(),
+            asm::addiu(2, 0, 4246),
+            asm::addu(4, 9, 0),
+            asm::syscall(),
+        ];
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(is.skipped_instructions(), [(4, 0xfc000000)]);
+        assert_eq!(is.skip_count(4, 0xfc000000), 100);
+
+        // the strict mode is the default
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        assert!(matches!(is.run(100), Err(EmulatorError::InvalidOpcode { pc: 4, .. })));
+        assert!(is.skipped_instructions().is_empty());
+    }
+
+    #[test]
+    fn test_bad_pc() {
+        let bad_pc = |is: &mut InstrumentedState| match is.run(100) {
+            Err(EmulatorError::BadPc { pc, reason, jump }) => (pc, reason, jump),
+            other => panic!("expected a bad pc, got {:?}", other.map(|r| r.status)),
+        };
+
+        // jr to an odd address fails at the fetch, or at the jr with the eager check
+        let program = [asm::addiu(9, 0, 0x103), asm::jr(9), asm::nop()];
+        let mut is = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        assert_eq!(bad_pc(&mut is), (0x103, BadPcReason::Misaligned, None));
+        let config = VmConfig { eager_pc_check: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        assert_eq!(bad_pc(&mut is), (0x103, BadPcReason::Misaligned, Some(4)));
+        assert_eq!(is.state.pc, 4);
+
+        // in strict mode, j to a page never written
+        let program = [asm::j(0x10000), asm::nop()];
+        let config = VmConfig { mode: ExecutionMode::Strict, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(
+            load_program(&program), Box::new(RecordingOracle::default()), config);
+        assert_eq!(bad_pc(&mut is), (0x10000, BadPcReason::Unmapped, None));
+
+        // j from the text of an ELF to its data
+        let text = asm::to_bytes(&[asm::j(0x800), asm::nop()]);
+        let data = ElfWriter::new(0x400)
+            .segment(0x400, text)
+            .segment(0x800, asm::to_bytes(&[asm::nop()]))
+            .build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert_eq!(bad_pc(&mut is), (0x800, BadPcReason::NotExecutable, None));
+        let (state, _) = State::load_elf(&file);
+        let config = VmConfig { eager_pc_check: true, ..Default::default() };
+        let mut is = InstrumentedState::new_with_config(state, Box::new(RecordingOracle::default()), config);
+        assert_eq!(bad_pc(&mut is), (0x800, BadPcReason::NotExecutable, Some(0x400)));
+    }
+
+    #[test]
+    fn test_replays() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/replays");
+        let mut names = vec![];
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let replay = Replay::load(&path).unwrap();
+            replay.verify().unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            names.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+        names.sort();
+        assert_eq!(names, ["alu.replay", "hello.replay", "memory.replay", "preimage.replay"]);
+    }
+
+    /// Returns the non-zero words of a memory holding `program` from address 0.
+    fn program_words(program: &[u32]) -> Vec<(u32, u32)> {
+        program.iter().enumerate().map(|(i, insn)| (4 * i as u32, *insn)).collect()
+    }
+
+    /// Returns the replays checked in tests/replays, by file name.
+    fn checked_in_replays() -> Vec<(&'static str, Replay)> {
+        let alu = program_words(&[
+            asm::addiu(8, 0, 1234),
+            asm::addiu(9, 0, 5678),
+            asm::r_type(8, 9, 0, 0, 0x18), // mult
+            asm::r_type(0, 0, 10, 0, 0x12), // mflo
+            asm::r_type(10, 9, 11, 0, 0x23), // subu
+            asm::r_type(11, 8, 12, 0, 0x26), // xor
+            asm::r_type(0, 12, 13, 5, 0x00), // sll
+            asm::r_type(13, 8, 14, 0, 0x2a), // slt
+            asm::r_type(12, 9, 0, 0, 0x1b), // divu
+            asm::r_type(0, 0, 15, 0, 0x10), // mfhi
+            asm::addu(4, 15, 13),
+            asm::ori(4, 4, 0xff),
+            asm::r_type(4, 0, 4, 0, 0x25), // or
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+
+        // stores 16 words and bytes from 0x10000, then sums them back with lw, lb and lhu
+        let memory = program_words(&[
+            asm::lui(16, 1),
+            asm::addiu(17, 0, 16),
+            asm::addiu(8, 0, 0),
+            asm::addu(9, 8, 8),
+            asm::addiu(9, 9, 3),
+            asm::sw(9, 16, 0),
+            asm::sb(8, 16, 2),
+            asm::addiu(16, 16, 4),
+            asm::addiu(8, 8, 1),
+            asm::bne(8, 17, -7),
+            asm::nop(),
+            asm::lui(16, 1),
+            asm::addiu(4, 0, 0),
+            asm::addiu(8, 0, 0),
+            asm::lw(10, 16, 0),
+            asm::i_type(0x20, 16, 11, 2), // lb
+            asm::i_type(0x25, 16, 12, 0), // lhu
+            asm::addu(4, 4, 10),
+            asm::addu(4, 4, 11),
+            asm::addu(4, 4, 12),
+            asm::addiu(16, 16, 4),
+            asm::addiu(8, 8, 1),
+            asm::bne(8, 17, -9),
+            asm::nop(),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+
+        // writes the key at 0x10000, reads the preimage at 0x10100 and exits with its first byte
+        let data = b"replay preimage".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut program = vec![];
+        for i in 0..8 {
+            program.extend(asm::li(5, 0x10000 + 4 * i));
+            program.extend([
+                asm::addiu(4, 0, FD_PREIMAGE_WRITE as i16),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, 4004),
+                asm::syscall(),
+            ]);
+        }
+        for i in 0..6 {
+            program.extend(asm::li(5, 0x10100 + 4 * i));
+            program.extend([
+                asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, 4003),
+                asm::syscall(),
+            ]);
+        }
+        program.extend([
+            asm::lui(5, 1),
+            asm::i_type(0x24, 5, 4, 0x108), // lbu of the first byte after the length
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let mut preimage = program_words(&program);
+        preimage.extend(key.chunks(4).enumerate().map(|(i, chunk)| {
+            (0x10000 + 4 * i as u32, u32::from_be_bytes(chunk.try_into().unwrap()))
+        }));
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data);
+
+        // writes "hello, world" to stdout, loaded from an ELF with its stack
+        let mut text = asm::li(8, 0x410000).to_vec();
+        text.extend([
+            asm::lw(5, 8, 0),
+            asm::lw(6, 8, 4),
+            asm::addiu(4, 0, 1),
+            asm::addiu(2, 0, 4004),
+            asm::syscall(),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]);
+        let mut data = vec![];
+        data.extend(0x410008u32.to_be_bytes());
+        data.extend(13u32.to_be_bytes());
+        data.extend(b"hello, world\n");
+        let hello = ElfWriter::new(0x400000)
+            .segment(0x400000, asm::to_bytes(&text))
+            .segment(0x410000, data)
+            .function("main", 0x400000, 40)
+            .build();
+
+        let record = |image, config, oracle: RecordingOracle, max_steps| {
+            Replay::record(image, config, vec![], Box::new(oracle), max_steps).unwrap()
+        };
+        let (config, none) = (VmConfig::default, RecordingOracle::default);
+        let seeded = VmConfig { random_seed: [5; 32], ..Default::default() };
+        vec![
+            ("alu.replay", record(ReplayImage::Memory(alu), config(), none(), 1000)),
+            ("hello.replay", record(ReplayImage::Elf(hello), seeded, none(), 1000)),
+            ("memory.replay", record(ReplayImage::Memory(memory), config(), none(), 10000)),
+            ("preimage.replay", record(ReplayImage::Memory(preimage), config(), oracle, 10000)),
+        ]
+    }
+
+    /// Writes the replays of tests/replays again, after a change of the format or of the
+    /// execution: `cargo test regenerate_replays -- --ignored`.
+    #[test]
+    #[ignore]
+    fn regenerate_replays() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/replays");
+        for (name, replay) in checked_in_replays() {
+            replay.save(dir.join(name)).unwrap();
+        }
+    }
+
+    /// Builds the state of `replay` with its config, silenced, its preimages served by a
+    /// `RecordingOracle` or through the builder.
+    fn replay_state(replay: &Replay, recording: bool) -> Box<InstrumentedState> {
+        let state = replay.image.state_for(&replay.config).unwrap();
+        let mut builder = InstrumentedStateBuilder::new(state)
+            .with_config(replay.config.clone());
+        if recording {
+            let mut oracle = RecordingOracle::default();
+            oracle.images.extend(replay.preimages.iter().cloned());
+            builder = builder.with_oracle(Box::new(oracle));
+        } else {
+            builder = builder.with_preimages(replay.preimages.iter().cloned().collect());
+        }
+        let mut is = builder.build().unwrap();
+        is.set_stdout_writer(Box::new(std::io::sink()));
+        is.set_stderr_writer(Box::new(std::io::sink()));
+        is
+    }
+
+    #[test]
+    fn test_lockstep_identical_configs() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/replays");
+        for entry in fs::read_dir(&dir).unwrap() {
+            let replay = Replay::load(entry.unwrap().path()).unwrap();
+            for interval in [1, 7] {
+                let a = replay_state(&replay, true);
+                let b = replay_state(&replay, false);
+                let result = run_lockstep_every(a, b, replay.max_steps, interval);
+                assert_eq!(result.divergence, None);
+                assert_eq!(result.error, None);
+                assert!(result.a.state.exited && result.b.state.exited);
+                assert!(result.steps > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lockstep_divergence() {
+        let program = [
+            asm::addiu(8, 0, 1),
+            asm::addiu(9, 0, 2),
+            asm::addu(10, 8, 9),
+            asm::sw(10, 0, 0x100),
+            asm::addiu(4, 0, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ];
+        let a = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        let mut b = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        b.disable_instruction(OpcodeId::ADDU);
+        let result = run_lockstep(a, b, 100);
+        let divergence = result.divergence.unwrap();
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.diff.registers, [(10, 3, 0)]);
+        assert!(divergence.diff.fields.is_empty() && divergence.diff.memory.is_empty());
+        assert_eq!(divergence.errors, [None, None]);
+        assert_eq!(divergence.to_string(), "diverged at step 3\n$10: 0x00000003 != 0x00000000\n");
+
+        // compared every 4 steps, the store of the sum is found too
+        let a = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        let mut b = InstrumentedState::new(load_program(&program), Box::new(RecordingOracle::default()));
+        b.disable_instruction(OpcodeId::ADDU);
+        let divergence = run_lockstep_every(a, b, 100, 4).divergence.unwrap();
+        assert_eq!(divergence.step, 4);
+        assert_eq!(divergence.diff.memory, [(0x100, 3, 0)]);
+    }
+
+    #[test]
+    fn test_replay_round_trip() {
+        let data = b"preimage".to_vec();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        let mut program = vec![];
+        for i in 0..8 {
+            program.extend(asm::li(5, 0x10000 + 4 * i));
+            program.extend([
+                asm::addiu(4, 0, FD_PREIMAGE_WRITE as i16),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, 4004),
+                asm::syscall(),
+            ]);
+        }
+        // reads the 8 bytes length prefix, exits with its low word
+        for i in 0..2 {
+            program.extend(asm::li(5, 0x10100 + 4 * i));
+            program.extend([
+                asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+                asm::addiu(6, 0, 4),
+                asm::addiu(2, 0, 4003),
+                asm::syscall(),
+            ]);
+        }
+        program.extend([asm::lw(4, 5, 0), asm::addiu(2, 0, 4246), asm::syscall()]);
+        let mut words: Vec<(u32, u32)> = program.iter().enumerate()
+            .map(|(i, insn)| (4 * i as u32, *insn))
+            .collect();
+        words.extend(key.chunks(4).enumerate().map(|(i, chunk)| {
+            (0x10000 + 4 * i as u32, u32::from_be_bytes(chunk.try_into().unwrap()))
+        }));
+
+        let mut oracle = RecordingOracle::default();
+        oracle.images.insert(key, data.clone());
+        let config = VmConfig { random_seed: [7; 32], hilo_hazards: true, ..Default::default() };
+        let replay = Replay::record(
+            ReplayImage::Memory(words), config, b"stdin".to_vec(), Box::new(oracle), 1000,
+        ).unwrap();
+        assert_eq!((replay.exit_code, replay.preimages.clone()), (8, vec![(key, data)]));
+
+        let path = std::env::temp_dir().join(format!("replay-{}.replay", std::process::id()));
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, replay);
+        loaded.verify().unwrap();
+
+        let mut bad = replay.clone();
+        bad.state_hash[0] ^= 1;
+        assert!(matches!(bad.verify(), Err(ReplayMismatch::StateHash { .. })));
+        let mut bad = replay.clone();
+        bad.exit_code ^= 1;
+        assert!(matches!(bad.verify(), Err(ReplayMismatch::ExitCode { .. })));
+        // without the preimage log the guest can't read the preimage
+        let mut bad = replay.clone();
+        bad.preimages.clear();
+        assert!(matches!(bad.verify(), Err(ReplayMismatch::Error(EmulatorError::PreimageNotFound { .. }))));
+
+        let mut bytes = replay.encode();
+        bytes[8..12].copy_from_slice(&(REPLAY_VERSION + 1).to_le_bytes());
+        assert!(Replay::decode(&bytes).is_err());
+        assert!(Replay::decode(&replay.encode()[..40]).is_err());
+    }
+
+    /// Returns the Cannon proof json of the next step of `state`, with the proof of the word at
+    /// `mem_addr` if any.
+    fn one_step_json(state: &mut State, mem_addr: Option<u32>) -> String {
+        state.memory.set_hash_function(HashFunction::Keccak256);
+        let mut proof_data = state.memory.merkle_proof(state.pc).to_vec();
+        if let Some(addr) = mem_addr {
+            proof_data.extend(state.memory.merkle_proof(addr));
+        }
+        format!(
+            r#"{{"step": 0, "pre": "0x{}", "state-data": "0x{}", "proof-data": "0x{}"}}"#,
+            hex::encode(cannon::state_hash(&state.encode_witness())),
+            hex::encode(state.encode_witness()),
+            hex::encode(proof_data),
+        )
+    }
+
+    /// Executes the next step of `state` with the whole memory, returns Cannon's hash of the
+    /// post-state.
+    fn full_step_hash(state: &State) -> [u8; 32] {
+        let config = VmConfig { memory_hash: HashFunction::Keccak256, ..Default::default() };
+        let oracle = Box::new(RecordingOracle::default());
+        let mut is = InstrumentedState::new_with_config(Box::new(state.clone()), oracle, config);
+        is.step(false).unwrap();
+        cannon::state_hash(&is.state.encode_witness())
+    }
+
+    #[test]
+    fn test_cannon_one_step_addiu() {
+        let mut state = load_program(&[asm::addiu(8, 8, 5)]);
+        state.registers[8] = 10;
+        state.memory.set_memory(0x10000, 0x12345678).unwrap();
+        let expected = full_step_hash(&state);
+
+        let input = OneStepInput::from_json(&one_step_json(&mut state, None)).unwrap();
+        let output = cannon::execute_one_step(&input).unwrap();
+        assert_eq!(output.post_hash, expected);
+        assert_eq!(output.post_state[98 + 4 * 8..98 + 4 * 9], 15u32.to_be_bytes());
+        // the first byte of the hash is the status of the running VM
+        assert_eq!(output.post_hash[0], 3);
+        assert_eq!(output.post_hash[1..], Keccak256::digest(&output.post_state)[1..]);
+
+        // the state data must hash to pre
+        let mut input = input;
+        input.state_data[98 + 4 * 8] ^= 1;
+        assert!(matches!(cannon::execute_one_step(&input), Err(OneStepError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_cannon_one_step_exit_status() {
+        // exit_group with the code in $a0, the status byte tells the exits apart
+        for (code, status) in [(0, 0), (1, 1), (2, 2), (0xff, 2)] {
+            let mut state = load_program(&[asm::syscall()]);
+            state.registers[2] = 4246;
+            state.registers[4] = code;
+            let expected = full_step_hash(&state);
+            let input = OneStepInput::from_json(&one_step_json(&mut state, None)).unwrap();
+            let output = cannon::execute_one_step(&input).unwrap();
+            assert_eq!(output.post_hash, expected);
+            assert_eq!(output.post_hash[0], status, "exit code {}", code);
+        }
+    }
+
+    #[test]
+    fn test_cannon_one_step_store() {
+        let mut state = load_program(&[asm::sw(9, 8, 4)]);
+        state.registers[8] = 0x10000;
+        state.registers[9] = 0xdeadbeef;
+        for i in 0..8 {
+            state.memory.set_memory(0x10000 + 4 * i, i).unwrap();
+        }
+        state.memory.set_memory(0x20000, 1).unwrap();
+        let expected = full_step_hash(&state);
+
+        let json = one_step_json(&mut state, Some(0x10004));
+        let output = cannon::execute_one_step(&OneStepInput::from_json(&json).unwrap()).unwrap();
+        assert_eq!(output.post_hash, expected);
+
+        // without the memory proof the store can't be checked
+        let json = one_step_json(&mut state, None);
+        assert!(matches!(
+            cannon::execute_one_step(&OneStepInput::from_json(&json).unwrap()),
+            Err(OneStepError::UncoveredMemory { addr: 0x10004 })
+        ));
+        // the proof of another word doesn't lead to the root along the path of the store
+        let json = one_step_json(&mut state, Some(0x20000));
+        assert!(matches!(
+            cannon::execute_one_step(&OneStepInput::from_json(&json).unwrap()),
+            Err(OneStepError::InvalidProof { addr: 0x10004 })
+        ));
+    }
+
+    #[test]
+    fn test_verifier_matches_emulator() {
+        let data = fs::read("./example/bin/hello.elf").expect("could not read file");
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).expect("opening elf file failed");
+        let (mut state, _) = State::load_elf(&file);
+        state.patch_go(&file);
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
+        let mut is = InstrumentedState::new_headless(state, Box::new(TestOracle::default()));
+
+        // every step checked against the proofs alone hashes like the emulator with the whole
+        // memory: the loads, stores and syscalls of the go runtime startup
+        let mut memory_steps = 0;
+        for _ in 0..10_000 {
+            if is.state.exited {
+                break;
+            }
+            let (wit, _, _) = is.step(true).unwrap();
+            let proofs = StepProofs::from_witness(&wit);
+            memory_steps += proofs.memory.is_some() as u32;
+            let post = verifier::step(
+                &StateWitness::from_witness(&wit), &proofs, PreimagePart::from_witness(&wit).as_ref(),
+            );
+            assert_eq!(post.unwrap(), is.state_hash(), "step {}", wit.step);
+        }
+        assert!(memory_steps > 1000, "{} steps accessed memory", memory_steps);
+
+        // a step can't be verified without the proof of the word it accesses
+        let (mut wit, _, _) = loop {
+            let step = is.step(true).unwrap();
+            if step.0.mem_ops.iter().any(|access| access.op != MemoryOperation::Fetch) {
+                break step;
+            }
+        };
+        wit.mem_proof.truncate(PROOF_SIZE);
+        let proofs = StepProofs::from_witness(&wit);
+        let result = verifier::step(&StateWitness::from_witness(&wit), &proofs, None);
+        assert!(matches!(result, Err(OneStepError::UncoveredMemory { .. })));
+    }
+
+    /// Writes its count to each of `pages` pages a page apart from 0x10000000, counting down,
+    /// then exits with the sum of the words read back.
+    fn page_walk_program(pages: i16) -> Vec<u32> {
+        vec![
+            asm::lui(8, 0x1000),
+            asm::addiu(9, 0, pages),
+            asm::sw(9, 8, 0),
+            asm::addiu(8, 8, 0x1000),
+            asm::addiu(9, 9, -1),
+            asm::bne(9, 0, -4),
+            asm::nop(),
+            asm::lui(8, 0x1000),
+            asm::addiu(9, 0, pages),
+            asm::addiu(10, 0, 0),
+            asm::lw(11, 8, 0),
+            asm::addu(10, 10, 11),
+            asm::addiu(8, 8, 0x1000),
+            asm::addiu(9, 9, -1),
+            asm::bne(9, 0, -5),
+            asm::nop(),
+            asm::addu(4, 10, 0),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ]
+    }
+
+    #[test]
+    fn test_file_backend() {
+        let program = page_walk_program(300);
+        let path = std::env::temp_dir().join(format!("pages-{}", std::process::id()));
+        let run = |memory: Memory| {
+            let mut state = State::new();
+            state.memory = Box::new(memory);
+            for (i, insn) in program.iter().enumerate() {
+                state.memory.set_memory(4 * i as u32, *insn).unwrap();
+            }
+            let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+            let status = is.run(100_000).unwrap().status;
+            (status, is)
+        };
+
+        let (status, mut expected) = run(Memory::new());
+        assert_eq!(status, VmStatus::Exited(45150u32 as u8));
+        let (status, mut is) = run(Memory::with_backend(Box::new(FileBackend::create(&path, 16).unwrap())));
+        assert_eq!(status, VmStatus::Exited(45150u32 as u8));
+        assert_eq!(is.state_hash(), expected.state_hash());
+        assert_eq!(is.state.memory.page_count(), 301);
+        assert!(is.state.memory.resident_pages() <= 16);
+        let addr = 0x10000000 + 7 * 0x1000;
+        assert_eq!(is.state.memory.merkle_proof(addr), expected.state.memory.merkle_proof(addr));
+
+        // the flushed page file is reopened with the same pages
+        is.state.memory.flush().unwrap();
+        let mut memory = Memory::with_backend(Box::new(FileBackend::open(&path, 16).unwrap()));
+        assert_eq!(memory.merkle_root(), expected.state.memory.merkle_root());
+        assert_eq!(memory.get_memory(addr), 293);
+
+        // a clone shares the page file, the pages it writes get their own slots
+        let mut clone = memory.clone();
+        clone.set_memory(addr, 1).unwrap();
+        for i in 0..300 {
+            clone.get_memory(0x10000000 + i * 0x1000);
+        }
+        assert_eq!(clone.get_memory(addr), 1);
+        assert_eq!(memory.get_memory(addr), 293);
+        assert_eq!(memory.merkle_root(), expected.state.memory.merkle_root());
+        drop((memory, clone));
+
+        // the saved state refers to the page file
+        let state_path = path.with_file_name(format!("state-{}", std::process::id()));
+        is.state.save(&state_path).unwrap();
+        assert!(fs::metadata(&state_path).unwrap().len() < 1024);
+        let mut loaded = State::load(&state_path, 16).unwrap();
+        assert_eq!(loaded.state_hash(&Keccak256Hasher), expected.state_hash());
+        assert_eq!(loaded.metadata(), expected.state.metadata());
+        assert!(State::new().save(&state_path).is_err());
+        fs::remove_file(&state_path).unwrap();
+
+        // a page that can't be read from the page file fails the step
+        let mut state = State::new();
+        let backend = FileBackend::open(&path, 16).unwrap();
+        state.memory = Box::new(Memory::with_backend(Box::new(backend)));
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        assert!(matches!(is.step(false), Err(EmulatorError::Io(_))));
+        assert_eq!(is.state.step(), 0);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_file_name(format!("pages-{}.index", std::process::id()))).unwrap();
+    }
+
+    #[test]
+    fn test_reset_to_snapshot() {
+        // the program and an image of 1k pages
+        let build = || {
+            let mut state = State::new();
+            for (i, insn) in page_walk_program(64).iter().enumerate() {
+                state.memory.set_memory(4 * i as u32, *insn).unwrap();
+            }
+            for i in 0..1024 {
+                state.memory.set_memory(0x20000000 + (i << 12), i).unwrap();
+            }
+            InstrumentedState::new(state, Box::new(RecordingOracle::default()))
+        };
+        let mut expected = build();
+        let snapshot = expected.snapshot();
+        let initial_hash = expected.state_hash();
+        assert_eq!(snapshot.state().clone().state_hash(&Keccak256Hasher), initial_hash);
+        let result = expected.run(100_000).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(2080u32 as u8));
+
+        let mut is = build();
+        for _ in 0..3 {
+            is.run(100_000).unwrap();
+            is.reset_to(&snapshot);
+            assert_eq!(is.state_hash(), initial_hash);
+            assert_eq!(is.state.memory.page_count(), 1025);
+            let reused = is.run(100_000).unwrap();
+            assert_eq!((reused.status, reused.steps), (result.status, result.steps));
+            assert_eq!(is.state_hash(), expected.state_hash());
+            assert_eq!(*is.state.memory, *expected.state.memory);
+        }
+        is.reset_to(&snapshot);
+        assert_eq!(is.state_hash(), initial_hash);
+    }
+
+    #[test]
+    fn test_reset_to_keeps_stdin_offset() {
+        let mut is = InstrumentedStateBuilder::new(State::new())
+            .with_stdin(b"hello".as_slice())
+            .build()
+            .unwrap();
+        assert_eq!(do_syscall(&mut is, 4003, FD_STDIN, 0x10000, 2), (2, 0));
+        let snapshot = is.snapshot();
+        assert_eq!(do_syscall(&mut is, 4003, FD_STDIN, 0x10000, 3), (3, 0));
+        assert_eq!(do_syscall(&mut is, 4003, FD_STDIN, 0x10000, 3), (0, 0));
+
+        // the rewound guest reads the rest of stdin again
+        is.reset_to(&snapshot);
+        assert_eq!(do_syscall(&mut is, 4003, FD_STDIN, 0x10000, 3), (3, 0));
+        assert_eq!(&is.state.memory.get_memory(0x10000).to_be_bytes()[..3], b"llo");
+    }
+
+    /// Program storing a counter to the 16 words from 0x10000 in turn, 200 times, then exiting.
+    fn store_loop_program() -> Vec<u32> {
+        vec![
+            asm::lui(8, 1),
+            asm::addiu(9, 0, 0),
+            asm::addiu(12, 0, 200),
+            asm::r_type(0, 9, 10, 2, 0x00), // loop: sll $10, $9, 2
+            asm::i_type(0x0c, 10, 10, 0x3c), // andi $10, $10, 0x3c
+            asm::addu(11, 8, 10),
+            asm::sw(9, 11, 0),
+            asm::sb(9, 11, 1),
+            asm::addiu(9, 9, 1),
+            asm::bne(9, 12, -7),
+            asm::nop(),
+            asm::addiu(2, 0, 4246),
+            asm::addiu(4, 0, 0),
+            asm::syscall(),
+        ]
+    }
+
+    /// Runs `is` to its exit a step at a time, returns the state hash after each step, the
+    /// initial one first, and the steps storing to each word.
+    fn linear_run(mut is: Box<InstrumentedState>) -> (Vec<[u8; 32]>, HashMap<u32, Vec<u64>>) {
+        let mut hashes = vec![is.state_hash()];
+        let mut writes: HashMap<u32, Vec<u64>> = HashMap::new();
+        while !is.state.exited {
+            let (_, _, mem_ops) = is.step(false).unwrap();
+            for access in mem_ops.iter().filter(|access| access.op == MemoryOperation::Write) {
+                writes.entry(access.addr).or_default().push(is.state.step());
+            }
+            hashes.push(is.state_hash());
+        }
+        (hashes, writes)
+    }
+
+    #[test]
+    fn test_time_travel() {
+        let build = || {
+            let oracle = Box::new(RecordingOracle::default());
+            InstrumentedState::new(load_program(&store_loop_program()), oracle)
+        };
+        let (hashes, writes) = linear_run(build());
+        let exit_step = hashes.len() as u64 - 1;
+        assert!(exit_step > 1500);
+
+        let mut tt = TimeTravel::new(build(), 64);
+        tt.run_to(1000).unwrap();
+        assert_eq!(tt.state_hash(), hashes[1000]);
+        for step in [999, 900, 640, 641, 5, 0, 1300, 1299, 64, 1000, 1] {
+            assert_eq!(tt.seek(step).unwrap().step(), step);
+            assert_eq!(tt.state_hash(), hashes[step as usize], "step {}", step);
+        }
+        tt.seek(1000).unwrap();
+        assert_eq!(tt.back(100).unwrap().step(), 900);
+        assert_eq!(tt.state_hash(), hashes[900]);
+        assert_eq!(tt.back(10_000).unwrap().step(), 0);
+        assert_eq!(tt.state_hash(), hashes[0]);
+
+        // the guest stops at its exit
+        assert!(tt.seek(u64::MAX).unwrap().exited);
+        assert_eq!(tt.state().step(), exit_step);
+        assert_eq!(tt.state_hash(), hashes[exit_step as usize]);
+        let steps: Vec<u64> = tt.snapshot_steps().collect();
+        assert_eq!(steps, (0..=exit_step).step_by(64).collect::<Vec<_>>());
+
+        // back to the stores to the word 0x10014 before step 1000, one after the other
+        tt.seek(1000).unwrap();
+        let expected: Vec<u64> = writes[&0x10014].iter().copied().filter(|&s| s <= 1000).collect();
+        for store in expected.iter().rev().take(6) {
+            assert_eq!(tt.reverse_to_write(0x10017).unwrap(), Some(*store));
+            assert_eq!(tt.state().step(), store - 1);
+            assert_eq!(tt.state_hash(), hashes[(store - 1) as usize]);
+        }
+        // a word never written leaves the state unchanged
+        let step = tt.state().step();
+        assert_eq!(tt.reverse_to_write(0x20000).unwrap(), None);
+        assert_eq!(tt.state().step(), step);
+        assert_eq!(tt.state_hash(), hashes[step as usize]);
+    }
+
+    /// Oracle counting the preimages it serves whole, serving parts too if `parts` is set.
+    struct CountingOracle {
+        images: HashMap<[u8; 32], Vec<u8>>,
+        calls: Arc<AtomicUsize>,
+        parts: bool,
+    }
+
+    impl PreimageOracle for CountingOracle {
+        fn hint(&mut self, _v: &[u8]) {}
+
+        fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.images.get(&k).cloned().ok_or(EmulatorError::PreimageNotFound { key: k })
+        }
+
+        fn serves_parts(&self) -> bool {
+            self.parts
+        }
+
+        fn preimage_len(&mut self, k: [u8; 32]) -> Result<u64, EmulatorError> {
+            let data = self.images.get(&k).ok_or(EmulatorError::PreimageNotFound { key: k })?;
+            Ok(data.len() as u64)
+        }
+
+        fn get_preimage_part(
+            &mut self,
+            k: [u8; 32],
+            offset: u64,
+            len: usize,
+        ) -> Result<Vec<u8>, EmulatorError> {
+            let data = self.images.get(&k).ok_or(EmulatorError::PreimageNotFound { key: k })?;
+            let part = data.get(offset as usize..).unwrap_or(&[]);
+            Ok(part[..part.len().min(len)].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_time_travel_replays_preimages() {
+        let data: Vec<u8> = (0..100).collect();
+        let key = Keccak256Key(Keccak256::digest(&data).into()).preimage_key();
+        // reads the preimage 4 bytes at a time to 0x30000, then exits
+        let program = [
+            asm::lui(16, 3),
+            asm::addiu(2, 0, 4003), // loop
+            asm::addiu(4, 0, FD_PREIMAGE_READ as i16),
+            asm::addu(5, 16, 0),
+            asm::addiu(6, 0, 4),
+            asm::syscall(),
+            asm::addu(16, 16, 2),
+            asm::bne(2, 0, -7),
+            asm::nop(),
+            asm::addiu(2, 0, 4246),
+            asm::addiu(4, 0, 0),
+            asm::syscall(),
+        ];
+        let calls = Arc::new(AtomicUsize::new(0));
+        let build = || {
+            let mut state = load_program(&program);
+            state.set_preimage_key(key);
+            let images = HashMap::from([(key, data.clone())]);
+            let oracle = CountingOracle { images, calls: calls.clone(), parts: false };
+            InstrumentedState::new(state, Box::new(oracle))
+        };
+        let (hashes, _) = linear_run(build());
+        let exit_step = hashes.len() as u64 - 1;
+
+        calls.store(0, Ordering::Relaxed);
+        let mut tt = TimeTravel::new(build(), 16);
+        tt.seek(exit_step).unwrap();
+        assert_eq!(tt.state().memory.peek_memory(0x30000 + 8), 0x00010203);
+        let served = calls.load(Ordering::Relaxed);
+        assert!(served > 0);
+        for step in [50, exit_step, 3, 120, 121, exit_step - 1] {
+            tt.seek(step).unwrap();
+            assert_eq!(tt.state_hash(), hashes[step as usize], "step {}", step);
+        }
+        // the steps executed again are served from the log
+        assert_eq!(calls.load(Ordering::Relaxed), served);
+
+        // the state given back runs with the live oracle, not the log
+        let is = build();
+        let start = is.snapshot();
+        calls.store(0, Ordering::Relaxed);
+        let mut tt = TimeTravel::new(is, 16);
+        tt.seek(exit_step).unwrap();
+        let mut is = tt.into_inner();
+        is.reset_to(&start);
+        assert_eq!(is.run(exit_step).unwrap().status, VmStatus::Exited(0));
+        assert_eq!(calls.load(Ordering::Relaxed), 2 * served);
+
+        // the parts dropped from a full log are fetched again
+        calls.store(0, Ordering::Relaxed);
+        let mut tt = TimeTravel::with_log_limit(build(), 16, 16);
+        tt.seek(exit_step).unwrap();
+        for step in [50, exit_step, 3] {
+            tt.seek(step).unwrap();
+            assert_eq!(tt.state_hash(), hashes[step as usize], "step {}", step);
+        }
+        assert!(calls.load(Ordering::Relaxed) > served);
+    }
+
+    /// Touches 1M pages, 4 GiB, with 1k pages in RAM: `cargo test many_pages -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_file_backend_many_pages() {
+        let path = std::env::temp_dir().join(format!("many-pages-{}", std::process::id()));
+        let mut memory = Memory::with_backend(Box::new(FileBackend::create(&path, 1024).unwrap()));
+        for i in 0..1 << 20 {
+            memory.set_memory(i << 12, i).unwrap();
+        }
+        assert_eq!(memory.page_count(), 1 << 20);
+        assert!(memory.resident_pages() <= 1024);
+        for i in (0..1 << 20).step_by(4099) {
+            assert_eq!(memory.get_memory(i << 12), i);
+        }
+        assert!(memory.resident_pages() <= 1024);
+        drop(memory);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hash_functions_from_config() {
+        let config = VmConfig {
+            state_hash: HashFunction::Sha3_256,
+            memory_hash: HashFunction::Keccak256,
+            ..Default::default()
+        };
+        let mut state = State::new();
+        state.memory.set_memory(0x1000, 7).unwrap();
+        let sha3_root = state.memory.merkle_root();
+        let mut is = InstrumentedState::new_with_config(state, Box::new(EmptyPreimageOracle), config.clone());
+
+        // the memory tree and the state hash are hashed with the hashers of the config
+        let (state_hasher, memory_hasher): (&'static dyn Hasher32, &'static dyn Hasher32) =
+            (config.state_hash.hasher(), config.memory_hash.hasher());
+        assert_eq!(is.state.memory.hash_function(), config.memory_hash);
+        let root = is.state.memory.merkle_root();
+        assert_ne!(root, sha3_root);
+        let zero = config.memory_hash.zero_hashes();
+        let page_root = is.state.memory.merklelize_subtree(1 << 20 | 1);
+        assert_eq!(page_root, is.state.memory.merklelize_subtree(1 << 20 | 1));
+        assert_ne!(page_root, zero[7]);
+        assert_eq!(is.state.memory.merklelize_subtree(1 << 20), zero[7]);
+        assert_eq!(memory_hasher.hash_pair(&zero[7], &page_root), is.state.memory.merklelize_subtree(1 << 19));
+        assert_eq!(is.state_hash(), state_hasher.hash(&is.state.encode_witness()));
+        assert_ne!(is.state_hash(), is.state.state_hash(&Keccak256Hasher));
+        // the memory and the state hash share the instances of the config
+        let addr = |hasher: &dyn Hasher32| hasher as *const dyn Hasher32 as *const u8;
+        let same = |a: &dyn Hasher32, b: &dyn Hasher32| std::ptr::eq(addr(a), addr(b));
+        assert!(same(is.state.memory.hash_function().hasher(), memory_hasher));
+        assert!(same(is.config().state_hash.hasher(), state_hasher));
+
+        // switching back restores the default root, without copying the pages shared with a
+        // clone
+        let clone = is.state.memory.clone();
+        let copies = is.state.memory.stats().page_copies;
+        is.state.memory.set_hash_function(HashFunction::Sha3_256);
+        assert_eq!(is.state.memory.stats().page_copies, copies);
+        assert_eq!(is.state.memory.merkle_root(), sha3_root);
+        drop(clone);
+
+        // the preimage keys are checked with the hasher of their type
+        let data = b"preimage";
+        let key = Keccak256Key(Keccak256Hasher.hash(data)).preimage_key();
+        assert!(verify_preimage(key, data).is_ok());
+        assert!(verify_preimage(key, b"other").is_err());
+    }
+
+    #[test]
+    fn test_reset() {
+        // leaves a word in memory, values in registers and hi/lo, prints "a" and exits with 3
+        let first = [
+            asm::addiu(9, 0, 0x61),
+            asm::r_type(9, 0, 0, 0, 0x11), // mthi
+            asm::r_type(9, 0, 0, 0, 0x13), // mtlo
+            asm::sw(9, 0, 0x400),
+            asm::addiu(2, 0, 4004),
+            asm::addiu(4, 0, 1),
+            asm::addiu(5, 0, 0x403),
+            asm::addiu(6, 0, 1),
+            asm::syscall(),
+            asm::addiu(2, 0, 4246),
+            asm::addiu(4, 0, 3),
+            asm::syscall(),
+        ];
+        // prints "b" and exits with 5 plus whatever the first program left behind
+        let second = [
+            asm::lw(10, 0, 0x400),
+            asm::r_type(0, 0, 11, 0, 0x10), // mfhi
+            asm::addiu(12, 0, 0x62),
+            asm::sb(12, 0, 0x500),
+            asm::addiu(2, 0, 4004),
+            asm::addiu(4, 0, 1),
+            asm::addiu(5, 0, 0x500),
+            asm::addiu(6, 0, 1),
+            asm::syscall(),
+            asm::addu(4, 10, 11),
+            asm::addu(4, 4, 9),
+            asm::addiu(4, 4, 5),
+            asm::addiu(2, 0, 4246),
+            asm::syscall(),
+        ];
+
+        let mut is = InstrumentedState::new(load_program(&first), Box::new(RecordingOracle::default()));
+        let stdout = SharedBuffer::default();
+        is.set_stdout_writer(Box::new(stdout.clone()));
+        let result = is.run(100).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(3));
+        assert_eq!(is.state.memory.get_memory(0x400), 0x61);
+
+        is.reset(0x2000);
+        assert_eq!(is.state.memory.page_count(), 0);
+        assert_eq!(is.state.memory.merkle_root(), Memory::new().merkle_root());
+        assert_eq!(is.state.pc, 0x2000);
+        assert_eq!(is.state.registers, [0; 32]);
+
+        for (i, insn) in second.iter().enumerate() {
+            is.state.memory.set_memory(0x2000 + 4 * i as u32, *insn).unwrap();
+        }
+        let result = is.run(100).unwrap();
+        assert_eq!(result.status, VmStatus::Exited(5));
+        assert_eq!(result.steps, second.len() as u64);
+        assert_eq!(is.state.memory.get_memory(0), 0);
+        assert_eq!(is.state.memory.get_memory(0x400), 0);
+        // the writer set before the reset received the output of both programs
+        assert_eq!(stdout.0.lock().unwrap().as_slice(), b"ab");
+    }
+
+    #[test]
+    fn test_streamed_state_hash() {
+        let data = memcpy_program().build();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).unwrap();
+        let (state, _) = State::load_elf(&file);
+        let mut is = InstrumentedState::new(state, Box::new(RecordingOracle::default()));
+        for _ in 0..20 {
+            let encoded: [u8; 32] = Keccak256::digest(is.state.encode_witness()).into();
+            assert_eq!(is.state_hash(), encoded);
+            is.step(false).unwrap();
+        }
+        is.state.registers[31] = 0xdeadbeef;
+        let encoded: [u8; 32] = Keccak256::digest(is.state.encode_witness()).into();
+        assert_eq!(is.state_hash(), encoded);
+
+        let registers: Vec<u8> = is.state.registers.iter().flat_map(|r| r.to_be_bytes()).collect();
+        assert_eq!(is.state.registers_hash(), <[u8; 32]>::from(Keccak256::digest(registers)));
     }
 }
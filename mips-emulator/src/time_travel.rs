@@ -0,0 +1,227 @@
+//! Reverse execution of the emulator: `TimeTravel` keeps a snapshot of the state every few
+//! steps while the guest runs, and reaches an earlier step by rewinding to the snapshot before it
+//! and executing forward again. The execution is deterministic, the preimages fetched again are
+//! served from the log of the first fetches, not by the oracle.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use crate::error::EmulatorError;
+use crate::pre_image::{EmptyPreimageOracle, PreimageLogOracle, PreimageOracle, ServedPreimage};
+use crate::state::{InstrumentedState, State, StateSnapshot};
+
+/// the steps between two snapshots by default.
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 10_000;
+
+/// the bytes of preimage parts the log holds by default, see `TimeTravel::with_log_limit`.
+pub const DEFAULT_PREIMAGE_LOG_LIMIT: usize = 64 << 20;
+
+/// the tag of the region `TimeTravel::reverse_to_write` watches.
+const REVERSE_WATCH_TAG: &str = "time travel";
+
+/// ReplayingOracle logs the parts of the preimages the live oracle serves and serves them again
+/// from the log. The hints of the steps executed again are not sent to the live oracle twice.
+/// The live oracle is shared with `TimeTravel`, which restores it in `into_inner`.
+struct ReplayingOracle {
+    live: Rc<RefCell<Box<dyn PreimageOracle>>>,
+    log: PreimageLogOracle,
+    replaying: Arc<AtomicBool>,
+}
+
+impl PreimageOracle for ReplayingOracle {
+    fn hint(&mut self, v: &[u8]) {
+        if !self.replaying.load(Ordering::Relaxed) {
+            self.live.borrow_mut().hint(v);
+        }
+    }
+
+    fn serves_parts(&self) -> bool {
+        self.live.borrow().serves_parts()
+    }
+
+    fn get_preimage(&mut self, k: [u8; 32]) -> Result<Vec<u8>, EmulatorError> {
+        if let Ok(data) = self.log.get_preimage(k) {
+            return Ok(data);
+        }
+        let data = self.live.borrow_mut().get_preimage(k)?;
+        let prefixed = [(data.len() as u64).to_be_bytes().as_slice(), &data].concat();
+        self.log.push(ServedPreimage { key: k, offset: 0, data: prefixed });
+        Ok(data)
+    }
+
+    fn preimage_len(&mut self, k: [u8; 32]) -> Result<u64, EmulatorError> {
+        if let Ok(len) = self.log.preimage_len(k) {
+            return Ok(len);
+        }
+        let len = self.live.borrow_mut().preimage_len(k)?;
+        self.log.push(ServedPreimage { key: k, offset: 0, data: len.to_be_bytes().to_vec() });
+        Ok(len)
+    }
+
+    fn get_preimage_part(
+        &mut self,
+        k: [u8; 32],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        if let Ok(part) = self.log.get_preimage_part(k, offset, len) {
+            return Ok(part);
+        }
+        let part = self.live.borrow_mut().get_preimage_part(k, offset, len)?;
+        self.log.push(ServedPreimage { key: k, offset: offset + 8, data: part.clone() });
+        Ok(part)
+    }
+}
+
+/// TimeTravel runs an `InstrumentedState` forward and backward by step. The guest output of
+/// the steps executed again is written again, and rewinding clears the journal events and the
+/// region logs like `InstrumentedState::reset_to` does.
+pub struct TimeTravel {
+    is: Box<InstrumentedState>,
+    interval: u64,
+    /// the snapshots by step, the first one is the state `TimeTravel` was created with.
+    checkpoints: BTreeMap<u64, StateSnapshot>,
+    /// the furthest step executed, the steps up to it are executed again when seeking.
+    frontier: u64,
+    replaying: Arc<AtomicBool>,
+    /// the oracle of the state `TimeTravel` was created with.
+    live: Rc<RefCell<Box<dyn PreimageOracle>>>,
+}
+
+impl TimeTravel {
+    /// Starts from the state of `is`, the earliest step it can rewind to. A snapshot is kept at
+    /// every step multiple of `interval`.
+    pub fn new(is: Box<InstrumentedState>, interval: u64) -> Self {
+        Self::with_log_limit(is, interval, DEFAULT_PREIMAGE_LOG_LIMIT)
+    }
+
+    /// Starts like `new`, the log of the preimages holds `limit` bytes of parts at most. The
+    /// parts dropped from the log are fetched again from the oracle by the steps executed
+    /// again, without their hints.
+    pub fn with_log_limit(mut is: Box<InstrumentedState>, interval: u64, limit: usize) -> Self {
+        let replaying = Arc::new(AtomicBool::new(false));
+        let live = Rc::new(RefCell::new(is.replace_oracle(Box::new(EmptyPreimageOracle))));
+        is.replace_oracle(Box::new(ReplayingOracle {
+            live: live.clone(),
+            log: PreimageLogOracle::trusted(limit),
+            replaying: replaying.clone(),
+        }));
+        let step = is.state.step();
+        let mut time_travel = Self {
+            is,
+            interval: interval.max(1),
+            checkpoints: BTreeMap::new(),
+            frontier: step,
+            replaying,
+            live,
+        };
+        time_travel.checkpoint(true);
+        time_travel
+    }
+
+    pub fn state(&self) -> &State {
+        &self.is.state
+    }
+
+    pub fn inner(&self) -> &InstrumentedState {
+        &self.is
+    }
+
+    /// Returns the state at the current step, with the oracle it was created with.
+    pub fn into_inner(mut self) -> Box<InstrumentedState> {
+        // dropping the replaying oracle leaves `live` the only owner of the live one
+        drop(self.is.replace_oracle(Box::new(EmptyPreimageOracle)));
+        let live = Rc::try_unwrap(self.live).ok().expect("the live oracle is shared");
+        self.is.replace_oracle(live.into_inner());
+        self.is
+    }
+
+    /// Returns the hash of the current state, see `InstrumentedState::state_hash`.
+    pub fn state_hash(&mut self) -> [u8; 32] {
+        self.is.state_hash()
+    }
+
+    /// The steps of the snapshots kept, in order.
+    pub fn snapshot_steps(&self) -> impl Iterator<Item = u64> + '_ {
+        self.checkpoints.keys().copied()
+    }
+
+    /// Runs forward until `step`, or until the guest exits before it. Does nothing if the state
+    /// is past `step` already.
+    pub fn run_to(&mut self, step: u64) -> Result<&State, EmulatorError> {
+        self.forward(step)?;
+        Ok(&self.is.state)
+    }
+
+    /// Moves to `step`, backward from the snapshot before it or forward from the current state.
+    /// A step before the first snapshot moves to the first snapshot, a step after the exit of
+    /// the guest to its exit.
+    pub fn seek(&mut self, step: u64) -> Result<&State, EmulatorError> {
+        if step < self.is.state.step() {
+            let start = match self.checkpoints.range(..=step).next_back() {
+                Some((start, _)) => *start,
+                None => *self.checkpoints.keys().next().unwrap(),
+            };
+            self.rewind(start);
+        }
+        self.run_to(step)
+    }
+
+    /// Moves `steps` steps backward, see `seek`.
+    pub fn back(&mut self, steps: u64) -> Result<&State, EmulatorError> {
+        self.seek(self.is.state.step().saturating_sub(steps))
+    }
+
+    /// Moves backward to the last store to the word of `addr` before the current step, the
+    /// state is the one before the store executed, its pc is the store. Returns the step of the
+    /// store, none if the word wasn't written since the first snapshot: then the state is left
+    /// unchanged.
+    pub fn reverse_to_write(&mut self, addr: u32) -> Result<Option<u64>, EmulatorError> {
+        let current = self.is.state.step();
+        let word = addr & !3;
+        let starts: Vec<u64> = self.checkpoints.range(..current).map(|(step, _)| *step).collect();
+        // watch the intervals between the snapshots from the latest, until one holds a store
+        let mut end = current;
+        for start in starts.into_iter().rev() {
+            self.rewind(start);
+            self.is.watch_region(word..word.saturating_add(4), REVERSE_WATCH_TAG);
+            let result = self.forward(end);
+            let log = self.is.unwatch_region(REVERSE_WATCH_TAG);
+            result?;
+            if let Some(access) = log.as_ref().and_then(|log| log.accesses().last()) {
+                let step = access.step;
+                self.seek(step - 1)?;
+                return Ok(Some(step));
+            }
+            end = start;
+        }
+        self.seek(current)?;
+        Ok(None)
+    }
+
+    /// Restores the snapshot of `step`.
+    fn rewind(&mut self, step: u64) {
+        self.is.reset_to(&self.checkpoints[&step]);
+    }
+
+    fn forward(&mut self, step: u64) -> Result<(), EmulatorError> {
+        while !self.is.state.exited && self.is.state.step() < step {
+            let replaying = self.is.state.step() < self.frontier;
+            self.replaying.store(replaying, Ordering::Relaxed);
+            self.is.step(false)?;
+            self.frontier = self.frontier.max(self.is.state.step());
+            self.checkpoint(false);
+        }
+        Ok(())
+    }
+
+    /// Keeps a snapshot of the current step if it is a multiple of the interval, or if `force`.
+    fn checkpoint(&mut self, force: bool) {
+        let step = self.is.state.step();
+        if (force || step % self.interval == 0) && !self.checkpoints.contains_key(&step) {
+            self.checkpoints.insert(step, self.is.snapshot());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Exporters of the executed instructions to the formats of existing tools, see
+//! `InstrumentedState::set_trace_exporter`. The exporters write each step as it is executed,
+//! the trace is never buffered.
+
+use std::io::{self, Write};
+use crate::decode;
+use crate::symbols::SymbolMap;
+
+/// TraceExporter receives the instructions executed by the guest.
+pub trait TraceExporter {
+    /// Records the instruction `insn` at `pc`, executed by the step `step`.
+    fn record(&mut self, step: u64, pc: u32, insn: u32) -> io::Result<()>;
+
+    /// Completes the output, called once after the last step.
+    fn finish(&mut self) -> io::Result<()>;
+
+    /// Starts a new run, called when the emulator is reset: the instructions recorded next
+    /// don't continue the ones before. Does nothing by default.
+    fn reset(&mut self) {}
+}
+
+/// Returns the mnemonic of `insn`, `.word` for the unsupported instructions.
+fn mnemonic(insn: u32) -> String {
+    match decode::opcode_id(insn) {
+        Some(_) if insn == 0 => String::from("nop"),
+        Some(id) => format!("{:?}", id).to_lowercase(),
+        None => String::from(".word"),
+    }
+}
+
+/// Returns the name of the function containing `pc`, or the address if no symbol contains it.
+fn function_name(symbols: &SymbolMap, pc: u32) -> String {
+    match symbols.lookup(pc) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{:x}", pc),
+    }
+}
+
+/// QemuExporter writes the instructions like `qemu -d in_asm`: each basic block under a header
+/// naming its function, then one `address: bytes mnemonic` line per instruction. A block ends
+/// with the delay slot of a branch or jump, or when the execution doesn't continue at the next
+/// instruction.
+pub struct QemuExporter<W: Write> {
+    writer: W,
+    symbols: SymbolMap,
+    /// the pc of the last instruction, none before the first one.
+    last_pc: Option<u32>,
+    last_is_control_transfer: bool,
+    block_ended: bool,
+}
+
+impl<W: Write> QemuExporter<W> {
+    pub fn new(writer: W, symbols: SymbolMap) -> Self {
+        Self {
+            writer,
+            symbols,
+            last_pc: None,
+            last_is_control_transfer: false,
+            block_ended: true,
+        }
+    }
+}
+
+impl<W: Write> TraceExporter for QemuExporter<W> {
+    fn record(&mut self, _step: u64, pc: u32, insn: u32) -> io::Result<()> {
+        // the instruction after a delay slot starts a block, even if the branch was not taken
+        if self.block_ended || self.last_pc.map(|last| last.wrapping_add(4)) != Some(pc) {
+            if self.last_pc.is_some() {
+                writeln!(self.writer)?;
+            }
+            writeln!(self.writer, "----------------")?;
+            writeln!(self.writer, "IN: {}", function_name(&self.symbols, pc))?;
+        }
+        writeln!(self.writer, "0x{:08x}:  {:08x}  {}", pc, insn, mnemonic(insn))?;
+
+        self.block_ended = self.last_is_control_transfer;
+        self.last_is_control_transfer = decode::is_control_transfer(insn);
+        self.last_pc = Some(pc);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn reset(&mut self) {
+        self.last_is_control_transfer = false;
+        self.block_ended = true;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Call,
+    Return,
+}
+
+/// Returns whether `insn` calls a function (jal, jalr) or returns from one (jr $ra).
+fn transfer(insn: u32) -> Option<Transfer> {
+    let rs = (insn >> 21) & 0x1f;
+    let rd = (insn >> 11) & 0x1f;
+    match (insn >> 26, insn & 0x3f) {
+        (3, _) => Some(Transfer::Call),
+        (0, 9) if rd != 0 => Some(Transfer::Call),
+        (0, 8) if rs == 31 => Some(Transfer::Return),
+        _ => None,
+    }
+}
+
+/// ChromeTraceExporter writes a Chrome trace event JSON, which perfetto and chrome://tracing
+/// open. Each function call is a duration slice named after the called function, the slice
+/// begins with the first instruction of the function and ends after the delay slot of its
+/// `jr $ra`. The timestamps are the steps. The slices still open at the end of the trace, the
+/// function of the first instruction included, are closed by `finish`. After a reset, the
+/// slices of the previous run are closed where it stopped and the new run follows it.
+pub struct ChromeTraceExporter<W: Write> {
+    writer: W,
+    symbols: SymbolMap,
+    started: bool,
+    /// the names of the open slices, the innermost last.
+    stack: Vec<String>,
+    /// the call or return being executed, applied at the step after its delay slot, and the pc
+    /// of its instruction.
+    pending: Option<(Transfer, u32)>,
+    /// the timestamp after the last step.
+    end_step: u64,
+    events: u64,
+    /// added to the steps of the current run for their timestamps.
+    offset: u64,
+    /// whether the emulator was reset since the last step.
+    restarted: bool,
+}
+
+impl<W: Write> ChromeTraceExporter<W> {
+    pub fn new(writer: W, symbols: SymbolMap) -> Self {
+        Self {
+            writer,
+            symbols,
+            started: false,
+            stack: Vec::new(),
+            pending: None,
+            end_step: 0,
+            events: 0,
+            offset: 0,
+            restarted: false,
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.started = true;
+            write!(self.writer, "{{\"traceEvents\":[")?;
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, name: &str, phase: char, step: u64) -> io::Result<()> {
+        if self.events != 0 {
+            write!(self.writer, ",")?;
+        }
+        self.events += 1;
+        write!(
+            self.writer,
+            "\n{{\"name\":{},\"ph\":\"{}\",\"ts\":{},\"pid\":1,\"tid\":1}}",
+            serde_json::to_string(name)?, phase, step,
+        )
+    }
+
+    fn begin(&mut self, pc: u32, step: u64) -> io::Result<()> {
+        let name = function_name(&self.symbols, pc);
+        self.event(&name, 'B', step)?;
+        self.stack.push(name);
+        Ok(())
+    }
+
+    fn end(&mut self, step: u64) -> io::Result<()> {
+        match self.stack.pop() {
+            Some(name) => self.event(&name, 'E', step),
+            // a return from a function entered before the trace started
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> TraceExporter for ChromeTraceExporter<W> {
+    fn record(&mut self, step: u64, pc: u32, insn: u32) -> io::Result<()> {
+        if self.restarted {
+            self.restarted = false;
+            while !self.stack.is_empty() {
+                self.end(self.end_step)?;
+            }
+            self.offset = self.end_step.wrapping_sub(step);
+            self.begin(pc, self.end_step)?;
+        }
+        let step = step.wrapping_add(self.offset);
+        if !self.started {
+            self.start()?;
+            self.begin(pc, step)?;
+        }
+        if let Some((transfer, from)) = self.pending {
+            // skip the delay slot
+            if pc != from.wrapping_add(4) {
+                self.pending = None;
+                match transfer {
+                    Transfer::Call => self.begin(pc, step)?,
+                    Transfer::Return => self.end(step)?,
+                }
+            }
+        }
+        if let Some(transfer) = transfer(insn) {
+            self.pending = Some((transfer, pc));
+        }
+        self.end_step = step + 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.start()?;
+        self.pending = None;
+        while !self.stack.is_empty() {
+            self.end(self.end_step)?;
+        }
+        write!(self.writer, "\n]}}\n")?;
+        self.writer.flush()
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+        self.restarted = self.started;
+    }
+}
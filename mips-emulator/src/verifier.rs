@@ -0,0 +1,95 @@
+//! The one-step verifier of the emulator: the post-state hash of a step computed from the
+//! pre-state witness and the memory proofs of the step alone, like the on-chain MIPS.sol
+//! verifier does. It runs the step of `compat::cannon::execute_one_step` with the memory tree
+//! and the state hash of the emulator, for hosts holding the witness of a step rather than the
+//! JSON of Cannon.
+
+use crate::compat::cannon::{self, OneStepError, OneStepInput, PROOF_SIZE};
+use crate::hash::{Hasher32, Keccak256Hasher};
+use crate::memory::DEFAULT_MEMORY_HASH;
+use crate::witness::{MemoryOperation, StepKind, StepWitness};
+
+/// VerifyError is returned when the step can't be verified: a malformed witness, a proof not
+/// leading to the memory root of the pre-state, or an access to a word no proof covers.
+pub type VerifyError = OneStepError;
+
+/// StateWitness is the witness encoding of the pre-state, see `State::encode_witness`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateWitness(pub Vec<u8>);
+
+/// StepProofs are the memory proofs of a step, each `PROOF_SIZE` bytes: the proof of the
+/// instruction, and the proof of the word the step accesses, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepProofs {
+    pub instruction: Vec<u8>,
+    pub memory: Option<Vec<u8>>,
+}
+
+/// PreimagePart is the preimage read by the step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreimagePart {
+    pub key: [u8; 32],
+    /// the preimage, including its 8 bytes length prefix.
+    pub value: Vec<u8>,
+    pub offset: u32,
+}
+
+impl StateWitness {
+    pub fn from_witness(wit: &StepWitness) -> Self {
+        Self(wit.state.clone())
+    }
+}
+
+impl StepProofs {
+    /// Returns the proofs of the step `wit`, taken by `InstrumentedState::step` with proofs.
+    pub fn from_witness(wit: &StepWitness) -> Self {
+        let accesses_memory = wit.mem_ops.iter().any(|access| access.op != MemoryOperation::Fetch)
+            || matches!(&wit.kind, StepKind::Syscall(syscall) if !syscall.mem_ops.is_empty());
+        let proof = |i: usize| {
+            wit.mem_proof.get(i * PROOF_SIZE..(i + 1) * PROOF_SIZE).map(<[u8]>::to_vec)
+        };
+        Self {
+            instruction: proof(0).unwrap_or_default(),
+            memory: proof(1).filter(|_| accesses_memory),
+        }
+    }
+}
+
+impl PreimagePart {
+    /// Returns the preimage read by the step `wit`, none if it reads none.
+    pub fn from_witness(wit: &StepWitness) -> Option<Self> {
+        if wit.preimage_value.is_empty() {
+            return None;
+        }
+        Some(Self {
+            key: wit.preimage_key,
+            value: wit.preimage_value.clone(),
+            offset: wit.preimage_offset,
+        })
+    }
+}
+
+/// Executes the step of the pre-state `pre` with only the memory words covered by `proofs`,
+/// returns the hash of the post-state, see `State::state_hash`. Its memory root is computed from
+/// the proof of the word the step writes.
+pub fn step(
+    pre: &StateWitness,
+    proofs: &StepProofs,
+    preimage: Option<&PreimagePart>,
+) -> Result<[u8; 32], VerifyError> {
+    let mut proof_data = proofs.instruction.clone();
+    if let Some(memory) = &proofs.memory {
+        proof_data.extend(memory);
+    }
+    let input = OneStepInput {
+        step: 0,
+        pre: None,
+        state_data: pre.0.clone(),
+        proof_data,
+        oracle_key: preimage.map(|preimage| preimage.key),
+        oracle_value: preimage.map(|preimage| preimage.value.clone()).unwrap_or_default(),
+        oracle_offset: preimage.map(|preimage| preimage.offset),
+    };
+    let hash = |data: &[u8]| Keccak256Hasher.hash(data);
+    Ok(cannon::execute_step(&input, DEFAULT_MEMORY_HASH, hash)?.post_hash)
+}
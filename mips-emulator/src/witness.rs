@@ -1,22 +1,129 @@
-use std::io::Read;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
 use std::iter;
+use std::ops::Range;
 use ff::PrimeFieldBits;
 use group::Curve;
 use pasta_curves::arithmetic::CurveAffine;
 use pasta_curves::pallas::Base;
+use serde::Serialize;
+use sha3::{Digest, Keccak256};
+use crate::memory::Memory;
+use crate::opcode_id;
 use crate::state::State;
 use super::sinsemilla::HashDomain;
 
 /// StepWitness is for fault proof in OP stack.
-#[derive(Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
 pub struct StepWitness {
     // encoded state witness
     pub state: Vec<u8>,
     pub mem_proof: Vec<u8>,
+    /// the words accessed after the first memory access, only by wide preimage reads. Their
+    /// proofs follow in `mem_proof`, each one taken after the previous words were written.
+    pub extra_mem_accesses: Vec<u32>,
 
     pub preimage_key: [u8; 32], // zeroed when no pre-image is accessed
-    pub preimage_value: Vec<u8>, // including the 8-byte length prefix
+    /// the whole preimage read by the step, including the 8-byte length prefix. Unlike the
+    /// reads, a witness with proofs fetches a preimage larger than a window whole.
+    pub preimage_value: Vec<u8>,
     pub preimage_offset: u32,
+
+    /// the kind of instruction executed by the step, syscalls carry their own witness.
+    pub kind: StepKind,
+    /// the step number, counted like `State::step`.
+    pub step: u64,
+    /// the instruction executed, at the pc before the step.
+    pub instruction: Instruction,
+    /// the memory accessed by the instruction, its fetch first. The accesses of syscalls are in
+    /// their witness.
+    pub mem_ops: Vec<MemoryAccess>,
+    /// the general purpose register written by the instruction. None for the steps writing
+    /// none, like branches and stores, and for syscalls, whose results are in their witness.
+    pub register_delta: Option<RegisterDelta>,
+    /// the hi/lo registers written by the instruction.
+    pub hilo_delta: Option<HiLoDelta>,
+}
+
+/// StepKind classifies the steps for the circuits, which constrain each kind differently.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum StepKind {
+    /// the ALU and load/store instructions.
+    #[default]
+    Alu,
+    Branch,
+    /// j/jal and jr/jalr.
+    Jump,
+    Syscall(SyscallWitness),
+    /// the instructions reading or writing the hi/lo registers.
+    HiLo,
+    /// break, which stops the guest with the code of bits 6-25.
+    Break { code: u32 },
+    /// sync, executed as a nop.
+    Sync,
+}
+
+impl StepKind {
+    /// Returns the number of the kind in the step table of the circuits.
+    pub fn id(&self) -> u32 {
+        match self {
+            StepKind::Alu => 0,
+            StepKind::Branch => 1,
+            StepKind::Jump => 2,
+            StepKind::Syscall(_) => 3,
+            StepKind::HiLo => 4,
+            StepKind::Break { .. } => 5,
+            StepKind::Sync => 6,
+        }
+    }
+
+    /// Returns the kind of the step executing `insn`, a syscall step carries `syscall`.
+    pub fn new(insn: u32, syscall: Option<SyscallWitness>) -> Self {
+        match (insn >> 26, insn & 0x3f) {
+            (2 | 3, _) | (0, 0x8 | 0x9) => StepKind::Jump,
+            (1 | 4..=7, _) => StepKind::Branch,
+            (0, 0xc) => StepKind::Syscall(syscall.unwrap_or_default()),
+            (0, 0x10..=0x1b) => StepKind::HiLo,
+            (0, 0xd) => StepKind::Break { code: (insn >> 6) & 0xfffff },
+            (0, 0xf) => StepKind::Sync,
+            _ => StepKind::Alu,
+        }
+    }
+}
+
+/// RegisterDelta is a register written by a step, with its value before and after. The
+/// circuits constrain the written register rather than all 32 of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RegisterDelta {
+    pub reg: u32,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// HiLoDelta is the hi/lo registers written by a step, with their values before and after.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HiLoDelta {
+    pub old_hi: u32,
+    pub old_lo: u32,
+    pub hi: u32,
+    pub lo: u32,
+}
+
+/// SyscallWitness is a syscall executed by a step, the rows of the syscall lookup table.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SyscallWitness {
+    pub step: u64,
+    /// the syscall number in v0.
+    pub num: u32,
+    /// the arguments in a0-a3.
+    pub args: [u32; 4],
+    /// the results in v0 and v1 (a3 on linux).
+    pub ret: (u32, u32),
+    /// the preimage key and offset after the syscall, if it was on a preimage fd.
+    pub preimage_key: Option<[u8; 32]>,
+    pub preimage_offset: Option<u32>,
+    /// the memory accessed by the syscall, only tracked for the preimage fds.
+    pub mem_ops: Vec<MemoryAccess>,
 }
 
 const MIPS_INSTRUCTION_LEN: usize = 32;
@@ -47,7 +154,7 @@ pub fn i2lebsp<const NUM_BITS: usize>(int: u64) -> [bool; NUM_BITS] {
 
 
 /// MIPS Instruction, it is fixed length, i.e., 32-bits.
-#[derive(Default, Copy, Clone, Debug)]
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Instruction {
     pub addr: u32,
     pub bytecode: u32,
@@ -61,30 +168,80 @@ impl Instruction {
 }
 
 
-/// ProgramSegment is a segment of program, it contains the start address and size of
+/// ProgramSegment is an executable segment of program, it contains the start address and size of
 /// the segment, and all the instructions in the segment.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct ProgramSegment {
     pub start_addr: u32,
     pub segment_size: u32,
     pub instructions: Vec<Instruction>,
 }
 
+/// InstructionImage is the static program: the words of the text by address, as they were in
+/// memory when execution started. The fetch lookups of the circuit reference it, while the
+/// loads and stores go to the dynamic access trace.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct InstructionImage {
+    words: BTreeMap<u32, u32>,
+}
+
+impl InstructionImage {
+    /// Captures the words of `range` from `memory`.
+    pub fn capture(memory: &mut Memory, range: Range<u32>) -> Self {
+        let words = (range.start & !3..range.end).step_by(4)
+            .map(|addr| (addr, memory.get_memory(addr)))
+            .collect();
+        Self { words }
+    }
+
+    pub fn get(&self, addr: u32) -> Option<u32> {
+        self.words.get(&addr).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Iterates the (address, instruction) pairs, by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.words.iter().map(|(addr, insn)| (*addr, *insn))
+    }
+}
+
 /// The program struct consists of all the segments.
 /// The `cur_segment`, `cur_instruction`, `cur_bit` variable are used to
 /// iterate the instructions of the program, to compute the program hash.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, )]
 pub struct Program {
     cur_segment: usize,
     cur_instruction: usize,
     cur_bit: usize, // each instruction has 32 bits
-    pub segments: Vec<ProgramSegment>
+    pub segments: Vec<ProgramSegment>,
+    /// the text of the program, captured by `load_instructions`.
+    pub image: InstructionImage,
+}
+
+/// Programs are equal when their segments and images are, whatever the positions of their bit
+/// iterators.
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.segments == other.segments && self.image == other.image
+    }
 }
 
+impl Eq for Program {}
+
 
 /// To initialize the Sinsemilla hasher, it is a math parameter.
 pub const PERSONALIZATION: &str = "zkMIPS-CRH";
 
+/// The domain separator prefixing the canonical serialization of `Program::code_bytes`.
+pub const CODE_HASH_DOMAIN: &[u8] = b"zkMIPS-code-v1";
+
 
 impl Iterator for Program {
     type Item = bool;
@@ -124,10 +281,13 @@ impl Program {
             cur_instruction: 0,
             cur_bit: 0,
             segments: vec![],
+            image: InstructionImage::default(),
         }
     }
 
     pub fn load_instructions(&mut self, state: &mut Box<State>) {
+        self.image = state.instruction_image();
+        let endianness = state.memory.endianness();
         for i in 0..self.segments.len() {
             let segment = &mut self.segments[i];
             let mut buf = Vec::<u8>::new();
@@ -142,12 +302,106 @@ impl Program {
             for i in (0..buf.len()).step_by(4) {
                 segment.instructions.push(Instruction {
                     addr: segment.start_addr + (i as u32),
-                    bytecode: u32::from_le_bytes(buf[i..i+4].try_into().unwrap())
+                    bytecode: endianness.word_from_bytes(buf[i..i+4].try_into().unwrap())
                 });
             }
         }
     }
 
+    /// The canonical serialization of the program committed by `code_hash`: `CODE_HASH_DOMAIN`
+    /// followed by the big endian (address, instruction) pairs, sorted by address. Committing to
+    /// the addresses keeps the same code at different addresses apart.
+    pub fn code_bytes(&self) -> Vec<u8> {
+        let words: BTreeMap<u32, u32> = self.segments.iter()
+            .flat_map(|segment| &segment.instructions)
+            .map(|instruction| (instruction.addr, instruction.bytecode))
+            .collect();
+        let mut out = CODE_HASH_DOMAIN.to_vec();
+        for (addr, insn) in words {
+            out.extend(addr.to_be_bytes());
+            out.extend(insn.to_be_bytes());
+        }
+        out
+    }
+
+    /// Returns the rows of the opcode table for the instructions of the program, by address.
+    pub fn opcode_rows(&self) -> Vec<OpcodeRow> {
+        let mut rows: Vec<OpcodeRow> = self.segments.iter()
+            .flat_map(|segment| &segment.instructions)
+            .map(OpcodeRow::from)
+            .collect();
+        rows.sort_by_key(|row| row.address);
+        rows
+    }
+
+    /// Keccak256 of `code_bytes`.
+    pub fn code_hash(&self) -> [u8; 32] {
+        Keccak256::digest(self.code_bytes()).into()
+    }
+
+    /// Checks that `memory` still holds the instructions of the program, it doesn't when the
+    /// guest modified its code while the text was not protected.
+    pub fn verify_against_memory(&self, memory: &Memory) -> bool {
+        self.segments.iter()
+            .flat_map(|segment| &segment.instructions)
+            .all(|instruction| memory.peek_memory(instruction.addr) == instruction.bytecode)
+    }
+
+    /// The binary encoding of the segments and the image of the program, little endian:
+    ///
+    /// - u32 segment count, then for each segment its u32 start address, u32 size, u32
+    ///   instruction count and the (u32 address, u32 instruction) pairs;
+    /// - u32 image word count, then the (u32 address, u32 instruction) pairs.
+    ///
+    /// The position of the bit iterator is not encoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write_bytes(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Program> {
+        let mut r = Reader(bytes);
+        let program = Program::read_bytes(&mut r)?;
+        r.finish()?;
+        Ok(program)
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        out.extend((self.segments.len() as u32).to_le_bytes());
+        for segment in &self.segments {
+            out.extend(segment.start_addr.to_le_bytes());
+            out.extend(segment.segment_size.to_le_bytes());
+            out.extend((segment.instructions.len() as u32).to_le_bytes());
+            for instruction in &segment.instructions {
+                out.extend(instruction.addr.to_le_bytes());
+                out.extend(instruction.bytecode.to_le_bytes());
+            }
+        }
+        out.extend((self.image.len() as u32).to_le_bytes());
+        for (addr, insn) in self.image.iter() {
+            out.extend(addr.to_le_bytes());
+            out.extend(insn.to_le_bytes());
+        }
+    }
+
+    fn read_bytes(r: &mut Reader) -> io::Result<Program> {
+        let mut program = Program::new();
+        for _ in 0..r.u32()? {
+            let start_addr = r.u32()?;
+            let segment_size = r.u32()?;
+            let instructions = (0..r.u32()?)
+                .map(|_| Ok(Instruction { addr: r.u32()?, bytecode: r.u32()? }))
+                .collect::<io::Result<_>>()?;
+            program.segments.push(ProgramSegment { start_addr, segment_size, instructions });
+        }
+        let words = (0..r.u32()?)
+            .map(|_| Ok((r.u32()?, r.u32()?)))
+            .collect::<io::Result<_>>()?;
+        program.image = InstructionImage { words };
+        Ok(program)
+    }
+
     pub fn reset_iterator(&mut self) {
         self.cur_segment = 0;
         self.cur_instruction = 0;
@@ -327,7 +581,7 @@ impl Program {
 
 /// ExecutionRow contains a instruction executed, and the registers state after execution
 /// pc, next_pc, heap and exited flag.
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
 pub struct ExecutionRow {
     pub instruction: Instruction,
     pub step: u64,
@@ -341,18 +595,21 @@ pub struct ExecutionRow {
 }
 
 
-/// Operation to memory access, Read/Write
-#[derive(Copy, Clone, Debug)]
+/// Operation to memory access, Read/Write, or Fetch for the instruction word of a step
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum MemoryOperation {
     Read,
     Write,
+    /// the fetch of the instruction at the pc, the first access of each step.
+    Fetch,
 }
 
 
 /// A memory access, contains the address, operation type, and the value returns.
 /// If the access is Read, then `value` is the read result.
 /// If the access is Write, then `value` is the write value.
-#[derive(Copy, Clone, Debug)]
+/// If the access is Fetch, then `value` is the instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct MemoryAccess {
     pub rw_counter: u64,
     pub addr: u32,
@@ -377,9 +634,283 @@ impl Default for MemoryAccess {
 /// Trace is the input to zk prover, which means we can separate the vm execution
 /// and proof generation.
 /// The trace contains the program struct, the execution trace list, the memory access list.
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct Trace {
     pub prog: Program,            // program table
     pub exec: Vec<ExecutionRow>,  // executed instructions
     pub mem: Vec<MemoryAccess>,   // memory access table
 }
+
+
+const TRACE_MAGIC: &[u8; 8] = b"MIPSTRCE";
+pub const TRACE_VERSION: u32 = 1;
+
+impl Trace {
+    /// The binary encoding of the trace, little endian, for traces too large for JSON:
+    ///
+    /// - the magic `MIPSTRCE` and the u32 `TRACE_VERSION`;
+    /// - the program, see `Program::to_bytes`;
+    /// - u64 execution row count, then for each row the u32 address and instruction, u64 step,
+    ///   the 32 u32 registers, u32 pc, next pc and heap, u8 exited, u32 hi and lo;
+    /// - u64 memory access count, then for each access its u64 rw counter, u32 address, u8
+    ///   operation (0 read, 1 write), u32 value and previous value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = TRACE_MAGIC.to_vec();
+        out.extend(TRACE_VERSION.to_le_bytes());
+        self.prog.write_bytes(&mut out);
+
+        out.extend((self.exec.len() as u64).to_le_bytes());
+        for row in &self.exec {
+            out.extend(row.instruction.addr.to_le_bytes());
+            out.extend(row.instruction.bytecode.to_le_bytes());
+            out.extend(row.step.to_le_bytes());
+            for register in row.registers {
+                out.extend(register.to_le_bytes());
+            }
+            for v in [row.pc, row.next_pc, row.heap] {
+                out.extend(v.to_le_bytes());
+            }
+            out.push(row.exited as u8);
+            out.extend(row.hi.to_le_bytes());
+            out.extend(row.lo.to_le_bytes());
+        }
+
+        out.extend((self.mem.len() as u64).to_le_bytes());
+        for access in &self.mem {
+            out.extend(access.rw_counter.to_le_bytes());
+            out.extend(access.addr.to_le_bytes());
+            out.push(match access.op {
+                MemoryOperation::Read => 0,
+                MemoryOperation::Write => 1,
+                MemoryOperation::Fetch => 2,
+            });
+            out.extend(access.value.to_le_bytes());
+            out.extend(access.value_prev.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Trace> {
+        let mut r = Reader(bytes);
+        if r.take(TRACE_MAGIC.len())? != TRACE_MAGIC {
+            return Err(invalid("not a trace"));
+        }
+        let version = r.u32()?;
+        if version != TRACE_VERSION {
+            return Err(invalid(&format!("unsupported trace version {}", version)));
+        }
+        let prog = Program::read_bytes(&mut r)?;
+
+        let exec = (0..r.u64()?)
+            .map(|_| {
+                let instruction = Instruction { addr: r.u32()?, bytecode: r.u32()? };
+                let step = r.u64()?;
+                let mut registers = [0; MIPS_REGISTERS_NUM];
+                for register in registers.iter_mut() {
+                    *register = r.u32()?;
+                }
+                Ok(ExecutionRow {
+                    instruction,
+                    step,
+                    registers,
+                    pc: r.u32()?,
+                    next_pc: r.u32()?,
+                    heap: r.u32()?,
+                    exited: r.bool()?,
+                    hi: r.u32()?,
+                    lo: r.u32()?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let mem = (0..r.u64()?)
+            .map(|_| {
+                let rw_counter = r.u64()?;
+                let addr = r.u32()?;
+                let op = match r.take(1)?[0] {
+                    0 => MemoryOperation::Read,
+                    1 => MemoryOperation::Write,
+                    2 => MemoryOperation::Fetch,
+                    op => return Err(invalid(&format!("unknown memory operation {}", op))),
+                };
+                Ok(MemoryAccess { rw_counter, addr, op, value: r.u32()?, value_prev: r.u32()? })
+            })
+            .collect::<io::Result<_>>()?;
+
+        r.finish()?;
+        Ok(Trace { prog, exec, mem })
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reader of the little endian encodings of the traces, the witness streams and the replay
+/// files.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated data"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Reads bytes prefixed by their u32 length.
+    pub(crate) fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn bool(&mut self) -> io::Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(invalid(&format!("invalid boolean {}", b))),
+        }
+    }
+
+    /// Reads a LEB128 varint.
+    pub(crate) fn varint(&mut self) -> io::Result<u64> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    pub(crate) fn finish(&self) -> io::Result<()> {
+        if !self.0.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(())
+    }
+}
+
+
+/// RwRow is a row of the rw table of the circuits, the fields in the order of its columns.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RwRow {
+    pub rw_counter: u64,
+    pub is_write: bool,
+    pub address: u32,
+    pub value: u32,
+    pub value_prev: u32,
+    pub init_value: u32,
+}
+
+impl From<&MemoryAccess> for RwRow {
+    fn from(access: &MemoryAccess) -> Self {
+        Self {
+            rw_counter: access.rw_counter,
+            is_write: access.op == MemoryOperation::Write,
+            address: access.addr,
+            value: access.value,
+            value_prev: access.value_prev,
+            init_value: 0,
+        }
+    }
+}
+
+/// OpcodeRow is a row of the opcode table of the circuits, the fields in the order of its
+/// columns.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpcodeRow {
+    pub address: u32,
+    pub bytecode: u32,
+    /// whether the emulator executes the bytecode, see `opcode_id::is_supported`.
+    pub supported: bool,
+}
+
+impl From<&Instruction> for OpcodeRow {
+    fn from(instruction: &Instruction) -> Self {
+        Self {
+            address: instruction.addr,
+            bytecode: instruction.bytecode,
+            supported: opcode_id::is_supported(instruction.bytecode),
+        }
+    }
+}
+
+/// StepRow is a row of the step table of the circuits, the fields in the order of its columns.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StepRow {
+    pub step: u64,
+    pub pc: u32,
+    pub bytecode: u32,
+    /// the `StepKind::id` of the step.
+    pub kind: u32,
+}
+
+impl From<&StepWitness> for StepRow {
+    fn from(wit: &StepWitness) -> Self {
+        Self {
+            step: wit.step,
+            pc: wit.instruction.addr,
+            bytecode: wit.instruction.bytecode,
+            kind: wit.kind.id(),
+        }
+    }
+}
+
+/// WitnessTables are the rows of the tables of the circuits for a trace, sorted the way the
+/// circuits assign them. The circuits only convert the values of the fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WitnessTables {
+    /// the memory accesses of the instructions and the syscalls, by (address, rw counter), the
+    /// instruction fetches excluded.
+    pub rw: Vec<RwRow>,
+    /// the instructions executed, once per address, by address.
+    pub opcode: Vec<OpcodeRow>,
+    /// the steps in execution order.
+    pub step: Vec<StepRow>,
+}
+
+impl WitnessTables {
+    /// Builds the tables of the witnesses returned by `InstrumentedState::step` with proofs.
+    pub fn from_trace(trace: &[StepWitness]) -> Self {
+        let mut rw: Vec<RwRow> = trace.iter()
+            .flat_map(|wit| {
+                let syscall_ops = match &wit.kind {
+                    StepKind::Syscall(syscall) => syscall.mem_ops.as_slice(),
+                    _ => &[],
+                };
+                wit.mem_ops.iter().chain(syscall_ops)
+            })
+            // the fetches are checked against the opcode table
+            .filter(|access| access.op != MemoryOperation::Fetch)
+            .map(RwRow::from)
+            .collect();
+        rw.sort_by_key(|row| (row.address, row.rw_counter));
+
+        let opcode: BTreeMap<u32, OpcodeRow> = trace.iter()
+            .map(|wit| (wit.instruction.addr, OpcodeRow::from(&wit.instruction)))
+            .collect();
+
+        Self {
+            rw,
+            opcode: opcode.into_values().collect(),
+            step: trace.iter().map(StepRow::from).collect(),
+        }
+    }
+}
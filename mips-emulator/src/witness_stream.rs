@@ -0,0 +1,621 @@
+//! The compact on-disk stream of the step witnesses, for the traces whose witnesses don't fit
+//! in memory. Each record is encoded against the previous one: the pc against the next
+//! instruction, the state witness by the fields and registers the step changed, the memory
+//! proofs by the nodes that differ. The records are framed by their length, `WitnessReader`
+//! decodes them one at a time.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use crate::state::{
+    STATE_WITNESS_SIZE, WITNESS_EXITED, WITNESS_EXIT_CODE, WITNESS_HEAP, WITNESS_HI, WITNESS_LO,
+    WITNESS_NEXT_PC, WITNESS_PC, WITNESS_PREIMAGE_KEY, WITNESS_PREIMAGE_OFFSET,
+    WITNESS_REGISTERS, WITNESS_STEP,
+};
+use crate::witness::{
+    invalid, HiLoDelta, Instruction, MemoryAccess, MemoryOperation, Reader, RegisterDelta,
+    StepKind, StepWitness, SyscallWitness,
+};
+
+const STREAM_MAGIC: &[u8; 8] = b"MIPSWSTR";
+pub const WITNESS_STREAM_VERSION: u32 = 2;
+
+/// the compression of `InstrumentedState::witness_to`.
+#[cfg(feature = "zstd")]
+pub const DEFAULT_COMPRESSION: Compression = Compression::Zstd(3);
+#[cfg(not(feature = "zstd"))]
+pub const DEFAULT_COMPRESSION: Compression = Compression::None;
+
+/// the size of the nodes of the memory proofs, each node is kept or replaced whole.
+const PROOF_NODE_SIZE: usize = 32;
+
+/// the fields of the state witness copied when they change, see `State::encode_witness`: the
+/// memory root, the preimage key and offset, lo, hi, heap, the exit code and the exited flag.
+/// The pc, the next pc and the step are encoded as deltas, the registers one by one.
+const STATE_FIELDS: [(usize, usize); 8] = [
+    (0, WITNESS_PREIMAGE_KEY),
+    (WITNESS_PREIMAGE_KEY, WITNESS_PREIMAGE_OFFSET),
+    (WITNESS_PREIMAGE_OFFSET, WITNESS_PC),
+    (WITNESS_LO, WITNESS_HI),
+    (WITNESS_HI, WITNESS_HEAP),
+    (WITNESS_HEAP, WITNESS_EXIT_CODE),
+    (WITNESS_EXIT_CODE, WITNESS_EXITED),
+    (WITNESS_EXITED, WITNESS_STEP),
+];
+
+/// Compression is applied to the records of a stream, its header is never compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// zstd at the level.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// The previous record, the next one is encoded against it.
+struct Context {
+    step: u64,
+    pc: u32,
+    state: Vec<u8>,
+    mem_proof: Vec<u8>,
+    rw_counter: u64,
+    preimage_key: [u8; 32],
+    preimage_value: Vec<u8>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            step: 0,
+            pc: 0,
+            state: vec![0; STATE_WITNESS_SIZE],
+            mem_proof: Vec::new(),
+            rw_counter: 0,
+            preimage_key: [0; 32],
+            preimage_value: Vec::new(),
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Writes `v` as its zigzag encoded difference to `base`.
+fn put_delta(out: &mut Vec<u8>, v: u64, base: u64) {
+    let delta = v.wrapping_sub(base) as i64;
+    put_varint(out, ((delta << 1) ^ (delta >> 63)) as u64);
+}
+
+fn put_delta32(out: &mut Vec<u8>, v: u32, base: u32) {
+    put_delta(out, v.wrapping_sub(base) as i32 as u64, 0);
+}
+
+fn delta(r: &mut Reader, base: u64) -> io::Result<u64> {
+    let v = r.varint()?;
+    Ok(base.wrapping_add(((v >> 1) as i64 ^ -((v & 1) as i64)) as u64))
+}
+
+fn delta32(r: &mut Reader, base: u32) -> io::Result<u32> {
+    Ok(base.wrapping_add(delta(r, 0)? as u32))
+}
+
+fn varint32(r: &mut Reader) -> io::Result<u32> {
+    u32::try_from(r.varint()?).map_err(|_| invalid("value out of range"))
+}
+
+/// Returns the bytes of the bitmap of the changed nodes of a proof of `len` bytes.
+fn bitmap_len(len: usize) -> usize {
+    (len + PROOF_NODE_SIZE * 8 - 1) / (PROOF_NODE_SIZE * 8)
+}
+
+fn be_word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Context {
+    fn encode(&mut self, wit: &StepWitness, out: &mut Vec<u8>) {
+        put_delta(out, wit.step, self.step.wrapping_add(1));
+        put_delta32(out, wit.instruction.addr, self.pc.wrapping_add(4));
+        out.extend(wit.instruction.bytecode.to_le_bytes());
+        self.step = wit.step;
+        self.pc = wit.instruction.addr;
+
+        self.encode_state(wit, out);
+
+        // the unchanged nodes of the proof are flagged rather than copied
+        let proof = &wit.mem_proof;
+        put_varint(out, proof.len() as u64);
+        let mut changed = vec![0u8; bitmap_len(proof.len())];
+        let mut nodes = Vec::<u8>::new();
+        for (i, node) in proof.chunks(PROOF_NODE_SIZE).enumerate() {
+            let start = i * PROOF_NODE_SIZE;
+            if self.mem_proof.get(start..start + node.len()) != Some(node) {
+                changed[i / 8] |= 1 << (i % 8);
+                nodes.extend(node);
+            }
+        }
+        out.extend(changed);
+        out.extend(nodes);
+        self.mem_proof.clone_from(proof);
+
+        put_varint(out, wit.extra_mem_accesses.len() as u64);
+        for addr in &wit.extra_mem_accesses {
+            put_varint(out, *addr as u64);
+        }
+
+        let same_preimage =
+            wit.preimage_key == self.preimage_key && wit.preimage_value == self.preimage_value;
+        if wit.preimage_key == [0; 32] && wit.preimage_value.is_empty() {
+            out.push(0);
+        } else if same_preimage {
+            out.push(1);
+        } else {
+            out.push(2);
+            out.extend(wit.preimage_key);
+            put_varint(out, wit.preimage_value.len() as u64);
+            out.extend(&wit.preimage_value);
+            self.preimage_key = wit.preimage_key;
+            self.preimage_value.clone_from(&wit.preimage_value);
+        }
+        put_varint(out, wit.preimage_offset as u64);
+
+        self.encode_accesses(&wit.mem_ops, wit.instruction.addr, out);
+        out.push(wit.kind.id() as u8);
+        match &wit.kind {
+            StepKind::Break { code } => put_varint(out, *code as u64),
+            StepKind::Syscall(syscall) => {
+                put_delta(out, syscall.step, wit.step);
+                put_varint(out, syscall.num as u64);
+                for v in syscall.args.into_iter().chain([syscall.ret.0, syscall.ret.1]) {
+                    put_varint(out, v as u64);
+                }
+                match syscall.preimage_key {
+                    Some(key) => {
+                        out.push(1);
+                        out.extend(key);
+                    }
+                    None => out.push(0),
+                }
+                match syscall.preimage_offset {
+                    Some(offset) => {
+                        out.push(1);
+                        put_varint(out, offset as u64);
+                    }
+                    None => out.push(0),
+                }
+                self.encode_accesses(&syscall.mem_ops, wit.instruction.addr, out);
+            }
+            _ => {}
+        }
+
+        match wit.register_delta {
+            Some(delta) => {
+                out.push(1);
+                out.push(delta.reg as u8);
+                put_varint(out, delta.old as u64);
+                put_varint(out, delta.new as u64);
+            }
+            None => out.push(0),
+        }
+        match wit.hilo_delta {
+            Some(delta) => {
+                out.push(1);
+                for v in [delta.old_hi, delta.old_lo, delta.hi, delta.lo] {
+                    put_varint(out, v as u64);
+                }
+            }
+            None => out.push(0),
+        }
+    }
+
+    fn decode(&mut self, r: &mut Reader) -> io::Result<StepWitness> {
+        let mut wit = StepWitness {
+            step: delta(r, self.step.wrapping_add(1))?,
+            ..Default::default()
+        };
+        let addr = delta32(r, self.pc.wrapping_add(4))?;
+        wit.instruction = Instruction { addr, bytecode: r.u32()? };
+        self.step = wit.step;
+        self.pc = addr;
+
+        wit.state = self.decode_state(&wit, r)?;
+
+        let len = r.varint()? as usize;
+        let changed = r.take(bitmap_len(len))?;
+        for start in (0..len).step_by(PROOF_NODE_SIZE) {
+            let i = start / PROOF_NODE_SIZE;
+            let end = len.min(start + PROOF_NODE_SIZE);
+            let node = if changed[i / 8] & (1 << (i % 8)) != 0 {
+                r.take(end - start)?
+            } else {
+                self.mem_proof.get(start..end)
+                    .ok_or_else(|| invalid("proof node not in the previous record"))?
+            };
+            wit.mem_proof.extend(node);
+        }
+        self.mem_proof.clone_from(&wit.mem_proof);
+
+        wit.extra_mem_accesses = (0..r.varint()?)
+            .map(|_| varint32(r))
+            .collect::<io::Result<_>>()?;
+
+        match r.take(1)?[0] {
+            0 => {}
+            1 => {
+                wit.preimage_key = self.preimage_key;
+                wit.preimage_value.clone_from(&self.preimage_value);
+            }
+            2 => {
+                wit.preimage_key = r.take(32)?.try_into().unwrap();
+                let len = r.varint()? as usize;
+                wit.preimage_value = r.take(len)?.to_vec();
+                self.preimage_key = wit.preimage_key;
+                self.preimage_value.clone_from(&wit.preimage_value);
+            }
+            tag => return Err(invalid(&format!("invalid preimage tag {}", tag))),
+        }
+        wit.preimage_offset = varint32(r)?;
+
+        wit.mem_ops = self.decode_accesses(addr, r)?;
+        wit.kind = match r.take(1)?[0] {
+            0 => StepKind::Alu,
+            1 => StepKind::Branch,
+            2 => StepKind::Jump,
+            3 => {
+                let step = delta(r, wit.step)?;
+                let num = varint32(r)?;
+                let mut args = [0; 4];
+                for arg in args.iter_mut() {
+                    *arg = varint32(r)?;
+                }
+                let ret = (varint32(r)?, varint32(r)?);
+                let preimage_key = if r.bool()? {
+                    Some(r.take(32)?.try_into().unwrap())
+                } else {
+                    None
+                };
+                let preimage_offset = if r.bool()? { Some(varint32(r)?) } else { None };
+                let mem_ops = self.decode_accesses(addr, r)?;
+                StepKind::Syscall(SyscallWitness {
+                    step,
+                    num,
+                    args,
+                    ret,
+                    preimage_key,
+                    preimage_offset,
+                    mem_ops,
+                })
+            }
+            4 => StepKind::HiLo,
+            5 => StepKind::Break { code: varint32(r)? },
+            6 => StepKind::Sync,
+            kind => return Err(invalid(&format!("unknown step kind {}", kind))),
+        };
+
+        if r.bool()? {
+            let reg = r.take(1)?[0] as u32;
+            wit.register_delta = Some(RegisterDelta { reg, old: varint32(r)?, new: varint32(r)? });
+        }
+        if r.bool()? {
+            wit.hilo_delta = Some(HiLoDelta {
+                old_hi: varint32(r)?,
+                old_lo: varint32(r)?,
+                hi: varint32(r)?,
+                lo: varint32(r)?,
+            });
+        }
+        Ok(wit)
+    }
+
+    fn encode_state(&mut self, wit: &StepWitness, out: &mut Vec<u8>) {
+        let state = &wit.state;
+        // the steps without proofs have no state witness
+        if state.len() != STATE_WITNESS_SIZE {
+            out.push(0);
+            put_varint(out, state.len() as u64);
+            out.extend(state);
+            return;
+        }
+        out.push(1);
+        let mut mask = 0u8;
+        let mut fields = Vec::<u8>::new();
+        for (bit, (start, end)) in STATE_FIELDS.into_iter().enumerate() {
+            if state[start..end] != self.state[start..end] {
+                mask |= 1 << bit;
+                fields.extend(&state[start..end]);
+            }
+        }
+        out.push(mask);
+        out.extend(fields);
+
+        let pc = be_word(state, WITNESS_PC);
+        put_delta32(out, pc, wit.instruction.addr);
+        put_delta32(out, be_word(state, WITNESS_NEXT_PC), pc.wrapping_add(4));
+        let step = u64::from_be_bytes(state[WITNESS_STEP..WITNESS_REGISTERS].try_into().unwrap());
+        put_delta(out, step, wit.step);
+
+        let registers: Vec<usize> = (0..32)
+            .filter(|i| {
+                let offset = WITNESS_REGISTERS + 4 * i;
+                state[offset..offset + 4] != self.state[offset..offset + 4]
+            })
+            .collect();
+        out.push(registers.len() as u8);
+        for i in registers {
+            out.push(i as u8);
+            put_varint(out, be_word(state, WITNESS_REGISTERS + 4 * i) as u64);
+        }
+        self.state.clone_from(state);
+    }
+
+    fn decode_state(&mut self, wit: &StepWitness, r: &mut Reader) -> io::Result<Vec<u8>> {
+        if !r.bool()? {
+            let len = r.varint()? as usize;
+            return Ok(r.take(len)?.to_vec());
+        }
+        let mut state = self.state.clone();
+        let mask = r.take(1)?[0];
+        for (bit, (start, end)) in STATE_FIELDS.into_iter().enumerate() {
+            if mask & (1 << bit) != 0 {
+                state[start..end].copy_from_slice(r.take(end - start)?);
+            }
+        }
+
+        let pc = delta32(r, wit.instruction.addr)?;
+        state[WITNESS_PC..WITNESS_PC + 4].copy_from_slice(&pc.to_be_bytes());
+        let next_pc = delta32(r, pc.wrapping_add(4))?;
+        state[WITNESS_NEXT_PC..WITNESS_NEXT_PC + 4].copy_from_slice(&next_pc.to_be_bytes());
+        let step = delta(r, wit.step)?;
+        state[WITNESS_STEP..WITNESS_REGISTERS].copy_from_slice(&step.to_be_bytes());
+
+        for _ in 0..r.take(1)?[0] {
+            let i = r.take(1)?[0] as usize;
+            if i >= 32 {
+                return Err(invalid(&format!("invalid register {}", i)));
+            }
+            let offset = WITNESS_REGISTERS + 4 * i;
+            state[offset..offset + 4].copy_from_slice(&varint32(r)?.to_be_bytes());
+        }
+        self.state.clone_from(&state);
+        Ok(state)
+    }
+
+    /// Encodes the accesses of the instruction at `pc`: the rw counters from the previous
+    /// access on, the addresses from the pc and the previous values from the values.
+    fn encode_accesses(&mut self, accesses: &[MemoryAccess], pc: u32, out: &mut Vec<u8>) {
+        put_varint(out, accesses.len() as u64);
+        for access in accesses {
+            put_delta(out, access.rw_counter, self.rw_counter.wrapping_add(1));
+            self.rw_counter = access.rw_counter;
+            put_delta32(out, access.addr, pc);
+            out.push(match access.op {
+                MemoryOperation::Read => 0,
+                MemoryOperation::Write => 1,
+                MemoryOperation::Fetch => 2,
+            });
+            put_varint(out, access.value as u64);
+            put_delta32(out, access.value_prev, access.value);
+        }
+    }
+
+    fn decode_accesses(&mut self, pc: u32, r: &mut Reader) -> io::Result<Vec<MemoryAccess>> {
+        (0..r.varint()?)
+            .map(|_| {
+                let rw_counter = delta(r, self.rw_counter.wrapping_add(1))?;
+                self.rw_counter = rw_counter;
+                let addr = delta32(r, pc)?;
+                let op = match r.take(1)?[0] {
+                    0 => MemoryOperation::Read,
+                    1 => MemoryOperation::Write,
+                    2 => MemoryOperation::Fetch,
+                    op => return Err(invalid(&format!("unknown memory operation {}", op))),
+                };
+                let value = varint32(r)?;
+                Ok(MemoryAccess { rw_counter, addr, op, value, value_prev: delta32(r, value)? })
+            })
+            .collect()
+    }
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// WitnessWriter writes the witnesses of consecutive steps as a stream, little endian:
+///
+/// - the magic `MIPSWSTR`, the u32 `WITNESS_STREAM_VERSION` and the u8 compression (0 none,
+///   1 zstd);
+/// - the records, each its varint length then the witness encoded against the previous one;
+/// - a zero length, ending the stream.
+///
+/// The stream is complete once `finish` returned, a stream without its end is truncated.
+pub struct WitnessWriter<W: Write> {
+    sink: Sink<W>,
+    context: Context,
+    record: Vec<u8>,
+    records: u64,
+}
+
+impl<W: Write> WitnessWriter<W> {
+    pub fn new(writer: W) -> io::Result<Self> {
+        Self::with_compression(writer, Compression::None)
+    }
+
+    pub fn with_compression(mut writer: W, compression: Compression) -> io::Result<Self> {
+        writer.write_all(STREAM_MAGIC)?;
+        writer.write_all(&WITNESS_STREAM_VERSION.to_le_bytes())?;
+        let sink = match compression {
+            Compression::None => {
+                writer.write_all(&[0])?;
+                Sink::Plain(writer)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                writer.write_all(&[1])?;
+                Sink::Zstd(zstd::stream::write::Encoder::new(writer, level)?)
+            }
+        };
+        Ok(Self {
+            sink,
+            context: Context::default(),
+            record: Vec::new(),
+            records: 0,
+        })
+    }
+
+    /// Appends the witness of the step after the last one written.
+    pub fn write(&mut self, wit: &StepWitness) -> io::Result<()> {
+        self.record.clear();
+        self.context.encode(wit, &mut self.record);
+        let mut len = Vec::with_capacity(4);
+        put_varint(&mut len, self.record.len() as u64);
+        self.sink.write_all(&len)?;
+        self.sink.write_all(&self.record)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// The witnesses written.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Completes the stream, returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.sink.write_all(&[0])?;
+        let mut writer = match self.sink {
+            Sink::Plain(writer) => writer,
+            #[cfg(feature = "zstd")]
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+enum Source<R: Read> {
+    Plain(R),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Plain(r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Source::Zstd(r) => r.read(buf),
+        }
+    }
+}
+
+/// WitnessReader reads back the witnesses of a stream of `WitnessWriter`, one record at a time.
+pub struct WitnessReader<R: Read> {
+    source: Source<R>,
+    context: Context,
+    record: Vec<u8>,
+    /// the end of the stream was read.
+    ended: bool,
+}
+
+impl WitnessReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> WitnessReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 13];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != STREAM_MAGIC {
+            return Err(invalid("not a witness stream"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != WITNESS_STREAM_VERSION {
+            return Err(invalid(&format!("unsupported witness stream version {}", version)));
+        }
+        let source = match header[12] {
+            0 => Source::Plain(reader),
+            #[cfg(feature = "zstd")]
+            1 => Source::Zstd(zstd::stream::read::Decoder::new(reader)?),
+            #[cfg(not(feature = "zstd"))]
+            1 => return Err(invalid("zstd compressed witness stream, the zstd feature is off")),
+            c => return Err(invalid(&format!("unknown compression {}", c))),
+        };
+        Ok(Self {
+            source,
+            context: Context::default(),
+            record: Vec::new(),
+            ended: false,
+        })
+    }
+
+    /// Returns the witness of the next step, none at the end of the stream. A stream missing
+    /// its end is truncated, the decompressor may read a truncated frame as empty.
+    pub fn next_witness(&mut self) -> io::Result<Option<StepWitness>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let mut len = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8];
+            match self.source.read_exact(&mut byte) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(invalid("truncated witness stream"));
+                }
+                result => result?,
+            }
+            len |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        if len == 0 {
+            self.ended = true;
+            return Ok(None);
+        }
+        self.record.clear();
+        (&mut self.source).take(len).read_to_end(&mut self.record)?;
+        if self.record.len() as u64 != len {
+            return Err(invalid("truncated witness stream"));
+        }
+        let mut r = Reader(&self.record);
+        let wit = self.context.decode(&mut r)?;
+        r.finish()?;
+        Ok(Some(wit))
+    }
+}
+
+impl<R: Read> Iterator for WitnessReader<R> {
+    type Item = io::Result<StepWitness>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_witness().transpose()
+    }
+}
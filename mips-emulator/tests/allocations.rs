@@ -0,0 +1,99 @@
+//! The allocations of the emulator, counted by a global allocator. The allocator counts the
+//! allocations of every test of its binary, so these tests have a binary of their own.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use mips_emulator::pre_image::EmptyPreimageOracle;
+use mips_emulator::state::{InstrumentedState, State, VmStatus};
+use sha3::{Digest, Keccak256};
+
+/// Counts the allocations of each thread, to measure the allocations of a call.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the result of `f` and the allocations it made.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(|n| n.get());
+    let out = f();
+    (out, ALLOCATIONS.with(|n| n.get()) - before)
+}
+
+fn i_type(opcode: u32, rs: u32, rt: u32, imm: i16) -> u32 {
+    (opcode << 26) | (rs << 21) | (rt << 16) | (imm as u16 as u32)
+}
+
+/// Writes its count to each of 16 pages a page apart from 0x10000000, counting down, then exits.
+fn page_walk_program() -> Vec<u32> {
+    vec![
+        i_type(0x0f, 0, 8, 0x1000), // lui $8, 0x1000
+        i_type(0x09, 0, 9, 16), // addiu $9, $0, 16
+        i_type(0x2b, 8, 9, 0), // sw $9, 0($8)
+        i_type(0x09, 8, 8, 0x1000), // addiu $8, $8, 0x1000
+        i_type(0x09, 9, 9, -1), // addiu $9, $9, -1
+        i_type(0x05, 9, 0, -4), // bne $9, $0, -4
+        0, // nop
+        i_type(0x09, 0, 4, 0), // addiu $4, $0, 0
+        i_type(0x09, 0, 2, 4246), // addiu $2, $0, 4246
+        0xc, // syscall
+    ]
+}
+
+#[test]
+fn test_reset_to_shares_pages() {
+    // the program and an image of 1k pages
+    let build = || {
+        let mut state = State::new();
+        for (i, insn) in page_walk_program().iter().enumerate() {
+            state.memory.set_memory(4 * i as u32, *insn).unwrap();
+        }
+        for i in 0..1024 {
+            state.memory.set_memory(0x20000000 + (i << 12), i).unwrap();
+        }
+        InstrumentedState::new(state, Box::new(EmptyPreimageOracle))
+    };
+    let mut is = build();
+    let snapshot = is.snapshot();
+    assert_eq!(is.run(1000).unwrap().status, VmStatus::Exited(0));
+
+    // the reset shares the pages of the snapshot, a reconstruction allocates them all
+    let (_, reset_allocations) = count_allocations(|| is.reset_to(&snapshot));
+    let (_, build_allocations) = count_allocations(build);
+    assert!(build_allocations >= 10 * reset_allocations.max(1),
+            "reset: {}, build: {}", reset_allocations, build_allocations);
+}
+
+#[test]
+fn test_streamed_state_hash_does_not_allocate() {
+    let mut state = State::new();
+    for (i, insn) in page_walk_program().iter().enumerate() {
+        state.memory.set_memory(4 * i as u32, *insn).unwrap();
+    }
+    let mut is = InstrumentedState::new(state, Box::new(EmptyPreimageOracle));
+    is.run(20).unwrap();
+
+    // with the memory root cached, streaming allocates nothing, unlike the encoding
+    is.state_hash();
+    let (streamed, streamed_allocations) = count_allocations(|| is.state_hash());
+    let (encoded, encoded_allocations) =
+        count_allocations(|| <[u8; 32]>::from(Keccak256::digest(is.state.encode_witness())));
+    assert_eq!(streamed, encoded);
+    assert_eq!(streamed_allocations, 0);
+    assert!(encoded_allocations > 0);
+}
@@ -0,0 +1,182 @@
+/*
+ * Runs fixture ELFs through the C ABI of the emulator, like a host embedding it does. The first
+ * fixture is a little endian program printing "hello, world\n" from 0x410008 and exiting, the
+ * second one reads the preimage of the keccak256 key at 0x410000 into 0x420000.
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "mips_emulator.h"
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            const char *msg = mips_vm_last_error_msg();                      \
+            fprintf(stderr, "%s:%d: check failed: %s (last error: %s)\n",    \
+                    __FILE__, __LINE__, #cond, msg ? msg : "none");          \
+            return __LINE__;                                                 \
+        }                                                                    \
+    } while (0)
+
+struct oracle {
+    unsigned hints;
+    unsigned gets;
+};
+
+static int get_preimage(void *ctx, const uint8_t *key, uint8_t *buf, size_t cap, size_t *len) {
+    (void)key;
+    (void)buf;
+    (void)cap;
+    (void)len;
+    ((struct oracle *)ctx)->gets++;
+    return 1;
+}
+
+static int hint(void *ctx, const uint8_t *data, size_t len) {
+    (void)data;
+    (void)len;
+    ((struct oracle *)ctx)->hints++;
+    return 0;
+}
+
+struct output {
+    char data[64];
+    size_t len;
+    unsigned other_fds;
+};
+
+static int write_output(void *ctx, int fd, const uint8_t *data, size_t len) {
+    struct output *output = ctx;
+    if (fd != 1) {
+        output->other_fds++;
+        return 0;
+    }
+    if (output->len + len >= sizeof(output->data)) {
+        return 1;
+    }
+    memcpy(output->data + output->len, data, len);
+    output->len += len;
+    return 0;
+}
+
+#define PREIMAGE_LEN 5000
+
+static uint8_t preimage_byte(size_t i) {
+    return (uint8_t)(i * 7 + 1);
+}
+
+struct preimage_oracle {
+    uint8_t key[32];
+    unsigned gets;
+};
+
+/* serves the preimage of `key` in two calls, the first buffer is too small for it */
+static int serve_preimage(void *ctx, const uint8_t *key, uint8_t *buf, size_t cap, size_t *len) {
+    struct preimage_oracle *oracle = ctx;
+    oracle->gets++;
+    if (memcmp(key, oracle->key, sizeof(oracle->key)) != 0) {
+        return 1;
+    }
+    *len = PREIMAGE_LEN;
+    if (cap >= PREIMAGE_LEN) {
+        for (size_t i = 0; i < PREIMAGE_LEN; i++) {
+            buf[i] = preimage_byte(i);
+        }
+    }
+    return 0;
+}
+
+static int read_file(const char *path, uint8_t **data, size_t *len) {
+    FILE *f = fopen(path, "rb");
+    if (!f) {
+        return -1;
+    }
+    fseek(f, 0, SEEK_END);
+    *len = (size_t)ftell(f);
+    fseek(f, 0, SEEK_SET);
+    *data = malloc(*len);
+    size_t n = *data ? fread(*data, 1, *len, f) : 0;
+    fclose(f);
+    return n == *len ? 0 : -1;
+}
+
+/*
+ * Returns 0 if the fixture at `elf_path` exits with the state hash `expected_hash`, the line of
+ * the failed check otherwise.
+ */
+int mips_ffi_test(const char *elf_path, const uint8_t *expected_hash) {
+    uint8_t *elf = NULL;
+    size_t elf_len = 0;
+    CHECK(read_file(elf_path, &elf, &elf_len) == 0);
+
+    /* the fixture is little endian, the default config is big endian */
+    CHECK(mips_vm_new(elf, elf_len, NULL) == NULL);
+    CHECK(mips_vm_last_error_msg() != NULL);
+    CHECK(mips_vm_new(elf, elf_len, "{\"endianess\": \"little\"}") == NULL);
+    CHECK(strstr(mips_vm_last_error_msg(), "endianess") != NULL);
+
+    MipsVm *vm = mips_vm_new(elf, elf_len, "{\"endianness\": \"little\", \"mode\": \"strict\"}");
+    free(elf);
+    CHECK(vm != NULL);
+
+    struct oracle oracle = {0, 0};
+    CHECK(mips_vm_register_oracle(vm, NULL, hint, &oracle) == MIPS_VM_ERR_NULL);
+    CHECK(mips_vm_register_oracle(vm, get_preimage, hint, &oracle) == MIPS_VM_OK);
+    struct output output = {{0}, 0, 0};
+    CHECK(mips_vm_set_output(NULL, write_output, &output) == MIPS_VM_ERR_NULL);
+    CHECK(mips_vm_set_output(vm, write_output, &output) == MIPS_VM_OK);
+
+    CHECK(mips_vm_step(vm, 2) == MIPS_VM_OK);
+    CHECK(mips_vm_step(vm, 1000) == MIPS_VM_EXITED);
+    CHECK(mips_vm_step(vm, 1) == MIPS_VM_EXITED);
+    CHECK(oracle.hints == 0 && oracle.gets == 0);
+    CHECK(strcmp(output.data, "hello, world\n") == 0 && output.other_fds == 0);
+
+    uint8_t hash[32];
+    CHECK(mips_vm_state_hash(vm, hash) == MIPS_VM_OK);
+    CHECK(memcmp(hash, expected_hash, sizeof(hash)) == 0);
+
+    char message[14] = {0};
+    CHECK(mips_vm_read_mem(vm, 0x410008, (uint8_t *)message, 13) == MIPS_VM_OK);
+    CHECK(strcmp(message, "hello, world\n") == 0);
+    CHECK(mips_vm_read_mem(vm, 0xfffffffe, (uint8_t *)message, 4) == MIPS_VM_ERR_INVALID_ARG);
+
+    CHECK(mips_vm_step(NULL, 1) == MIPS_VM_ERR_NULL);
+    CHECK(mips_vm_state_hash(vm, NULL) == MIPS_VM_ERR_NULL);
+    mips_vm_free(vm);
+    mips_vm_free(NULL);
+    return 0;
+}
+
+/*
+ * Returns 0 if the fixture at `elf_path` reads the preimage served by the host, the line of the
+ * failed check otherwise.
+ */
+int mips_ffi_preimage_test(const char *elf_path) {
+    uint8_t *elf = NULL;
+    size_t elf_len = 0;
+    CHECK(read_file(elf_path, &elf, &elf_len) == 0);
+    MipsVm *vm = mips_vm_new(elf, elf_len, "{\"endianness\": \"little\"}");
+    free(elf);
+    CHECK(vm != NULL);
+
+    struct preimage_oracle oracle = {{0}, 0};
+    CHECK(mips_vm_read_mem(vm, 0x410000, oracle.key, sizeof(oracle.key)) == MIPS_VM_OK);
+    CHECK(mips_vm_register_oracle(vm, serve_preimage, NULL, &oracle) == MIPS_VM_OK);
+    CHECK(mips_vm_step(vm, 100000) == MIPS_VM_EXITED);
+    CHECK(oracle.gets == 2);
+
+    /* the preimage follows its length as a big endian u64 */
+    static uint8_t read[8 + PREIMAGE_LEN];
+    CHECK(mips_vm_read_mem(vm, 0x420000, read, sizeof(read)) == MIPS_VM_OK);
+    uint64_t len = 0;
+    for (size_t i = 0; i < 8; i++) {
+        len = len << 8 | read[i];
+    }
+    CHECK(len == PREIMAGE_LEN);
+    for (size_t i = 0; i < PREIMAGE_LEN; i++) {
+        CHECK(read[8 + i] == preimage_byte(i));
+    }
+    mips_vm_free(vm);
+    return 0;
+}
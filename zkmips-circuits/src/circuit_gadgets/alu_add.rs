@@ -0,0 +1,506 @@
+//! AluAdd chip constrains the 32 bits addition c = a + b of add/addu, and the subtraction
+//! c = a - b of sub/subu as a = c + b, over the bytes of the operands. The signed overflow tells
+//! the trapping add/sub from addu/subu.
+
+use crate::mips_types::Field;
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, VirtualCells},
+    poly::Rotation,
+};
+
+use super::{bool_check, util::expr_from_bytes, Expr};
+
+/// Instruction that the AluAdd chip needs to implement.
+pub trait AluAddInstruction<F: Field> {
+    /// Assign the witnesses of `a + b`, or `a - b` if `is_sub`, to the AluAdd chip's region.
+    /// Returns the result and the signed overflow.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        b: Value<u32>,
+        is_sub: Value<bool>,
+    ) -> Result<(Value<u32>, Value<bool>), Error>;
+
+    /// Load the u8 lookup table.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the AluAdd chip.
+#[derive(Clone, Copy, Debug)]
+pub struct AluAddConfig {
+    /// Denotes the little endian bytes of the result.
+    pub result: [Column<Advice>; 4],
+    /// Denotes the carries out of each byte of the addition, the last one overflows 32 bits.
+    pub carry: [Column<Advice>; 4],
+    /// Denotes the sign bits of a, b and the result.
+    pub sign: [Column<Advice>; 3],
+    /// Denotes the low 7 bits of the top bytes of a, b and the result.
+    pub low: [Column<Advice>; 3],
+    /// Denotes the carry into bit 31 of the addition.
+    pub carry_31: Column<Advice>,
+    /// Denotes the signed overflow, the carry into bit 31 differs from the carry out of it.
+    pub overflow: Column<Advice>,
+    /// Denotes the range within which each byte should lie.
+    pub u8: Column<Fixed>,
+}
+
+impl AluAddConfig {
+    /// Returns the expressions of the little endian bytes of the result.
+    pub fn result_bytes<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> [Expression<F>; 4] {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        self.result.map(|column| meta.query_advice(column, rotation))
+    }
+
+    /// Returns an expression of the result.
+    pub fn result<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        expr_from_bytes(&self.result_bytes(meta, rotation))
+    }
+
+    /// Returns an expression of the signed overflow, 1 if the add or sub would trap.
+    pub fn overflow<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        meta.query_advice(self.overflow, rotation.unwrap_or_else(Rotation::cur))
+    }
+}
+
+/// Chip that computes the sum or the difference of two words.
+#[derive(Clone, Debug)]
+pub struct AluAddChip<F> {
+    config: AluAddConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> AluAddChip<F> {
+    /// Configures the AluAdd chip, `a` and `b` are the little endian bytes of the operands, each
+    /// in the u8 range, `is_sub` is a boolean selecting the subtraction.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl FnOnce(&mut VirtualCells<'_, F>) -> Expression<F>,
+        a: impl FnOnce(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        b: impl FnOnce(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        is_sub: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+    ) -> AluAddConfig {
+        let result = [(); 4].map(|_| meta.advice_column());
+        let carry = [(); 4].map(|_| meta.advice_column());
+        let sign = [(); 3].map(|_| meta.advice_column());
+        let low = [(); 3].map(|_| meta.advice_column());
+        let carry_31 = meta.advice_column();
+        let overflow = meta.advice_column();
+        let u8 = meta.fixed_column();
+
+        meta.create_gate("alu add gate", |meta| {
+            let q_enable = q_enable(meta);
+            let a = a(meta);
+            let b = b(meta);
+            let is_sub = is_sub(meta);
+            let result = result.map(|c| meta.query_advice(c, Rotation::cur()));
+            let carry = carry.map(|c| meta.query_advice(c, Rotation::cur()));
+            let sign = sign.map(|c| meta.query_advice(c, Rotation::cur()));
+            let low = low.map(|c| meta.query_advice(c, Rotation::cur()));
+            let carry_31 = meta.query_advice(carry_31, Rotation::cur());
+            let overflow = meta.query_advice(overflow, Rotation::cur());
+
+            // the addition x + b = y is a + b = c, or c + b = a for the subtraction
+            let select = |add: &Expression<F>, sub: &Expression<F>| {
+                add.clone() * (1.expr() - is_sub.clone()) + sub.clone() * is_sub.clone()
+            };
+
+            let mut checks = vec![];
+            // y = x + b, byte by byte with the carry chain
+            let mut carry_in = 0.expr();
+            for i in 0..4 {
+                let (x, y) = (select(&a[i], &result[i]), select(&result[i], &a[i]));
+                checks.push(x + b[i].clone() + carry_in - y - carry[i].clone() * 256.expr());
+                checks.push(bool_check(carry[i].clone()));
+                carry_in = carry[i].clone();
+            }
+
+            // the top bytes split in their sign bit and low 7 bits
+            for (i, byte) in [&a[3], &b[3], &result[3]].into_iter().enumerate() {
+                checks.push(byte.clone() - sign[i].clone() * 128.expr() - low[i].clone());
+                checks.push(bool_check(sign[i].clone()));
+            }
+            // the low 7 bits of the top bytes add up with the carry into bit 31
+            let (x_low, y_low) = (select(&low[0], &low[2]), select(&low[2], &low[0]));
+            checks.push(
+                x_low + low[1].clone() + carry[2].clone() - y_low - carry_31.clone() * 128.expr(),
+            );
+            checks.push(bool_check(carry_31.clone()));
+            // overflow = carry_31 xor carry out of bit 31
+            checks.push(
+                overflow
+                    - carry_31.clone()
+                    - carry[3].clone()
+                    + carry_31 * carry[3].clone() * 2.expr(),
+            );
+
+            checks.into_iter().map(move |poly| q_enable.clone() * poly)
+        });
+
+        meta.annotate_lookup_any_column(u8, || "LOOKUP_u8");
+
+        for column in result {
+            meta.lookup_any("range check for u8", |meta| {
+                let u8_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u8_cell, u8_range)]
+            });
+        }
+        // the low 7 bits lie in [0, 128): they and they + 128 are both bytes
+        for column in low {
+            meta.lookup_any("range check for u7", |meta| {
+                let u7_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u7_cell, u8_range)]
+            });
+            meta.lookup_any("range check for u7 + 128", |meta| {
+                let u7_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u7_cell + 128.expr(), u8_range)]
+            });
+        }
+
+        AluAddConfig {
+            result,
+            carry,
+            sign,
+            low,
+            carry_31,
+            overflow,
+            u8,
+        }
+    }
+
+    /// Constructs an AluAdd chip given a config.
+    pub fn construct(config: AluAddConfig) -> AluAddChip<F> {
+        AluAddChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> AluAddInstruction<F> for AluAddChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        b: Value<u32>,
+        is_sub: Value<bool>,
+    ) -> Result<(Value<u32>, Value<bool>), Error> {
+        let config = self.config();
+
+        let result = a.zip(b).zip(is_sub).map(|((a, b), is_sub)| {
+            if is_sub { a.wrapping_sub(b) } else { a.wrapping_add(b) }
+        });
+        // the operands of the addition x + b = y
+        let x = a.zip(result).zip(is_sub).map(|((a, result), is_sub)| if is_sub { result } else { a });
+        // the carry out of byte i is set if the bytes up to i overflow
+        let carries = x.zip(b).map(|(x, b)| {
+            let (x, b) = (x as u64, b as u64);
+            [1, 2, 3, 4].map(|i| {
+                let mask = (1u64 << (8 * i)) - 1;
+                ((x & mask) + (b & mask)) >> (8 * i) != 0
+            })
+        });
+        let carry_31 = x.zip(b).map(|(x, b)| ((x & 0x7fffffff) + (b & 0x7fffffff)) >> 31 != 0);
+        let overflow = carry_31.zip(carries).map(|(carry_31, carries)| carry_31 != carries[3]);
+
+        for idx in 0..4 {
+            region.assign_advice(
+                || format!("alu add chip: result byte {}", idx),
+                config.result[idx],
+                offset,
+                || result.map(|v| F::from(((v >> (8 * idx)) & 0xff) as u64)),
+            )?;
+            region.assign_advice(
+                || format!("alu add chip: carry {}", idx),
+                config.carry[idx],
+                offset,
+                || carries.map(|carries| F::from(carries[idx] as u64)),
+            )?;
+        }
+        for (idx, word) in [a, b, result].into_iter().enumerate() {
+            region.assign_advice(
+                || format!("alu add chip: sign {}", idx),
+                config.sign[idx],
+                offset,
+                || word.map(|v| F::from((v >> 31) as u64)),
+            )?;
+            region.assign_advice(
+                || format!("alu add chip: low 7 bits {}", idx),
+                config.low[idx],
+                offset,
+                || word.map(|v| F::from(((v >> 24) & 0x7f) as u64)),
+            )?;
+        }
+        region.assign_advice(
+            || "alu add chip: carry into bit 31",
+            config.carry_31,
+            offset,
+            || carry_31.map(|carry| F::from(carry as u64)),
+        )?;
+        region.assign_advice(
+            || "alu add chip: overflow",
+            config.overflow,
+            offset,
+            || overflow.map(|overflow| F::from(overflow as u64)),
+        )?;
+
+        Ok((result, overflow))
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        const RANGE: usize = 256;
+
+        layouter.assign_region(
+            || "load u8 range check table",
+            |mut region| {
+                for i in 0..RANGE {
+                    region.assign_fixed(
+                        || "assign cell in fixed column",
+                        self.config.u8,
+                        i,
+                        || Value::known(F::from(i as u64)),
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl<F: Field> Chip<F> for AluAddChip<F> {
+    type Config = AluAddConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    macro_rules! try_test_circuit {
+        ($ops:expr, $result:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($ops:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        a: [Column<Advice>; 4],
+        b: [Column<Advice>; 4],
+        is_sub: Column<Advice>,
+        expected: Column<Advice>,
+        expected_overflow: Column<Advice>,
+        alu_add: AluAddConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (a, b, is_sub, expected result, expected overflow)
+        ops: Option<Vec<(u32, u32, bool, u32, bool)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let a = [(); 4].map(|_| meta.advice_column());
+            let b = [(); 4].map(|_| meta.advice_column());
+            let is_sub = meta.advice_column();
+            let expected = meta.advice_column();
+            let expected_overflow = meta.advice_column();
+
+            let alu_add = AluAddChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| a.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| b.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| meta.query_advice(is_sub, Rotation::cur()),
+            );
+
+            let config = Self::Config {
+                q_enable,
+                a,
+                b,
+                is_sub,
+                expected,
+                expected_overflow,
+                alu_add,
+            };
+
+            meta.create_gate("check the result and the overflow", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let expected = meta.query_advice(config.expected, Rotation::cur());
+                let expected_overflow = meta.query_advice(config.expected_overflow, Rotation::cur());
+
+                vec![
+                    q_enable.clone() * (config.alu_add.result(meta, None) - expected),
+                    q_enable * (config.alu_add.overflow(meta, None) - expected_overflow),
+                ]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = AluAddChip::construct(config.alu_add);
+            let ops = self.ops.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (a, b, is_sub, expected, overflow)) in ops.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        for (columns, word) in [(config.a, a), (config.b, b)] {
+                            for (i, column) in columns.iter().enumerate() {
+                                region.assign_advice(
+                                    || "operand byte",
+                                    *column,
+                                    idx,
+                                    || Value::known(F::from(((word >> (8 * i)) & 0xff) as u64)),
+                                )?;
+                            }
+                        }
+                        region.assign_advice(
+                            || "is_sub",
+                            config.is_sub,
+                            idx,
+                            || Value::known(F::from(*is_sub as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "expected",
+                            config.expected,
+                            idx,
+                            || Value::known(F::from(*expected as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "expected overflow",
+                            config.expected_overflow,
+                            idx,
+                            || Value::known(F::from(*overflow as u64)),
+                        )?;
+                        chip.assign(
+                            &mut region,
+                            idx,
+                            Value::known(*a),
+                            Value::known(*b),
+                            Value::known(*is_sub),
+                        )?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn alu_add_no_carry() {
+        try_test_circuit!(
+            vec![
+                (1, 2, false, 3, false),
+                (0x12003400, 0x00560078, false, 0x12563478, false),
+                (5, 3, true, 2, false),
+                (0x12563478, 0x00560078, true, 0x12003400, false),
+            ],
+            Ok(())
+        );
+        try_test_circuit_error!(vec![(1, 2, false, 4, false)]);
+        try_test_circuit_error!(vec![(5, 3, true, 8, false)]);
+    }
+
+    #[test]
+    fn alu_add_carry_propagation() {
+        try_test_circuit!(
+            vec![
+                // the carry ripples through the bytes, and out of the 32 bits
+                (0x000000ff, 1, false, 0x00000100, false),
+                (0x00ffffff, 1, false, 0x01000000, false),
+                (0xffffffff, 1, false, 0, false),
+                (0xffffffff, 0xffffffff, false, 0xfffffffe, false),
+                // the borrow of the subtraction is the carry of the addition it checks
+                (0x00000100, 1, true, 0x000000ff, false),
+                (0, 1, true, 0xffffffff, false),
+            ],
+            Ok(())
+        );
+        try_test_circuit_error!(vec![(0x00ffffff, 1, false, 0x00000000, false)]);
+        try_test_circuit_error!(vec![(0, 1, true, 0x000000ff, false)]);
+    }
+
+    #[test]
+    fn alu_add_signed_overflow() {
+        try_test_circuit!(
+            vec![
+                (0x7fffffff, 1, false, 0x80000000, true),
+                (0x80000000, 0x80000000, false, 0, true),
+                (0x80000000, 0xffffffff, false, 0x7fffffff, true),
+                (0x80000000, 1, true, 0x7fffffff, true),
+                (0x7fffffff, 0xffffffff, true, 0x80000000, true),
+                // the operands of opposite signs never overflow
+                (0x7fffffff, 0x80000000, false, 0xffffffff, false),
+                (0xffffffff, 0x7fffffff, true, 0x80000000, false),
+            ],
+            Ok(())
+        );
+        // the overflow bit can't be flipped
+        try_test_circuit_error!(vec![(0x7fffffff, 1, false, 0x80000000, false)]);
+        try_test_circuit_error!(vec![(1, 2, false, 3, true)]);
+        try_test_circuit_error!(vec![(0x80000000, 1, true, 0x7fffffff, false)]);
+    }
+}
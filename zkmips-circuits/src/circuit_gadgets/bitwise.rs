@@ -0,0 +1,344 @@
+//! Bitwise chip constrains the and/or/xor/nor of two words, byte by byte with a lookup into the
+//! `BitwiseTable`. Nor looks up the or of the bytes and constrains the complement of the result.
+
+use crate::mips_types::Field;
+use crate::table::{BitwiseOp, BitwiseTable, LookupTable};
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, VirtualCells},
+    poly::Rotation,
+};
+
+use super::{bool_check, util::expr_from_bytes, Expr};
+
+/// Instruction that the Bitwise chip needs to implement.
+pub trait BitwiseInstruction<F: Field> {
+    /// Assign the witnesses of `a op b` to the Bitwise chip's region. Returns the result.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        b: Value<u32>,
+        op: Value<BitwiseOp>,
+    ) -> Result<Value<u32>, Error>;
+
+    /// Load the bitwise lookup table.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the Bitwise chip.
+#[derive(Clone, Copy, Debug)]
+pub struct BitwiseConfig {
+    /// Denotes the little endian bytes of the result.
+    pub result: [Column<Advice>; 4],
+    /// Denotes whether the operation is nor.
+    pub is_nor: Column<Advice>,
+    /// Denotes the byte operations looked up for each limb.
+    pub table: BitwiseTable,
+}
+
+impl BitwiseConfig {
+    /// Returns the expressions of the little endian bytes of the result.
+    pub fn result_bytes<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> [Expression<F>; 4] {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        self.result.map(|column| meta.query_advice(column, rotation))
+    }
+
+    /// Returns an expression of the result.
+    pub fn result<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        expr_from_bytes(&self.result_bytes(meta, rotation))
+    }
+}
+
+/// Chip that computes the bitwise operations of two words.
+#[derive(Clone, Debug)]
+pub struct BitwiseChip<F> {
+    config: BitwiseConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> BitwiseChip<F> {
+    /// Configures the Bitwise chip, `a` and `b` are the little endian bytes of the operands, `op`
+    /// is the `BitwiseOp` of the row. The lookups range check the operand bytes.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl Fn(&mut VirtualCells<'_, F>) -> Expression<F>,
+        a: impl Fn(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        b: impl Fn(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        op: impl Fn(&mut VirtualCells<F>) -> Expression<F>,
+        table: BitwiseTable,
+    ) -> BitwiseConfig {
+        let result = [(); 4].map(|_| meta.advice_column());
+        let is_nor = meta.advice_column();
+
+        meta.create_gate("bitwise gate", |meta| {
+            let q_enable = q_enable(meta);
+            let op = op(meta);
+            let is_nor = meta.query_advice(is_nor, Rotation::cur());
+
+            // an op other than nor isn't in the table with is_nor unset
+            vec![
+                q_enable.clone() * bool_check(is_nor.clone()),
+                q_enable * is_nor * (op - (BitwiseOp::Nor as u64).expr()),
+            ]
+        });
+
+        table.annotate_columns(meta);
+
+        for i in 0..4 {
+            meta.lookup_any("bitwise byte lookup", |meta| {
+                let q_enable = q_enable(meta);
+                let is_nor = meta.query_advice(is_nor, Rotation::cur());
+                let result = meta.query_advice(result[i], Rotation::cur());
+                // nor is the complement of the or, 255 - result
+                let op = op(meta) - is_nor.clone() * 2.expr();
+                let looked_up = result.clone() + is_nor * (255.expr() - result * 2.expr());
+                let inputs = [a(meta)[i].clone(), b(meta)[i].clone(), op, looked_up];
+
+                inputs
+                    .into_iter()
+                    .zip(table.table_exprs(meta))
+                    .map(|(input, table)| (q_enable.clone() * input, table))
+                    .collect()
+            });
+        }
+
+        BitwiseConfig { result, is_nor, table }
+    }
+
+    /// Constructs a Bitwise chip given a config.
+    pub fn construct(config: BitwiseConfig) -> BitwiseChip<F> {
+        BitwiseChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> BitwiseInstruction<F> for BitwiseChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        b: Value<u32>,
+        op: Value<BitwiseOp>,
+    ) -> Result<Value<u32>, Error> {
+        let config = self.config();
+
+        let result = a.zip(b).zip(op).map(|((a, b), op)| op.apply(a, b));
+        for idx in 0..4 {
+            region.assign_advice(
+                || format!("bitwise chip: result byte {}", idx),
+                config.result[idx],
+                offset,
+                || result.map(|v| F::from(((v >> (8 * idx)) & 0xff) as u64)),
+            )?;
+        }
+        region.assign_advice(
+            || "bitwise chip: is nor",
+            config.is_nor,
+            offset,
+            || op.map(|op| F::from((op == BitwiseOp::Nor) as u64)),
+        )?;
+
+        Ok(result)
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        self.config.table.load(layouter)
+    }
+}
+
+impl<F: Field> Chip<F> for BitwiseChip<F> {
+    type Config = BitwiseConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    // the table has 3 * 2^16 rows
+    const K: u32 = 18;
+
+    macro_rules! try_test_circuit {
+        ($ops:expr, $result:expr) => {{
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(K, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($ops:expr) => {{
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(K, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        a: [Column<Advice>; 4],
+        b: [Column<Advice>; 4],
+        op: Column<Advice>,
+        expected: Column<Advice>,
+        bitwise: BitwiseConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (a, b, op, expected result)
+        ops: Option<Vec<(u32, u32, BitwiseOp, u32)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let a = [(); 4].map(|_| meta.advice_column());
+            let b = [(); 4].map(|_| meta.advice_column());
+            let op = meta.advice_column();
+            let expected = meta.advice_column();
+            let table = BitwiseTable::construct(meta);
+
+            let bitwise = BitwiseChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| a.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| b.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| meta.query_advice(op, Rotation::cur()),
+                table,
+            );
+
+            let config = Self::Config {
+                q_enable,
+                a,
+                b,
+                op,
+                expected,
+                bitwise,
+            };
+
+            meta.create_gate("check the result", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let expected = meta.query_advice(config.expected, Rotation::cur());
+
+                vec![q_enable * (config.bitwise.result(meta, None) - expected)]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = BitwiseChip::construct(config.bitwise);
+            let ops = self.ops.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (a, b, op, expected)) in ops.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        for (columns, word) in [(config.a, a), (config.b, b)] {
+                            for (i, column) in columns.iter().enumerate() {
+                                region.assign_advice(
+                                    || "operand byte",
+                                    *column,
+                                    idx,
+                                    || Value::known(F::from(((word >> (8 * i)) & 0xff) as u64)),
+                                )?;
+                            }
+                        }
+                        region.assign_advice(
+                            || "op",
+                            config.op,
+                            idx,
+                            || Value::known(F::from(*op as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "expected",
+                            config.expected,
+                            idx,
+                            || Value::known(F::from(*expected as u64)),
+                        )?;
+                        chip.assign(
+                            &mut region,
+                            idx,
+                            Value::known(*a),
+                            Value::known(*b),
+                            Value::known(*op),
+                        )?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn bitwise_ops() {
+        let pairs = [
+            (0, 0),
+            (0xffffffff, 0),
+            (0x12345678, 0x0f0f0f0f),
+            (0xdeadbeef, 0xcafebabe),
+        ];
+        let mut ops = vec![];
+        for op in BitwiseOp::ALL {
+            for (a, b) in pairs {
+                ops.push((a, b, op, op.apply(a, b)));
+            }
+        }
+        try_test_circuit!(ops, Ok(()));
+    }
+
+    #[test]
+    fn bitwise_wrong_result() {
+        try_test_circuit_error!(vec![(0x12345678, 0x0f0f0f0f, BitwiseOp::And, 0x12345678)]);
+        try_test_circuit_error!(vec![(0x12345678, 0x0f0f0f0f, BitwiseOp::Xor, 0x1f3f5f7f)]);
+        // nor isn't or
+        try_test_circuit_error!(vec![(0x12345678, 0x0f0f0f0f, BitwiseOp::Nor, 0x1f3f5f7f)]);
+    }
+}
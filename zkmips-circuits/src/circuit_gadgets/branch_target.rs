@@ -0,0 +1,428 @@
+//! BranchTarget chip constrains the target of a branch, pc + 4 + (sign_extension(imm) << 2),
+//! over the bytes of the pc.
+
+use crate::mips_types::Field;
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, VirtualCells},
+    poly::Rotation,
+};
+
+use super::{
+    bool_check,
+    util::{expr_from_bytes, pow_of_two},
+    Expr,
+};
+
+/// The bits the sign extension of the 16 bits immediate shifted by 2 sets, 0xFFFF0000 << 2
+/// truncated to 32 bits.
+const SIGN_OFFSET: u64 = 0xFFFC0000;
+
+/// Instruction that the BranchTarget chip needs to implement.
+pub trait BranchTargetInstruction<F: Field> {
+    /// Assign the pc and immediate witnesses to the BranchTarget chip's region.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        pc: Value<u32>,
+        imm: Value<u32>,
+    ) -> Result<(), Error>;
+
+    /// Load the u8 lookup table.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the BranchTarget chip.
+#[derive(Clone, Copy, Debug)]
+pub struct BranchTargetConfig {
+    /// Denotes the sign bit of the immediate.
+    pub sign: Column<Advice>,
+    /// Denotes the low 15 bits of the immediate, in a byte and a 7 bits limb.
+    pub imm_low: [Column<Advice>; 2],
+    /// Denotes the little endian bytes of the sign extended immediate shifted by 2.
+    pub offset: [Column<Advice>; 4],
+    /// Denotes the little endian bytes of the branch target.
+    pub target: [Column<Advice>; 4],
+    /// Denotes the carries out of each byte of the addition, the last one overflows 32 bits.
+    pub carry: [Column<Advice>; 4],
+    /// Denotes the range within which each byte should lie.
+    pub u8: Column<Fixed>,
+}
+
+impl BranchTargetConfig {
+    /// Returns the expressions of the little endian bytes of the branch target.
+    pub fn target_bytes<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> [Expression<F>; 4] {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        self.target.map(|column| meta.query_advice(column, rotation))
+    }
+
+    /// Returns an expression of the branch target.
+    pub fn target<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        expr_from_bytes(&self.target_bytes(meta, rotation))
+    }
+}
+
+/// Chip that computes the target of a branch at pc with a 16 bits immediate.
+#[derive(Clone, Debug)]
+pub struct BranchTargetChip<F> {
+    config: BranchTargetConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> BranchTargetChip<F> {
+    /// Configures the BranchTarget chip, `pc` are the little endian bytes of the pc of the
+    /// branch, each in the u8 range, `imm` is its 16 bits immediate.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl FnOnce(&mut VirtualCells<'_, F>) -> Expression<F>,
+        pc: impl FnOnce(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        imm: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+    ) -> BranchTargetConfig {
+        let sign = meta.advice_column();
+        let imm_low = [(); 2].map(|_| meta.advice_column());
+        let offset = [(); 4].map(|_| meta.advice_column());
+        let target = [(); 4].map(|_| meta.advice_column());
+        let carry = [(); 4].map(|_| meta.advice_column());
+        let u8 = meta.fixed_column();
+
+        meta.create_gate("branch target gate", |meta| {
+            let q_enable = q_enable(meta);
+            let pc = pc(meta);
+            let imm = imm(meta);
+            let sign = meta.query_advice(sign, Rotation::cur());
+            let imm_low = imm_low.map(|c| meta.query_advice(c, Rotation::cur()));
+            let offset = offset.map(|c| meta.query_advice(c, Rotation::cur()));
+            let target = target.map(|c| meta.query_advice(c, Rotation::cur()));
+            let carry = carry.map(|c| meta.query_advice(c, Rotation::cur()));
+
+            // imm = sign * 2^15 + low 15 bits
+            let check_imm = imm.clone()
+                - sign.clone() * pow_of_two::<F>(15)
+                - expr_from_bytes(&imm_low);
+            // the sign extension sets the bits above the immediate, the shift scales it by 4
+            let check_offset = expr_from_bytes(&offset)
+                - imm * 4.expr()
+                - sign.clone() * Expression::Constant(F::from(SIGN_OFFSET));
+
+            let mut checks = vec![check_imm, check_offset, bool_check(sign)];
+            // target = pc + 4 + offset, byte by byte with the carry chain
+            let mut carry_in = 0.expr();
+            for i in 0..4 {
+                let four = if i == 0 { 4.expr() } else { 0.expr() };
+                checks.push(
+                    pc[i].clone() + four + offset[i].clone() + carry_in
+                        - target[i].clone()
+                        - carry[i].clone() * 256.expr(),
+                );
+                checks.push(bool_check(carry[i].clone()));
+                carry_in = carry[i].clone();
+            }
+
+            checks.into_iter().map(move |poly| q_enable.clone() * poly)
+        });
+
+        meta.annotate_lookup_any_column(u8, || "LOOKUP_u8");
+
+        let mut bytes = vec![];
+        bytes.push(imm_low[0]);
+        bytes.extend(offset);
+        bytes.extend(target);
+        for column in bytes {
+            meta.lookup_any("range check for u8", |meta| {
+                let u8_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u8_cell, u8_range)]
+            });
+        }
+        // the high limb of the low 15 bits lies in [0, 128): it and it + 128 are both bytes
+        meta.lookup_any("range check for u7", |meta| {
+            let u7_cell = meta.query_advice(imm_low[1], Rotation::cur());
+            let u8_range = meta.query_fixed(u8, Rotation::cur());
+            vec![(u7_cell, u8_range)]
+        });
+        meta.lookup_any("range check for u7 + 128", |meta| {
+            let u7_cell = meta.query_advice(imm_low[1], Rotation::cur());
+            let u8_range = meta.query_fixed(u8, Rotation::cur());
+            vec![(u7_cell + 128.expr(), u8_range)]
+        });
+
+        BranchTargetConfig {
+            sign,
+            imm_low,
+            offset,
+            target,
+            carry,
+            u8,
+        }
+    }
+
+    /// Constructs a BranchTarget chip given a config.
+    pub fn construct(config: BranchTargetConfig) -> BranchTargetChip<F> {
+        BranchTargetChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> BranchTargetInstruction<F> for BranchTargetChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        pc: Value<u32>,
+        imm: Value<u32>,
+    ) -> Result<(), Error> {
+        let config = self.config();
+
+        let imm = imm.map(|imm| imm & 0xffff);
+        let sign = imm.map(|imm| imm >> 15);
+        region.assign_advice(
+            || "branch target chip: sign",
+            config.sign,
+            offset,
+            || sign.map(|sign| F::from(sign as u64)),
+        )?;
+        for (idx, column) in config.imm_low.iter().enumerate() {
+            region.assign_advice(
+                || format!("branch target chip: imm low limb {}", idx),
+                *column,
+                offset,
+                || imm.map(|imm| F::from((((imm & 0x7fff) >> (8 * idx)) & 0xff) as u64)),
+            )?;
+        }
+
+        let branch_offset = imm.map(|imm| (imm as u16 as i16 as i32 as u32) << 2);
+        let target = pc.zip(branch_offset).map(|(pc, branch_offset)| {
+            pc.wrapping_add(4).wrapping_add(branch_offset)
+        });
+        // the carry out of byte i is set if the bytes up to i overflow
+        let carries = pc.zip(branch_offset).map(|(pc, branch_offset)| {
+            let (pc, branch_offset) = (pc as u64, branch_offset as u64);
+            [1, 2, 3, 4].map(|i| {
+                let mask = (1u64 << (8 * i)) - 1;
+                ((pc & mask) + 4 + (branch_offset & mask)) >> (8 * i) != 0
+            })
+        });
+
+        for idx in 0..4 {
+            region.assign_advice(
+                || format!("branch target chip: offset byte {}", idx),
+                config.offset[idx],
+                offset,
+                || branch_offset.map(|v| F::from(((v >> (8 * idx)) & 0xff) as u64)),
+            )?;
+            region.assign_advice(
+                || format!("branch target chip: target byte {}", idx),
+                config.target[idx],
+                offset,
+                || target.map(|v| F::from(((v >> (8 * idx)) & 0xff) as u64)),
+            )?;
+            region.assign_advice(
+                || format!("branch target chip: carry {}", idx),
+                config.carry[idx],
+                offset,
+                || carries.map(|carries| F::from(carries[idx] as u64)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        const RANGE: usize = 256;
+
+        layouter.assign_region(
+            || "load u8 range check table",
+            |mut region| {
+                for i in 0..RANGE {
+                    region.assign_fixed(
+                        || "assign cell in fixed column",
+                        self.config.u8,
+                        i,
+                        || Value::known(F::from(i as u64)),
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl<F: Field> Chip<F> for BranchTargetChip<F> {
+    type Config = BranchTargetConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    macro_rules! try_test_circuit {
+        ($branches:expr, $result:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                branches: Some($branches),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($branches:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                branches: Some($branches),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        pc: [Column<Advice>; 4],
+        imm: Column<Advice>,
+        expected: Column<Advice>,
+        branch_target: BranchTargetConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (pc, imm, expected target)
+        branches: Option<Vec<(u32, u32, u32)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let pc = [(); 4].map(|_| meta.advice_column());
+            let imm = meta.advice_column();
+            let expected = meta.advice_column();
+
+            let branch_target = BranchTargetChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| pc.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| meta.query_advice(imm, Rotation::cur()),
+            );
+
+            let config = Self::Config {
+                q_enable,
+                pc,
+                imm,
+                expected,
+                branch_target,
+            };
+
+            meta.create_gate("check the branch target", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let expected = meta.query_advice(config.expected, Rotation::cur());
+
+                vec![q_enable * (config.branch_target.target(meta, None) - expected)]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = BranchTargetChip::construct(config.branch_target);
+            let branches = self.branches.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (pc, imm, expected)) in branches.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        for (i, column) in config.pc.iter().enumerate() {
+                            region.assign_advice(
+                                || "pc byte",
+                                *column,
+                                idx,
+                                || Value::known(F::from(((pc >> (8 * i)) & 0xff) as u64)),
+                            )?;
+                        }
+                        region.assign_advice(
+                            || "imm",
+                            config.imm,
+                            idx,
+                            || Value::known(F::from(*imm as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "expected",
+                            config.expected,
+                            idx,
+                            || Value::known(F::from(*expected as u64)),
+                        )?;
+                        chip.assign(&mut region, idx, Value::known(*pc), Value::known(*imm))?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn branch_target() {
+        // ok
+        try_test_circuit!(
+            vec![
+                // forward
+                (0x00400000, 0x0003, 0x00400010),
+                (0x00400000, 0x7fff, 0x00420000),
+                // backward
+                (0x00400100, 0xfffe, 0x004000fc),
+                (0x00400000, 0x8000, 0x003e0004),
+                // the carry propagates through the bytes, and out of the 32 bits
+                (0x000000fc, 0x0000, 0x00000100),
+                (0x00fffff8, 0x0001, 0x01000000),
+                (0xfffffffc, 0x0000, 0x00000000),
+                (0x00000000, 0xffff, 0x00000000),
+            ],
+            Ok(())
+        );
+        // error
+        try_test_circuit_error!(vec![(0x00400000, 0x0003, 0x0040000c)]);
+        try_test_circuit_error!(vec![(0x00400100, 0xfffe, 0x004400fc)]);
+        try_test_circuit_error!(vec![(0x000000fc, 0x0000, 0x00000000)]);
+    }
+}
@@ -0,0 +1,352 @@
+//! JumpTarget chip constrains the target of a j/jal, the top 4 bits of the delay slot address
+//! followed by the 26 bits index shifted by 2, like `decode::jump_target` of the emulator.
+
+use crate::mips_types::Field;
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, VirtualCells},
+    poly::Rotation,
+};
+
+use super::{
+    util::{expr_from_bytes, pow_of_two},
+    Expr,
+};
+
+/// Instruction that the JumpTarget chip needs to implement.
+pub trait JumpTargetInstruction<F: Field> {
+    /// Assign the delay slot address and instruction witnesses to the JumpTarget chip's region.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        next_pc: Value<u32>,
+        insn: Value<u32>,
+    ) -> Result<(), Error>;
+
+    /// Load the u8 lookup table.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the JumpTarget chip.
+#[derive(Clone, Copy, Debug)]
+pub struct JumpTargetConfig {
+    /// Denotes the high and the low nibble of the top byte of the delay slot address.
+    pub nibbles: [Column<Advice>; 2],
+    /// Denotes the little endian bytes of the jump target.
+    pub target: [Column<Advice>; 4],
+    /// Denotes the range within which each byte should lie.
+    pub u8: Column<Fixed>,
+}
+
+impl JumpTargetConfig {
+    /// Returns the expressions of the little endian bytes of the jump target.
+    pub fn target_bytes<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> [Expression<F>; 4] {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        self.target.map(|column| meta.query_advice(column, rotation))
+    }
+
+    /// Returns an expression of the jump target.
+    pub fn target<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        expr_from_bytes(&self.target_bytes(meta, rotation))
+    }
+}
+
+/// Chip that computes the target of a j/jal.
+#[derive(Clone, Debug)]
+pub struct JumpTargetChip<F> {
+    config: JumpTargetConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> JumpTargetChip<F> {
+    /// Configures the JumpTarget chip, `next_pc_top` is the top byte of the delay slot address
+    /// in the u8 range, `index` the 26 bits index of the instruction.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl FnOnce(&mut VirtualCells<'_, F>) -> Expression<F>,
+        next_pc_top: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+        index: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+    ) -> JumpTargetConfig {
+        let nibbles = [(); 2].map(|_| meta.advice_column());
+        let target = [(); 4].map(|_| meta.advice_column());
+        let u8 = meta.fixed_column();
+
+        meta.create_gate("jump target gate", |meta| {
+            let q_enable = q_enable(meta);
+            let next_pc_top = next_pc_top(meta);
+            let index = index(meta);
+            let [high, low] = nibbles.map(|c| meta.query_advice(c, Rotation::cur()));
+            let target = target.map(|c| meta.query_advice(c, Rotation::cur()));
+
+            let check_nibbles = next_pc_top - high.clone() * 16.expr() - low;
+            let check_target =
+                expr_from_bytes(&target) - high * pow_of_two::<F>(28) - index * 4.expr();
+
+            [check_nibbles, check_target]
+                .into_iter()
+                .map(move |poly| q_enable.clone() * poly)
+        });
+
+        meta.annotate_lookup_any_column(u8, || "LOOKUP_u8");
+
+        for column in target {
+            meta.lookup_any("range check for u8", |meta| {
+                let u8_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u8_cell, u8_range)]
+            });
+        }
+        // a nibble lies in [0, 16): it and it + 240 are both bytes
+        for column in nibbles {
+            meta.lookup_any("range check for u4", |meta| {
+                let u4_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u4_cell, u8_range)]
+            });
+            meta.lookup_any("range check for u4 + 240", |meta| {
+                let u4_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u4_cell + 240.expr(), u8_range)]
+            });
+        }
+
+        JumpTargetConfig { nibbles, target, u8 }
+    }
+
+    /// Constructs a JumpTarget chip given a config.
+    pub fn construct(config: JumpTargetConfig) -> JumpTargetChip<F> {
+        JumpTargetChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> JumpTargetInstruction<F> for JumpTargetChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        next_pc: Value<u32>,
+        insn: Value<u32>,
+    ) -> Result<(), Error> {
+        let config = self.config();
+
+        let nibbles = next_pc.map(|next_pc| [next_pc >> 28, (next_pc >> 24) & 0xf]);
+        for (idx, column) in config.nibbles.iter().enumerate() {
+            region.assign_advice(
+                || format!("jump target chip: nibble {}", idx),
+                *column,
+                offset,
+                || nibbles.map(|nibbles| F::from(nibbles[idx] as u64)),
+            )?;
+        }
+
+        let target = next_pc.zip(insn).map(|(next_pc, insn)| {
+            (next_pc & 0xF0000000) | ((insn & 0x03ffFFff) << 2)
+        });
+        for (idx, column) in config.target.iter().enumerate() {
+            region.assign_advice(
+                || format!("jump target chip: target byte {}", idx),
+                *column,
+                offset,
+                || target.map(|v| F::from(((v >> (8 * idx)) & 0xff) as u64)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        const RANGE: usize = 256;
+
+        layouter.assign_region(
+            || "load u8 range check table",
+            |mut region| {
+                for i in 0..RANGE {
+                    region.assign_fixed(
+                        || "assign cell in fixed column",
+                        self.config.u8,
+                        i,
+                        || Value::known(F::from(i as u64)),
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl<F: Field> Chip<F> for JumpTargetChip<F> {
+    type Config = JumpTargetConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    macro_rules! try_test_circuit {
+        ($jumps:expr, $result:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                jumps: Some($jumps),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($jumps:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                jumps: Some($jumps),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        next_pc_top: Column<Advice>,
+        index: Column<Advice>,
+        expected: Column<Advice>,
+        jump_target: JumpTargetConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (next_pc, insn, expected target)
+        jumps: Option<Vec<(u32, u32, u32)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let next_pc_top = meta.advice_column();
+            let index = meta.advice_column();
+            let expected = meta.advice_column();
+
+            let jump_target = JumpTargetChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| meta.query_advice(next_pc_top, Rotation::cur()),
+                |meta| meta.query_advice(index, Rotation::cur()),
+            );
+
+            let config = Self::Config {
+                q_enable,
+                next_pc_top,
+                index,
+                expected,
+                jump_target,
+            };
+
+            meta.create_gate("check the jump target", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let expected = meta.query_advice(config.expected, Rotation::cur());
+
+                vec![q_enable * (config.jump_target.target(meta, None) - expected)]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = JumpTargetChip::construct(config.jump_target);
+            let jumps = self.jumps.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (next_pc, insn, expected)) in jumps.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        region.assign_advice(
+                            || "next pc top byte",
+                            config.next_pc_top,
+                            idx,
+                            || Value::known(F::from((next_pc >> 24) as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "index",
+                            config.index,
+                            idx,
+                            || Value::known(F::from((insn & 0x03ffffff) as u64)),
+                        )?;
+                        region.assign_advice(
+                            || "expected",
+                            config.expected,
+                            idx,
+                            || Value::known(F::from(*expected as u64)),
+                        )?;
+                        chip.assign(&mut region, idx, Value::known(*next_pc), Value::known(*insn))?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn jump_target() {
+        // j 0x200 and j 0x0ffffff0, whose index has the top bit set
+        let (j_low, j_high) = (0x08000080, 0x0bfffffc);
+        // ok
+        try_test_circuit!(
+            vec![
+                (0x00400004, j_low, 0x00000200),
+                // the index is not sign extended
+                (0x00400004, j_high, 0x0ffffff0),
+                // the delay slot on either side of the 256 MiB region boundary
+                (0x0ffffffc, j_low, 0x00000200),
+                (0x10000000, j_low, 0x10000200),
+                (0x10000000, j_high, 0x1ffffff0),
+                (0xfffffffc, j_high, 0xfffffff0),
+            ],
+            Ok(())
+        );
+        // error
+        try_test_circuit_error!(vec![(0x00400004, j_high, 0xfffffff0)]);
+        try_test_circuit_error!(vec![(0x10000000, j_low, 0x00000200)]);
+    }
+}
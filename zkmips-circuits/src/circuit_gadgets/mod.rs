@@ -8,6 +8,12 @@ pub mod is_zero;
 pub mod util;
 pub mod less_than;
 pub mod binary_number;
+pub mod branch_target;
+pub mod jump_target;
+pub mod alu_add;
+pub mod bitwise;
+pub mod shift;
+pub mod syscall;
 mod batch_is_zero;
 
 use halo2_proofs::plonk::Expression;
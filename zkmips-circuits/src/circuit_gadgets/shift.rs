@@ -0,0 +1,495 @@
+//! Shift chip constrains the shifts sll/srl/sra and their variable forms sllv/srlv/srav of a
+//! word by a 5 bits amount. The word is multiplied by a power of two into a 64 bits product,
+//! x * 2^s for a left shift and x * 2^(32 - s) for a right shift: the low word of the product is
+//! the left shift, the high word the logical right shift. The arithmetic right shift fills the
+//! vacated bits with the sign bit, adding sign * (2^32 - 2^(32 - s)).
+
+use crate::mips_types::Field;
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, VirtualCells},
+    poly::Rotation,
+};
+
+use super::{bool_check, util::expr_from_bytes, Expr};
+
+/// Instruction that the Shift chip needs to implement.
+pub trait ShiftInstruction<F: Field> {
+    /// Assign the witnesses of `a` shifted by `shamt` to the Shift chip's region, right if
+    /// `is_right` and filled with the sign bit if `is_arith` too. Returns the result.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        shamt: Value<u32>,
+        is_right: Value<bool>,
+        is_arith: Value<bool>,
+    ) -> Result<Value<u32>, Error>;
+
+    /// Load the u8 lookup table and the table of the powers of two.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the Shift chip.
+#[derive(Clone, Copy, Debug)]
+pub struct ShiftConfig {
+    /// Denotes the little endian bytes of the result.
+    pub result: [Column<Advice>; 4],
+    /// Denotes the little endian bytes of the low word of the product.
+    pub lo: [Column<Advice>; 4],
+    /// Denotes the little endian bytes of the high word of the product.
+    pub hi: [Column<Advice>; 4],
+    /// Denotes the power of two multiplying the operand, 2^s or 2^(32 - s).
+    pub pow: Column<Advice>,
+    /// Denotes the sign bit of the operand.
+    pub sign: Column<Advice>,
+    /// Denotes the low 7 bits of the top byte of the operand.
+    pub low: Column<Advice>,
+    /// Denotes the range within which each byte should lie.
+    pub u8: Column<Fixed>,
+    /// Denotes the (shamt, is_right, pow) rows of the table of the powers of two, the rows with
+    /// the tag set.
+    pub pow_table: [Column<Fixed>; 4],
+}
+
+impl ShiftConfig {
+    /// Returns the expressions of the little endian bytes of the result.
+    pub fn result_bytes<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> [Expression<F>; 4] {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        self.result.map(|column| meta.query_advice(column, rotation))
+    }
+
+    /// Returns an expression of the result.
+    pub fn result<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        expr_from_bytes(&self.result_bytes(meta, rotation))
+    }
+}
+
+/// Chip that computes the shifts of a word.
+#[derive(Clone, Debug)]
+pub struct ShiftChip<F> {
+    config: ShiftConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> ShiftChip<F> {
+    /// Configures the Shift chip, `a` are the little endian bytes of the operand, each in the u8
+    /// range, `shamt` the shift amount, the sa field or the low 5 bits of rs. `is_right` and
+    /// `is_arith` select the right and the arithmetic shifts, `is_arith` is ignored for a left
+    /// shift. The lookup of the power of two checks that `shamt` lies in [0, 32) and that
+    /// `is_right` is a boolean.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl Fn(&mut VirtualCells<'_, F>) -> Expression<F>,
+        a: impl FnOnce(&mut VirtualCells<F>) -> [Expression<F>; 4],
+        shamt: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+        is_right: impl Fn(&mut VirtualCells<F>) -> Expression<F>,
+        is_arith: impl FnOnce(&mut VirtualCells<F>) -> Expression<F>,
+    ) -> ShiftConfig {
+        let result = [(); 4].map(|_| meta.advice_column());
+        let lo = [(); 4].map(|_| meta.advice_column());
+        let hi = [(); 4].map(|_| meta.advice_column());
+        let pow = meta.advice_column();
+        let sign = meta.advice_column();
+        let low = meta.advice_column();
+        let u8 = meta.fixed_column();
+        let pow_table = [(); 4].map(|_| meta.fixed_column());
+
+        meta.create_gate("shift gate", |meta| {
+            let q_enable = q_enable(meta);
+            let a = a(meta);
+            let is_right = is_right(meta);
+            let is_arith = is_arith(meta);
+            let result = expr_from_bytes(&result.map(|c| meta.query_advice(c, Rotation::cur())));
+            let lo = expr_from_bytes(&lo.map(|c| meta.query_advice(c, Rotation::cur())));
+            let hi = expr_from_bytes(&hi.map(|c| meta.query_advice(c, Rotation::cur())));
+            let pow = meta.query_advice(pow, Rotation::cur());
+            let sign = meta.query_advice(sign, Rotation::cur());
+            let low = meta.query_advice(low, Rotation::cur());
+            let word = 256.expr() * 256.expr() * 256.expr() * 256.expr();
+
+            // the low word is the left shift, the high word the logical right shift
+            let shifted = hi.clone() * is_right.clone() + lo.clone() * (1.expr() - is_right.clone());
+            // the sign bit fills the top s bits of the arithmetic right shift
+            let fill = is_right * is_arith * sign.clone() * (word.clone() - pow.clone());
+
+            let checks = vec![
+                // x * pow = hi * 2^32 + lo, unique as both words are range checked
+                expr_from_bytes(&a) * pow - hi * word - lo,
+                result - shifted - fill,
+                // the top byte splits in its sign bit and low 7 bits
+                a[3].clone() - sign.clone() * 128.expr() - low,
+                bool_check(sign),
+            ];
+
+            checks.into_iter().map(move |poly| q_enable.clone() * poly)
+        });
+
+        meta.annotate_lookup_any_column(u8, || "LOOKUP_u8");
+
+        meta.lookup_any("power of two of the shift amount", |meta| {
+            let q_enable = q_enable(meta);
+            let inputs = [
+                1.expr(),
+                shamt(meta),
+                is_right(meta),
+                meta.query_advice(pow, Rotation::cur()),
+            ];
+            inputs
+                .into_iter()
+                .zip(pow_table)
+                .map(|(input, column)| (q_enable.clone() * input, meta.query_fixed(column, Rotation::cur())))
+                .collect()
+        });
+
+        for column in result.into_iter().chain(lo).chain(hi) {
+            meta.lookup_any("range check for u8", |meta| {
+                let u8_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(u8, Rotation::cur());
+                vec![(u8_cell, u8_range)]
+            });
+        }
+        // the low 7 bits lie in [0, 128): they and they + 128 are both bytes
+        meta.lookup_any("range check for u7", |meta| {
+            let u7_cell = meta.query_advice(low, Rotation::cur());
+            let u8_range = meta.query_fixed(u8, Rotation::cur());
+            vec![(u7_cell, u8_range)]
+        });
+        meta.lookup_any("range check for u7 + 128", |meta| {
+            let u7_cell = meta.query_advice(low, Rotation::cur());
+            let u8_range = meta.query_fixed(u8, Rotation::cur());
+            vec![(u7_cell + 128.expr(), u8_range)]
+        });
+
+        ShiftConfig {
+            result,
+            lo,
+            hi,
+            pow,
+            sign,
+            low,
+            u8,
+            pow_table,
+        }
+    }
+
+    /// Constructs a Shift chip given a config.
+    pub fn construct(config: ShiftConfig) -> ShiftChip<F> {
+        ShiftChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> ShiftInstruction<F> for ShiftChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        a: Value<u32>,
+        shamt: Value<u32>,
+        is_right: Value<bool>,
+        is_arith: Value<bool>,
+    ) -> Result<Value<u32>, Error> {
+        let config = self.config();
+
+        let pow = shamt.zip(is_right).map(|(shamt, is_right)| {
+            if is_right { 1u64 << (32 - shamt) } else { 1u64 << shamt }
+        });
+        let product = a.zip(pow).map(|(a, pow)| a as u64 * pow);
+        let result = a.zip(shamt).zip(is_right.zip(is_arith)).map(|((a, shamt), (is_right, is_arith))| {
+            match (is_right, is_arith) {
+                (false, _) => a << shamt,
+                (true, false) => a >> shamt,
+                (true, true) => ((a as i32) >> shamt) as u32,
+            }
+        });
+
+        for idx in 0..4 {
+            for (column, word, name) in [
+                (config.result[idx], result.map(|v| v as u64), "result"),
+                (config.lo[idx], product, "product low word"),
+                (config.hi[idx], product.map(|v| v >> 32), "product high word"),
+            ] {
+                region.assign_advice(
+                    || format!("shift chip: {} byte {}", name, idx),
+                    column,
+                    offset,
+                    || word.map(|v| F::from((v >> (8 * idx)) & 0xff)),
+                )?;
+            }
+        }
+        region.assign_advice(
+            || "shift chip: power of two",
+            config.pow,
+            offset,
+            || pow.map(F::from),
+        )?;
+        region.assign_advice(
+            || "shift chip: sign",
+            config.sign,
+            offset,
+            || a.map(|v| F::from((v >> 31) as u64)),
+        )?;
+        region.assign_advice(
+            || "shift chip: low 7 bits",
+            config.low,
+            offset,
+            || a.map(|v| F::from(((v >> 24) & 0x7f) as u64)),
+        )?;
+
+        Ok(result)
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        const RANGE: usize = 256;
+
+        layouter.assign_region(
+            || "load u8 range check table",
+            |mut region| {
+                for i in 0..RANGE {
+                    region.assign_fixed(
+                        || "assign cell in fixed column",
+                        self.config.u8,
+                        i,
+                        || Value::known(F::from(i as u64)),
+                    )?;
+                }
+                Ok(())
+            },
+        )?;
+
+        layouter.assign_region(
+            || "load power of two table",
+            |mut region| {
+                let mut offset = 0;
+                for is_right in [false, true] {
+                    for shamt in 0..32u64 {
+                        let pow = if is_right { 1u64 << (32 - shamt) } else { 1u64 << shamt };
+                        for (column, value) in self.config.pow_table.into_iter().zip([1, shamt, is_right as u64, pow]) {
+                            region.assign_fixed(
+                                || "assign cell in fixed column",
+                                column,
+                                offset,
+                                || Value::known(F::from(value)),
+                            )?;
+                        }
+                        offset += 1;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl<F: Field> Chip<F> for ShiftChip<F> {
+    type Config = ShiftConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    macro_rules! try_test_circuit {
+        ($ops:expr, $result:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($ops:expr) => {{
+            let k = 9;
+            let circuit = TestCircuit::<Fp> {
+                ops: Some($ops),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(k, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        a: [Column<Advice>; 4],
+        shamt: Column<Advice>,
+        is_right: Column<Advice>,
+        is_arith: Column<Advice>,
+        expected: Column<Advice>,
+        shift: ShiftConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (a, shamt, is_right, is_arith, expected result)
+        ops: Option<Vec<(u32, u32, bool, bool, u32)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let a = [(); 4].map(|_| meta.advice_column());
+            let shamt = meta.advice_column();
+            let is_right = meta.advice_column();
+            let is_arith = meta.advice_column();
+            let expected = meta.advice_column();
+
+            let shift = ShiftChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| a.map(|c| meta.query_advice(c, Rotation::cur())),
+                |meta| meta.query_advice(shamt, Rotation::cur()),
+                |meta| meta.query_advice(is_right, Rotation::cur()),
+                |meta| meta.query_advice(is_arith, Rotation::cur()),
+            );
+
+            let config = Self::Config {
+                q_enable,
+                a,
+                shamt,
+                is_right,
+                is_arith,
+                expected,
+                shift,
+            };
+
+            meta.create_gate("check the result", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let expected = meta.query_advice(config.expected, Rotation::cur());
+
+                vec![q_enable * (config.shift.result(meta, None) - expected)]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = ShiftChip::construct(config.shift);
+            let ops = self.ops.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (a, shamt, is_right, is_arith, expected)) in ops.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        for (i, column) in config.a.iter().enumerate() {
+                            region.assign_advice(
+                                || "operand byte",
+                                *column,
+                                idx,
+                                || Value::known(F::from(((a >> (8 * i)) & 0xff) as u64)),
+                            )?;
+                        }
+                        for (column, value, name) in [
+                            (config.shamt, *shamt, "shamt"),
+                            (config.is_right, *is_right as u32, "is_right"),
+                            (config.is_arith, *is_arith as u32, "is_arith"),
+                            (config.expected, *expected, "expected"),
+                        ] {
+                            region.assign_advice(
+                                || name,
+                                column,
+                                idx,
+                                || Value::known(F::from(value as u64)),
+                            )?;
+                        }
+                        chip.assign(
+                            &mut region,
+                            idx,
+                            Value::known(*a),
+                            Value::known(*shamt),
+                            Value::known(*is_right),
+                            Value::known(*is_arith),
+                        )?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn shift_logical() {
+        try_test_circuit!(
+            vec![
+                // sll
+                (0x80000001, 0, false, false, 0x80000001),
+                (0x80000001, 1, false, false, 0x00000002),
+                (0x80000001, 31, false, false, 0x80000000),
+                (0x12345678, 4, false, false, 0x23456780),
+                // srl
+                (0x80000001, 0, true, false, 0x80000001),
+                (0x80000001, 1, true, false, 0x40000000),
+                (0x80000001, 31, true, false, 0x00000001),
+                (0x12345678, 4, true, false, 0x01234567),
+            ],
+            Ok(())
+        );
+        try_test_circuit_error!(vec![(0x80000001, 1, false, false, 0x00000003)]);
+        try_test_circuit_error!(vec![(0x80000001, 1, true, false, 0xc0000000)]);
+    }
+
+    #[test]
+    fn shift_arithmetic() {
+        try_test_circuit!(
+            vec![
+                // sra fills with the sign bit
+                (0x80000001, 0, true, true, 0x80000001),
+                (0x80000001, 1, true, true, 0xc0000000),
+                (0x80000001, 31, true, true, 0xffffffff),
+                (0x7fffffff, 1, true, true, 0x3fffffff),
+                (0x7fffffff, 31, true, true, 0x00000000),
+                // a left shift is logical, whatever is_arith
+                (0x80000001, 1, false, true, 0x00000002),
+            ],
+            Ok(())
+        );
+        // the sign bit can't be dropped nor made up
+        try_test_circuit_error!(vec![(0x80000001, 1, true, true, 0x40000000)]);
+        try_test_circuit_error!(vec![(0x7fffffff, 1, true, true, 0xbfffffff)]);
+    }
+}
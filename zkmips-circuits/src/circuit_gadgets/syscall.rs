@@ -0,0 +1,464 @@
+//! Syscall chip constrains the effect of a syscall on v0/v1, given the syscall number in v0. The
+//! number is looked up in the `SyscallTable` with the kind of the syscall, one boolean per kind
+//! selects the results among the supported syscalls. Brk, clone and exit_group are constrained,
+//! the exit code of exit_group too: a0 is split into bytes, the low one is the exit code. The
+//! results of mmap, read, write and fcntl are witnessed unconstrained for now: they depend on
+//! the heap, the file descriptors and the memory the syscall reads or writes.
+
+use crate::mips_types::Field;
+use crate::table::{LookupTable, Syscall, SyscallTable};
+use halo2_proofs::{
+    circuit::{Chip, Layouter, Region, Value},
+    plonk::{Advice, Column, ConstraintSystem, Error, Expression, Fixed, VirtualCells},
+    poly::Rotation,
+};
+use mips_emulator::layout::BRK_START;
+
+use super::{
+    bool_check,
+    util::{expr_from_bytes, select, sum},
+    Expr,
+};
+
+/// Instruction that the Syscall chip needs to implement.
+pub trait SyscallInstruction<F: Field> {
+    /// Assign the witnesses of the syscall `num` with the first argument `a0` to the Syscall
+    /// chip's region, `result` is the (v0, v1) after the syscall taken from the trace.
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        num: Value<u32>,
+        a0: Value<u32>,
+        result: Value<(u32, u32)>,
+    ) -> Result<(), Error>;
+
+    /// Load the syscall lookup table and the u8 lookup table.
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error>;
+}
+
+/// Config for the Syscall chip.
+#[derive(Clone, Copy, Debug)]
+pub struct SyscallConfig {
+    /// Denotes whether the syscall is `Syscall::ALL[i]`, exactly one is set.
+    pub is_syscall: [Column<Advice>; 7],
+    /// Denotes v0 after the syscall.
+    pub v0: Column<Advice>,
+    /// Denotes v1 after the syscall.
+    pub v1: Column<Advice>,
+    /// Denotes the little endian bytes of a0.
+    pub a0: [Column<Advice>; 4],
+    /// Denotes the range within which each byte should lie.
+    pub u8: Column<Fixed>,
+    /// Denotes the (number, kind) rows of the supported syscalls.
+    pub table: SyscallTable,
+}
+
+impl SyscallConfig {
+    /// Returns an expression that is 1 if the syscall is `syscall`, 0 otherwise.
+    pub fn is<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        syscall: Syscall,
+        rotation: Option<Rotation>,
+    ) -> Expression<F> {
+        let rotation = rotation.unwrap_or_else(Rotation::cur);
+        meta.query_advice(self.is_syscall[syscall as usize - 1], rotation)
+    }
+
+    /// Returns an expression that is 1 if the syscall exits the guest, its exit code is
+    /// `exit_code`.
+    pub fn exited<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        self.is(meta, Syscall::ExitGroup, rotation)
+    }
+
+    /// Returns an expression of the exit code of exit_group, the low byte of a0. It is only
+    /// constrained when the syscall exits.
+    pub fn exit_code<F: Field>(
+        &self,
+        meta: &mut VirtualCells<F>,
+        rotation: Option<Rotation>,
+    ) -> Expression<F> {
+        meta.query_advice(self.a0[0], rotation.unwrap_or_else(Rotation::cur))
+    }
+
+    /// Returns an expression of v0 after the syscall.
+    pub fn v0<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        meta.query_advice(self.v0, rotation.unwrap_or_else(Rotation::cur))
+    }
+
+    /// Returns an expression of v1 after the syscall.
+    pub fn v1<F: Field>(&self, meta: &mut VirtualCells<F>, rotation: Option<Rotation>) -> Expression<F> {
+        meta.query_advice(self.v1, rotation.unwrap_or_else(Rotation::cur))
+    }
+}
+
+/// Chip that constrains the results of the supported syscalls.
+#[derive(Clone, Debug)]
+pub struct SyscallChip<F> {
+    config: SyscallConfig,
+    _marker: std::marker::PhantomData<F>,
+}
+
+impl<F: Field> SyscallChip<F> {
+    /// Configures the Syscall chip, `num` is v0, `a0` is a0 and `v1` is v1 before the syscall.
+    /// The lookup rejects the syscalls the circuit doesn't support.
+    pub fn configure(
+        meta: &mut ConstraintSystem<F>,
+        q_enable: impl Fn(&mut VirtualCells<'_, F>) -> Expression<F>,
+        num: impl Fn(&mut VirtualCells<F>) -> Expression<F>,
+        a0: impl Fn(&mut VirtualCells<F>) -> Expression<F>,
+        v1: impl Fn(&mut VirtualCells<F>) -> Expression<F>,
+        table: SyscallTable,
+    ) -> SyscallConfig {
+        let config = SyscallConfig {
+            is_syscall: [(); 7].map(|_| meta.advice_column()),
+            v0: meta.advice_column(),
+            v1: meta.advice_column(),
+            a0: [(); 4].map(|_| meta.advice_column()),
+            u8: meta.fixed_column(),
+            table,
+        };
+
+        meta.create_gate("syscall gate", |meta| {
+            let q_enable = q_enable(meta);
+            let is = Syscall::ALL.map(|syscall| config.is(meta, syscall, None));
+            let [_, is_brk, is_clone, is_exit, ..] = is.clone();
+            let v0_out = config.v0(meta, None);
+            let v1_out = config.v1(meta, None);
+
+            // brk returns the start of the brk region, clone returns 1 as if it were the child
+            // thread, exit_group leaves the registers untouched. The other syscalls keep the
+            // witnessed results.
+            let expected_v0 = select::expr(
+                is_brk.clone(),
+                (BRK_START as u64).expr(),
+                select::expr(
+                    is_clone.clone(),
+                    1.expr(),
+                    select::expr(is_exit.clone(), num(meta), v0_out.clone()),
+                ),
+            );
+            let expected_v1 = select::expr(
+                is_brk + is_clone,
+                0.expr(),
+                select::expr(is_exit.clone(), v1(meta), v1_out.clone()),
+            );
+            // the bytes of a0 hold the exit code of exit_group
+            let a0_bytes = config.a0.map(|column| meta.query_advice(column, Rotation::cur()));
+            let check_a0 = a0(meta) - expr_from_bytes(&a0_bytes);
+
+            let mut constraints: Vec<_> =
+                is.iter().map(|is| q_enable.clone() * bool_check(is.clone())).collect();
+            constraints.push(q_enable.clone() * (sum::expr(&is) - 1.expr()));
+            constraints.push(q_enable.clone() * (v0_out - expected_v0));
+            constraints.push(q_enable.clone() * (v1_out - expected_v1));
+            constraints.push(q_enable * is_exit * check_a0);
+            constraints
+        });
+
+        meta.annotate_lookup_any_column(config.u8, || "LOOKUP_u8");
+
+        for column in config.a0 {
+            meta.lookup_any("range check for u8", |meta| {
+                let u8_cell = meta.query_advice(column, Rotation::cur());
+                let u8_range = meta.query_fixed(config.u8, Rotation::cur());
+                vec![(u8_cell, u8_range)]
+            });
+        }
+
+        table.annotate_columns(meta);
+
+        meta.lookup_any("syscall number lookup", |meta| {
+            let q_enable = q_enable(meta);
+            let kind = sum::expr(
+                Syscall::ALL.map(|kind| config.is(meta, kind, None) * (kind as u64).expr()),
+            );
+            let inputs = [num(meta), kind];
+
+            inputs
+                .into_iter()
+                .zip(table.table_exprs(meta))
+                .map(|(input, table)| (q_enable.clone() * input, table))
+                .collect()
+        });
+
+        config
+    }
+
+    /// Constructs a Syscall chip given a config.
+    pub fn construct(config: SyscallConfig) -> SyscallChip<F> {
+        SyscallChip { config, _marker: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field> SyscallInstruction<F> for SyscallChip<F> {
+    fn assign(
+        &self,
+        region: &mut Region<'_, F>,
+        offset: usize,
+        num: Value<u32>,
+        a0: Value<u32>,
+        result: Value<(u32, u32)>,
+    ) -> Result<(), Error> {
+        let config = self.config();
+
+        // an unsupported syscall sets no flag, which fails the gate
+        let syscall = num.map(Syscall::from_number);
+        for (column, kind) in config.is_syscall.iter().zip(Syscall::ALL) {
+            region.assign_advice(
+                || format!("syscall chip: is {:?}", kind),
+                *column,
+                offset,
+                || syscall.map(|syscall| F::from((syscall == Some(kind)) as u64)),
+            )?;
+        }
+        region.assign_advice(
+            || "syscall chip: v0",
+            config.v0,
+            offset,
+            || result.map(|(v0, _)| F::from(v0 as u64)),
+        )?;
+        region.assign_advice(
+            || "syscall chip: v1",
+            config.v1,
+            offset,
+            || result.map(|(_, v1)| F::from(v1 as u64)),
+        )?;
+        for (idx, column) in config.a0.iter().enumerate() {
+            region.assign_advice(
+                || format!("syscall chip: a0 byte {}", idx),
+                *column,
+                offset,
+                || a0.map(|a0| F::from(a0.to_le_bytes()[idx] as u64)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn load(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        const RANGE: usize = 256;
+
+        self.config.table.load(layouter)?;
+        layouter.assign_region(
+            || "load u8 range check table",
+            |mut region| {
+                for i in 0..RANGE {
+                    region.assign_fixed(
+                        || "assign cell in fixed column",
+                        self.config.u8,
+                        i,
+                        || Value::known(F::from(i as u64)),
+                    )?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+impl<F: Field> Chip<F> for SyscallChip<F> {
+    type Config = SyscallConfig;
+    type Loaded = ();
+
+    fn config(&self) -> &Self::Config {
+        &self.config
+    }
+
+    fn loaded(&self) -> &Self::Loaded {
+        &()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mips_types::Field;
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner, Value},
+        dev::MockProver,
+        halo2curves::bn256::Fr as Fp,
+        plonk::{Advice, Circuit, Column, ConstraintSystem, Error, Selector},
+        poly::Rotation,
+    };
+    use std::marker::PhantomData;
+
+    const K: u32 = 9;
+
+    macro_rules! try_test_circuit {
+        ($syscalls:expr, $result:expr) => {{
+            let circuit = TestCircuit::<Fp> {
+                syscalls: Some($syscalls),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(K, &circuit, vec![]).unwrap();
+            assert_eq!(prover.verify(), $result);
+        }};
+    }
+
+    macro_rules! try_test_circuit_error {
+        ($syscalls:expr) => {{
+            let circuit = TestCircuit::<Fp> {
+                syscalls: Some($syscalls),
+                _marker: PhantomData,
+            };
+            let prover = MockProver::<Fp>::run(K, &circuit, vec![]).unwrap();
+            assert!(prover.verify().is_err());
+        }};
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestCircuitConfig {
+        q_enable: Selector,
+        num: Column<Advice>,
+        a0: Column<Advice>,
+        v1: Column<Advice>,
+        exited: Column<Advice>,
+        exit_code: Column<Advice>,
+        syscall: SyscallConfig,
+    }
+
+    #[derive(Default)]
+    struct TestCircuit<F: Field> {
+        // (v0, a0, v1 before the syscall, (v0, v1) after it, the exit code if it exited)
+        syscalls: Option<Vec<(u32, u32, u32, (u32, u32), Option<u8>)>>,
+        _marker: PhantomData<F>,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self::default()
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let q_enable = meta.complex_selector();
+            let num = meta.advice_column();
+            let a0 = meta.advice_column();
+            let v1 = meta.advice_column();
+            let exited = meta.advice_column();
+            let exit_code = meta.advice_column();
+            let table = SyscallTable::construct(meta);
+
+            let syscall = SyscallChip::configure(
+                meta,
+                |meta| meta.query_selector(q_enable),
+                |meta| meta.query_advice(num, Rotation::cur()),
+                |meta| meta.query_advice(a0, Rotation::cur()),
+                |meta| meta.query_advice(v1, Rotation::cur()),
+                table,
+            );
+
+            let config = Self::Config {
+                q_enable,
+                num,
+                a0,
+                v1,
+                exited,
+                exit_code,
+                syscall,
+            };
+
+            meta.create_gate("check exited", |meta| {
+                let q_enable = meta.query_selector(q_enable);
+                let exited = meta.query_advice(config.exited, Rotation::cur());
+                let exit_code = meta.query_advice(config.exit_code, Rotation::cur());
+                let is_exit = config.syscall.exited(meta, None);
+                let check_exit_code = config.syscall.exit_code(meta, None) - exit_code;
+
+                vec![
+                    q_enable.clone() * (is_exit.clone() - exited),
+                    q_enable * is_exit * check_exit_code,
+                ]
+            });
+
+            config
+        }
+
+        fn synthesize(
+            &self,
+            config: Self::Config,
+            mut layouter: impl Layouter<F>,
+        ) -> Result<(), Error> {
+            let chip = SyscallChip::construct(config.syscall);
+            let syscalls = self.syscalls.as_ref().ok_or(Error::Synthesis)?;
+
+            chip.load(&mut layouter)?;
+
+            layouter.assign_region(
+                || "witness",
+                |mut region| {
+                    for (idx, (num, a0, v1, result, exit)) in syscalls.iter().enumerate() {
+                        config.q_enable.enable(&mut region, idx)?;
+                        let inputs = [
+                            (config.num, *num),
+                            (config.a0, *a0),
+                            (config.v1, *v1),
+                            (config.exited, exit.is_some() as u32),
+                            (config.exit_code, exit.unwrap_or(0) as u32),
+                        ];
+                        for (column, value) in inputs {
+                            region.assign_advice(
+                                || "input",
+                                column,
+                                idx,
+                                || Value::known(F::from(value as u64)),
+                            )?;
+                        }
+                        let (num, a0, result) =
+                            (Value::known(*num), Value::known(*a0), Value::known(*result));
+                        chip.assign(&mut region, idx, num, a0, result)?;
+                    }
+
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    const BRK: u32 = 4045;
+    const CLONE: u32 = 4120;
+
+    const EXIT_GROUP: u32 = 4246;
+
+    #[test]
+    fn syscall_brk() {
+        try_test_circuit!(vec![(BRK, 0, 0, (BRK_START, 0), None)], Ok(()));
+        // the results don't depend on a0 or v1 before the syscall
+        try_test_circuit!(vec![(BRK, 0x10000, 0x1234, (BRK_START, 0), None)], Ok(()));
+        try_test_circuit_error!(vec![(BRK, 0, 0, (BRK_START + 0x1000, 0), None)]);
+        try_test_circuit_error!(vec![(BRK, 0, 0, (BRK_START, 9), None)]);
+        try_test_circuit_error!(vec![(BRK, 0, 0, (BRK_START, 0), Some(0))]);
+    }
+
+    #[test]
+    fn syscall_clone() {
+        try_test_circuit!(
+            vec![(CLONE, 0, 0, (1, 0), None), (CLONE, 0, 7, (1, 0), None)],
+            Ok(())
+        );
+        try_test_circuit_error!(vec![(CLONE, 0, 0, (0, 0), None)]);
+        try_test_circuit_error!(vec![(CLONE, 0, 0, (1, 7), None)]);
+    }
+
+    #[test]
+    fn syscall_exit_group() {
+        try_test_circuit!(vec![(EXIT_GROUP, 1, 3, (EXIT_GROUP, 3), Some(1))], Ok(()));
+        // the exit code is the low byte of a0
+        try_test_circuit!(vec![(EXIT_GROUP, 0x1ff, 3, (EXIT_GROUP, 3), Some(0xff))], Ok(()));
+        try_test_circuit_error!(vec![(EXIT_GROUP, 1, 3, (EXIT_GROUP, 3), Some(0))]);
+        try_test_circuit_error!(vec![(EXIT_GROUP, 0x1ff, 3, (EXIT_GROUP, 3), Some(1))]);
+        try_test_circuit_error!(vec![(EXIT_GROUP, 1, 3, (0, 0), Some(1))]);
+        try_test_circuit_error!(vec![(EXIT_GROUP, 1, 3, (EXIT_GROUP, 3), None)]);
+    }
+
+    #[test]
+    fn syscall_unsupported() {
+        // getpid
+        try_test_circuit_error!(vec![(4020, 0, 0, (1, 0), None)]);
+    }
+}
@@ -583,6 +583,7 @@ mod tests {
     use halo2_proofs::dev::MockProver;
     use halo2_proofs::halo2curves::pasta::pallas;
     use halo2_proofs::plonk::{Circuit, ConstraintSystem, Error};
+    use mips_emulator::config::VmConfig;
     use mips_emulator::state::State;
     use mips_emulator::witness::{Instruction, Program, ProgramSegment};
     use crate::program::{ProgramCommitDomain, ProgramFixedBases, ProgramHashDomain, ProgramTableChip, ProgramTableConfig, Q};
@@ -779,7 +780,7 @@ mod tests {
         let (mut state, mut program) = State::load_elf(&file);
 
         state.patch_go(&file);
-        state.patch_stack();
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
 
         program.load_instructions(&mut state);
         let res = program.compute_hash();
@@ -807,7 +808,7 @@ mod tests {
         let (mut state, mut program) = State::load_elf(&file);
 
         state.patch_go(&file);
-        state.patch_stack();
+        state.patch_stack(&VmConfig::default().random_seed).unwrap();
 
         program.load_instructions(&mut state);
 
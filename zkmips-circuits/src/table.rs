@@ -5,9 +5,7 @@ use halo2_proofs::{
     poly::Rotation,
 };
 
-use mips_emulator::witness::{
-    MemoryAccess, MemoryOperation, Program,
-};
+use mips_emulator::witness::{self, OpcodeRow};
 
 use num_traits::{FromPrimitive, One, Zero};
 use std::ops::{Shl, BitAnd, Index};
@@ -16,8 +14,12 @@ use itertools::Itertools;
 
 mod rw_table;
 mod opcode_table;
+mod bitwise_table;
+mod syscall_table;
+pub use bitwise_table::{BitwiseOp, BitwiseTable};
 pub use opcode_table::OpcodeTable;
 pub use rw_table::RwTable;
+pub use syscall_table::{Syscall, SyscallTable};
 use crate::util::int_to_field;
 
 /// Trait used to define lookup tables
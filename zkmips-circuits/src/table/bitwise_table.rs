@@ -0,0 +1,102 @@
+use super::*;
+use halo2_proofs::plonk::Fixed;
+
+/// The bitwise operations of the and/or/xor/nor instructions, the discriminant is the `op` of
+/// the `BitwiseTable` rows. Nor has no rows, it is the complement of or.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitwiseOp {
+    And = 0,
+    Or = 1,
+    Xor = 2,
+    Nor = 3,
+}
+
+impl BitwiseOp {
+    pub const ALL: [BitwiseOp; 4] = [BitwiseOp::And, BitwiseOp::Or, BitwiseOp::Xor, BitwiseOp::Nor];
+
+    /// The operations with rows in the `BitwiseTable`.
+    pub const TABLE: [BitwiseOp; 3] = [BitwiseOp::And, BitwiseOp::Or, BitwiseOp::Xor];
+
+    pub fn apply(self, a: u32, b: u32) -> u32 {
+        match self {
+            BitwiseOp::And => a & b,
+            BitwiseOp::Or => a | b,
+            BitwiseOp::Xor => a ^ b,
+            BitwiseOp::Nor => !(a | b),
+        }
+    }
+}
+
+/// The table of (a, b, op, a op b) over all pairs of bytes, 3 * 2^16 rows.
+#[derive(Debug, Copy, Clone)]
+pub struct BitwiseTable {
+    // Left byte
+    pub a: Column<Fixed>,
+    // Right byte
+    pub b: Column<Fixed>,
+    // BitwiseOp of the row
+    pub op: Column<Fixed>,
+    // a op b
+    pub result: Column<Fixed>,
+}
+
+impl<F: Field> LookupTable<F> for BitwiseTable {
+    fn columns(&self) -> Vec<Column<Any>> {
+        vec![
+            self.a.into(),
+            self.b.into(),
+            self.op.into(),
+            self.result.into(),
+        ]
+    }
+
+    fn annotations(&self) -> Vec<String> {
+        vec![
+            String::from("a"),
+            String::from("b"),
+            String::from("op"),
+            String::from("result"),
+        ]
+    }
+}
+
+impl BitwiseTable {
+    pub fn construct<F: Field>(meta: &mut ConstraintSystem<F>) -> Self {
+        Self {
+            a: meta.fixed_column(),
+            b: meta.fixed_column(),
+            op: meta.fixed_column(),
+            result: meta.fixed_column(),
+        }
+    }
+
+    pub fn load<F: Field>(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        layouter.assign_region(
+            || "bitwise table",
+            |mut region| {
+                let mut offset = 0;
+                for op in BitwiseOp::TABLE {
+                    for a in 0..256u32 {
+                        for b in 0..256u32 {
+                            for (column, value) in [
+                                (self.a, a),
+                                (self.b, b),
+                                (self.op, op as u32),
+                                (self.result, op.apply(a, b)),
+                            ] {
+                                region.assign_fixed(
+                                    || "assign row on bitwise table",
+                                    column,
+                                    offset,
+                                    || Value::known(F::from(value as u64)),
+                                )?;
+                            }
+                            offset += 1;
+                        }
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+}
@@ -6,6 +6,8 @@ pub struct OpcodeTable {
     pub address: Column<Advice>,
     // Bytecode
     pub bytecode: Column<Advice>,
+    // 1 if the emulator executes the bytecode, see `opcode_id::is_supported`
+    pub supported: Column<Advice>,
 }
 
 impl<F: Field> LookupTable<F> for OpcodeTable {
@@ -13,6 +15,7 @@ impl<F: Field> LookupTable<F> for OpcodeTable {
         vec![
             self.address.into(),
             self.bytecode.into(),
+            self.supported.into(),
         ]
     }
 
@@ -20,6 +23,7 @@ impl<F: Field> LookupTable<F> for OpcodeTable {
         vec![
             String::from("address"),
             String::from("bytecode"),
+            String::from("supported"),
         ]
     }
 }
@@ -29,6 +33,7 @@ impl OpcodeTable {
         Self {
             address: meta.advice_column(),
             bytecode: meta.advice_column(),
+            supported: meta.advice_column(),
         }
     }
 
@@ -36,11 +41,12 @@ impl OpcodeTable {
         &self,
         region: &mut Region<'_, F>,
         offset: usize,
-        row: (Value<F>, Value<F>),
+        row: (Value<F>, Value<F>, Value<F>),
     ) -> Result<(), Error> {
         for (column, value) in [
             (self.address, row.0),
-            (self.bytecode, row.1)
+            (self.bytecode, row.1),
+            (self.supported, row.2),
         ] {
             region.assign_advice(|| "assign bytecode on bytecode table",
                                  column, offset, || value)?;
@@ -48,47 +54,28 @@ impl OpcodeTable {
         Ok(())
     }
 
-    /// Assign the `BytecodeTable` from a `Program`
+    /// Assign the `BytecodeTable` from the rows of `Program::opcode_rows`
     pub fn load<F: Field>(
         &self,
         layouter: &mut impl Layouter<F>,
-        program: &Program,
+        rows: &[OpcodeRow],
     ) -> Result<(), Error> {
         layouter.assign_region(
             || "bytecode table",
-            |mut region| self.load_with_region(&mut region, program),
+            |mut region| self.load_with_region(&mut region, rows),
         )
     }
 
     pub fn load_with_region<F: Field>(
         &self,
         region: &mut Region<'_, F>,
-        program: &Program,
+        rows: &[OpcodeRow],
     ) -> Result<(), Error> {
-        let (mut cur_segment, mut cur_instruction) = (0, 0);
-        let mut offset = 0;
-        loop {
-            match program.next_instruction(cur_segment, cur_instruction) {
-                (None, _, _) => {
-                    break
-                }
-                (Some(instruction), cursor1, cursor2) => {
-                    let addr = Value::known(
-                        int_to_field::<u32, 32, F>(instruction.addr));
-                    let bytecode = Value::known(
-                        int_to_field::<u32, 32, F>(instruction.bytecode));
-
-                    self.assign(
-                        region,
-                        offset,
-                        (addr, bytecode)
-                    )?;
-
-                    offset += 1;
-                    cur_segment = cursor1;
-                    cur_instruction = cursor2;
-                }
-            }
+        for (offset, row) in rows.iter().enumerate() {
+            let addr = Value::known(int_to_field::<u32, 32, F>(row.address));
+            let bytecode = Value::known(int_to_field::<u32, 32, F>(row.bytecode));
+            let supported = Value::known(F::from(row.supported as u64));
+            self.assign(region, offset, (addr, bytecode, supported))?;
         }
         Ok(())
     }
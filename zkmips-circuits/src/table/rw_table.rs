@@ -71,11 +71,11 @@ impl RwTable {
         Ok(())
     }
 
-    /// Assign the `RwTable` from a `RwMap`
+    /// Assign the `RwTable` from the rows of `WitnessTables::rw`
     pub fn load<F: Field>(
         &self,
         layouter: &mut impl Layouter<F>,
-        rws: &[MemoryAccess],
+        rws: &[witness::RwRow],
         n_rows: usize,
     ) -> Result<(), Error> {
         layouter.assign_region(
@@ -87,7 +87,7 @@ impl RwTable {
     pub(crate) fn load_with_region<F: Field>(
         &self,
         region: &mut Region<'_, F>,
-        rws: &[MemoryAccess],
+        rws: &[witness::RwRow],
         n_rows: usize,
     ) -> Result<(), Error> {
         let (rows, _) = RwVec::table_assignments_prepad(rws, n_rows);
@@ -123,17 +123,13 @@ impl<F: Field> RwRow<F> {
 }
 
 impl<F: Field> RwRow<Value<F>> {
-    pub fn table_assignment(mem_access: &MemoryAccess) -> Self {
-        let rw_counter: F = int_to_field::<u64, 64, F>(mem_access.rw_counter);
-        let is_write = if matches!(mem_access.op, MemoryOperation::Write) {
-            F::ONE
-        } else {
-            F::ZERO
-        };
-        let address= int_to_field::<u32, 32, F>(mem_access.addr);
-        let value = int_to_field::<u32, 32, F>(mem_access.value);
-        let value_prev = int_to_field::<u32, 32, F>(mem_access.value_prev);
-        let init_value = F::ZERO;
+    pub fn table_assignment(row: &witness::RwRow) -> Self {
+        let rw_counter: F = int_to_field::<u64, 64, F>(row.rw_counter);
+        let is_write = F::from(row.is_write as u64);
+        let address= int_to_field::<u32, 32, F>(row.address);
+        let value = int_to_field::<u32, 32, F>(row.value);
+        let value_prev = int_to_field::<u32, 32, F>(row.value_prev);
+        let init_value = int_to_field::<u32, 32, F>(row.init_value);
 
         Self {
             rw_counter: Value::known(rw_counter),
@@ -166,10 +162,10 @@ impl<F: Field> RwRow<Value<F>> {
 }
 
 #[derive(Default, Clone, Debug)]
-pub struct RwVec(pub Vec<MemoryAccess>);
+pub struct RwVec(pub Vec<witness::RwRow>);
 
 impl Index<usize> for RwVec {
-    type Output = MemoryAccess;
+    type Output = witness::RwRow;
 
     fn index(&self, index: usize) -> &Self::Output {
         &self.0[index]
@@ -189,16 +185,6 @@ impl RwVec {
         }
     }
 
-    /// Build Rws for assignment
-    pub fn table_assignments(&mut self) {
-        self.0.sort_by_key(|row| {
-            (
-                row.addr,
-                row.rw_counter,
-            )
-        });
-    }
-
     /// Calculates the number of Rw::Start rows needed.
     /// `target_len` is allowed to be 0 as an "auto" mode,
     /// then only 1 Rw::Start row will be prepadded.
@@ -217,16 +203,16 @@ impl RwVec {
     }
 
     /// Prepad rows to target length
-    pub fn table_assignments_prepad(rows: &[MemoryAccess], target_len: usize)
-        -> (Vec<MemoryAccess>, usize) {
+    pub fn table_assignments_prepad(rows: &[witness::RwRow], target_len: usize)
+        -> (Vec<witness::RwRow>, usize) {
         // Remove Start rows as we will add them from scratch.
-        let rows: Vec<MemoryAccess> = rows
+        let rows: Vec<witness::RwRow> = rows
             .iter()
             .cloned()
             .collect();
         let padding_length = Self::padding_len(rows.len(), target_len);
         let padding = (1..=padding_length)
-            .map(|rw_counter| MemoryAccess::default());
+            .map(|rw_counter| witness::RwRow::default());
         (padding.chain(rows.into_iter()).collect(), padding_length)
     }
 }
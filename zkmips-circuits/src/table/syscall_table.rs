@@ -0,0 +1,93 @@
+use super::*;
+use halo2_proofs::plonk::Fixed;
+
+/// The syscalls the circuit supports, the discriminant is the `kind` of the `SyscallTable` rows.
+/// No kind is 0, so the unassigned rows of the table match no enabled syscall.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Syscall {
+    Mmap = 1,
+    Brk = 2,
+    Clone = 3,
+    ExitGroup = 4,
+    Read = 5,
+    Write = 6,
+    Fcntl = 7,
+}
+
+impl Syscall {
+    pub const ALL: [Syscall; 7] = [
+        Syscall::Mmap,
+        Syscall::Brk,
+        Syscall::Clone,
+        Syscall::ExitGroup,
+        Syscall::Read,
+        Syscall::Write,
+        Syscall::Fcntl,
+    ];
+
+    /// Returns the syscall number of the o32 ABI, read from v0.
+    pub fn number(self) -> u32 {
+        match self {
+            Syscall::Mmap => 4090,
+            Syscall::Brk => 4045,
+            Syscall::Clone => 4120,
+            Syscall::ExitGroup => 4246,
+            Syscall::Read => 4003,
+            Syscall::Write => 4004,
+            Syscall::Fcntl => 4055,
+        }
+    }
+
+    pub fn from_number(num: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|syscall| syscall.number() == num)
+    }
+}
+
+/// The table of (number, kind) of the supported syscalls.
+#[derive(Debug, Copy, Clone)]
+pub struct SyscallTable {
+    // Syscall number
+    pub num: Column<Fixed>,
+    // Syscall of the row
+    pub kind: Column<Fixed>,
+}
+
+impl<F: Field> LookupTable<F> for SyscallTable {
+    fn columns(&self) -> Vec<Column<Any>> {
+        vec![self.num.into(), self.kind.into()]
+    }
+
+    fn annotations(&self) -> Vec<String> {
+        vec![String::from("num"), String::from("kind")]
+    }
+}
+
+impl SyscallTable {
+    pub fn construct<F: Field>(meta: &mut ConstraintSystem<F>) -> Self {
+        Self {
+            num: meta.fixed_column(),
+            kind: meta.fixed_column(),
+        }
+    }
+
+    pub fn load<F: Field>(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        layouter.assign_region(
+            || "syscall table",
+            |mut region| {
+                for (offset, syscall) in Syscall::ALL.into_iter().enumerate() {
+                    for (column, value) in
+                        [(self.num, syscall.number()), (self.kind, syscall as u32)]
+                    {
+                        region.assign_fixed(
+                            || "assign row on syscall table",
+                            column,
+                            offset,
+                            || Value::known(F::from(value as u64)),
+                        )?;
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+}